@@ -3,28 +3,37 @@
 
 use crate::services::{demand_proxy::DemandProxyClient, PresetServiceRegistry};
 use anyhow::{Context as AnyhowContext, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use futures::{future::*, *};
+#[cfg(unix)]
+use snocat::common::protocol::proxy_unix::UnixStreamService;
 use snocat::{
   common::{
-    authentication::{AuthenticationAttributes, SimpleAckAuthenticationHandler},
+    authentication::{
+      AuthenticationAttributes, AuthenticationHandler, AuthenticationHandlerExt,
+      CertificateAuthenticationHandler, JwtAuthenticationHandler,
+      PresharedKeyAuthenticationHandler, SimpleAckAuthenticationHandler,
+    },
     daemon::{
       ArcRecordConstructor, ModularDaemon, PeerTracker, PeersView, RecordConstructorArgs,
       RecordConstructorResult,
     },
     protocol::{
-      negotiation::NegotiationClient,
+      datagram::DatagramDispatcher,
+      negotiation::{self, NegotiationClient},
       proxy_tcp::{DnsTarget, TcpStreamService},
+      proxy_udp::UdpProxyService,
       service::{Client, Request, Router, RouterResult, RoutingError},
       tunnel::{
-        id::MonotonicAtomicGenerator, registry::memory::InMemoryTunnelRegistry, TunnelId,
-        TunnelName, TunnelSide, TunnelUplink,
+        id::MonotonicAtomicGenerator, registry::memory::InMemoryTunnelRegistry, ArcTunnel,
+        TunnelId, TunnelName, TunnelSide, TunnelUplink,
       },
     },
     tunnel_source::DynamicConnectionSet,
   },
   util::tunnel_stream::WrappedStream,
 };
-use std::{path::PathBuf, sync::Arc};
+use std::{ops::RangeInclusive, path::PathBuf, sync::Arc};
 use tokio_util::sync::CancellationToken;
 
 #[derive(Eq, PartialEq, Clone, Debug)]
@@ -33,18 +42,50 @@ pub struct ClientArgs {
   pub driver_host: std::net::SocketAddr,
   pub driver_san: String,
   pub proxy_target_host: std::net::SocketAddr,
+  pub allowed_unix_sockets: Vec<PathBuf>,
+  /// Certificate chain and private key presented to the server, enabling certificate authentication
+  pub client_cert: Option<(PathBuf, PathBuf)>,
+  /// Name to authenticate as using the pre-shared key held in [PRESHARED_KEY_VARIABLE]
+  pub preshared_key_name: Option<String>,
+  /// File holding a signed token to present to the server, enabling JWT authentication
+  ///
+  /// The file is read anew upon each re-authentication, so the token may be refreshed in place.
+  pub jwt_file: Option<PathBuf>,
+  /// Offer negotiation protocol v1 to the server, falling back to v0 for servers without it
+  pub offer_negotiation_v1: bool,
 }
 
+/// Environment variable holding the base64 key shared with the server
+pub const PRESHARED_KEY_VARIABLE: &str = "SNOCAT_PSK_KEY";
+
 pub struct SnocatClientRouter {
   peers: Arc<PeersView>,
+  protocol_versions: Option<RangeInclusive<u8>>,
 }
 
 impl SnocatClientRouter {
   pub fn new(peers: PeersView) -> Self {
     Self {
       peers: peers.into(),
+      protocol_versions: None,
     }
   }
+
+  /// Sets the negotiation protocol versions offered to the services of connected tunnels
+  ///
+  /// If unset, only requests with headers offer v1, as by [NegotiationClient::with_headers].
+  /// Services which only speak v0 are retried over another link with v0 alone, as by
+  /// [NegotiationClient::negotiate_with_fallback]. Panics if `protocol_versions` is empty or is
+  /// not a subset of [SUPPORTED_PROTOCOL_VERSIONS](negotiation::SUPPORTED_PROTOCOL_VERSIONS).
+  pub fn with_protocol_versions(mut self, protocol_versions: RangeInclusive<u8>) -> Self {
+    assert!(
+      negotiation::is_supported_range(&protocol_versions),
+      "Unsupported negotiation protocol version range {:?}",
+      protocol_versions
+    );
+    self.protocol_versions = Some(protocol_versions);
+    self
+  }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -77,6 +118,7 @@ impl Router for SnocatClientRouter {
     let err_addr = request.address.clone();
     let err_not_found = move || RoutingError::RouteNotFound(err_addr.clone());
     let peers = self.peers.clone();
+    let protocol_versions = self.protocol_versions.clone();
     async move {
       // Select the highest keyed tunnel or bail; the highest tunnel is the newest connection, in our case
       let record = peers
@@ -87,11 +129,26 @@ impl Router for SnocatClientRouter {
         .open_link()
         .await
         .map_err(RoutingError::LinkOpenFailure)?;
-      let negotiator = NegotiationClient::new();
-      let link = negotiator
-        .negotiate::<_, Self::Error>(addr.clone(), link)
+      let mut negotiator = NegotiationClient::new()
+        .with_service_versions(TProtocolClient::service_versions())
+        .with_headers(request.headers);
+      if let Some(protocol_versions) = protocol_versions {
+        negotiator = negotiator.with_protocol_versions(protocol_versions);
+      }
+      let (link, service_version) = negotiator
+        .negotiate_with_fallback::<_, Self::Error, _, _>(addr.clone(), link, || {
+          record
+            .tunnel
+            .open_link()
+            .map_err(RoutingError::LinkOpenFailure)
+        })
         .await?;
-      Ok(request.protocol_client.handle(addr, link))
+      let tunnel: ArcTunnel<'static> = Arc::new(Arc::clone(&record.tunnel));
+      Ok(
+        request
+          .protocol_client
+          .handle(addr, service_version, link, tunnel),
+      )
     }
     .boxed()
   }
@@ -123,12 +180,18 @@ pub async fn client_main(config: ClientArgs) -> Result<()> {
       .map(|c| c.0)
       .collect::<Vec<_>>(),
   );
-  let mut crypto_config = rustls::ClientConfig::builder()
+  let crypto_config = rustls::ClientConfig::builder()
     .with_safe_default_cipher_suites()
     .with_safe_default_kx_groups()
     .with_protocol_versions(&[&rustls::version::TLS13])?
-    .with_root_certificates(root_authorities)
-    .with_no_client_auth();
+    .with_root_certificates(root_authorities);
+  let mut crypto_config = match &config.client_cert {
+    Some((cert, key)) => crypto_config.with_client_auth_cert(
+      crate::server::read_certificates(cert)?,
+      crate::server::read_private_key(key)?,
+    )?,
+    None => crypto_config.with_no_client_auth(),
+  };
 
   crypto_config.alpn_protocols = vec![crate::util::ALPN_MS_SNOCAT_1.to_vec()];
   crypto_config.key_log = Arc::new(rustls::KeyLogFile::new());
@@ -152,6 +215,17 @@ pub async fn client_main(config: ClientArgs) -> Result<()> {
   let tcp_proxy_service = TcpStreamService::new(false);
   service_registry.add_service_blocking(Arc::new(tcp_proxy_service));
 
+  let datagram_dispatcher = Arc::new(DatagramDispatcher::new());
+  let udp_proxy_service =
+    UdpProxyService::new(false).with_datagram_dispatcher(Arc::clone(&datagram_dispatcher));
+  service_registry.add_service_blocking(Arc::new(udp_proxy_service));
+
+  #[cfg(unix)]
+  if !config.allowed_unix_sockets.is_empty() {
+    let unix_proxy_service = UnixStreamService::new(config.allowed_unix_sockets.iter());
+    service_registry.add_service_blocking(Arc::new(unix_proxy_service));
+  }
+
   let tunnel_registry = Arc::new(InMemoryTunnelRegistry::<(
     TunnelId,
     TunnelName,
@@ -159,9 +233,39 @@ pub async fn client_main(config: ClientArgs) -> Result<()> {
   )>::new());
 
   let peer_tracker = PeerTracker::default();
-  let router = Arc::new(SnocatClientRouter::new(peer_tracker.view()));
+  let mut router = SnocatClientRouter::new(peer_tracker.view());
+  if config.offer_negotiation_v1 {
+    router = router.with_protocol_versions(negotiation::SUPPORTED_PROTOCOL_VERSIONS);
+  }
+  let router = Arc::new(router);
 
-  let authentication_handler = Arc::new(SimpleAckAuthenticationHandler::new());
+  // Servers requiring client certificates authenticate with them instead of the simple handshake
+  let authentication_handler: Arc<dyn AuthenticationHandler<Error = anyhow::Error>> = match (
+    &config.client_cert,
+    &config.preshared_key_name,
+    &config.jwt_file,
+  ) {
+    (Some(_), _, _) => Arc::new(CertificateAuthenticationHandler::default().err_into()),
+    (None, Some(name), _) => {
+      let key = std::env::var(PRESHARED_KEY_VARIABLE)
+        .with_context(|| format!("{} must hold the pre-shared key", PRESHARED_KEY_VARIABLE))?;
+      let key = BASE64
+        .decode(key.trim())
+        .context("Pre-shared key must be base64")?;
+      Arc::new(
+        PresharedKeyAuthenticationHandler::new()
+          .with_credentials(TunnelName::new(name), key)
+          .context("Invalid pre-shared key credentials")?
+          .err_into(),
+      )
+    }
+    (None, None, Some(jwt_file)) => Arc::new(
+      JwtAuthenticationHandler::new()
+        .with_token_file(jwt_file)
+        .err_into(),
+    ),
+    (None, None, None) => Arc::new(SimpleAckAuthenticationHandler::new().err_into()),
+  };
 
   // Our tunnel IDs are just increments atop the unix timestamp millisecond we started the server
   // This would still likely lead to eventual collisions in a shared-ID cluster, so don't do that
@@ -179,15 +283,18 @@ pub async fn client_main(config: ClientArgs) -> Result<()> {
       futures::future::ready(Ok(((args.id, args.name, attrs.clone()), attrs))).boxed()
     },
   ));
-  let modular = Arc::new(ModularDaemon::new(
-    service_registry,
-    tunnel_registry,
-    peer_tracker,
-    router,
-    authentication_handler,
-    tunnel_id_generator,
-    record_constructor,
-  ));
+  let modular = Arc::new(
+    ModularDaemon::new(
+      service_registry,
+      tunnel_registry,
+      peer_tracker,
+      router,
+      authentication_handler,
+      tunnel_id_generator,
+      record_constructor,
+    )
+    .with_datagram_dispatcher(datagram_dispatcher),
+  );
 
   let endpoint = quinn::Endpoint::client("[::]:0".parse()?)?; // Should this be IPv4 if the server is?
 
@@ -6,7 +6,7 @@
 
 use anyhow::Result;
 use clap::{Arg, ArgMatches, Command};
-use snocat::util;
+use snocat::{common::authentication::certificate_authentication::CertificateNameRule, util};
 use std::{
   path::{Path, PathBuf},
   str::FromStr,
@@ -75,6 +75,50 @@ fn main() {
             .validator(validate_socketaddr)
             .takes_value(true)
             .required(true),
+        )
+        .arg(
+          Arg::new("client-cert")
+            .help("Certificate chain to present to servers requiring client certificates")
+            .long("client-cert")
+            .validator(validate_existing_file)
+            .takes_value(true)
+            .requires("client-key"),
+        )
+        .arg(
+          Arg::new("client-key")
+            .help("Private key of the --client-cert certificate")
+            .long("client-key")
+            .validator(validate_existing_file)
+            .takes_value(true)
+            .requires("client-cert"),
+        )
+        .arg(
+          Arg::new("psk-name")
+            .help("Authenticate as this name, using the base64 key in the SNOCAT_PSK_KEY variable")
+            .long("psk-name")
+            .takes_value(true)
+            .conflicts_with("client-cert"),
+        )
+        .arg(
+          Arg::new("jwt-file")
+            .help("Authenticate by presenting the signed JWT held in this file, read anew each time")
+            .long("jwt-file")
+            .validator(validate_existing_file)
+            .takes_value(true)
+            .conflicts_with_all(&["client-cert", "psk-name"]),
+        )
+        .arg(
+          Arg::new("negotiation-v1")
+            .help("Offer negotiation protocol v1 to the server, retrying with v0 over another stream if it has yet to be upgraded")
+            .long("negotiation-v1"),
+        )
+        .arg(
+          Arg::new("allow-unix-socket")
+            .help("Unix socket path the server may request proxying to; may be repeated")
+            .long("allow-unix-socket")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .required(false),
         ),
     )
     .subcommand(
@@ -97,6 +141,68 @@ fn main() {
             .takes_value(true)
             .required(true),
         )
+        .arg(
+          Arg::new("client-ca")
+            .help("Require client certificates issued by these authorities, naming tunnels by them")
+            .long("client-ca")
+            .validator(validate_existing_file)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::new("client-name-rule")
+            .help("Name tunnels by certificate field, as <dns|uri|email|cn>:<prefix>*<suffix>")
+            .long("client-name-rule")
+            .validator(|v| v.parse::<CertificateNameRule>())
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .requires("client-ca"),
+        )
+        .arg(
+          Arg::new("psk-file")
+            .help("Authenticate clients by keys in this file, one <name>=<base64 key> per line")
+            .long("psk-file")
+            .validator(validate_existing_file)
+            .takes_value(true)
+            .conflicts_with_all(&["client-ca", "psk-env"]),
+        )
+        .arg(
+          Arg::new("psk-env")
+            .help("Authenticate clients by keys in this environment variable, as in --psk-file")
+            .long("psk-env")
+            .takes_value(true)
+            .conflicts_with("client-ca"),
+        )
+        .arg(
+          Arg::new("jwks")
+            .help("Authenticate clients by JWTs signed with a key from this JWKS file")
+            .long("jwks")
+            .validator(validate_existing_file)
+            .takes_value(true)
+            .conflicts_with_all(&["client-ca", "psk-file", "psk-env"]),
+        )
+        .arg(
+          Arg::new("jwt-audience")
+            .help("Require client JWTs to name this audience; may be repeated")
+            .long("jwt-audience")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .requires("jwks"),
+        )
+        .arg(
+          Arg::new("jwt-issuer")
+            .help("Require client JWTs to be from this issuer; may be repeated")
+            .long("jwt-issuer")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .requires("jwks"),
+        )
+        .arg(
+          Arg::new("jwt-name-claim")
+            .help("Name tunnels by this claim of their JWT instead of sub")
+            .long("jwt-name-claim")
+            .takes_value(true)
+            .requires("jwks"),
+        )
         .arg(
           Arg::new("tcp")
             .long("tcp")
@@ -122,6 +228,99 @@ fn main() {
             .validator(validate_socketaddr)
             .default_value("127.0.0.1:9090")
             .takes_value(true),
+        )
+        .arg(
+          Arg::new("socks5")
+            .help("Address to accept SOCKS5 connections on, forwarding them through --socks5-peer")
+            .long("socks5")
+            .validator(validate_socketaddr)
+            .takes_value(true)
+            .requires("socks5-peer"),
+        )
+        .arg(
+          Arg::new("socks5-peer")
+            .help("Name of the connected tunnel to forward SOCKS5 connections through")
+            .long("socks5-peer")
+            .takes_value(true)
+            .requires("socks5"),
+        )
+        .arg(
+          Arg::new("http-proxy")
+            .help("Address to accept HTTP CONNECT requests on, routed by --http-proxy-route")
+            .long("http-proxy")
+            .validator(validate_socketaddr)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::new("http-proxy-route")
+            .help("Route CONNECT requests for a domain and its subdomains, as <domain>=<tunnel>")
+            .long("http-proxy-route")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .requires("http-proxy"),
+        )
+        .arg(
+          Arg::new("http-proxy-user-routing")
+            .help("Route CONNECT requests to the tunnel named by their Proxy-Authorization user, whose password is not checked")
+            .long("http-proxy-user-routing")
+            .requires("http-proxy"),
+        )
+        .arg(
+          Arg::new("vhost")
+            .help("Address to accept HTTP and TLS connections on, routed by Host header or SNI")
+            .long("vhost")
+            .validator(validate_socketaddr)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::new("vhost-domain")
+            .help("Base domain whose subdomains name the tunnels served by --vhost")
+            .long("vhost-domain")
+            .takes_value(true)
+            .requires("vhost"),
+        )
+        .arg(
+          Arg::new("vhost-port")
+            .help("Port on the peer's side to forward --vhost connections to")
+            .long("vhost-port")
+            .validator(|v| v.parse::<u16>())
+            .takes_value(true)
+            .default_value("80"),
+        )
+        .arg(
+          Arg::new("auth-timeout")
+            .help("Seconds a client may take to authenticate before it is disconnected")
+            .long("auth-timeout")
+            .validator(|v| v.parse::<u64>())
+            .takes_value(true)
+            .default_value("30"),
+        )
+        .arg(
+          Arg::new("auth-lockout-threshold")
+            .help("Failed authentications after which a client address is locked out")
+            .long("auth-lockout-threshold")
+            .validator(|v| v.parse::<u32>())
+            .takes_value(true)
+            .default_value("5"),
+        )
+        .arg(
+          Arg::new("reauthenticate-before")
+            .help("Seconds before a client's credentials expire to re-authenticate it, instead of disconnecting it")
+            .long("reauthenticate-before")
+            .validator(|v| v.parse::<u64>())
+            .takes_value(true),
+        )
+        .arg(
+          Arg::new("revocation-list")
+            .help("Disconnect and refuse clients listed in this file, one name:<name>, id:<id>, or attribute:<key>=<value> (or =hex:<value>) per line; reloaded when changed")
+            .long("revocation-list")
+            .validator(validate_existing_file)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::new("negotiation-v1")
+            .help("Offer negotiation protocol v1 to clients, retrying with v0 over another stream for those yet to be upgraded")
+            .long("negotiation-v1"),
         ),
     )
     .subcommand(
@@ -165,6 +364,17 @@ pub async fn client_arg_handling(args: &'_ ArgMatches) -> Result<client::ClientA
     driver_host: parse_socketaddr(args.value_of("driver").unwrap())?,
     driver_san: args.value_of("driver-san").unwrap().into(),
     proxy_target_host: parse_socketaddr(args.value_of("target").unwrap())?,
+    allowed_unix_sockets: args
+      .values_of("allow-unix-socket")
+      .map(|paths| paths.map(PathBuf::from).collect())
+      .unwrap_or_default(),
+    client_cert: match (args.value_of("client-cert"), args.value_of("client-key")) {
+      (Some(cert), Some(key)) => Some((cert.into(), key.into())),
+      _ => None,
+    },
+    preshared_key_name: args.value_of("psk-name").map(Into::into),
+    jwt_file: args.value_of("jwt-file").map(PathBuf::from),
+    offer_negotiation_v1: args.is_present("negotiation-v1"),
   })
 }
 
@@ -178,6 +388,63 @@ pub async fn server_arg_handling(args: &'_ ArgMatches) -> Result<server::ServerA
     quinn_bind_addr: parse_socketaddr(args.value_of("quic").unwrap())?,
     tcp_bind_ip: parse_ipaddr(args.value_of("tcp").unwrap())?,
     tcp_bind_port_range: parse_port_range(args.value_of("bind_range").unwrap())?,
+    socks5: match (args.value_of("socks5"), args.value_of("socks5-peer")) {
+      (Some(bind_addr), Some(peer)) => Some((parse_socketaddr(bind_addr)?, peer.into())),
+      _ => None,
+    },
+    http_proxy: args
+      .value_of("http-proxy")
+      .map(parse_socketaddr)
+      .transpose()?,
+    http_proxy_routes: args
+      .values_of("http-proxy-route")
+      .map(|routes| {
+        routes
+          .map(|route| {
+            route
+              .split_once('=')
+              .map(|(suffix, peer)| (suffix.to_string(), peer.to_string()))
+              .ok_or_else(|| anyhow::Error::msg("HTTP proxy routes must be <domain>=<tunnel>"))
+          })
+          .collect::<Result<_>>()
+      })
+      .transpose()?
+      .unwrap_or_default(),
+    http_proxy_user_routing: args.is_present("http-proxy-user-routing"),
+    vhost: args.value_of("vhost").map(parse_socketaddr).transpose()?,
+    vhost_domain: args.value_of("vhost-domain").map(Into::into),
+    vhost_port: args.value_of("vhost-port").unwrap().parse()?,
+    authentication_timeout: std::time::Duration::from_secs(
+      args.value_of("auth-timeout").unwrap().parse()?,
+    ),
+    authentication_lockout_threshold: args.value_of("auth-lockout-threshold").unwrap().parse()?,
+    reauthentication_lead: args
+      .value_of("reauthenticate-before")
+      .map(|secs| secs.parse().map(std::time::Duration::from_secs))
+      .transpose()?,
+    revocation_list: args.value_of("revocation-list").map(PathBuf::from),
+    client_ca: args.value_of("client-ca").map(PathBuf::from),
+    client_name_rules: args
+      .values_of("client-name-rule")
+      .map(|rules| rules.map(str::parse).collect::<Result<_, _>>())
+      .transpose()?
+      .unwrap_or_default(),
+    preshared_keys: match (args.value_of("psk-file"), args.value_of("psk-env")) {
+      (Some(path), _) => Some(server::PresharedKeySource::File(path.into())),
+      (None, Some(variable)) => Some(server::PresharedKeySource::Environment(variable.into())),
+      (None, None) => None,
+    },
+    jwks: args.value_of("jwks").map(PathBuf::from),
+    jwt_audiences: args
+      .values_of("jwt-audience")
+      .map(|audiences| audiences.map(Into::into).collect())
+      .unwrap_or_default(),
+    jwt_issuers: args
+      .values_of("jwt-issuer")
+      .map(|issuers| issuers.map(Into::into).collect())
+      .unwrap_or_default(),
+    jwt_name_claim: args.value_of("jwt-name-claim").map(Into::into),
+    offer_negotiation_v1: args.is_present("negotiation-v1"),
   })
 }
 
@@ -8,19 +8,29 @@ use futures::{
 };
 use quinn::{TransportConfig, VarInt};
 use snocat::{
+  client::{
+    http_connect::HttpConnectListener, socks5::Socks5Listener, virtual_host::VirtualHostListener,
+  },
   common::{
-    authentication::{AuthenticationAttributes, SimpleAckAuthenticationHandler},
+    authentication::{
+      certificate_authentication::CertificateNameRule, jwt_authentication::JwksKeyProvider,
+      lockout::AuthenticationFailureTracker, preshared_key_authentication::StaticKeyStore,
+      AuthenticationAttributes, AuthenticationHandler, AuthenticationHandlerExt,
+      CertificateAuthenticationHandler, JwtAuthenticationHandler, LockoutAuthenticationHandler,
+      PresharedKeyAuthenticationHandler, SimpleAckAuthenticationHandler,
+    },
     daemon::{
-      ArcRecordConstructor, ModularDaemon, PeerTracker, PeersView, RecordConstructorArgs,
-      RecordConstructorResult,
+      revocation::RevocationList, ArcRecordConstructor, ModularDaemon, PeerTracker, PeersView,
+      RecordConstructorArgs, RecordConstructorResult,
     },
     protocol::{
-      negotiation::NegotiationClient,
+      negotiation::{self, NegotiationClient},
+      proxy_tcp::TcpStreamTarget,
       service::{Client, Request, Router, RouterResult, RoutingError},
       tunnel::{
         id::MonotonicAtomicGenerator,
         registry::{memory::InMemoryTunnelRegistry, TunnelRegistry},
-        TunnelId, TunnelName,
+        ArcTunnel, TunnelCloseReason, TunnelId, TunnelName,
       },
     },
     tunnel_source::QuinnListenEndpoint,
@@ -31,11 +41,15 @@ use snocat::{
 use std::{
   convert::TryInto,
   net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  ops::RangeInclusive,
   path::PathBuf,
   sync::Arc,
 };
 use tokio_util::sync::CancellationToken;
 
+/// How often the revocation list's file is checked for changes
+const REVOCATION_LIST_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 /// Parameters used to run an Snocat server binding TCP connections
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct ServerArgs {
@@ -44,18 +58,78 @@ pub struct ServerArgs {
   pub quinn_bind_addr: std::net::SocketAddr,
   pub tcp_bind_ip: std::net::IpAddr,
   pub tcp_bind_port_range: std::ops::RangeInclusive<u16>,
+  /// Address to serve SOCKS5 on, and the name of the tunnel to forward its connections through
+  pub socks5: Option<(std::net::SocketAddr, String)>,
+  pub http_proxy: Option<std::net::SocketAddr>,
+  /// Domain suffixes whose HTTP CONNECT requests are routed to the paired tunnel name
+  pub http_proxy_routes: Vec<(String, String)>,
+  /// Route HTTP CONNECT requests by their unauthenticated `Proxy-Authorization` user
+  pub http_proxy_user_routing: bool,
+  /// Address to serve HTTP and TLS connections on, routed to tunnels by their host names
+  pub vhost: Option<std::net::SocketAddr>,
+  pub vhost_domain: Option<String>,
+  /// Port on the side of each peer that virtual host connections are forwarded to
+  pub vhost_port: u16,
+  /// Time clients are given to authenticate before being disconnected
+  pub authentication_timeout: std::time::Duration,
+  /// Consecutive authentication failures after which a client's address is locked out
+  pub authentication_lockout_threshold: u32,
+  /// Time before a client's authentication expires at which it is re-authenticated
+  pub reauthentication_lead: Option<std::time::Duration>,
+  /// File of tunnels which may not connect, watched for changes and applied to live tunnels
+  pub revocation_list: Option<PathBuf>,
+  /// Authorities whose client certificates are accepted, enabling certificate authentication
+  pub client_ca: Option<PathBuf>,
+  /// Rules naming tunnels from their client certificates, in order of precedence
+  pub client_name_rules: Vec<CertificateNameRule>,
+  /// Keys shared with clients, enabling pre-shared key authentication
+  pub preshared_keys: Option<PresharedKeySource>,
+  /// Keys trusted to sign client tokens, enabling JWT authentication
+  pub jwks: Option<PathBuf>,
+  /// Audiences and issuers a client token must name one of, if any are given
+  pub jwt_audiences: Vec<String>,
+  pub jwt_issuers: Vec<String>,
+  /// Claim naming the tunnel of each client token, if not `sub`
+  pub jwt_name_claim: Option<String>,
+  /// Offer negotiation protocol v1 to clients, falling back to v0 for clients without it
+  pub offer_negotiation_v1: bool,
+}
+
+/// Where the server loads the keys it shares with clients from
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum PresharedKeySource {
+  File(PathBuf),
+  Environment(String),
 }
 
 pub struct SnocatServerRouter {
   active_tunnels: Arc<PeersView>,
+  protocol_versions: Option<RangeInclusive<u8>>,
 }
 
 impl SnocatServerRouter {
   pub fn new(active_tunnels: PeersView) -> Self {
     Self {
       active_tunnels: active_tunnels.into(),
+      protocol_versions: None,
     }
   }
+
+  /// Sets the negotiation protocol versions offered to the services of connected tunnels
+  ///
+  /// If unset, only requests with headers offer v1, as by [NegotiationClient::with_headers].
+  /// Services which only speak v0 are retried over another link with v0 alone, as by
+  /// [NegotiationClient::negotiate_with_fallback]. Panics if `protocol_versions` is empty or is
+  /// not a subset of [SUPPORTED_PROTOCOL_VERSIONS](negotiation::SUPPORTED_PROTOCOL_VERSIONS).
+  pub fn with_protocol_versions(mut self, protocol_versions: RangeInclusive<u8>) -> Self {
+    assert!(
+      negotiation::is_supported_range(&protocol_versions),
+      "Unsupported negotiation protocol version range {:?}",
+      protocol_versions
+    );
+    self.protocol_versions = Some(protocol_versions);
+    self
+  }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -79,6 +153,7 @@ impl Router for SnocatServerRouter {
     let active_tunnels = self.active_tunnels.clone();
     let err_addr = request.address.clone();
     let err_not_found = move || RoutingError::RouteNotFound(err_addr.clone());
+    let protocol_versions = self.protocol_versions.clone();
     async move {
       // Lookup the tunnel or bail if it doesn't exist anymore
       let dest_name: TunnelName = local_address.into();
@@ -90,11 +165,26 @@ impl Router for SnocatServerRouter {
         .open_link()
         .await
         .map_err(RoutingError::LinkOpenFailure)?;
-      let negotiator = NegotiationClient::new();
-      let link = negotiator
-        .negotiate::<_, Self::Error>(addr.clone(), link)
+      let mut negotiator = NegotiationClient::new()
+        .with_service_versions(TProtocolClient::service_versions())
+        .with_headers(request.headers);
+      if let Some(protocol_versions) = protocol_versions {
+        negotiator = negotiator.with_protocol_versions(protocol_versions);
+      }
+      let (link, service_version) = negotiator
+        .negotiate_with_fallback::<_, Self::Error, _, _>(addr.clone(), link, || {
+          tunnel
+            .tunnel
+            .open_link()
+            .map_err(RoutingError::LinkOpenFailure)
+        })
         .await?;
-      Ok(request.protocol_client.handle(addr, link))
+      let tunnel: ArcTunnel<'static> = Arc::new(Arc::clone(&tunnel.tunnel));
+      Ok(
+        request
+          .protocol_client
+          .handle(addr, service_version, link, tunnel),
+      )
     }
     .boxed()
   }
@@ -152,9 +242,59 @@ pub async fn server_main(config: self::ServerArgs) -> Result<()> {
   let service_registry = Arc::new(PresetServiceRegistry::<anyhow::Error>::new());
 
   let peer_tracker = PeerTracker::default();
-  let router = { Arc::new(SnocatServerRouter::new(peer_tracker.view())) };
+  let router = {
+    let mut router = SnocatServerRouter::new(peer_tracker.view());
+    if config.offer_negotiation_v1 {
+      router = router.with_protocol_versions(negotiation::SUPPORTED_PROTOCOL_VERSIONS);
+    }
+    Arc::new(router)
+  };
 
-  let authentication_handler = Arc::new(SimpleAckAuthenticationHandler::new());
+  let authentication_handler: Arc<dyn AuthenticationHandler<Error = anyhow::Error>> =
+    match (&config.client_ca, &config.preshared_keys, &config.jwks) {
+      (Some(_), _, _) if config.client_name_rules.is_empty() => {
+        Arc::new(CertificateAuthenticationHandler::default().err_into())
+      }
+      (Some(_), _, _) => Arc::new(
+        CertificateAuthenticationHandler::new(config.client_name_rules.iter().cloned()).err_into(),
+      ),
+      (None, Some(source), _) => {
+        let key_store = match source {
+          PresharedKeySource::File(path) => StaticKeyStore::from_file(path),
+          PresharedKeySource::Environment(variable) => StaticKeyStore::from_env(variable),
+        }
+        .context("Loading pre-shared keys")?;
+        tracing::info!(
+          keys = key_store.len(),
+          "Authenticating clients by pre-shared key"
+        );
+        Arc::new(
+          PresharedKeyAuthenticationHandler::new()
+            .with_key_store(key_store)
+            .err_into(),
+        )
+      }
+      (None, None, Some(jwks)) => {
+        let key_provider = JwksKeyProvider::from_file(jwks).context("Loading JWKS")?;
+        let mut handler = JwtAuthenticationHandler::new().with_key_provider(key_provider);
+        if !config.jwt_audiences.is_empty() {
+          handler = handler.with_audiences(config.jwt_audiences.iter().cloned());
+        }
+        if !config.jwt_issuers.is_empty() {
+          handler = handler.with_issuers(config.jwt_issuers.iter().cloned());
+        }
+        if let Some(claim) = &config.jwt_name_claim {
+          handler = handler.with_name_claim(claim);
+        }
+        Arc::new(handler.err_into())
+      }
+      (None, None, None) => Arc::new(SimpleAckAuthenticationHandler::new().err_into()),
+    };
+  let authentication_handler = Arc::new(
+    LockoutAuthenticationHandler::new(authentication_handler).with_tracker(Arc::new(
+      AuthenticationFailureTracker::new().with_threshold(config.authentication_lockout_threshold),
+    )),
+  );
 
   // Our tunnel IDs are just increments atop the unix timestamp millisecond we started the server
   // This would still likely lead to eventual collisions in a shared-ID cluster, so don't do that
@@ -177,15 +317,41 @@ pub async fn server_main(config: self::ServerArgs) -> Result<()> {
       futures::future::ready(Ok(((args.id, args.name, attrs.clone()), attrs))).boxed()
     },
   ));
-  let modular = Arc::new(ModularDaemon::new(
-    service_registry.clone(),
-    tunnel_registry.clone(),
-    peer_tracker.clone(),
-    router,
-    authentication_handler,
-    tunnel_id_generator,
-    record_constructor,
-  ));
+  let revocation_list = config
+    .revocation_list
+    .as_ref()
+    .map(|path| RevocationList::from_file(path).context("Loading revocation list"))
+    .transpose()?
+    .map(Arc::new);
+  let modular = Arc::new({
+    let daemon = ModularDaemon::new(
+      service_registry.clone(),
+      tunnel_registry.clone(),
+      peer_tracker.clone(),
+      router,
+      authentication_handler,
+      tunnel_id_generator,
+      record_constructor,
+    )
+    .with_authentication_timeout(config.authentication_timeout);
+    let daemon = match config.reauthentication_lead {
+      Some(lead_time) => daemon.with_reauthentication(lead_time),
+      None => daemon,
+    };
+    match &revocation_list {
+      Some(revocation_list) => daemon.with_revocation_list(Arc::clone(revocation_list)),
+      None => daemon,
+    }
+  });
+
+  let revocation_task = match (&config.revocation_list, revocation_list) {
+    (Some(path), Some(revocation_list)) => Some(tokio::task::spawn(watch_revocation_list(
+      path.clone(),
+      revocation_list,
+      peer_tracker.view(),
+    ))),
+    _ => None,
+  };
 
   {
     let demand_proxy_service = Arc::new(DemandProxyService::new(
@@ -201,46 +367,164 @@ pub async fn server_main(config: self::ServerArgs) -> Result<()> {
     drop(service_registry);
   }
 
+  let socks5_task = match &config.socks5 {
+    Some((bind_addr, peer)) => {
+      let listener = tokio::net::TcpListener::bind(bind_addr)
+        .await
+        .context("Binding SOCKS5 listener")?;
+      tracing::info!(addr = %bind_addr, peer = %peer, "Serving SOCKS5");
+      let socks5 = Socks5Listener::new(Arc::clone(modular.router()), TunnelName::new(peer));
+      let stop_accepting = shutdown.clone();
+      Some(tokio::task::spawn(async move {
+        socks5.serve(listener, stop_accepting).await
+      }))
+    }
+    None => None,
+  };
+
+  let http_proxy_task = match &config.http_proxy {
+    Some(bind_addr) => {
+      let listener = tokio::net::TcpListener::bind(bind_addr)
+        .await
+        .context("Binding HTTP proxy listener")?;
+      tracing::info!(addr = %bind_addr, "Serving HTTP CONNECT proxy");
+      let http_proxy = config.http_proxy_routes.iter().fold(
+        HttpConnectListener::new(Arc::clone(modular.router()))
+          .with_proxy_user_routing(config.http_proxy_user_routing),
+        |http_proxy, (suffix, peer)| http_proxy.with_host_suffix(suffix, TunnelName::new(peer)),
+      );
+      let stop_accepting = shutdown.clone();
+      Some(tokio::task::spawn(async move {
+        http_proxy.serve(listener, stop_accepting).await
+      }))
+    }
+    None => None,
+  };
+
+  let vhost_task = match &config.vhost {
+    Some(bind_addr) => {
+      let listener = tokio::net::TcpListener::bind(bind_addr)
+        .await
+        .context("Binding virtual host listener")?;
+      tracing::info!(addr = %bind_addr, domain = ?config.vhost_domain, "Serving virtual hosts");
+      let vhost = VirtualHostListener::new(
+        Arc::clone(modular.router()),
+        TcpStreamTarget::Port(config.vhost_port),
+      );
+      let vhost = match &config.vhost_domain {
+        Some(domain) => vhost.with_base_domain(domain),
+        None => vhost,
+      };
+      let stop_accepting = shutdown.clone();
+      Some(tokio::task::spawn(async move {
+        vhost.serve(listener, stop_accepting).await
+      }))
+    }
+    None => None,
+  };
+
   let endpoint = modular.construct_tunnels(endpoint);
   modular
     .run(endpoint, shutdown.into())
     .map_err(|_| anyhow::Error::msg("Modular runtime panicked and lost context"))
     .await?;
 
+  for task in [socks5_task, http_proxy_task, vhost_task]
+    .into_iter()
+    .flatten()
+  {
+    task.abort();
+  }
+  if let Some(revocation_task) = revocation_task {
+    revocation_task.abort();
+  }
+
   sigint_handler_task.abort();
   let _cancelled = sigint_handler_task.await;
 
   Ok(())
 }
 
-fn build_quinn_config(config: &ServerArgs) -> Result<quinn::ServerConfig> {
-  let cert_pem = std::fs::read(&config.cert).context("Failed reading cert file")?;
-  let priv_pem = std::fs::read(&config.key).context("Failed reading private key file")?;
-  let priv_key = {
-    let priv_key = rustls_pemfile::pkcs8_private_keys(&mut std::io::Cursor::new(&priv_pem))
-      .context("Quinn .pem parsing of private key failed")?;
-
-    // TODO: We check at least one private key; check at most one as well
-    let priv_key = priv_key
-      .into_iter()
-      .next()
-      .context("Quinn private key .pem must contain exactly one private key")?;
-
-    // Encapsulate the parsed key into rustls's wrapper type
-    rustls::PrivateKey(priv_key)
-  };
-  let cert_chain: Vec<rustls::Certificate> = {
-    let cert_chain = rustls_pemfile::certs(&mut std::io::Cursor::new(&cert_pem))
-      .context("Quinn .pem parsing of certificates failed")?;
+/// Reloads the revocation list whenever its file changes, closing newly-revoked tunnels
+async fn watch_revocation_list(
+  path: PathBuf,
+  revocation_list: Arc<RevocationList>,
+  peers: PeersView,
+) {
+  let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+  let mut last_modified = modified(&path);
+  let mut interval = tokio::time::interval(REVOCATION_LIST_POLL_INTERVAL);
+  loop {
+    interval.tick().await;
+    let current = modified(&path);
+    if current == last_modified {
+      continue;
+    }
+    last_modified = current;
+    match revocation_list.reload_from_file(&path) {
+      Ok(()) => {
+        let revoked = peers
+          .revoke_matching(
+            |peer| revocation_list.is_peer_revoked(peer),
+            TunnelCloseReason::AuthenticationFailure {
+              remote_responsible: Some(true),
+            },
+          )
+          .await;
+        tracing::info!(?path, revoked, "Revocation list reloaded");
+      }
+      Err(e) => tracing::warn!(?path, error = %e, "Failed to reload revocation list"),
+    }
+  }
+}
+
+/// Reads the single PKCS#8 private key from a .pem file
+pub(crate) fn read_private_key(path: &std::path::Path) -> Result<rustls::PrivateKey> {
+  let priv_pem = std::fs::read(path).context("Failed reading private key file")?;
+  let priv_key = rustls_pemfile::pkcs8_private_keys(&mut std::io::Cursor::new(&priv_pem))
+    .context("Quinn .pem parsing of private key failed")?;
+
+  // TODO: We check at least one private key; check at most one as well
+  let priv_key = priv_key
+    .into_iter()
+    .next()
+    .context("Quinn private key .pem must contain exactly one private key")?;
+
+  // Encapsulate the parsed key into rustls's wrapper type
+  Ok(rustls::PrivateKey(priv_key))
+}
+
+/// Reads all certificates from a .pem file, in the order they appear
+pub(crate) fn read_certificates(path: &std::path::Path) -> Result<Vec<rustls::Certificate>> {
+  let cert_pem = std::fs::read(path).context("Failed reading cert file")?;
+  let cert_chain = rustls_pemfile::certs(&mut std::io::Cursor::new(&cert_pem))
+    .context("Quinn .pem parsing of certificates failed")?;
+
+  // Map all certificates in the chain to rustls's wrapper type and construct a vector
+  Ok(cert_chain.into_iter().map(rustls::Certificate).collect())
+}
 
-    // Map all certificates in the chain to rustls's wrapper type and construct a vector
-    cert_chain.into_iter().map(rustls::Certificate).collect()
+fn build_quinn_config(config: &ServerArgs) -> Result<quinn::ServerConfig> {
+  let priv_key = read_private_key(&config.key)?;
+  let cert_chain = read_certificates(&config.cert)?;
+  // Clients must present a certificate issued by one of the given authorities, if any are given
+  let client_cert_verifier = match &config.client_ca {
+    Some(client_ca) => {
+      let mut client_authorities = rustls::RootCertStore::empty();
+      for authority in read_certificates(client_ca)? {
+        client_authorities
+          .add(&authority)
+          .context("Failed to add client authority")?;
+      }
+      rustls::server::AllowAnyAuthenticatedClient::new(client_authorities).boxed()
+    }
+    None => rustls::server::NoClientAuth::boxed(),
   };
   let mut crypto_config = rustls::ServerConfig::builder()
     .with_safe_default_cipher_suites()
     .with_safe_default_kx_groups()
     .with_protocol_versions(&[&rustls::version::TLS13])?
-    .with_no_client_auth()
+    .with_client_cert_verifier(client_cert_verifier)
     .with_single_cert(cert_chain, priv_key)?;
   crypto_config.alpn_protocols = vec![crate::util::ALPN_MS_SNOCAT_1.to_vec()];
   crypto_config.key_log = Arc::new(rustls::KeyLogFile::new());
@@ -12,7 +12,7 @@ use snocat::{
         RoutingError,
       },
       tunnel::{ArcTunnel, TunnelName},
-      RouteAddress, Service, ServiceError,
+      RequestHeaders, RouteAddress, Service, ServiceError, ServiceVersion,
     },
   },
   server::PortRangeAllocator,
@@ -103,7 +103,13 @@ where
 
   type Future = BoxFuture<'stream, ClientResult<'stream, Self, TStream>>;
 
-  fn handle(self, addr: RouteAddress, mut tunnel: TStream) -> Self::Future {
+  fn handle(
+    self,
+    addr: RouteAddress,
+    _version: ServiceVersion,
+    mut tunnel: TStream,
+    _parent_tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
     let span = tracing::span!(tracing::Level::DEBUG, "demand_proxy_client", target=?addr);
     let fut = async move {
       tracing::info!("Sending subject to service");
@@ -226,6 +232,11 @@ where
               RoutingError::RouterError(_) => ServiceError::DependencyFailure,
               RoutingError::LinkOpenFailure(_) => ServiceError::DependencyFailure,
               RoutingError::InvalidAddress => ServiceError::AddressError,
+              RoutingError::NoSuchService(_)
+              | RoutingError::Forbidden(_)
+              | RoutingError::Overloaded(_)
+              | RoutingError::ShuttingDown(_) => ServiceError::Refused,
+              RoutingError::BadAddress(_) => ServiceError::AddressError,
               RoutingError::NegotiationError(negotiation_error) => negotiation_error.into(),
             })?
             .await
@@ -339,6 +350,8 @@ where
   fn handle(
     &'_ self,
     addr: RouteAddress,
+    _version: ServiceVersion,
+    _headers: RequestHeaders,
     mut stream: Box<dyn TunnelStream + Send + 'static>,
     tunnel: ArcTunnel,
   ) -> BoxFuture<'_, Result<(), ServiceError<Self::Error>>> {
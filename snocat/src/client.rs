@@ -1,3 +0,0 @@
-// Copyright (c) Microsoft Corporation.
-// Licensed under the MIT license OR Apache 2.0
-//! Types for building a Snocat client and forwarding connections
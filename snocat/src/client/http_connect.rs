@@ -0,0 +1,622 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! An HTTP/1.1 `CONNECT` proxy front end which forwards each request over a chosen tunnel
+//!
+//! The tunnel for each request is selected by the user named in its `Proxy-Authorization`
+//! header, if enabled, then by the longest configured host suffix matching the requested
+//! authority, and finally by a default tunnel if one is configured. The password of the
+//! `Proxy-Authorization` header is not checked; the user is used only for routing.
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use std::{
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+};
+use tokio::{
+  io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+  },
+  net::TcpListener,
+};
+use tokio_util::sync::CancellationToken;
+use tracing_futures::Instrument;
+
+use super::DeferredTcpStreamClient;
+use crate::{
+  common::protocol::{
+    proxy_tcp::{DnsTarget, TcpStreamTarget},
+    service::{ClientError, Request, Router, RoutingError},
+    tunnel::TunnelName,
+  },
+  util::tunnel_stream::TunnelStream,
+};
+
+/// Maximum length of a request line and its headers
+pub(super) const MAX_REQUEST_HEAD_LENGTH: u64 = 8192;
+
+#[derive(thiserror::Error, Debug)]
+pub enum HttpConnectError {
+  #[error("HTTP client IO failure")]
+  IOError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    std::io::Error,
+  ),
+  #[error("HTTP request head exceeded the maximum length")]
+  HeadTooLarge,
+  #[error("Malformed HTTP request")]
+  MalformedRequest,
+  #[error("Unsupported HTTP method {0}")]
+  MethodNotAllowed(String),
+  #[error("Invalid CONNECT authority {0:?}")]
+  InvalidAuthority(String),
+  #[error("No tunnel could be selected for the request")]
+  NoTunnelSelected,
+  #[error("CONNECT request refused with status {0}")]
+  Refused(u16),
+  #[error("Proxying of CONNECT request failed")]
+  ProxyFailure(#[source] ClientError<std::io::Error>),
+}
+
+impl HttpConnectError {
+  /// The status with which the error is reported to the HTTP client, if it can still be reported
+  pub fn status(&self) -> Option<u16> {
+    match self {
+      HttpConnectError::IOError(_) | HttpConnectError::ProxyFailure(_) => None,
+      HttpConnectError::HeadTooLarge => Some(431),
+      HttpConnectError::MalformedRequest | HttpConnectError::InvalidAuthority(_) => Some(400),
+      HttpConnectError::MethodNotAllowed(_) => Some(405),
+      HttpConnectError::NoTunnelSelected => Some(407),
+      HttpConnectError::Refused(status) => Some(*status),
+    }
+  }
+}
+
+pub(super) fn routing_status<RouterError>(error: &RoutingError<RouterError>) -> u16 {
+  match error {
+    RoutingError::NoSuchService(_) | RoutingError::Forbidden(_) => 403,
+    RoutingError::InvalidAddress | RoutingError::BadAddress(_) => 400,
+    RoutingError::Overloaded(_) | RoutingError::ShuttingDown(_) => 503,
+    RoutingError::RouteNotFound(_)
+    | RoutingError::RouteUnavailable(_)
+    | RoutingError::LinkOpenFailure(_)
+    | RoutingError::NegotiationError(_)
+    | RoutingError::RouterError(_) => 502,
+  }
+}
+
+pub(super) fn reason_phrase(status: u16) -> &'static str {
+  match status {
+    200 => "Connection Established",
+    400 => "Bad Request",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    407 => "Proxy Authentication Required",
+    431 => "Request Header Fields Too Large",
+    502 => "Bad Gateway",
+    503 => "Service Unavailable",
+    _ => "Error",
+  }
+}
+
+/// The request line and headers of an HTTP/1.1 request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+  pub method: String,
+  pub target: String,
+  pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+  /// Finds the first value of a header, ignoring the case of its name
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+}
+
+/// Reads a request line and headers, leaving any following bytes unread in the reader
+pub async fn read_request_head<R>(reader: &mut R) -> Result<RequestHead, HttpConnectError>
+where
+  R: AsyncBufRead + Unpin,
+{
+  let mut remaining = MAX_REQUEST_HEAD_LENGTH;
+  let mut lines = Vec::new();
+  loop {
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+      .take(remaining)
+      .read_until(b'\n', &mut line)
+      .await?;
+    if !line.ends_with(b"\n") {
+      return Err(if read as u64 == remaining {
+        HttpConnectError::HeadTooLarge
+      } else {
+        HttpConnectError::MalformedRequest
+      });
+    }
+    remaining -= read as u64;
+    let line = String::from_utf8(line).map_err(|_| HttpConnectError::MalformedRequest)?;
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line.is_empty() {
+      break;
+    }
+    lines.push(line.to_string());
+  }
+
+  let mut lines = lines.into_iter();
+  let request_line = lines.next().ok_or(HttpConnectError::MalformedRequest)?;
+  let (method, target, version) = match request_line.split(' ').collect::<Vec<_>>()[..] {
+    [method, target, version] => (method, target, version),
+    _ => return Err(HttpConnectError::MalformedRequest),
+  };
+  if !version.starts_with("HTTP/1.") {
+    return Err(HttpConnectError::MalformedRequest);
+  }
+  let headers = lines
+    .map(|line| {
+      line
+        .split_once(':')
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .ok_or(HttpConnectError::MalformedRequest)
+    })
+    .collect::<Result<_, _>>()?;
+  Ok(RequestHead {
+    method: method.to_string(),
+    target: target.to_string(),
+    headers,
+  })
+}
+
+/// Splits a `CONNECT` authority of the form `host:port` or `[v6addr]:port`
+fn split_authority(authority: &str) -> Option<(&str, u16)> {
+  let (host, port) = authority.rsplit_once(':')?;
+  let port = port.parse().ok()?;
+  let host = match host.strip_prefix('[') {
+    Some(bracketed) => bracketed.strip_suffix(']')?,
+    None if host.contains(':') => return None,
+    None => host,
+  };
+  (!host.is_empty()).then_some((host, port))
+}
+
+/// Parses the authority of a `CONNECT` request into a [TcpStreamTarget]
+pub fn parse_authority(authority: &str) -> Result<TcpStreamTarget, HttpConnectError> {
+  let (host, port) = split_authority(authority)
+    .ok_or_else(|| HttpConnectError::InvalidAuthority(authority.to_string()))?;
+  Ok(match host.parse::<IpAddr>() {
+    Ok(ip) => TcpStreamTarget::SocketAddr(SocketAddr::new(ip, port)),
+    Err(_) => DnsTarget::PreferHigher {
+      host: host.to_string(),
+      port,
+    }
+    .into(),
+  })
+}
+
+/// Extracts the user from a `Basic` scheme `Proxy-Authorization` header value
+pub fn proxy_authorization_user(value: &str) -> Option<String> {
+  let (scheme, credentials) = value.split_once(' ')?;
+  if !scheme.eq_ignore_ascii_case("basic") {
+    return None;
+  }
+  let credentials = BASE64.decode(credentials.trim()).ok()?;
+  let credentials = String::from_utf8(credentials).ok()?;
+  let (user, _password) = credentials.split_once(':')?;
+  (!user.is_empty()).then(|| user.to_string())
+}
+
+/// Accepts HTTP `CONNECT` requests, routing each to a tunnel selected from the request
+pub struct HttpConnectListener<TRouter> {
+  router: Arc<TRouter>,
+  route_by_proxy_user: bool,
+  host_suffixes: Vec<(String, TunnelName)>,
+  default_tunnel: Option<TunnelName>,
+}
+
+impl<TRouter> std::fmt::Debug for HttpConnectListener<TRouter> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("HttpConnectListener")
+      .field("route_by_proxy_user", &self.route_by_proxy_user)
+      .field("host_suffixes", &self.host_suffixes)
+      .field("default_tunnel", &self.default_tunnel)
+      .finish_non_exhaustive()
+  }
+}
+
+impl<TRouter> HttpConnectListener<TRouter> {
+  /// Creates a listener without routes, which selects no tunnel until some are added
+  pub fn new(router: Arc<TRouter>) -> Self {
+    Self {
+      router,
+      route_by_proxy_user: false,
+      host_suffixes: Vec::new(),
+      default_tunnel: None,
+    }
+  }
+
+  /// Routes requests to the tunnel named by the user of their `Proxy-Authorization` header, before
+  /// any other rule; disabled by default
+  ///
+  /// The password is not checked, so this performs no authentication: any HTTP client able to
+  /// reach the listener may select any tunnel by naming it. Enable only where that is acceptable.
+  pub fn with_proxy_user_routing(mut self, enabled: bool) -> Self {
+    self.route_by_proxy_user = enabled;
+    self
+  }
+
+  /// Routes requests for the given domain and its subdomains to a tunnel
+  pub fn with_host_suffix<S: Into<String>>(mut self, suffix: S, tunnel: TunnelName) -> Self {
+    let suffix = suffix.into();
+    let suffix = suffix.trim_start_matches('.').to_ascii_lowercase();
+    self.host_suffixes.push((suffix, tunnel));
+    self
+  }
+
+  /// Routes requests which match no other rule to a tunnel
+  pub fn with_default_tunnel(mut self, tunnel: TunnelName) -> Self {
+    self.default_tunnel = Some(tunnel);
+    self
+  }
+
+  /// Selects the tunnel for a request to the given host
+  pub fn select_tunnel(&self, host: &str, proxy_user: Option<&str>) -> Option<TunnelName> {
+    if let Some(user) = proxy_user.filter(|_| self.route_by_proxy_user) {
+      return Some(TunnelName::new(user));
+    }
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    self
+      .host_suffixes
+      .iter()
+      .filter(|(suffix, _)| {
+        host == *suffix
+          || host
+            .strip_suffix(suffix.as_str())
+            .map_or(false, |prefix| prefix.ends_with('.'))
+      })
+      .max_by_key(|(suffix, _)| suffix.len())
+      .map(|(_, tunnel)| tunnel.clone())
+      .or_else(|| self.default_tunnel.clone())
+  }
+
+  fn resolve(&self, head: &RequestHead) -> Result<(TcpStreamTarget, TunnelName), HttpConnectError> {
+    if head.method != "CONNECT" {
+      return Err(HttpConnectError::MethodNotAllowed(head.method.clone()));
+    }
+    let target = parse_authority(&head.target)?;
+    let (host, _port) = split_authority(&head.target).expect("Authority was already parsed");
+    let proxy_user = head
+      .header("Proxy-Authorization")
+      .and_then(proxy_authorization_user);
+    let tunnel = self
+      .select_tunnel(host, proxy_user.as_deref())
+      .ok_or(HttpConnectError::NoTunnelSelected)?;
+    Ok((target, tunnel))
+  }
+
+  async fn write_failure<S>(&self, stream: &mut S, error: &HttpConnectError) -> std::io::Result<()>
+  where
+    S: AsyncWrite + Unpin,
+  {
+    let status = match error.status() {
+      Some(status) => status,
+      None => return Ok(()),
+    };
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status, reason_phrase(status));
+    match error {
+      HttpConnectError::MethodNotAllowed(_) => response.push_str("Allow: CONNECT\r\n"),
+      HttpConnectError::NoTunnelSelected if self.route_by_proxy_user => {
+        response.push_str("Proxy-Authenticate: Basic realm=\"snocat\"\r\n")
+      }
+      _ => (),
+    }
+    response.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    stream.write_all(response.as_bytes()).await
+  }
+}
+
+impl<TRouter> HttpConnectListener<TRouter>
+where
+  TRouter: Router + Send + Sync,
+  TRouter::Error: std::fmt::Debug,
+  TRouter::Stream: TunnelStream + Send + 'static,
+  TRouter::LocalAddress: From<TunnelName>,
+{
+  /// Serves HTTP proxy clients from the listener until `stop_accepting` is cancelled
+  ///
+  /// Connections accepted before cancellation are served to completion.
+  pub async fn serve(&self, listener: TcpListener, stop_accepting: CancellationToken) {
+    use futures::stream::StreamExt;
+    tokio_stream::wrappers::TcpListenerStream::new(listener)
+      .take_until(stop_accepting.cancelled())
+      .for_each_concurrent(None, |connection| async move {
+        let connection = match connection {
+          Ok(connection) => connection,
+          Err(e) => {
+            tracing::warn!(error = ?e, "Failed to accept HTTP proxy connection");
+            return;
+          }
+        };
+        let peer = connection.peer_addr().ok();
+        let span = tracing::span!(tracing::Level::DEBUG, "http_connect", peer = ?peer);
+        match self.handle_connection(connection).instrument(span).await {
+          Ok((client_to_tunnel, tunnel_to_client)) => tracing::info!(
+            target = "http_connect_close",
+            client_to_tunnel,
+            tunnel_to_client,
+            "Closing HTTP CONNECT tunnel",
+          ),
+          Err(e) => tracing::debug!(
+            target = "http_connect_error",
+            error = ?e,
+            "HTTP CONNECT request failed"
+          ),
+        }
+      })
+      .await
+  }
+
+  /// Reads a `CONNECT` request from a stream, then proxies it to the requested authority
+  pub async fn handle_connection<S>(&self, stream: S) -> Result<(u64, u64), HttpConnectError>
+  where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+  {
+    // Bytes sent by the client after its request head remain buffered for the proxy
+    let mut stream = BufReader::new(stream);
+    let resolved = match read_request_head(&mut stream).await {
+      Ok(head) => self.resolve(&head),
+      Err(e) => Err(e),
+    };
+    let (target, tunnel) = match resolved {
+      Ok(resolved) => resolved,
+      Err(e) => {
+        self.write_failure(&mut stream, &e).await?;
+        return Err(e);
+      }
+    };
+    tracing::debug!(%target, tunnel = ?tunnel, "HTTP CONNECT requested");
+    let (handoff, client) = DeferredTcpStreamClient::new();
+    let request = Request::new(client, target).unwrap_or_else(|never| match never {});
+    let proxy = match self.router.route(request, tunnel).await {
+      Ok(proxy) => proxy,
+      Err(e) => {
+        tracing::debug!(error = %e, "Routing of HTTP CONNECT request failed");
+        let error = HttpConnectError::Refused(routing_status(&e));
+        self.write_failure(&mut stream, &error).await?;
+        return Err(error);
+      }
+    };
+    stream
+      .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+      .await?;
+    if handoff.send(stream).is_err() {
+      unreachable!("Client future is held until after the stream is handed off");
+    }
+    proxy.await.map_err(HttpConnectError::ProxyFailure)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    assert_matches::assert_matches,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+  };
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+  };
+
+  use super::{parse_authority, proxy_authorization_user, HttpConnectError, HttpConnectListener};
+  use crate::{
+    client::tests::{accept_stream, LoopbackRouter},
+    common::{
+      daemon::{
+        balancing::{
+          tests::{peer, track},
+          BalancingRouter,
+        },
+        PeerTracker,
+      },
+      protocol::{
+        negotiation::{
+          tests::RefusingServiceRegistry, NegotiationService, Refusal, RefusalReason,
+          SUPPORTED_PROTOCOL_VERSIONS,
+        },
+        proxy_tcp::{DnsTarget, TcpStreamTarget},
+        service::Router,
+        tunnel::{duplex, ArcTunnel, TunnelName},
+      },
+    },
+    util::tunnel_stream::TunnelStream,
+  };
+
+  #[test]
+  fn authority_parsing() {
+    assert_eq!(
+      parse_authority("example.com:443").unwrap(),
+      TcpStreamTarget::Dns(DnsTarget::PreferHigher {
+        host: "example.com".into(),
+        port: 443
+      })
+    );
+    assert_eq!(
+      parse_authority("127.0.0.1:22").unwrap(),
+      TcpStreamTarget::SocketAddr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 22))
+    );
+    assert_eq!(
+      parse_authority("[::1]:8080").unwrap(),
+      TcpStreamTarget::SocketAddr(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8080))
+    );
+    assert!(parse_authority("example.com").is_err());
+    assert!(parse_authority("::1:8080").is_err());
+    assert!(parse_authority(":443").is_err());
+  }
+
+  #[test]
+  fn tunnel_selection() {
+    // "alice:secret"
+    assert_eq!(
+      proxy_authorization_user("Basic YWxpY2U6c2VjcmV0").as_deref(),
+      Some("alice")
+    );
+    assert_eq!(proxy_authorization_user("Bearer YWxpY2U6c2VjcmV0"), None);
+
+    // The proxy user is unauthenticated, so only selects tunnels when enabled
+    let listener = HttpConnectListener::new(Arc::new(()));
+    assert_eq!(listener.select_tunnel("example.com", Some("alice")), None);
+
+    let listener = listener
+      .with_proxy_user_routing(true)
+      .with_host_suffix("corp.internal", TunnelName::new("corp"))
+      .with_host_suffix(".lab.corp.internal", TunnelName::new("lab"));
+    assert_eq!(
+      listener.select_tunnel("db.corp.internal", Some("alice")),
+      Some(TunnelName::new("alice"))
+    );
+    assert_eq!(
+      listener.select_tunnel("DB.Corp.Internal", None),
+      Some(TunnelName::new("corp"))
+    );
+    assert_eq!(
+      listener.select_tunnel("host.lab.corp.internal", None),
+      Some(TunnelName::new("lab"))
+    );
+    // Suffixes only match whole labels
+    assert_eq!(listener.select_tunnel("notcorp.internal", None), None);
+
+    let listener = listener
+      .with_proxy_user_routing(false)
+      .with_default_tunnel(TunnelName::new("default"));
+    assert_eq!(
+      listener.select_tunnel("corp.internal", Some("alice")),
+      Some(TunnelName::new("corp"))
+    );
+    assert_eq!(
+      listener.select_tunnel("example.com", None),
+      Some(TunnelName::new("default"))
+    );
+  }
+
+  /// Sends a request head, returning the response head and the result of handling it
+  async fn http_connect<TRouter>(
+    listener: &HttpConnectListener<TRouter>,
+    request: String,
+  ) -> (String, Result<(u64, u64), HttpConnectError>)
+  where
+    TRouter: Router + Send + Sync,
+    TRouter::Error: std::fmt::Debug,
+    TRouter::Stream: TunnelStream + Send + 'static,
+    TRouter::LocalAddress: From<TunnelName>,
+  {
+    let (mut http_client, http_server) = tokio::io::duplex(8192);
+    let client = async move {
+      // Send the first payload alongside the request, as clients which pipeline would
+      http_client.write_all(request.as_bytes()).await.unwrap();
+      http_client.write_all(b"ping").await.unwrap();
+      let mut response = Vec::new();
+      while !response.ends_with(b"\r\n\r\n") {
+        response.push(http_client.read_u8().await.unwrap());
+      }
+      let response = String::from_utf8(response).unwrap();
+      if response.starts_with("HTTP/1.1 200 ") {
+        let mut echoed = [0u8; 4];
+        http_client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+      }
+      response
+    };
+    let (handled, response) =
+      futures::future::join(listener.handle_connection(http_server), client).await;
+    (response, handled)
+  }
+
+  #[tokio::test]
+  async fn connect_over_tunnel() {
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::task::spawn(async move {
+      let (mut connection, _) = echo.accept().await.unwrap();
+      let mut buffer = [0u8; 4];
+      connection.read_exact(&mut buffer).await.unwrap();
+      connection.write_all(&buffer).await.unwrap();
+    });
+
+    let listener = HttpConnectListener::new(Arc::new(LoopbackRouter::new(TunnelName::new("peer"))))
+      .with_proxy_user_routing(true);
+    // "peer:"
+    let (response, handled) = http_connect(
+      &listener,
+      format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Authorization: Basic cGVlcjo=\r\n\r\n",
+        echo_addr
+      ),
+    )
+    .await;
+    assert_eq!(response, "HTTP/1.1 200 Connection Established\r\n\r\n");
+    assert_eq!(handled.unwrap(), (4, 4));
+
+    let (response, handled) =
+      http_connect(&listener, format!("CONNECT {} HTTP/1.1\r\n\r\n", echo_addr)).await;
+    assert!(response.starts_with("HTTP/1.1 407 "));
+    assert!(response.contains("Proxy-Authenticate: Basic"));
+    assert_matches!(handled, Err(HttpConnectError::NoTunnelSelected));
+
+    let listener = listener.with_default_tunnel(TunnelName::new("absent"));
+    let (response, handled) =
+      http_connect(&listener, format!("CONNECT {} HTTP/1.1\r\n\r\n", echo_addr)).await;
+    assert!(response.starts_with("HTTP/1.1 502 "));
+    assert_matches!(handled, Err(HttpConnectError::Refused(502)));
+
+    let (response, handled) =
+      http_connect(&listener, "GET http://example.com/ HTTP/1.1\r\n\r\n".into()).await;
+    assert!(response.starts_with("HTTP/1.1 405 "));
+    assert_matches!(handled, Err(HttpConnectError::MethodNotAllowed(_)));
+  }
+
+  /// Test that refusals by the remote service are reported with their own statuses
+  #[tokio::test]
+  async fn refusals_through_router() {
+    let remote = duplex::channel();
+    let tracker = PeerTracker::new();
+    let peers = [peer(1, Arc::new(remote.listener), Default::default())];
+    track(&tracker, &peers);
+    let router = BalancingRouter::round_robin(tracker.view())
+      .with_protocol_versions(SUPPORTED_PROTOCOL_VERSIONS);
+    let listener =
+      HttpConnectListener::new(Arc::new(router)).with_default_tunnel(TunnelName::new("site"));
+    let services: ArcTunnel<'static> = Arc::new(remote.connector);
+
+    for (reason, status) in [
+      (RefusalReason::Forbidden, 403),
+      (RefusalReason::NoSuchService, 403),
+      (RefusalReason::Overloaded, 503),
+      (RefusalReason::ShuttingDown, 503),
+    ] {
+      let refusing = async {
+        let registry = RefusingServiceRegistry(Refusal::with_message(reason, "Refused by test"));
+        let stream = accept_stream(&services).await;
+        let negotiated = NegotiationService::new(Arc::new(registry))
+          .negotiate(stream, Arc::clone(&services))
+          .await;
+        assert!(negotiated.is_err());
+      };
+      let ((response, handled), ()) = futures::future::join(
+        http_connect(&listener, "CONNECT example.com:443 HTTP/1.1\r\n\r\n".into()),
+        refusing,
+      )
+      .await;
+      assert!(
+        response.starts_with(&format!("HTTP/1.1 {} ", status)),
+        "{:?}: {}",
+        reason,
+        response
+      );
+      assert_matches!(handled, Err(HttpConnectError::Refused(refused)) if refused == status);
+    }
+  }
+}
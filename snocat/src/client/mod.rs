@@ -0,0 +1,303 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Types for building a Snocat client and forwarding connections
+
+use futures::future::{BoxFuture, FutureExt};
+use std::convert::Infallible;
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  sync::oneshot,
+};
+
+use crate::{
+  common::protocol::{
+    proxy_tcp::{TcpStreamClient, TcpStreamService, TcpStreamTarget},
+    service::{Client, ClientError, ClientResult, ProtocolInfo, RouteAddressBuilder},
+    tunnel::ArcTunnel,
+    RouteAddress, ServiceVersion,
+  },
+  util::tunnel_stream::TunnelStream,
+};
+
+pub mod http_connect;
+pub mod socks5;
+pub mod virtual_host;
+
+/// Proxies a local stream over a tunnel as a [TcpStreamClient], once the stream is provided
+///
+/// Proxy front ends must answer their own clients differently depending on whether routing
+/// succeeded; the local stream is only handed to this client once it has, so that a failure
+/// response can still be written to the stream otherwise.
+#[derive(Debug)]
+pub struct DeferredTcpStreamClient<S> {
+  stream: oneshot::Receiver<S>,
+}
+
+impl<S> DeferredTcpStreamClient<S> {
+  pub fn new() -> (oneshot::Sender<S>, Self) {
+    let (sender, stream) = oneshot::channel();
+    (sender, Self { stream })
+  }
+}
+
+impl<S> ProtocolInfo for DeferredTcpStreamClient<S> {
+  fn protocol_name() -> &'static str
+  where
+    Self: Sized,
+  {
+    TcpStreamService::protocol_name()
+  }
+}
+
+impl<S> RouteAddressBuilder for DeferredTcpStreamClient<S> {
+  type Params = TcpStreamTarget;
+  type BuildError = Infallible;
+
+  fn build_addr(args: Self::Params) -> Result<RouteAddress, Self::BuildError>
+  where
+    Self: Sized,
+  {
+    Ok(args.into())
+  }
+}
+
+impl<'stream, TStream, S> Client<'stream, TStream> for DeferredTcpStreamClient<S>
+where
+  S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+  TStream: TunnelStream + Send + 'stream,
+{
+  type Response = (u64, u64);
+
+  type Error = std::io::Error;
+
+  type Future = BoxFuture<'stream, ClientResult<'stream, Self, TStream>>;
+
+  fn handle(
+    self,
+    addr: RouteAddress,
+    version: ServiceVersion,
+    tunnel: TStream,
+    parent_tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    let fut = async move {
+      // The sender is dropped without a stream if the local client could not be told of success
+      let stream = self.stream.await.map_err(|_| ClientError::UnexpectedEnd)?;
+      let (recv, send) = tokio::io::split(stream);
+      TcpStreamClient::new(recv, send)
+        .handle(addr, version, tunnel, parent_tunnel)
+        .await
+    };
+    fut.fuse().boxed()
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use futures::{
+    future::{self, BoxFuture, FutureExt},
+    TryStreamExt,
+  };
+  use std::{convert::Infallible, ops::RangeInclusive, sync::Arc};
+  use tokio::sync::mpsc;
+
+  use crate::{
+    common::protocol::{
+      negotiation::{ArcService, NegotiationService},
+      proxy_tcp::TcpStreamService,
+      service::{
+        Client, ClientError, ClientResult, ProtocolInfo, Request, RouteAddressBuilder, Router,
+        RoutingError,
+      },
+      traits::ServiceRegistry,
+      tunnel::{duplex, ArcTunnel, Tunnel, TunnelIncomingType, TunnelName},
+      RequestHeaders, RouteAddress, Service, ServiceError, ServiceVersion,
+    },
+    util::tunnel_stream::{TunnelStream, WrappedStream},
+  };
+
+  /// Routes requests for a single tunnel name directly to a local [TcpStreamService]
+  pub(crate) struct LoopbackRouter {
+    tunnel_name: TunnelName,
+    listener: ArcTunnel<'static>,
+    connector: ArcTunnel<'static>,
+  }
+
+  impl LoopbackRouter {
+    pub(crate) fn new(tunnel_name: TunnelName) -> Self {
+      let duplex::EntangledTunnels {
+        listener,
+        connector,
+      } = duplex::channel();
+      Self {
+        tunnel_name,
+        listener: Arc::new(listener),
+        connector: Arc::new(connector),
+      }
+    }
+  }
+
+  impl Router for LoopbackRouter {
+    type Error = ();
+    type Stream = WrappedStream;
+    type LocalAddress = TunnelName;
+
+    fn route<'client, 'result, TProtocolClient, IntoLocalAddress: Into<Self::LocalAddress>>(
+      &self,
+      request: Request<'client, Self::Stream, TProtocolClient>,
+      local_address: IntoLocalAddress,
+    ) -> BoxFuture<'client, Result<TProtocolClient::Future, RoutingError<Self::Error>>>
+    where
+      TProtocolClient: Client<'result, Self::Stream> + Send + 'client,
+    {
+      let found = local_address.into() == self.tunnel_name;
+      let (listener, connector) = (Arc::clone(&self.listener), Arc::clone(&self.connector));
+      async move {
+        if !found {
+          return Err(RoutingError::RouteNotFound(request.address));
+        }
+        let (client_link, service_link) = WrappedStream::duplex(8192);
+        let addr = request.address.clone();
+        tokio::task::spawn({
+          let addr = addr.clone();
+          async move {
+            let service = TcpStreamService::new(true);
+            let _ = service
+              .handle(
+                addr,
+                0,
+                Default::default(),
+                Box::new(service_link),
+                listener,
+              )
+              .await;
+          }
+        });
+        Ok(
+          request
+            .protocol_client
+            .handle(addr, 0, client_link, connector),
+        )
+      }
+      .boxed()
+    }
+  }
+
+  /// Serves every request to a single service, if it accepts them
+  pub(crate) struct SingleServiceRegistry(pub(crate) ArcService<anyhow::Error>);
+
+  impl ServiceRegistry for SingleServiceRegistry {
+    type Error = anyhow::Error;
+
+    fn find_service(
+      self: Arc<Self>,
+      addr: &RouteAddress,
+      tunnel: &ArcTunnel,
+    ) -> Option<ArcService<anyhow::Error>> {
+      Some(Arc::clone(&self.0)).filter(|service| service.accepts(addr, tunnel))
+    }
+  }
+
+  /// Offers service versions 0 and 1, responding with the version selected during negotiation
+  pub(crate) struct VersionedClient;
+
+  impl ProtocolInfo for VersionedClient {
+    fn protocol_name() -> &'static str {
+      "versioned"
+    }
+
+    fn service_versions() -> RangeInclusive<ServiceVersion> {
+      0..=1
+    }
+  }
+
+  impl RouteAddressBuilder for VersionedClient {
+    type Params = RouteAddress;
+    type BuildError = Infallible;
+
+    fn build_addr(args: Self::Params) -> Result<RouteAddress, Self::BuildError> {
+      Ok(args)
+    }
+  }
+
+  impl<'stream, TStream: 'stream> Client<'stream, TStream> for VersionedClient {
+    type Response = ServiceVersion;
+    type Error = Infallible;
+    type Future = future::Ready<ClientResult<'stream, Self, TStream>>;
+
+    fn handle(
+      self,
+      _addr: RouteAddress,
+      version: ServiceVersion,
+      _stream: TStream,
+      _tunnel: ArcTunnel<'static>,
+    ) -> Self::Future {
+      future::ready(Result::<_, ClientError<_>>::Ok(version))
+    }
+  }
+
+  /// Accepts every address, reporting the version and headers of each request it handles
+  pub(crate) struct RecordingService {
+    versions: RangeInclusive<ServiceVersion>,
+    handled: mpsc::UnboundedSender<(ServiceVersion, RequestHeaders)>,
+  }
+
+  impl RecordingService {
+    pub(crate) fn new(
+      versions: RangeInclusive<ServiceVersion>,
+    ) -> (
+      Self,
+      mpsc::UnboundedReceiver<(ServiceVersion, RequestHeaders)>,
+    ) {
+      let (handled, receiver) = mpsc::unbounded_channel();
+      (Self { versions, handled }, receiver)
+    }
+  }
+
+  impl Service for RecordingService {
+    type Error = anyhow::Error;
+
+    fn accepts(&self, _addr: &RouteAddress, _tunnel: &ArcTunnel) -> bool {
+      true
+    }
+
+    fn service_versions(&self) -> RangeInclusive<ServiceVersion> {
+      self.versions.clone()
+    }
+
+    fn handle<'a>(
+      &'a self,
+      _addr: RouteAddress,
+      version: ServiceVersion,
+      headers: RequestHeaders,
+      _stream: Box<dyn TunnelStream + Send + 'static>,
+      _tunnel: ArcTunnel<'static>,
+    ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>> {
+      let _ = self.handled.send((version, headers));
+      future::ready(Ok(())).boxed()
+    }
+  }
+
+  /// Accepts the next stream opened to a tunnel
+  pub(crate) async fn accept_stream(tunnel: &ArcTunnel<'static>) -> WrappedStream {
+    let mut downlink = tunnel.downlink().await.unwrap();
+    let next = downlink.as_stream().try_next().await.unwrap();
+    match next {
+      Some(TunnelIncomingType::BiStream(stream)) => stream,
+      _ => panic!("Expected a stream to be opened"),
+    }
+  }
+
+  /// Negotiates and handles the first stream opened to a tunnel with the given service
+  pub(crate) async fn serve_one(tunnel: ArcTunnel<'static>, service: ArcService<anyhow::Error>) {
+    let stream = accept_stream(&tunnel).await;
+    let (stream, addr, version, headers, service) =
+      NegotiationService::new(Arc::new(SingleServiceRegistry(service)))
+        .negotiate(stream, Arc::clone(&tunnel))
+        .await
+        .unwrap();
+    service
+      .handle(addr, version, headers, Box::new(stream), tunnel)
+      .await
+      .unwrap();
+  }
+}
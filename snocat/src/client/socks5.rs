@@ -0,0 +1,372 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! A SOCKS5 front end which forwards each CONNECT request over a tunnel
+//!
+//! Connections accepted by a [Socks5Listener] perform the SOCKS5 handshake locally, after which
+//! the requested destination is sent to a remote [TcpStreamService] as a [TcpStreamTarget], as
+//! with `ssh -D`. Only the `CONNECT` command without authentication is supported; `BIND` and
+//! `UDP ASSOCIATE` requests are refused as unsupported commands.
+//!
+//! [TcpStreamService]: crate::common::protocol::proxy_tcp::TcpStreamService
+use std::{
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+  sync::Arc,
+};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::TcpListener,
+};
+use tokio_util::sync::CancellationToken;
+use tracing_futures::Instrument;
+
+use super::DeferredTcpStreamClient;
+use crate::{
+  common::protocol::{
+    proxy_tcp::{DnsTarget, TcpStreamTarget},
+    service::{ClientError, Request, Router, RoutingError},
+  },
+  util::tunnel_stream::TunnelStream,
+};
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTHENTICATION: u8 = 0x00;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+const COMMAND_CONNECT: u8 = 0x01;
+const ADDRESS_TYPE_IPV4: u8 = 0x01;
+const ADDRESS_TYPE_DOMAIN: u8 = 0x03;
+const ADDRESS_TYPE_IPV6: u8 = 0x04;
+
+/// Reply codes sent in response to a SOCKS5 request, per RFC 1928 section 6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Socks5Reply {
+  Succeeded = 0x00,
+  GeneralFailure = 0x01,
+  NotAllowed = 0x02,
+  NetworkUnreachable = 0x03,
+  HostUnreachable = 0x04,
+  ConnectionRefused = 0x05,
+  TtlExpired = 0x06,
+  CommandNotSupported = 0x07,
+  AddressTypeNotSupported = 0x08,
+}
+
+impl<RouterError> From<&RoutingError<RouterError>> for Socks5Reply {
+  fn from(error: &RoutingError<RouterError>) -> Self {
+    match error {
+      RoutingError::RouteNotFound(_)
+      | RoutingError::RouteUnavailable(_)
+      | RoutingError::LinkOpenFailure(_) => Socks5Reply::NetworkUnreachable,
+      RoutingError::NoSuchService(_) | RoutingError::Forbidden(_) => Socks5Reply::NotAllowed,
+      RoutingError::InvalidAddress | RoutingError::BadAddress(_) => Socks5Reply::HostUnreachable,
+      RoutingError::Overloaded(_)
+      | RoutingError::ShuttingDown(_)
+      | RoutingError::NegotiationError(_)
+      | RoutingError::RouterError(_) => Socks5Reply::GeneralFailure,
+    }
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Socks5Error {
+  #[error("SOCKS client IO failure")]
+  IOError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    std::io::Error,
+  ),
+  #[error("Unsupported SOCKS version {0}")]
+  UnsupportedVersion(u8),
+  #[error("SOCKS client offered no supported authentication method")]
+  NoAcceptableMethod,
+  #[error("Unsupported SOCKS command {0}")]
+  UnsupportedCommand(u8),
+  #[error("Unsupported SOCKS address type {0}")]
+  UnsupportedAddressType(u8),
+  #[error("SOCKS destination domain was not valid UTF-8")]
+  InvalidDomain,
+  #[error("SOCKS request refused with reply {0:?}")]
+  Refused(Socks5Reply),
+  #[error("Proxying of SOCKS connection failed")]
+  ProxyFailure(#[source] ClientError<std::io::Error>),
+}
+
+/// Performs method selection and reads a `CONNECT` request from a SOCKS5 client
+///
+/// Requests which cannot be served are answered with a failure reply before returning an error.
+pub async fn accept_connect<S>(stream: &mut S) -> Result<TcpStreamTarget, Socks5Error>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let mut greeting = [0u8; 2];
+  stream.read_exact(&mut greeting).await?;
+  let [version, method_count] = greeting;
+  if version != SOCKS_VERSION {
+    return Err(Socks5Error::UnsupportedVersion(version));
+  }
+  let mut methods = vec![0u8; method_count as usize];
+  stream.read_exact(&mut methods).await?;
+  if !methods.contains(&METHOD_NO_AUTHENTICATION) {
+    stream
+      .write_all(&[SOCKS_VERSION, METHOD_NO_ACCEPTABLE])
+      .await?;
+    return Err(Socks5Error::NoAcceptableMethod);
+  }
+  stream
+    .write_all(&[SOCKS_VERSION, METHOD_NO_AUTHENTICATION])
+    .await?;
+
+  let mut header = [0u8; 4];
+  stream.read_exact(&mut header).await?;
+  let [version, command, _reserved, address_type] = header;
+  if version != SOCKS_VERSION {
+    return Err(Socks5Error::UnsupportedVersion(version));
+  }
+  if command != COMMAND_CONNECT {
+    write_reply(stream, Socks5Reply::CommandNotSupported).await?;
+    return Err(Socks5Error::UnsupportedCommand(command));
+  }
+  let target = match address_type {
+    ADDRESS_TYPE_IPV4 => {
+      let mut addr = [0u8; 4];
+      stream.read_exact(&mut addr).await?;
+      let port = stream.read_u16().await?;
+      TcpStreamTarget::SocketAddr(SocketAddr::new(Ipv4Addr::from(addr).into(), port))
+    }
+    ADDRESS_TYPE_IPV6 => {
+      let mut addr = [0u8; 16];
+      stream.read_exact(&mut addr).await?;
+      let port = stream.read_u16().await?;
+      TcpStreamTarget::SocketAddr(SocketAddr::new(Ipv6Addr::from(addr).into(), port))
+    }
+    ADDRESS_TYPE_DOMAIN => {
+      let length = stream.read_u8().await?;
+      let mut host = vec![0u8; length as usize];
+      stream.read_exact(&mut host).await?;
+      let port = stream.read_u16().await?;
+      let host = match String::from_utf8(host) {
+        Ok(host) => host,
+        Err(_) => {
+          write_reply(stream, Socks5Reply::HostUnreachable).await?;
+          return Err(Socks5Error::InvalidDomain);
+        }
+      };
+      DnsTarget::PreferHigher { host, port }.into()
+    }
+    other => {
+      write_reply(stream, Socks5Reply::AddressTypeNotSupported).await?;
+      return Err(Socks5Error::UnsupportedAddressType(other));
+    }
+  };
+  Ok(target)
+}
+
+/// Replies to a SOCKS5 request
+///
+/// The bound address is always reported as unspecified, as the
+/// outbound connection is made by the remote end of the tunnel.
+pub async fn write_reply<S>(stream: &mut S, reply: Socks5Reply) -> Result<(), std::io::Error>
+where
+  S: AsyncWrite + Unpin,
+{
+  let mut message = [0u8; 10];
+  message[..4].copy_from_slice(&[SOCKS_VERSION, reply as u8, 0, ADDRESS_TYPE_IPV4]);
+  stream.write_all(&message).await
+}
+
+/// Accepts SOCKS5 connections, routing each to the tunnel at a fixed local address
+pub struct Socks5Listener<TRouter: Router> {
+  router: Arc<TRouter>,
+  local_address: TRouter::LocalAddress,
+}
+
+impl<TRouter: Router> std::fmt::Debug for Socks5Listener<TRouter> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Socks5Listener").finish_non_exhaustive()
+  }
+}
+
+impl<TRouter> Socks5Listener<TRouter>
+where
+  TRouter: Router + Send + Sync,
+  TRouter::Error: std::fmt::Debug,
+  TRouter::Stream: TunnelStream + Send + 'static,
+  TRouter::LocalAddress: Clone + Send + Sync,
+{
+  pub fn new(router: Arc<TRouter>, local_address: TRouter::LocalAddress) -> Self {
+    Self {
+      router,
+      local_address,
+    }
+  }
+
+  /// Serves SOCKS5 clients from the listener until `stop_accepting` is cancelled
+  ///
+  /// Connections accepted before cancellation are served to completion.
+  pub async fn serve(&self, listener: TcpListener, stop_accepting: CancellationToken) {
+    use futures::stream::StreamExt;
+    tokio_stream::wrappers::TcpListenerStream::new(listener)
+      .take_until(stop_accepting.cancelled())
+      .for_each_concurrent(None, |connection| async move {
+        let connection = match connection {
+          Ok(connection) => connection,
+          Err(e) => {
+            tracing::warn!(error = ?e, "Failed to accept SOCKS5 connection");
+            return;
+          }
+        };
+        let peer = connection.peer_addr().ok();
+        let span = tracing::span!(tracing::Level::DEBUG, "socks5", peer = ?peer);
+        match self.handle_connection(connection).instrument(span).await {
+          Ok((client_to_tunnel, tunnel_to_client)) => tracing::info!(
+            target = "socks5_close",
+            client_to_tunnel,
+            tunnel_to_client,
+            "Closing SOCKS5 connection",
+          ),
+          Err(e) => {
+            tracing::debug!(target = "socks5_error", error = ?e, "SOCKS5 connection failed")
+          }
+        }
+      })
+      .await
+  }
+
+  /// Performs the SOCKS5 handshake on a stream, then proxies it to the requested destination
+  pub async fn handle_connection<S>(&self, mut stream: S) -> Result<(u64, u64), Socks5Error>
+  where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+  {
+    let target = accept_connect(&mut stream).await?;
+    tracing::debug!(%target, "SOCKS5 connect requested");
+    let (handoff, client) = DeferredTcpStreamClient::new();
+    let request = Request::new(client, target).unwrap_or_else(|never| match never {});
+    let proxy = match self.router.route(request, self.local_address.clone()).await {
+      Ok(proxy) => proxy,
+      Err(e) => {
+        let reply = Socks5Reply::from(&e);
+        tracing::debug!(error = %e, ?reply, "Routing of SOCKS5 request failed");
+        write_reply(&mut stream, reply).await?;
+        return Err(Socks5Error::Refused(reply));
+      }
+    };
+    write_reply(&mut stream, Socks5Reply::Succeeded).await?;
+    if handoff.send(stream).is_err() {
+      unreachable!("Client future is held until after the stream is handed off");
+    }
+    proxy.await.map_err(Socks5Error::ProxyFailure)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    assert_matches::assert_matches,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+  };
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+  };
+
+  use super::{accept_connect, Socks5Error, Socks5Listener, Socks5Reply};
+  use crate::{
+    client::tests::LoopbackRouter,
+    common::protocol::{
+      proxy_tcp::{DnsTarget, TcpStreamTarget},
+      tunnel::TunnelName,
+    },
+  };
+
+  #[tokio::test]
+  async fn connect_request_parsing() {
+    let (mut socks_client, mut socks_server) = tokio::io::duplex(256);
+    socks_client.write_all(&[5, 2, 0x02, 0x00]).await.unwrap();
+    socks_client.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+    socks_client.write_all(b"example.com").await.unwrap();
+    socks_client.write_all(&443u16.to_be_bytes()).await.unwrap();
+    assert_eq!(
+      accept_connect(&mut socks_server).await.unwrap(),
+      TcpStreamTarget::Dns(DnsTarget::PreferHigher {
+        host: "example.com".into(),
+        port: 443
+      })
+    );
+    let mut method = [0u8; 2];
+    socks_client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0]);
+
+    // UDP ASSOCIATE is refused as an unsupported command
+    socks_client.write_all(&[5, 1, 0x00]).await.unwrap();
+    socks_client
+      .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+      .await
+      .unwrap();
+    assert_matches!(
+      accept_connect(&mut socks_server).await,
+      Err(Socks5Error::UnsupportedCommand(3))
+    );
+    let mut reply = [0u8; 12];
+    socks_client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(
+      reply[..4],
+      [5, 0, 5, Socks5Reply::CommandNotSupported as u8]
+    );
+  }
+
+  /// Requests a connection to the target, returning the reply code once the connection closes
+  async fn socks_connect(listener: &Socks5Listener<LoopbackRouter>, target: SocketAddr) -> u8 {
+    let (mut socks_client, socks_server) = tokio::io::duplex(8192);
+    let client = async move {
+      socks_client.write_all(&[5, 1, 0x00]).await.unwrap();
+      let mut request = vec![5, 1, 0, 1];
+      request.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+      request.extend_from_slice(&target.port().to_be_bytes());
+      socks_client.write_all(&request).await.unwrap();
+      let mut reply = [0u8; 12];
+      socks_client.read_exact(&mut reply).await.unwrap();
+      assert_eq!(reply[..2], [5, 0]);
+      if reply[3] == Socks5Reply::Succeeded as u8 {
+        socks_client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        socks_client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+      }
+      reply[3]
+    };
+    let (handled, reply) =
+      futures::future::join(listener.handle_connection(socks_server), client).await;
+    if reply == Socks5Reply::Succeeded as u8 {
+      assert_eq!(handled.unwrap(), (4, 4));
+    } else {
+      assert_matches!(handled, Err(Socks5Error::Refused(_)));
+    }
+    reply
+  }
+
+  #[tokio::test]
+  async fn connect_over_tunnel() {
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::task::spawn(async move {
+      let (mut connection, _) = echo.accept().await.unwrap();
+      let mut buffer = [0u8; 4];
+      connection.read_exact(&mut buffer).await.unwrap();
+      connection.write_all(&buffer).await.unwrap();
+    });
+
+    let router = Arc::new(LoopbackRouter::new(TunnelName::new("peer")));
+    let listener = Socks5Listener::new(Arc::clone(&router), TunnelName::new("peer"));
+    assert_eq!(
+      socks_connect(&listener, echo_addr).await,
+      Socks5Reply::Succeeded as u8
+    );
+
+    let unroutable = Socks5Listener::new(router, TunnelName::new("absent"));
+    assert_eq!(
+      socks_connect(&unroutable, echo_addr).await,
+      Socks5Reply::NetworkUnreachable as u8
+    );
+  }
+}
@@ -0,0 +1,575 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! A reverse proxy front end which selects a tunnel by the host name a connection was made for
+//!
+//! The host name is taken from the `Host` header of plain HTTP/1.x connections, or from the
+//! server name indication of a TLS ClientHello, without terminating TLS. With a base domain
+//! configured, `<name>.<base domain>` is routed to the tunnel named `<name>`, allowing a single
+//! listener behind a wildcard DNS entry to serve every connected peer. Without one, the whole
+//! host name is used as the tunnel name.
+//!
+//! The bytes read to find the host name are replayed to the upstream target, so the stream seen
+//! by the peer is exactly the one sent by the client. Only the first request of a connection is
+//! inspected; later requests over a kept-alive connection are sent to the same tunnel.
+use bytes::{Buf, Bytes};
+use std::{
+  pin::Pin,
+  sync::Arc,
+  task::{Context, Poll},
+};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+  net::TcpListener,
+};
+use tokio_util::sync::CancellationToken;
+use tracing_futures::Instrument;
+
+use super::{
+  http_connect::{
+    read_request_head, reason_phrase, routing_status, HttpConnectError, MAX_REQUEST_HEAD_LENGTH,
+  },
+  DeferredTcpStreamClient,
+};
+use crate::{
+  common::protocol::{
+    proxy_tcp::TcpStreamTarget,
+    service::{ClientError, Request, Router},
+    tunnel::TunnelName,
+  },
+  util::tunnel_stream::TunnelStream,
+};
+
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_SERVER_NAME_EXTENSION: u16 = 0x0000;
+const TLS_HOST_NAME: u8 = 0x00;
+/// Maximum length of a TLS plaintext record, which a ClientHello must fit within
+const MAX_TLS_RECORD_LENGTH: usize = 16384;
+const TLS_RECORD_HEADER_LENGTH: usize = 5;
+
+#[derive(thiserror::Error, Debug)]
+pub enum VirtualHostError {
+  #[error("Virtual host client IO failure")]
+  IOError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    std::io::Error,
+  ),
+  #[error("HTTP request head exceeded the maximum length")]
+  HeadTooLarge,
+  #[error("Malformed HTTP request")]
+  MalformedRequest,
+  #[error("Malformed TLS ClientHello")]
+  MalformedClientHello,
+  #[error("No host name was given for the connection")]
+  MissingHost,
+  #[error("No tunnel is served for host {0:?}")]
+  UnknownHost(String),
+  #[error("Connection refused with status {0}")]
+  Refused(u16),
+  #[error("Proxying of connection failed")]
+  ProxyFailure(#[source] ClientError<std::io::Error>),
+}
+
+impl VirtualHostError {
+  /// The status with which the error is reported to an HTTP client, if it can still be reported
+  pub fn status(&self) -> Option<u16> {
+    match self {
+      VirtualHostError::IOError(_)
+      | VirtualHostError::MalformedClientHello
+      | VirtualHostError::ProxyFailure(_) => None,
+      VirtualHostError::HeadTooLarge => Some(431),
+      VirtualHostError::MalformedRequest | VirtualHostError::MissingHost => Some(400),
+      VirtualHostError::UnknownHost(_) => Some(404),
+      VirtualHostError::Refused(status) => Some(*status),
+    }
+  }
+}
+
+/// The protocol of a connection, as detected from its first byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniffedProtocol {
+  Http,
+  Tls,
+}
+
+fn take<'a>(input: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+  if input.len() < length {
+    return None;
+  }
+  let (head, tail) = input.split_at(length);
+  *input = tail;
+  Some(head)
+}
+
+fn take_u8(input: &mut &[u8]) -> Option<u8> {
+  take(input, 1).map(|bytes| bytes[0])
+}
+
+fn take_u16(input: &mut &[u8]) -> Option<u16> {
+  take(input, 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn take_u8_prefixed<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+  let length = take_u8(input)?;
+  take(input, length as usize)
+}
+
+fn take_u16_prefixed<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+  let length = take_u16(input)?;
+  take(input, length as usize)
+}
+
+/// Parses the host name from the server name indication of a TLS ClientHello record
+///
+/// Returns `None` if the record is not a complete ClientHello, or if it names no host. A
+/// ClientHello fragmented across multiple records is not supported.
+pub fn client_hello_server_name(record: &[u8]) -> Option<String> {
+  let mut input = record;
+  let header = take(&mut input, TLS_RECORD_HEADER_LENGTH)?;
+  if header[0] != TLS_HANDSHAKE_RECORD {
+    return None;
+  }
+  let mut handshake = take(
+    &mut input,
+    u16::from_be_bytes([header[3], header[4]]) as usize,
+  )?;
+  if take_u8(&mut handshake)? != TLS_CLIENT_HELLO {
+    return None;
+  }
+  let length = take(&mut handshake, 3)?;
+  let length = u32::from_be_bytes([0, length[0], length[1], length[2]]);
+  let mut hello = take(&mut handshake, length as usize)?;
+  // Legacy version and client random
+  take(&mut hello, 2 + 32)?;
+  let _session_id = take_u8_prefixed(&mut hello)?;
+  let _cipher_suites = take_u16_prefixed(&mut hello)?;
+  let _compression_methods = take_u8_prefixed(&mut hello)?;
+  let mut extensions = take_u16_prefixed(&mut hello)?;
+  while !extensions.is_empty() {
+    let extension_type = take_u16(&mut extensions)?;
+    let mut extension = take_u16_prefixed(&mut extensions)?;
+    if extension_type != TLS_SERVER_NAME_EXTENSION {
+      continue;
+    }
+    let mut names = take_u16_prefixed(&mut extension)?;
+    while !names.is_empty() {
+      let name_type = take_u8(&mut names)?;
+      let name = take_u16_prefixed(&mut names)?;
+      if name_type == TLS_HOST_NAME {
+        return std::str::from_utf8(name).ok().map(str::to_string);
+      }
+    }
+    return None;
+  }
+  None
+}
+
+/// Reads from the stream until the buffer holds at least `length` bytes
+async fn fill<S>(stream: &mut S, buffer: &mut Vec<u8>, length: usize) -> std::io::Result<()>
+where
+  S: AsyncRead + Unpin,
+{
+  while buffer.len() < length {
+    if stream.read_buf(buffer).await? == 0 {
+      return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+  }
+  Ok(())
+}
+
+async fn read_server_name<S>(
+  stream: &mut S,
+  buffer: &mut Vec<u8>,
+) -> Result<String, VirtualHostError>
+where
+  S: AsyncRead + Unpin,
+{
+  fill(stream, buffer, TLS_RECORD_HEADER_LENGTH).await?;
+  let record_length = u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+  if record_length > MAX_TLS_RECORD_LENGTH {
+    return Err(VirtualHostError::MalformedClientHello);
+  }
+  let record_length = TLS_RECORD_HEADER_LENGTH + record_length;
+  fill(stream, buffer, record_length).await?;
+  client_hello_server_name(&buffer[..record_length]).ok_or(VirtualHostError::MissingHost)
+}
+
+async fn read_host_header<S>(
+  stream: &mut S,
+  buffer: &mut Vec<u8>,
+) -> Result<String, VirtualHostError>
+where
+  S: AsyncRead + Unpin,
+{
+  let head_length = loop {
+    if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+      break end + 4;
+    }
+    if buffer.len() as u64 >= MAX_REQUEST_HEAD_LENGTH {
+      return Err(VirtualHostError::HeadTooLarge);
+    }
+    let wanted = buffer.len() + 1;
+    fill(stream, buffer, wanted).await?;
+  };
+  let head = read_request_head(&mut &buffer[..head_length])
+    .await
+    .map_err(|e| match e {
+      HttpConnectError::HeadTooLarge => VirtualHostError::HeadTooLarge,
+      _ => VirtualHostError::MalformedRequest,
+    })?;
+  head
+    .header("Host")
+    .map(str::to_string)
+    .ok_or(VirtualHostError::MissingHost)
+}
+
+/// Replays bytes already read from a stream before continuing to read from the stream itself
+struct PrefixedStream<S> {
+  prefix: Bytes,
+  inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<std::io::Result<()>> {
+    if self.prefix.is_empty() {
+      return Pin::new(&mut self.inner).poll_read(cx, buf);
+    }
+    let length = self.prefix.len().min(buf.remaining());
+    buf.put_slice(&self.prefix[..length]);
+    self.prefix.advance(length);
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<std::io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
+
+/// Accepts HTTP or TLS connections, routing each to the tunnel named by its host
+pub struct VirtualHostListener<TRouter> {
+  router: Arc<TRouter>,
+  upstream: TcpStreamTarget,
+  base_domain: Option<String>,
+}
+
+impl<TRouter> std::fmt::Debug for VirtualHostListener<TRouter> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("VirtualHostListener")
+      .field("upstream", &self.upstream)
+      .field("base_domain", &self.base_domain)
+      .finish_non_exhaustive()
+  }
+}
+
+impl<TRouter> VirtualHostListener<TRouter> {
+  /// Creates a listener which forwards connections to `upstream` on the side of the chosen peer
+  pub fn new(router: Arc<TRouter>, upstream: TcpStreamTarget) -> Self {
+    Self {
+      router,
+      upstream,
+      base_domain: None,
+    }
+  }
+
+  /// Serves only subdomains of the given domain, naming each tunnel by its subdomain label
+  pub fn with_base_domain<S: Into<String>>(mut self, base_domain: S) -> Self {
+    let base_domain = base_domain.into();
+    let base_domain = base_domain.trim_matches('.').to_ascii_lowercase();
+    self.base_domain = Some(base_domain);
+    self
+  }
+
+  /// Selects the tunnel for a host name, which may include a port
+  pub fn tunnel_for_host(&self, host: &str) -> Option<TunnelName> {
+    let host = match host.strip_prefix('[') {
+      Some(bracketed) => bracketed.split_once(']')?.0,
+      None => match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        Some(_) => return None,
+        None => host,
+      },
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let name = match &self.base_domain {
+      Some(base_domain) => {
+        let label = host.strip_suffix(base_domain.as_str())?.strip_suffix('.')?;
+        if label.contains('.') {
+          return None;
+        }
+        label.to_string()
+      }
+      None => host,
+    };
+    (!name.is_empty()).then(|| TunnelName::new(name))
+  }
+
+  async fn write_failure<S>(
+    stream: &mut S,
+    protocol: SniffedProtocol,
+    error: &VirtualHostError,
+  ) -> std::io::Result<()>
+  where
+    S: AsyncWrite + Unpin,
+  {
+    // A failure can't be reported to TLS clients without terminating TLS; they are disconnected
+    let status = match (protocol, error.status()) {
+      (SniffedProtocol::Http, Some(status)) => status,
+      _ => return Ok(()),
+    };
+    let response = format!(
+      "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+      status,
+      reason_phrase(status)
+    );
+    stream.write_all(response.as_bytes()).await
+  }
+}
+
+impl<TRouter> VirtualHostListener<TRouter>
+where
+  TRouter: Router + Send + Sync,
+  TRouter::Error: std::fmt::Debug,
+  TRouter::Stream: TunnelStream + Send + 'static,
+  TRouter::LocalAddress: From<TunnelName>,
+{
+  /// Serves connections from the listener until `stop_accepting` is cancelled
+  ///
+  /// Connections accepted before cancellation are served to completion.
+  pub async fn serve(&self, listener: TcpListener, stop_accepting: CancellationToken) {
+    use futures::stream::StreamExt;
+    tokio_stream::wrappers::TcpListenerStream::new(listener)
+      .take_until(stop_accepting.cancelled())
+      .for_each_concurrent(None, |connection| async move {
+        let connection = match connection {
+          Ok(connection) => connection,
+          Err(e) => {
+            tracing::warn!(error = ?e, "Failed to accept virtual host connection");
+            return;
+          }
+        };
+        let peer = connection.peer_addr().ok();
+        let span = tracing::span!(tracing::Level::DEBUG, "virtual_host", peer = ?peer);
+        match self.handle_connection(connection).instrument(span).await {
+          Ok((client_to_tunnel, tunnel_to_client)) => tracing::info!(
+            target = "virtual_host_close",
+            client_to_tunnel,
+            tunnel_to_client,
+            "Closing virtual host connection",
+          ),
+          Err(e) => tracing::debug!(
+            target = "virtual_host_error",
+            error = ?e,
+            "Virtual host connection failed"
+          ),
+        }
+      })
+      .await
+  }
+
+  /// Reads the host name of a connection, then proxies it to the upstream of the matching tunnel
+  pub async fn handle_connection<S>(&self, mut stream: S) -> Result<(u64, u64), VirtualHostError>
+  where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+  {
+    let mut preamble = Vec::new();
+    fill(&mut stream, &mut preamble, 1).await?;
+    let protocol = match preamble[0] {
+      TLS_HANDSHAKE_RECORD => SniffedProtocol::Tls,
+      _ => SniffedProtocol::Http,
+    };
+    let host = match protocol {
+      SniffedProtocol::Tls => read_server_name(&mut stream, &mut preamble).await,
+      SniffedProtocol::Http => read_host_header(&mut stream, &mut preamble).await,
+    };
+    let tunnel = host.and_then(|host| {
+      self
+        .tunnel_for_host(&host)
+        .ok_or(VirtualHostError::UnknownHost(host))
+    });
+    let tunnel = match tunnel {
+      Ok(tunnel) => tunnel,
+      Err(e) => {
+        Self::write_failure(&mut stream, protocol, &e).await?;
+        return Err(e);
+      }
+    };
+    tracing::debug!(?protocol, tunnel = ?tunnel, "Virtual host connection received");
+    let (handoff, client) = DeferredTcpStreamClient::new();
+    let request =
+      Request::new(client, self.upstream.clone()).unwrap_or_else(|never| match never {});
+    let proxy = match self.router.route(request, tunnel).await {
+      Ok(proxy) => proxy,
+      Err(e) => {
+        tracing::debug!(error = %e, "Routing of virtual host connection failed");
+        let error = VirtualHostError::Refused(routing_status(&e));
+        Self::write_failure(&mut stream, protocol, &error).await?;
+        return Err(error);
+      }
+    };
+    let stream = PrefixedStream {
+      prefix: preamble.into(),
+      inner: stream,
+    };
+    if handoff.send(stream).is_err() {
+      unreachable!("Client future is held until after the stream is handed off");
+    }
+    proxy.await.map_err(VirtualHostError::ProxyFailure)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{assert_matches::assert_matches, sync::Arc};
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+  };
+
+  use super::{client_hello_server_name, VirtualHostError, VirtualHostListener};
+  use crate::{
+    client::tests::LoopbackRouter,
+    common::protocol::{proxy_tcp::TcpStreamTarget, tunnel::TunnelName},
+  };
+
+  /// Builds a minimal TLS 1.3 ClientHello record naming the given host
+  fn client_hello(server_name: &str) -> Vec<u8> {
+    let name = server_name.as_bytes();
+    let mut server_name_list = vec![0x00];
+    server_name_list.extend((name.len() as u16).to_be_bytes());
+    server_name_list.extend(name);
+    let mut extension = (server_name_list.len() as u16).to_be_bytes().to_vec();
+    extension.extend(server_name_list);
+    // An unrelated extension precedes server name indication: supported_versions, TLS 1.3
+    let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x00];
+    extensions.extend((extension.len() as u16).to_be_bytes());
+    extensions.extend(extension);
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend([0u8; 32]);
+    hello.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    hello.extend((extensions.len() as u16).to_be_bytes());
+    hello.extend(extensions);
+
+    let mut handshake = vec![0x01];
+    handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend(hello);
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend((handshake.len() as u16).to_be_bytes());
+    record.extend(handshake);
+    record
+  }
+
+  #[test]
+  fn host_selection() {
+    let record = client_hello("peer.preview.test");
+    assert_eq!(
+      client_hello_server_name(&record).as_deref(),
+      Some("peer.preview.test")
+    );
+    assert_eq!(client_hello_server_name(&record[..record.len() - 1]), None);
+
+    let listener = VirtualHostListener::new(Arc::new(()), TcpStreamTarget::Port(80));
+    assert_eq!(
+      listener.tunnel_for_host("Peer.Example.com:8080"),
+      Some(TunnelName::new("peer.example.com"))
+    );
+    assert_eq!(
+      listener.tunnel_for_host("[::1]:80"),
+      Some(TunnelName::new("::1"))
+    );
+
+    let listener = listener.with_base_domain(".Preview.Test");
+    assert_eq!(
+      listener.tunnel_for_host("peer.preview.test"),
+      Some(TunnelName::new("peer"))
+    );
+    assert_eq!(
+      listener.tunnel_for_host("PEER.preview.test.:443"),
+      Some(TunnelName::new("peer"))
+    );
+    assert_eq!(listener.tunnel_for_host("preview.test"), None);
+    assert_eq!(listener.tunnel_for_host("a.peer.preview.test"), None);
+    assert_eq!(listener.tunnel_for_host("peerpreview.test"), None);
+  }
+
+  /// Sends a preamble through a listener to an echo server, returning what came back
+  async fn round_trip(preamble: Vec<u8>) -> (Vec<u8>, Result<(u64, u64), VirtualHostError>) {
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = TcpStreamTarget::SocketAddr(echo.local_addr().unwrap());
+    let length = preamble.len();
+    tokio::task::spawn(async move {
+      let (mut connection, _) = echo.accept().await.unwrap();
+      let mut buffer = vec![0u8; length];
+      connection.read_exact(&mut buffer).await.unwrap();
+      connection.write_all(&buffer).await.unwrap();
+    });
+
+    let listener = VirtualHostListener::new(
+      Arc::new(LoopbackRouter::new(TunnelName::new("peer"))),
+      upstream,
+    )
+    .with_base_domain("preview.test");
+    let (mut client, server) = tokio::io::duplex(8192);
+    let client = async move {
+      client.write_all(&preamble).await.unwrap();
+      let mut response = Vec::new();
+      (&mut client)
+        .take(length as u64)
+        .read_to_end(&mut response)
+        .await
+        .unwrap();
+      response
+    };
+    let (handled, response) =
+      futures::future::join(listener.handle_connection(server), client).await;
+    (response, handled)
+  }
+
+  #[tokio::test]
+  async fn proxy_over_tunnel() {
+    let request = b"GET / HTTP/1.1\r\nHost: peer.preview.test\r\n\r\n".to_vec();
+    let (response, handled) = round_trip(request.clone()).await;
+    assert_eq!(response, request);
+    let length = request.len() as u64;
+    assert_eq!(handled.unwrap(), (length, length));
+
+    let record = client_hello("peer.preview.test");
+    let (response, handled) = round_trip(record.clone()).await;
+    assert_eq!(response, record);
+    assert!(handled.is_ok());
+
+    let (response, handled) =
+      round_trip(b"GET / HTTP/1.1\r\nHost: absent.preview.test\r\n\r\n".to_vec()).await;
+    assert!(response.starts_with(b"HTTP/1.1 502 "));
+    assert_matches!(handled, Err(VirtualHostError::Refused(502)));
+
+    let (response, handled) =
+      round_trip(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec()).await;
+    assert!(response.starts_with(b"HTTP/1.1 404 "));
+    assert_matches!(handled, Err(VirtualHostError::UnknownHost(_)));
+
+    // TLS clients are disconnected without a response
+    let (response, handled) = round_trip(client_hello("absent.preview.test")).await;
+    assert!(response.is_empty());
+    assert_matches!(handled, Err(VirtualHostError::Refused(502)));
+  }
+}
@@ -0,0 +1,506 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Names tunnels after the identity in the TLS certificate presented by their remote
+//!
+//! The certificate chain is verified by the transport before authentication begins; this
+//! handler only maps the end-entity certificate to a [TunnelName] and records its identity and
+//! expiry in the tunnel's [AuthenticationAttributes]. The listening side refuses remotes whose
+//! certificate matches none of its rules, and reports the outcome to the connecting side as a
+//! single byte.
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use std::{
+  str::FromStr,
+  time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use super::{
+  limit_authentication_expiry, AuthenticationAttributes, AuthenticationChannel,
+  AuthenticationError, AuthenticationHandler, RemoteAuthenticationError, TunnelInfo,
+};
+use crate::{
+  common::protocol::tunnel::{TunnelName, TunnelSide},
+  util::cancellation::CancellationListener,
+};
+
+/// SHA-256 digest of the DER encoding of the remote's end-entity certificate
+pub const FINGERPRINT_ATTRIBUTE: &str = "x509.fingerprint.sha256";
+/// UTF-8 common name of the certificate subject, if present
+pub const COMMON_NAME_ATTRIBUTE: &str = "x509.subject.cn";
+/// JSON array of the DNS name subject alternative names
+pub const DNS_NAMES_ATTRIBUTE: &str = "x509.san.dns";
+/// JSON array of the URI subject alternative names
+pub const URIS_ATTRIBUTE: &str = "x509.san.uri";
+/// JSON array of the email address subject alternative names
+pub const EMAILS_ATTRIBUTE: &str = "x509.san.email";
+
+const AUTHENTICATION_ACCEPTED: u8 = 1;
+const AUTHENTICATION_REFUSED: u8 = 0;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CertificateParseError {
+  #[error("Certificate was not a valid DER-encoded X.509 certificate")]
+  InvalidCertificate,
+  #[error("Certificate subject alternative names were malformed")]
+  InvalidSubjectAlternativeNames,
+}
+
+/// A field of a certificate which may identify its subject
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CertificateField {
+  DnsName,
+  Uri,
+  Email,
+  CommonName,
+}
+
+impl CertificateField {
+  fn prefix(&self) -> &'static str {
+    match self {
+      CertificateField::DnsName => "dns",
+      CertificateField::Uri => "uri",
+      CertificateField::Email => "email",
+      CertificateField::CommonName => "cn",
+    }
+  }
+}
+
+/// The identifying fields of an X.509 certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateIdentity {
+  pub fingerprint: [u8; 32],
+  pub common_name: Option<String>,
+  pub dns_names: Vec<String>,
+  pub uris: Vec<String>,
+  pub emails: Vec<String>,
+  /// The end of the certificate's validity period
+  pub not_after: SystemTime,
+}
+
+impl CertificateIdentity {
+  pub fn from_der(der: &[u8]) -> Result<Self, CertificateParseError> {
+    let (_, certificate) =
+      X509Certificate::from_der(der).map_err(|_| CertificateParseError::InvalidCertificate)?;
+    let common_name = certificate
+      .subject()
+      .iter_common_name()
+      .next()
+      .and_then(|cn| cn.as_str().ok())
+      .map(str::to_string);
+    let (mut dns_names, mut uris, mut emails) = (Vec::new(), Vec::new(), Vec::new());
+    let alternative_names = certificate
+      .subject_alternative_name()
+      .map_err(|_| CertificateParseError::InvalidSubjectAlternativeNames)?;
+    for name in alternative_names
+      .iter()
+      .flat_map(|extension| extension.value.general_names.iter())
+    {
+      match name {
+        GeneralName::DNSName(name) => dns_names.push(name.to_string()),
+        GeneralName::URI(uri) => uris.push(uri.to_string()),
+        GeneralName::RFC822Name(email) => emails.push(email.to_string()),
+        _ => (),
+      }
+    }
+    let not_after = SystemTime::UNIX_EPOCH
+      + Duration::from_secs(certificate.validity().not_after.timestamp().max(0) as u64);
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, der).as_ref());
+    Ok(Self {
+      fingerprint,
+      common_name,
+      dns_names,
+      uris,
+      emails,
+      not_after,
+    })
+  }
+
+  /// All values of the given field, in the order they appear in the certificate
+  pub fn values(&self, field: CertificateField) -> Vec<&str> {
+    match field {
+      CertificateField::DnsName => self.dns_names.iter().map(String::as_str).collect(),
+      CertificateField::Uri => self.uris.iter().map(String::as_str).collect(),
+      CertificateField::Email => self.emails.iter().map(String::as_str).collect(),
+      CertificateField::CommonName => self.common_name.iter().map(String::as_str).collect(),
+    }
+  }
+
+  pub fn to_attributes(&self) -> AuthenticationAttributes {
+    let to_json = |values: &Vec<String>| serde_json::to_vec(values).expect("Strings serialize");
+    let mut attributes = AuthenticationAttributes::new();
+    attributes.insert(FINGERPRINT_ATTRIBUTE.into(), self.fingerprint.to_vec());
+    if let Some(common_name) = &self.common_name {
+      attributes.insert(
+        COMMON_NAME_ATTRIBUTE.into(),
+        common_name.as_bytes().to_vec(),
+      );
+    }
+    attributes.insert(DNS_NAMES_ATTRIBUTE.into(), to_json(&self.dns_names));
+    attributes.insert(URIS_ATTRIBUTE.into(), to_json(&self.uris));
+    attributes.insert(EMAILS_ATTRIBUTE.into(), to_json(&self.emails));
+    limit_authentication_expiry(&mut attributes, self.not_after);
+    attributes
+  }
+}
+
+/// Maps a certificate field to a tunnel name by stripping a required prefix and suffix
+///
+/// Written as `<field>:<prefix>*<suffix>`, where field is one of `dns`, `uri`, `email`, or `cn`;
+/// `dns:*.tunnels.example.com` names the certificate for `a.tunnels.example.com` as `a`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateNameRule {
+  pub field: CertificateField,
+  pub prefix: String,
+  pub suffix: String,
+}
+
+impl CertificateNameRule {
+  /// Creates a rule using the whole value of the field as the tunnel name
+  pub fn new(field: CertificateField) -> Self {
+    Self {
+      field,
+      prefix: String::new(),
+      suffix: String::new(),
+    }
+  }
+
+  pub fn with_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+    self.prefix = prefix.into();
+    self
+  }
+
+  pub fn with_suffix<S: Into<String>>(mut self, suffix: S) -> Self {
+    self.suffix = suffix.into();
+    self
+  }
+
+  /// Maps the first value of the rule's field which carries its prefix and suffix
+  pub fn apply(&self, identity: &CertificateIdentity) -> Option<TunnelName> {
+    identity
+      .values(self.field)
+      .into_iter()
+      .filter_map(|value| {
+        let name = value
+          .strip_prefix(self.prefix.as_str())?
+          .strip_suffix(self.suffix.as_str())?;
+        (!name.is_empty()).then(|| TunnelName::new(name))
+      })
+      .next()
+  }
+}
+
+impl std::fmt::Display for CertificateNameRule {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}:{}*{}", self.field.prefix(), self.prefix, self.suffix)
+  }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CertificateNameRuleParseError {
+  #[error("Certificate name rules must be of the form <field>:<prefix>*<suffix>")]
+  InvalidFormat,
+  #[error("Unknown certificate field {0:?}; expected one of dns, uri, email, or cn")]
+  UnknownField(String),
+}
+
+impl FromStr for CertificateNameRule {
+  type Err = CertificateNameRuleParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (field, pattern) = s
+      .split_once(':')
+      .ok_or(CertificateNameRuleParseError::InvalidFormat)?;
+    let field = [
+      CertificateField::DnsName,
+      CertificateField::Uri,
+      CertificateField::Email,
+      CertificateField::CommonName,
+    ]
+    .into_iter()
+    .find(|candidate| candidate.prefix().eq_ignore_ascii_case(field))
+    .ok_or_else(|| CertificateNameRuleParseError::UnknownField(field.to_string()))?;
+    let (prefix, suffix) = pattern
+      .split_once('*')
+      .filter(|(_, suffix)| !suffix.contains('*'))
+      .ok_or(CertificateNameRuleParseError::InvalidFormat)?;
+    Ok(
+      CertificateNameRule::new(field)
+        .with_prefix(prefix)
+        .with_suffix(suffix),
+    )
+  }
+}
+
+/// Authenticates tunnels by the certificate their remote presented to the transport
+///
+/// Rules are tried in order, and the first to match names the tunnel. When connecting, a remote
+/// whose certificate matches no rule is named by its address instead, as the transport has
+/// already verified it against the expected server name.
+pub struct CertificateAuthenticationHandler {
+  rules: Vec<CertificateNameRule>,
+}
+
+impl CertificateAuthenticationHandler {
+  pub fn new<Rules: IntoIterator<Item = CertificateNameRule>>(rules: Rules) -> Self {
+    Self {
+      rules: rules.into_iter().collect(),
+    }
+  }
+
+  pub fn rules(&self) -> &[CertificateNameRule] {
+    &self.rules
+  }
+
+  /// Names an identity by the first matching rule
+  pub fn identify(&self, identity: &CertificateIdentity) -> Option<TunnelName> {
+    self.rules.iter().find_map(|rule| rule.apply(identity))
+  }
+
+  fn peer_identity(tunnel_info: &TunnelInfo) -> Option<CertificateIdentity> {
+    let end_entity = tunnel_info.peer_certificates.as_ref()?.first()?;
+    CertificateIdentity::from_der(&end_entity.0)
+      .map_err(|e| tracing::debug!(error = %e, "Peer certificate could not be parsed"))
+      .ok()
+  }
+}
+
+impl Default for CertificateAuthenticationHandler {
+  /// Names tunnels by their first DNS subject alternative name, or else their common name
+  fn default() -> Self {
+    Self::new([
+      CertificateNameRule::new(CertificateField::DnsName),
+      CertificateNameRule::new(CertificateField::CommonName),
+    ])
+  }
+}
+
+impl std::fmt::Debug for CertificateAuthenticationHandler {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("CertificateAuthenticationHandler")
+      .field("rules", &self.rules)
+      .finish()
+  }
+}
+
+impl AuthenticationHandler for CertificateAuthenticationHandler {
+  type Error = std::convert::Infallible;
+
+  fn authenticate<'a>(
+    &'a self,
+    channel: &'a mut AuthenticationChannel<'a>,
+    tunnel_info: TunnelInfo,
+    _shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>>
+  {
+    async move {
+      let identity = Self::peer_identity(&tunnel_info);
+      match tunnel_info.side {
+        TunnelSide::Listen => {
+          let identified = identity
+            .as_ref()
+            .and_then(|identity| Some((self.identify(identity)?, identity.to_attributes())));
+          let outcome = match identified {
+            Some(_) => AUTHENTICATION_ACCEPTED,
+            None => AUTHENTICATION_REFUSED,
+          };
+          channel
+            .write_all(&[outcome])
+            .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+            .await?;
+          match identified {
+            Some(identified) => Ok(identified),
+            None => {
+              tracing::debug!(addr = ?tunnel_info.addr, "No certificate rule matched remote");
+              Err(RemoteAuthenticationError::Refused.into())
+            }
+          }
+        }
+        TunnelSide::Connect => {
+          let outcome = channel
+            .read_u8()
+            .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+            .await?;
+          match outcome {
+            AUTHENTICATION_ACCEPTED => (),
+            AUTHENTICATION_REFUSED => return Err(RemoteAuthenticationError::Refused.into()),
+            _ => {
+              return Err(
+                RemoteAuthenticationError::ProtocolViolation(
+                  "Invalid authentication outcome".into(),
+                )
+                .into(),
+              )
+            }
+          }
+          let name = identity
+            .as_ref()
+            .and_then(|identity| self.identify(identity))
+            .unwrap_or_else(|| TunnelName::new(tunnel_info.addr.to_string()));
+          let attributes = identity
+            .as_ref()
+            .map(CertificateIdentity::to_attributes)
+            .unwrap_or_default();
+          Ok((name, attributes))
+        }
+      }
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::assert_matches::assert_matches;
+
+  use super::{
+    CertificateAuthenticationHandler, CertificateField, CertificateIdentity, CertificateNameRule,
+    TunnelInfo, DNS_NAMES_ATTRIBUTE, FINGERPRINT_ATTRIBUTE,
+  };
+  use crate::{
+    common::{
+      authentication::{
+        tests, AuthenticationError, AuthenticationHandler, RemoteAuthenticationError,
+      },
+      protocol::tunnel::{TunnelName, TunnelSide},
+    },
+    util::{cancellation::CancellationListener, tunnel_stream::WrappedStream},
+  };
+
+  fn certificate(dns_names: &[&str], uri: Option<&str>, common_name: &str) -> rustls::Certificate {
+    let mut params = rcgen::CertificateParams::new(
+      dns_names
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>(),
+    );
+    if let Some(uri) = uri {
+      params
+        .subject_alt_names
+        .push(rcgen::SanType::URI(uri.to_string()));
+    }
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+      .distinguished_name
+      .push(rcgen::DnType::CommonName, common_name);
+    let certificate = rcgen::Certificate::from_params(params).unwrap();
+    rustls::Certificate(certificate.serialize_der().unwrap())
+  }
+
+  #[test]
+  fn certificate_name_rules() {
+    let der = certificate(
+      &["alice.tunnels.example.com", "alice.example.com"],
+      Some("spiffe://example.com/tunnel/alice"),
+      "Alice",
+    );
+    let identity = CertificateIdentity::from_der(&der.0).unwrap();
+    assert_eq!(identity.common_name.as_deref(), Some("Alice"));
+    assert_eq!(
+      identity.dns_names,
+      vec!["alice.tunnels.example.com", "alice.example.com"]
+    );
+    assert_eq!(identity.uris, vec!["spiffe://example.com/tunnel/alice"]);
+    assert_eq!(
+      identity.fingerprint.as_ref(),
+      ring::digest::digest(&ring::digest::SHA256, &der.0).as_ref()
+    );
+    let attributes = identity.to_attributes();
+    assert_eq!(attributes[FINGERPRINT_ATTRIBUTE], identity.fingerprint);
+    assert_eq!(
+      attributes[DNS_NAMES_ATTRIBUTE],
+      br#"["alice.tunnels.example.com","alice.example.com"]"#
+    );
+
+    let rule: CertificateNameRule = "dns:*.tunnels.example.com".parse().unwrap();
+    assert_eq!(
+      rule,
+      CertificateNameRule::new(CertificateField::DnsName).with_suffix(".tunnels.example.com")
+    );
+    assert_eq!(rule.to_string(), "dns:*.tunnels.example.com");
+    assert_eq!(rule.apply(&identity), Some(TunnelName::new("alice")));
+    let rule: CertificateNameRule = "uri:spiffe://example.com/tunnel/*".parse().unwrap();
+    assert_eq!(rule.apply(&identity), Some(TunnelName::new("alice")));
+    let rule: CertificateNameRule = "email:*".parse().unwrap();
+    assert_eq!(rule.apply(&identity), None);
+    assert!("dns".parse::<CertificateNameRule>().is_err());
+    assert!("ip:*".parse::<CertificateNameRule>().is_err());
+    assert!("cn:*a*".parse::<CertificateNameRule>().is_err());
+
+    let handler = CertificateAuthenticationHandler::new([
+      "dns:*.internal".parse().unwrap(),
+      "cn:*".parse().unwrap(),
+    ]);
+    assert_eq!(handler.identify(&identity), Some(TunnelName::new("Alice")));
+    assert_eq!(
+      CertificateAuthenticationHandler::default().identify(&identity),
+      Some(TunnelName::new("alice.tunnels.example.com"))
+    );
+  }
+
+  fn tunnel_info(side: TunnelSide, certificate: Option<rustls::Certificate>) -> TunnelInfo {
+    TunnelInfo {
+      peer_certificates: certificate.map(|certificate| vec![certificate]),
+      ..tests::tunnel_info(side)
+    }
+  }
+
+  #[tokio::test]
+  async fn run_auth() {
+    let never_shutdown = CancellationListener::default();
+    let handler =
+      CertificateAuthenticationHandler::new(["dns:*.tunnels.example.com".parse().unwrap()]);
+    let server_certificate = certificate(&["server.example.com"], None, "server");
+
+    let client_certificate = certificate(&["alice.tunnels.example.com"], None, "alice");
+    let (mut listen_channel, mut connect_channel) = WrappedStream::duplex(64);
+    let (server_res, client_res) = futures::future::join(
+      handler.authenticate(
+        &mut listen_channel,
+        tunnel_info(TunnelSide::Listen, Some(client_certificate)),
+        &never_shutdown,
+      ),
+      handler.authenticate(
+        &mut connect_channel,
+        tunnel_info(TunnelSide::Connect, Some(server_certificate.clone())),
+        &never_shutdown,
+      ),
+    )
+    .await;
+    let (server_name, server_attributes) = server_res.unwrap();
+    assert_eq!(server_name, TunnelName::new("alice"));
+    assert!(server_attributes.contains_key(FINGERPRINT_ATTRIBUTE));
+    // The server's certificate matches no rule, so it is named by its address
+    assert_eq!(client_res.unwrap().0, TunnelName::new("Unidentified"));
+
+    for client_certificate in [None, Some(certificate(&["bob.example.com"], None, "bob"))] {
+      let (mut listen_channel, mut connect_channel) = WrappedStream::duplex(64);
+      let (server_res, client_res) = futures::future::join(
+        handler.authenticate(
+          &mut listen_channel,
+          tunnel_info(TunnelSide::Listen, client_certificate),
+          &never_shutdown,
+        ),
+        handler.authenticate(
+          &mut connect_channel,
+          tunnel_info(TunnelSide::Connect, Some(server_certificate.clone())),
+          &never_shutdown,
+        ),
+      )
+      .await;
+      assert_matches!(
+        server_res,
+        Err(AuthenticationError::Remote(
+          RemoteAuthenticationError::Refused
+        ))
+      );
+      assert_matches!(
+        client_res,
+        Err(AuthenticationError::Remote(
+          RemoteAuthenticationError::Refused
+        ))
+      );
+    }
+  }
+}
@@ -0,0 +1,548 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Handlers composing other handlers over a single authentication channel
+//!
+//! Sub-handlers run one after another on the same channel, so both sides of a tunnel must be
+//! configured with matching compositions. A sub-handler's refusal leaves the channel usable only
+//! when that handler reports refusal to both sides, as the handlers in this crate do; any other
+//! failure ends authentication.
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{
+  authentication_expiry, limit_authentication_expiry, AuthenticationAttributes,
+  AuthenticationChannel, AuthenticationError, AuthenticationHandler, RemoteAuthenticationError,
+  TunnelInfo,
+};
+use crate::{
+  common::protocol::tunnel::{TunnelName, TunnelSide},
+  util::cancellation::CancellationListener,
+};
+
+/// Attribute recording the name of the method chosen by a [MethodSelectingAuthenticationHandler]
+pub const METHOD_ATTRIBUTE: &str = "authentication.method";
+
+/// Tries a primary handler, and falls back to another if the remote is refused by it
+///
+/// The tunnel is named and attributed by whichever handler accepted it.
+///
+/// Built with [AuthenticationHandlerExt::fallback](super::AuthenticationHandlerExt::fallback).
+#[derive(Debug, Clone)]
+pub struct FallbackAuthenticationHandler<TPrimary, TFallback> {
+  pub(super) primary: TPrimary,
+  pub(super) fallback: TFallback,
+}
+
+impl<TPrimary, TFallback> AuthenticationHandler
+  for FallbackAuthenticationHandler<TPrimary, TFallback>
+where
+  TPrimary: AuthenticationHandler,
+  TFallback: AuthenticationHandler<Error = TPrimary::Error>,
+{
+  type Error = TPrimary::Error;
+
+  fn authenticate<'a>(
+    &'a self,
+    channel: &'a mut AuthenticationChannel<'a>,
+    tunnel_info: TunnelInfo,
+    shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>>
+  {
+    async move {
+      match self
+        .primary
+        .authenticate(&mut *channel, tunnel_info.clone(), shutdown_notifier)
+        .await
+      {
+        Err(AuthenticationError::Remote(RemoteAuthenticationError::Refused)) => {
+          tracing::debug!("Primary authentication refused; falling back");
+          self
+            .fallback
+            .authenticate(channel, tunnel_info, shutdown_notifier)
+            .await
+        }
+        res => res,
+      }
+    }
+    .boxed()
+  }
+}
+
+/// Requires every one of a list of handlers to accept the remote, in order
+///
+/// Every handler must give the tunnel the same name, or the remote is refused; as that refusal
+/// is not reported over the channel, it ends authentication rather than allowing a fallback.
+/// Attributes from all handlers are merged, with those of earlier handlers taking precedence
+/// when the same attribute is set more than once, except for the
+/// [expiry](super::EXPIRY_ATTRIBUTE), where the earliest is kept.
+pub struct RequireAllAuthenticationHandler<TError> {
+  handlers: Vec<Arc<dyn AuthenticationHandler<Error = TError>>>,
+}
+
+impl<TError> RequireAllAuthenticationHandler<TError> {
+  pub fn new() -> Self {
+    Self {
+      handlers: Vec::new(),
+    }
+  }
+
+  pub fn with_handler<H: AuthenticationHandler<Error = TError> + 'static>(
+    mut self,
+    handler: H,
+  ) -> Self {
+    self.handlers.push(Arc::new(handler));
+    self
+  }
+}
+
+impl<TError> Default for RequireAllAuthenticationHandler<TError> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<TError> std::fmt::Debug for RequireAllAuthenticationHandler<TError> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("RequireAllAuthenticationHandler")
+      .field("handlers", &self.handlers)
+      .finish()
+  }
+}
+
+impl<TError: Send> AuthenticationHandler for RequireAllAuthenticationHandler<TError> {
+  type Error = TError;
+
+  fn authenticate<'a>(
+    &'a self,
+    channel: &'a mut AuthenticationChannel<'a>,
+    tunnel_info: TunnelInfo,
+    shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>>
+  {
+    async move {
+      let mut identity: Option<(TunnelName, AuthenticationAttributes)> = None;
+      for handler in &self.handlers {
+        let (name, attributes) = handler
+          .authenticate(&mut *channel, tunnel_info.clone(), shutdown_notifier)
+          .await?;
+        match &mut identity {
+          None => identity = Some((name, attributes)),
+          Some((named, _)) if *named != name => {
+            tracing::debug!(
+              first = ?named,
+              mismatched = ?name,
+              "Authentication handlers named the tunnel differently"
+            );
+            return Err(RemoteAuthenticationError::Refused.into());
+          }
+          Some((_, merged)) => {
+            let expiry = authentication_expiry(&attributes);
+            for (key, value) in attributes {
+              merged.entry(key).or_insert(value);
+            }
+            if let Some(expiry) = expiry {
+              limit_authentication_expiry(merged, expiry);
+            }
+          }
+        }
+      }
+      // With nothing to require, there is nothing to name the tunnel by
+      identity.ok_or_else(|| RemoteAuthenticationError::Refused.into())
+    }
+    .boxed()
+  }
+}
+
+/// Delegates to one of several named handlers, chosen by a method-selection exchange
+///
+/// The connecting side offers the names of its methods in the order they were added, and the
+/// listening side selects the first offered method it also supports, replying with its name, or
+/// with an empty name if there is none. The chosen handler's result is returned, with the
+/// method's name recorded under [METHOD_ATTRIBUTE].
+pub struct MethodSelectingAuthenticationHandler<TError> {
+  methods: Vec<(String, Arc<dyn AuthenticationHandler<Error = TError>>)>,
+}
+
+impl<TError> MethodSelectingAuthenticationHandler<TError> {
+  pub fn new() -> Self {
+    Self {
+      methods: Vec::new(),
+    }
+  }
+
+  /// Adds a method under the given name, which must be 1 to 255 bytes long
+  ///
+  /// Methods added earlier are preferred when connecting.
+  pub fn with_method<S: Into<String>, H: AuthenticationHandler<Error = TError> + 'static>(
+    mut self,
+    name: S,
+    handler: H,
+  ) -> Self {
+    let name = name.into();
+    assert!(
+      (1..=u8::MAX as usize).contains(&name.len()),
+      "Method names must be 1 to 255 bytes long"
+    );
+    assert!(
+      self.methods.len() < u8::MAX as usize,
+      "At most 255 methods may be offered"
+    );
+    self.methods.push((name, Arc::new(handler)));
+    self
+  }
+
+  fn method(&self, name: &str) -> Option<&Arc<dyn AuthenticationHandler<Error = TError>>> {
+    self
+      .methods
+      .iter()
+      .find(|(method, _)| method == name)
+      .map(|(_, handler)| handler)
+  }
+
+  async fn read_name(
+    channel: &mut AuthenticationChannel<'_>,
+  ) -> Result<String, RemoteAuthenticationError> {
+    let length = channel
+      .read_u8()
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+    let mut name = vec![0u8; length as usize];
+    channel
+      .read_exact(&mut name)
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+    String::from_utf8(name).map_err(|_| {
+      RemoteAuthenticationError::ProtocolViolation("Method name was not valid UTF8".into())
+    })
+  }
+
+  async fn select_listen_side(
+    &self,
+    channel: &mut AuthenticationChannel<'_>,
+  ) -> Result<String, RemoteAuthenticationError> {
+    let offered_count = channel
+      .read_u8()
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+    let mut selected = None;
+    for _ in 0..offered_count {
+      let offered = Self::read_name(channel).await?;
+      if selected.is_none() && self.method(&offered).is_some() {
+        selected = Some(offered);
+      }
+    }
+    let reply = selected.as_deref().unwrap_or_default();
+    let mut message = vec![reply.len() as u8];
+    message.extend_from_slice(reply.as_bytes());
+    channel
+      .write_all(&message)
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+      .await?;
+    selected.ok_or_else(|| {
+      tracing::debug!("Remote offered no supported authentication method");
+      RemoteAuthenticationError::Refused
+    })
+  }
+
+  async fn select_connecting_side(
+    &self,
+    channel: &mut AuthenticationChannel<'_>,
+  ) -> Result<String, RemoteAuthenticationError> {
+    let mut message = vec![self.methods.len() as u8];
+    for (name, _) in &self.methods {
+      message.push(name.len() as u8);
+      message.extend_from_slice(name.as_bytes());
+    }
+    channel
+      .write_all(&message)
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+      .await?;
+    match Self::read_name(channel).await? {
+      selected if selected.is_empty() => Err(RemoteAuthenticationError::Refused),
+      selected if self.method(&selected).is_some() => Ok(selected),
+      _ => Err(RemoteAuthenticationError::ProtocolViolation(
+        "Selected method was not offered".into(),
+      )),
+    }
+  }
+}
+
+impl<TError> Default for MethodSelectingAuthenticationHandler<TError> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<TError> std::fmt::Debug for MethodSelectingAuthenticationHandler<TError> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("MethodSelectingAuthenticationHandler")
+      .field("methods", &self.methods)
+      .finish()
+  }
+}
+
+impl<TError: Send> AuthenticationHandler for MethodSelectingAuthenticationHandler<TError> {
+  type Error = TError;
+
+  fn authenticate<'a>(
+    &'a self,
+    channel: &'a mut AuthenticationChannel<'a>,
+    tunnel_info: TunnelInfo,
+    shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>>
+  {
+    async move {
+      let selected = match tunnel_info.side {
+        TunnelSide::Listen => self.select_listen_side(&mut *channel).await?,
+        TunnelSide::Connect => self.select_connecting_side(&mut *channel).await?,
+      };
+      let handler = self
+        .method(&selected)
+        .expect("Selected methods are supported");
+      let (name, mut attributes) = handler
+        .authenticate(channel, tunnel_info, shutdown_notifier)
+        .await?;
+      attributes.insert(METHOD_ATTRIBUTE.into(), selected.into_bytes());
+      Ok((name, attributes))
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+  use std::assert_matches::assert_matches;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  use super::{
+    MethodSelectingAuthenticationHandler, RequireAllAuthenticationHandler, METHOD_ATTRIBUTE,
+  };
+  use crate::{
+    common::{
+      authentication::{
+        tests::tunnel_info, AuthenticationAttributes, AuthenticationChannel, AuthenticationError,
+        AuthenticationHandler, AuthenticationHandlerExt, RemoteAuthenticationError, TunnelInfo,
+      },
+      protocol::tunnel::{TunnelName, TunnelSide},
+    },
+    util::{cancellation::CancellationListener, tunnel_stream::WrappedStream},
+  };
+
+  /// Accepts or refuses by a fixed outcome the listening side reports to the connecting side
+  #[derive(Debug, Clone)]
+  struct FixedOutcomeHandler {
+    accept: bool,
+    name: &'static str,
+    attribute: (&'static str, &'static str),
+  }
+
+  impl FixedOutcomeHandler {
+    fn new(accept: bool, name: &'static str, attribute: (&'static str, &'static str)) -> Self {
+      Self {
+        accept,
+        name,
+        attribute,
+      }
+    }
+  }
+
+  impl AuthenticationHandler for FixedOutcomeHandler {
+    type Error = std::convert::Infallible;
+
+    fn authenticate<'a>(
+      &'a self,
+      channel: &'a mut AuthenticationChannel<'a>,
+      tunnel_info: TunnelInfo,
+      _shutdown_notifier: &'a CancellationListener,
+    ) -> BoxFuture<
+      'a,
+      Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>,
+    > {
+      async move {
+        let accepted = match tunnel_info.side {
+          TunnelSide::Listen => {
+            channel
+              .write_u8(self.accept as u8)
+              .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+              .await?;
+            self.accept
+          }
+          TunnelSide::Connect => {
+            channel
+              .read_u8()
+              .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+              .await?
+              == 1
+          }
+        };
+        match accepted {
+          true => Ok((
+            TunnelName::new(self.name),
+            [(self.attribute.0.into(), self.attribute.1.into())].into(),
+          )),
+          false => Err(RemoteAuthenticationError::Refused.into()),
+        }
+      }
+      .boxed()
+    }
+  }
+
+  async fn run_auth<L: AuthenticationHandler, C: AuthenticationHandler>(
+    listener: &L,
+    connector: &C,
+  ) -> (
+    Result<(TunnelName, AuthenticationAttributes), AuthenticationError<L::Error>>,
+    Result<(TunnelName, AuthenticationAttributes), AuthenticationError<C::Error>>,
+  ) {
+    let never_shutdown = CancellationListener::default();
+    let (mut listen_channel, mut connect_channel) = WrappedStream::duplex(4096);
+    futures::future::join(
+      listener.authenticate(
+        &mut listen_channel,
+        tunnel_info(TunnelSide::Listen),
+        &never_shutdown,
+      ),
+      connector.authenticate(
+        &mut connect_channel,
+        tunnel_info(TunnelSide::Connect),
+        &never_shutdown,
+      ),
+    )
+    .await
+  }
+
+  fn attribute(identity: &(TunnelName, AuthenticationAttributes), key: &str) -> String {
+    String::from_utf8(identity.1[key].clone()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn fallback() {
+    let refusing = FixedOutcomeHandler::new(false, "primary", ("scheme", "old"));
+    let accepting = FixedOutcomeHandler::new(true, "fallback", ("scheme", "new"));
+    let handler = refusing.clone().fallback(accepting.clone());
+    let (listen_res, connect_res) = run_auth(&handler, &handler).await;
+    let identity = listen_res.unwrap();
+    assert_eq!(identity.0, TunnelName::new("fallback"));
+    assert_eq!(attribute(&identity, "scheme"), "new");
+    assert_eq!(connect_res.unwrap().0, TunnelName::new("fallback"));
+
+    let handler = accepting.fallback(refusing.clone());
+    let (listen_res, _) = run_auth(&handler, &handler).await;
+    assert_eq!(listen_res.unwrap().0, TunnelName::new("fallback"));
+
+    let handler = refusing.clone().fallback(refusing);
+    let (listen_res, connect_res) = run_auth(&handler, &handler).await;
+    assert_matches!(
+      listen_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+    assert_matches!(
+      connect_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+  }
+
+  #[tokio::test]
+  async fn require_all() {
+    let handler = RequireAllAuthenticationHandler::new()
+      .with_handler(FixedOutcomeHandler::new(true, "site", ("scheme", "old")))
+      .with_handler(FixedOutcomeHandler::new(true, "site", ("scheme", "new")))
+      .with_handler(FixedOutcomeHandler::new(true, "site", ("extra", "yes")));
+    let (listen_res, connect_res) = run_auth(&handler, &handler).await;
+    let identity = listen_res.unwrap();
+    assert_eq!(identity.0, TunnelName::new("site"));
+    assert_eq!(attribute(&identity, "scheme"), "old");
+    assert_eq!(attribute(&identity, "extra"), "yes");
+    connect_res.unwrap();
+
+    let handler = RequireAllAuthenticationHandler::new()
+      .with_handler(FixedOutcomeHandler::new(true, "site", ("scheme", "old")))
+      .with_handler(FixedOutcomeHandler::new(false, "site", ("scheme", "new")));
+    let (listen_res, connect_res) = run_auth(&handler, &handler).await;
+    assert_matches!(
+      listen_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+    assert_matches!(
+      connect_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+  }
+
+  /// Test that handlers which accept the remote under different names refuse it together
+  #[tokio::test]
+  async fn require_all_name_mismatch() {
+    let handler = RequireAllAuthenticationHandler::new()
+      .with_handler(FixedOutcomeHandler::new(true, "site", ("scheme", "old")))
+      .with_handler(FixedOutcomeHandler::new(true, "other", ("scheme", "new")));
+    let (listen_res, connect_res) = run_auth(&handler, &handler).await;
+    assert_matches!(
+      listen_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+    assert_matches!(
+      connect_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+  }
+
+  #[tokio::test]
+  async fn method_selection() {
+    let listener = MethodSelectingAuthenticationHandler::new()
+      .with_method(
+        "token",
+        FixedOutcomeHandler::new(true, "token", ("scheme", "new")),
+      )
+      .with_method(
+        "cert",
+        FixedOutcomeHandler::new(true, "cert", ("scheme", "old")),
+      );
+    let legacy_connector = MethodSelectingAuthenticationHandler::new()
+      .with_method(
+        "psk",
+        FixedOutcomeHandler::new(true, "psk", ("scheme", "older")),
+      )
+      .with_method(
+        "cert",
+        FixedOutcomeHandler::new(true, "cert", ("scheme", "old")),
+      );
+    let (listen_res, connect_res) = run_auth(&listener, &legacy_connector).await;
+    let identity = listen_res.unwrap();
+    assert_eq!(identity.0, TunnelName::new("cert"));
+    assert_eq!(attribute(&identity, "scheme"), "old");
+    assert_eq!(attribute(&identity, METHOD_ATTRIBUTE), "cert");
+    assert_eq!(attribute(&connect_res.unwrap(), METHOD_ATTRIBUTE), "cert");
+
+    let unsupported_connector = MethodSelectingAuthenticationHandler::new().with_method(
+      "psk",
+      FixedOutcomeHandler::new(true, "psk", ("scheme", "older")),
+    );
+    let (listen_res, connect_res) = run_auth(&listener, &unsupported_connector).await;
+    assert_matches!(
+      listen_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+    assert_matches!(
+      connect_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+  }
+}
@@ -0,0 +1,643 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Authenticates connecting tunnels by a signed JSON Web Token issued by a trusted provider
+//!
+//! The connecting side sends its token, and the listening side verifies its signature with a
+//! key from a [JwtKeyProvider] such as a local JWKS, then checks its `exp`, `nbf`, `aud`, and
+//! `iss` claims. The tunnel is named by a configured claim, and chosen claims are copied into
+//! its [AuthenticationAttributes] under the [CLAIM_ATTRIBUTE_PREFIX], along with the token's
+//! expiry. The listening side reports the outcome to the connecting side as a single byte.
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Header, Validation};
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{
+  limit_authentication_expiry, AuthenticationAttributes, AuthenticationChannel,
+  AuthenticationError, AuthenticationHandler, AuthenticationHandlingError,
+  RemoteAuthenticationError, TunnelInfo,
+};
+use crate::{
+  common::protocol::tunnel::{TunnelName, TunnelSide},
+  util::cancellation::CancellationListener,
+};
+
+/// Prefix of the attribute names under which token claims are recorded
+pub const CLAIM_ATTRIBUTE_PREFIX: &str = "jwt.";
+
+const MAX_TOKEN_LENGTH: u32 = 64 * 1024;
+
+const AUTHENTICATION_ACCEPTED: u8 = 1;
+const AUTHENTICATION_REFUSED: u8 = 0;
+
+/// Provides the keys which tokens may be signed with
+pub trait JwtKeyProvider: std::fmt::Debug + Send + Sync {
+  /// Finds the key which should have signed a token with the given header, if it is trusted
+  fn decoding_key<'a>(&'a self, header: &'a Header) -> BoxFuture<'a, Option<DecodingKey>>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum JwksLoadError {
+  #[error("JSON Web Key Set could not be read")]
+  IOError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    std::io::Error,
+  ),
+  #[error("JSON Web Key Set was malformed")]
+  InvalidKeySet(#[source] serde_json::Error),
+}
+
+/// Trusts the keys of a fixed JSON Web Key Set
+///
+/// Tokens naming a key ID are verified with the key of that ID; tokens without one are only
+/// accepted when the set holds a single key. Keys which declare an algorithm are only used for
+/// tokens signed with that algorithm.
+#[derive(Debug, Clone)]
+pub struct JwksKeyProvider {
+  keys: JwkSet,
+}
+
+impl JwksKeyProvider {
+  pub fn new(keys: JwkSet) -> Self {
+    Self { keys }
+  }
+
+  pub fn from_json(json: &str) -> Result<Self, JwksLoadError> {
+    serde_json::from_str(json)
+      .map(Self::new)
+      .map_err(JwksLoadError::InvalidKeySet)
+  }
+
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, JwksLoadError> {
+    Self::from_json(&std::fs::read_to_string(path)?)
+  }
+
+  pub fn keys(&self) -> &JwkSet {
+    &self.keys
+  }
+}
+
+impl JwtKeyProvider for JwksKeyProvider {
+  fn decoding_key<'a>(&'a self, header: &'a Header) -> BoxFuture<'a, Option<DecodingKey>> {
+    let jwk = match (&header.kid, self.keys.keys.as_slice()) {
+      (Some(kid), _) => self.keys.find(kid),
+      (None, [only]) => Some(only),
+      (None, _) => None,
+    };
+    let key = jwk
+      .filter(|jwk| jwk.common.algorithm.map_or(true, |alg| alg == header.alg))
+      .and_then(|jwk| DecodingKey::from_jwk(jwk).ok());
+    futures::future::ready(key).boxed()
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum JwtError {
+  #[error("No key provider is configured to verify connecting tunnels")]
+  MissingKeyProvider,
+  #[error("No token is configured to authenticate with listening tunnels")]
+  MissingToken,
+  #[error("Token file could not be read")]
+  TokenFileUnreadable(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    std::io::Error,
+  ),
+}
+
+/// Where a connecting handler finds the token it presents
+#[derive(Clone)]
+enum TokenSource {
+  Static(String),
+  /// Read anew upon each authentication, so that the token may be replaced while connected
+  File(PathBuf),
+}
+
+/// Authenticates tunnels by a signed JWT presented by the connecting side
+///
+/// Listening requires a key provider, and connecting requires a token; a handler may be
+/// configured with both to authenticate in either role.
+pub struct JwtAuthenticationHandler {
+  key_provider: Option<Arc<dyn JwtKeyProvider>>,
+  algorithms: Vec<Algorithm>,
+  audiences: Option<Vec<String>>,
+  issuers: Option<Vec<String>>,
+  leeway_seconds: u64,
+  name_claim: String,
+  attribute_claims: Vec<String>,
+  token: Option<TokenSource>,
+}
+
+impl JwtAuthenticationHandler {
+  /// Creates a handler accepting asymmetrically signed tokens, named by their `sub` claim
+  pub fn new() -> Self {
+    Self {
+      key_provider: None,
+      algorithms: vec![
+        Algorithm::RS256,
+        Algorithm::RS384,
+        Algorithm::RS512,
+        Algorithm::PS256,
+        Algorithm::PS384,
+        Algorithm::PS512,
+        Algorithm::ES256,
+        Algorithm::ES384,
+        Algorithm::EdDSA,
+      ],
+      audiences: None,
+      issuers: None,
+      leeway_seconds: 60,
+      name_claim: "sub".into(),
+      attribute_claims: Vec::new(),
+      token: None,
+    }
+  }
+
+  /// Verifies connecting tunnels' tokens with keys from the given provider
+  pub fn with_key_provider<P: JwtKeyProvider + 'static>(mut self, key_provider: P) -> Self {
+    self.key_provider = Some(Arc::new(key_provider));
+    self
+  }
+
+  /// Restricts the algorithms tokens may be signed with
+  pub fn with_algorithms<A: IntoIterator<Item = Algorithm>>(mut self, algorithms: A) -> Self {
+    self.algorithms = algorithms.into_iter().collect();
+    self
+  }
+
+  /// Requires tokens to carry an `aud` claim naming one of the given audiences
+  pub fn with_audiences<A: IntoIterator<Item = S>, S: Into<String>>(
+    mut self,
+    audiences: A,
+  ) -> Self {
+    self.audiences = Some(audiences.into_iter().map(Into::into).collect());
+    self
+  }
+
+  /// Requires tokens to carry an `iss` claim naming one of the given issuers
+  pub fn with_issuers<I: IntoIterator<Item = S>, S: Into<String>>(mut self, issuers: I) -> Self {
+    self.issuers = Some(issuers.into_iter().map(Into::into).collect());
+    self
+  }
+
+  /// Sets the clock skew tolerated when checking `exp` and `nbf`, which defaults to a minute
+  pub fn with_leeway(mut self, leeway: std::time::Duration) -> Self {
+    self.leeway_seconds = leeway.as_secs();
+    self
+  }
+
+  /// Names tunnels by the given string claim instead of `sub`
+  pub fn with_name_claim<S: Into<String>>(mut self, claim: S) -> Self {
+    self.name_claim = claim.into();
+    self
+  }
+
+  /// Records the given claim, if present, as an attribute of authenticated tunnels
+  ///
+  /// String claims are recorded as their UTF-8 bytes, and other claims as their JSON encoding.
+  pub fn with_attribute_claim<S: Into<String>>(mut self, claim: S) -> Self {
+    self.attribute_claims.push(claim.into());
+    self
+  }
+
+  /// Authenticates with listening tunnels by presenting the given token
+  pub fn with_token<S: Into<String>>(mut self, token: S) -> Self {
+    self.token = Some(TokenSource::Static(token.into()));
+    self
+  }
+
+  /// Authenticates with listening tunnels by presenting the token held in the given file
+  ///
+  /// The file is read upon each authentication, including re-authentication of connected
+  /// tunnels, so a token may be refreshed before it expires by replacing the file's contents.
+  pub fn with_token_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.token = Some(TokenSource::File(path.into()));
+    self
+  }
+
+  fn validation(&self, algorithm: Algorithm) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.validate_nbf = true;
+    validation.leeway = self.leeway_seconds;
+    let mut required_claims = vec!["exp"];
+    if let Some(audiences) = &self.audiences {
+      validation.set_audience(audiences);
+      required_claims.push("aud");
+    }
+    if let Some(issuers) = &self.issuers {
+      validation.set_issuer(issuers);
+      required_claims.push("iss");
+    }
+    validation.set_required_spec_claims(&required_claims);
+    validation
+  }
+
+  /// Verifies a token, returning the name and attributes of the tunnel which presented it
+  pub async fn verify(
+    &self,
+    token: &str,
+  ) -> Result<Option<(TunnelName, AuthenticationAttributes)>, JwtError> {
+    let key_provider = self
+      .key_provider
+      .as_ref()
+      .ok_or(JwtError::MissingKeyProvider)?;
+    let header = match jsonwebtoken::decode_header(token) {
+      Ok(header) if self.algorithms.contains(&header.alg) => header,
+      Ok(header) => {
+        tracing::debug!(algorithm = ?header.alg, "Token signed with a disallowed algorithm");
+        return Ok(None);
+      }
+      Err(e) => {
+        tracing::debug!(error = %e, "Token header was malformed");
+        return Ok(None);
+      }
+    };
+    let key = match key_provider.decoding_key(&header).await {
+      Some(key) => key,
+      None => {
+        tracing::debug!(kid = ?header.kid, "No trusted key matches token");
+        return Ok(None);
+      }
+    };
+    let claims = match jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+      token,
+      &key,
+      &self.validation(header.alg),
+    ) {
+      Ok(token) => token.claims,
+      Err(e) => {
+        tracing::debug!(error = %e, "Token was not valid");
+        return Ok(None);
+      }
+    };
+    let name = match claims.get(&self.name_claim).and_then(|name| name.as_str()) {
+      Some(name) if !name.is_empty() => TunnelName::new(name),
+      _ => {
+        tracing::debug!(claim = %self.name_claim, "Token lacks a name claim");
+        return Ok(None);
+      }
+    };
+    let mut attributes: AuthenticationAttributes = self
+      .attribute_claims
+      .iter()
+      .filter_map(|claim| {
+        let value = match claims.get(claim)? {
+          serde_json::Value::String(value) => value.as_bytes().to_vec(),
+          value => serde_json::to_vec(value).expect("JSON values serialize"),
+        };
+        Some((format!("{}{}", CLAIM_ATTRIBUTE_PREFIX, claim), value))
+      })
+      .collect();
+    if let Some(expiry) = claims.get("exp").and_then(|exp| exp.as_u64()) {
+      limit_authentication_expiry(
+        &mut attributes,
+        SystemTime::UNIX_EPOCH + Duration::from_secs(expiry),
+      );
+    }
+    Ok(Some((name, attributes)))
+  }
+
+  async fn authenticate_listen_side(
+    &self,
+    channel: &mut AuthenticationChannel<'_>,
+  ) -> Result<(TunnelName, AuthenticationAttributes), AuthenticationError<JwtError>> {
+    if self.key_provider.is_none() {
+      return Err(
+        AuthenticationHandlingError::ApplicationError(JwtError::MissingKeyProvider).into(),
+      );
+    }
+    let token_length = channel
+      .read_u32()
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+    if token_length == 0 || token_length > MAX_TOKEN_LENGTH {
+      return Err(
+        RemoteAuthenticationError::ProtocolViolation("Invalid token length".into()).into(),
+      );
+    }
+    let mut token = vec![0u8; token_length as usize];
+    channel
+      .read_exact(&mut token)
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+    let token = String::from_utf8(token).map_err(|_| {
+      RemoteAuthenticationError::ProtocolViolation("Received token was not valid UTF8".into())
+    })?;
+
+    let verified = self
+      .verify(&token)
+      .await
+      .map_err(AuthenticationHandlingError::ApplicationError)?;
+    let outcome = match verified {
+      Some(_) => AUTHENTICATION_ACCEPTED,
+      None => AUTHENTICATION_REFUSED,
+    };
+    channel
+      .write_all(&[outcome])
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+      .await?;
+    verified.ok_or_else(|| RemoteAuthenticationError::Refused.into())
+  }
+
+  async fn authenticate_connecting_side(
+    &self,
+    channel: &mut AuthenticationChannel<'_>,
+    tunnel_info: TunnelInfo,
+  ) -> Result<(TunnelName, AuthenticationAttributes), AuthenticationError<JwtError>> {
+    let token = match self.token.clone() {
+      Some(TokenSource::Static(token)) => token,
+      Some(TokenSource::File(path)) => tokio::task::spawn_blocking(move || {
+        std::fs::read_to_string(path).map(|token| token.trim().to_owned())
+      })
+      .await
+      .map_err(AuthenticationHandlingError::JoinError)?
+      .map_err(|e| AuthenticationHandlingError::ApplicationError(JwtError::from(e)))?,
+      None => {
+        return Err(AuthenticationHandlingError::ApplicationError(JwtError::MissingToken).into())
+      }
+    };
+    let mut message = Vec::with_capacity(4 + token.len());
+    message.extend_from_slice(&(token.len() as u32).to_be_bytes());
+    message.extend_from_slice(token.as_bytes());
+    channel
+      .write_all(&message)
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+      .await?;
+
+    let outcome = channel
+      .read_u8()
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+    match outcome {
+      AUTHENTICATION_ACCEPTED => Ok((
+        TunnelName::new(tunnel_info.addr.to_string()),
+        AuthenticationAttributes::default(),
+      )),
+      AUTHENTICATION_REFUSED => Err(RemoteAuthenticationError::Refused.into()),
+      _ => Err(
+        RemoteAuthenticationError::ProtocolViolation("Invalid authentication outcome".into())
+          .into(),
+      ),
+    }
+  }
+}
+
+impl Default for JwtAuthenticationHandler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl std::fmt::Debug for JwtAuthenticationHandler {
+  // The token is a bearer credential, so only its presence is shown
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("JwtAuthenticationHandler")
+      .field("key_provider", &self.key_provider)
+      .field("algorithms", &self.algorithms)
+      .field("audiences", &self.audiences)
+      .field("issuers", &self.issuers)
+      .field("name_claim", &self.name_claim)
+      .field("attribute_claims", &self.attribute_claims)
+      .field("has_token", &self.token.is_some())
+      .finish()
+  }
+}
+
+impl AuthenticationHandler for JwtAuthenticationHandler {
+  type Error = JwtError;
+
+  fn authenticate<'a>(
+    &'a self,
+    channel: &'a mut AuthenticationChannel<'a>,
+    tunnel_info: TunnelInfo,
+    _shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>>
+  {
+    match tunnel_info.side {
+      TunnelSide::Listen => self.authenticate_listen_side(channel).boxed(),
+      TunnelSide::Connect => self
+        .authenticate_connecting_side(channel, tunnel_info)
+        .boxed(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+  use jsonwebtoken::{Algorithm, EncodingKey, Header};
+  use ring::signature::{Ed25519KeyPair, KeyPair};
+  use std::{assert_matches::assert_matches, time::SystemTime};
+
+  use super::{JwksKeyProvider, JwtAuthenticationHandler, CLAIM_ATTRIBUTE_PREFIX};
+  use crate::{
+    common::{
+      authentication::{
+        authentication_expiry, tests::tunnel_info, AuthenticationError, AuthenticationHandler,
+        RemoteAuthenticationError,
+      },
+      protocol::tunnel::{TunnelName, TunnelSide},
+    },
+    util::{cancellation::CancellationListener, tunnel_stream::WrappedStream},
+  };
+
+  /// Generates an Ed25519 signing key and a JWKS holding its public key under the given ID
+  fn signing_key(kid: &str) -> (EncodingKey, String) {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+    let public_key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+      .unwrap()
+      .public_key()
+      .as_ref()
+      .to_vec();
+    let jwks = serde_json::json!({
+      "keys": [{
+        "kty": "OKP",
+        "crv": "Ed25519",
+        "alg": "EdDSA",
+        "kid": kid,
+        "x": BASE64_URL.encode(public_key),
+      }]
+    });
+    (EncodingKey::from_ed_der(pkcs8.as_ref()), jwks.to_string())
+  }
+
+  fn token(key: &EncodingKey, kid: &str, claims: serde_json::Value) -> String {
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(kid.into());
+    jsonwebtoken::encode(&header, &claims, key).unwrap()
+  }
+
+  fn now() -> u64 {
+    SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap()
+      .as_secs()
+  }
+
+  #[tokio::test]
+  async fn token_verification() {
+    let (key, jwks) = signing_key("device-ca");
+    let (untrusted_key, _) = signing_key("device-ca");
+    let handler = JwtAuthenticationHandler::new()
+      .with_key_provider(JwksKeyProvider::from_json(&jwks).unwrap())
+      .with_audiences(["snocat"])
+      .with_issuers(["https://idp.example.com"])
+      .with_name_claim("device_id")
+      .with_attribute_claim("sub")
+      .with_attribute_claim("groups");
+    let claims = |overrides: serde_json::Value| {
+      let mut claims = serde_json::json!({
+        "sub": "user-1",
+        "device_id": "edge-7",
+        "groups": ["edge", "eu"],
+        "aud": "snocat",
+        "iss": "https://idp.example.com",
+        "exp": now() + 600,
+        "nbf": now() - 600,
+      });
+      claims
+        .as_object_mut()
+        .unwrap()
+        .extend(overrides.as_object().unwrap().clone());
+      claims
+    };
+
+    let (name, attributes) = handler
+      .verify(&token(&key, "device-ca", claims(serde_json::json!({}))))
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(name, TunnelName::new("edge-7"));
+    assert_eq!(
+      attributes[&format!("{}sub", CLAIM_ATTRIBUTE_PREFIX)],
+      b"user-1"
+    );
+    assert_eq!(
+      attributes[&format!("{}groups", CLAIM_ATTRIBUTE_PREFIX)],
+      br#"["edge","eu"]"#
+    );
+    assert!(authentication_expiry(&attributes).unwrap() > SystemTime::now());
+
+    for rejected in [
+      token(
+        &key,
+        "device-ca",
+        claims(serde_json::json!({"exp": now() - 600})),
+      ),
+      token(
+        &key,
+        "device-ca",
+        claims(serde_json::json!({"nbf": now() + 600})),
+      ),
+      token(
+        &key,
+        "device-ca",
+        claims(serde_json::json!({"aud": "other"})),
+      ),
+      token(
+        &key,
+        "device-ca",
+        claims(serde_json::json!({"iss": "https://evil.example.com"})),
+      ),
+      token(
+        &key,
+        "device-ca",
+        claims(serde_json::json!({"device_id": 7})),
+      ),
+      token(&key, "other-kid", claims(serde_json::json!({}))),
+      token(&untrusted_key, "device-ca", claims(serde_json::json!({}))),
+      "not.a.token".into(),
+    ] {
+      assert_eq!(handler.verify(&rejected).await.unwrap(), None);
+    }
+  }
+
+  #[tokio::test]
+  async fn run_auth() {
+    let (key, jwks) = signing_key("device-ca");
+    let listener =
+      JwtAuthenticationHandler::new().with_key_provider(JwksKeyProvider::from_json(&jwks).unwrap());
+    let never_shutdown = CancellationListener::default();
+
+    for (subject, expiry) in [("edge-7", now() + 600), ("edge-8", now() - 600)] {
+      let connector = JwtAuthenticationHandler::new().with_token(token(
+        &key,
+        "device-ca",
+        serde_json::json!({"sub": subject, "exp": expiry}),
+      ));
+      let (mut listen_channel, mut connect_channel) = WrappedStream::duplex(4096);
+      let (listen_res, connect_res) = futures::future::join(
+        listener.authenticate(
+          &mut listen_channel,
+          tunnel_info(TunnelSide::Listen),
+          &never_shutdown,
+        ),
+        connector.authenticate(
+          &mut connect_channel,
+          tunnel_info(TunnelSide::Connect),
+          &never_shutdown,
+        ),
+      )
+      .await;
+      if expiry > now() {
+        assert_eq!(listen_res.unwrap().0, TunnelName::new(subject));
+        assert_eq!(connect_res.unwrap().0, TunnelName::new("Unidentified"));
+      } else {
+        assert_matches!(
+          listen_res,
+          Err(AuthenticationError::Remote(
+            RemoteAuthenticationError::Refused
+          ))
+        );
+        assert_matches!(
+          connect_res,
+          Err(AuthenticationError::Remote(
+            RemoteAuthenticationError::Refused
+          ))
+        );
+      }
+    }
+  }
+
+  /// Test that token files are read anew upon each authentication
+  #[tokio::test]
+  async fn token_file_reread() {
+    let (key, jwks) = signing_key("device-ca");
+    let listener =
+      JwtAuthenticationHandler::new().with_key_provider(JwksKeyProvider::from_json(&jwks).unwrap());
+    let path = std::env::temp_dir().join(format!("snocat-jwt-{}", uuid::Uuid::new_v4()));
+    let connector = JwtAuthenticationHandler::new().with_token_file(&path);
+    let never_shutdown = CancellationListener::default();
+
+    for subject in ["edge-7", "edge-8"] {
+      let token = token(
+        &key,
+        "device-ca",
+        serde_json::json!({"sub": subject, "exp": now() + 600}),
+      );
+      std::fs::write(&path, format!("{}\n", token)).unwrap();
+      let (mut listen_channel, mut connect_channel) = WrappedStream::duplex(4096);
+      let (listen_res, connect_res) = futures::future::join(
+        listener.authenticate(
+          &mut listen_channel,
+          tunnel_info(TunnelSide::Listen),
+          &never_shutdown,
+        ),
+        connector.authenticate(
+          &mut connect_channel,
+          tunnel_info(TunnelSide::Connect),
+          &never_shutdown,
+        ),
+      )
+      .await;
+      assert_eq!(listen_res.unwrap().0, TunnelName::new(subject));
+      assert!(connect_res.is_ok());
+    }
+    std::fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,330 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Locks out remote addresses which repeatedly fail authentication
+//!
+//! After a number of consecutive refusals from the same IP address, further attempts from it are
+//! refused without consulting the wrapped handler, for a lockout which doubles with each further
+//! refusal. Successful authentication clears an address's record.
+use dashmap::DashMap;
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+  net::IpAddr,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use super::{
+  AuthenticationAttributes, AuthenticationChannel, AuthenticationError, AuthenticationHandler,
+  RemoteAuthenticationError, TunnelInfo,
+};
+use crate::{
+  common::protocol::tunnel::{TunnelAddressInfo, TunnelName},
+  util::{cancellation::CancellationListener, dropkick::Dropkick},
+};
+
+/// Records above which stale entries are pruned when another failure is recorded
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+struct FailureRecord {
+  failures: u32,
+  last_failure: Instant,
+  locked_until: Option<Instant>,
+}
+
+/// Counts authentication failures by remote IP address, and decides when to lock them out
+#[derive(Debug)]
+pub struct AuthenticationFailureTracker {
+  records: DashMap<IpAddr, FailureRecord>,
+  threshold: u32,
+  base_lockout: Duration,
+  max_lockout: Duration,
+  forget_after: Duration,
+}
+
+impl AuthenticationFailureTracker {
+  /// Creates a tracker locking addresses out for a second after 5 failures, up to an hour
+  pub fn new() -> Self {
+    Self {
+      records: DashMap::new(),
+      threshold: 5,
+      base_lockout: Duration::from_secs(1),
+      max_lockout: Duration::from_secs(60 * 60),
+      forget_after: Duration::from_secs(24 * 60 * 60),
+    }
+  }
+
+  /// Sets the number of consecutive failures after which an address is locked out
+  pub fn with_threshold(mut self, threshold: u32) -> Self {
+    self.threshold = threshold.max(1);
+    self
+  }
+
+  /// Sets the first lockout's duration, which doubles with each further failure
+  pub fn with_base_lockout(mut self, lockout: Duration) -> Self {
+    self.base_lockout = lockout;
+    self
+  }
+
+  /// Sets the longest an address may be locked out for after a single failure
+  pub fn with_max_lockout(mut self, lockout: Duration) -> Self {
+    self.max_lockout = lockout;
+    self
+  }
+
+  /// Sets how long after its last failure an address's failures are forgotten
+  pub fn with_forget_after(mut self, forget_after: Duration) -> Self {
+    self.forget_after = forget_after;
+    self
+  }
+
+  /// The time until which an address is locked out, if it is currently locked out
+  pub fn locked_until(&self, addr: IpAddr) -> Option<Instant> {
+    self
+      .records
+      .get(&addr)
+      .and_then(|record| record.locked_until)
+      .filter(|locked_until| *locked_until > Instant::now())
+  }
+
+  /// Records a failure by an address, returning the lockout it incurred, if any
+  pub fn record_failure(&self, addr: IpAddr) -> Option<Duration> {
+    if self.records.len() > PRUNE_THRESHOLD {
+      self.prune();
+    }
+    let now = Instant::now();
+    let mut record = self.records.entry(addr).or_insert(FailureRecord {
+      failures: 0,
+      last_failure: now,
+      locked_until: None,
+    });
+    if now.duration_since(record.last_failure) > self.forget_after {
+      record.failures = 0;
+    }
+    record.failures = record.failures.saturating_add(1);
+    record.last_failure = now;
+    let excess_failures = record.failures.checked_sub(self.threshold)?;
+    let lockout = self
+      .base_lockout
+      .checked_mul(2u32.checked_pow(excess_failures).unwrap_or(u32::MAX))
+      .unwrap_or(self.max_lockout)
+      .min(self.max_lockout);
+    record.locked_until = Some(now + lockout);
+    Some(lockout)
+  }
+
+  /// Clears the failures recorded for an address
+  pub fn record_success(&self, addr: IpAddr) {
+    self.records.remove(&addr);
+  }
+
+  /// Forgets addresses which are no longer locked out and have not failed recently
+  pub fn prune(&self) {
+    let now = Instant::now();
+    self.records.retain(|_, record| {
+      record.locked_until.map_or(false, |until| until > now)
+        || now.duration_since(record.last_failure) <= self.forget_after
+    });
+  }
+}
+
+impl Default for AuthenticationFailureTracker {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Refuses remotes locked out by a failure tracker, and records the outcomes of the wrapped handler
+///
+/// [RemoteAuthenticationError::Refused] and [RemoteAuthenticationError::TimedOut] count as failures,
+/// as does authentication abandoned before the wrapped handler completes, such as upon reaching a
+/// daemon's [authentication timeout](crate::common::daemon::ModularDaemon::with_authentication_timeout),
+/// so remotes which stall authentication are locked out as well. Tunnels without an IP address,
+/// such as those over local duplex streams, are passed to the wrapped handler untracked.
+#[derive(Debug)]
+pub struct LockoutAuthenticationHandler<TInner> {
+  inner: TInner,
+  tracker: Arc<AuthenticationFailureTracker>,
+}
+
+impl<TInner> LockoutAuthenticationHandler<TInner> {
+  pub fn new(inner: TInner) -> Self {
+    Self {
+      inner,
+      tracker: Default::default(),
+    }
+  }
+
+  /// Uses the given tracker, which may be shared with other handlers or inspected elsewhere
+  pub fn with_tracker(mut self, tracker: Arc<AuthenticationFailureTracker>) -> Self {
+    self.tracker = tracker;
+    self
+  }
+
+  pub fn tracker(&self) -> &Arc<AuthenticationFailureTracker> {
+    &self.tracker
+  }
+
+  fn record_failure(&self, remote_ip: IpAddr) {
+    if let Some(lockout) = self.tracker.record_failure(remote_ip) {
+      tracing::info!(
+        remote = %remote_ip,
+        ?lockout,
+        "Locking out address after repeated authentication failures"
+      );
+    }
+  }
+}
+
+impl<TInner: AuthenticationHandler> AuthenticationHandler for LockoutAuthenticationHandler<TInner> {
+  type Error = TInner::Error;
+
+  fn authenticate<'a>(
+    &'a self,
+    channel: &'a mut AuthenticationChannel<'a>,
+    tunnel_info: TunnelInfo,
+    shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>>
+  {
+    let remote_ip = match &tunnel_info.addr {
+      TunnelAddressInfo::Socket(addr) => addr.ip(),
+      TunnelAddressInfo::Port(_) | TunnelAddressInfo::Unidentified => {
+        return self
+          .inner
+          .authenticate(channel, tunnel_info, shutdown_notifier)
+      }
+    };
+    async move {
+      if let Some(locked_until) = self.tracker.locked_until(remote_ip) {
+        tracing::debug!(
+          remote = %remote_ip,
+          remaining = ?locked_until.saturating_duration_since(Instant::now()),
+          "Refusing authentication from locked out address"
+        );
+        return Err(RemoteAuthenticationError::Refused.into());
+      }
+      // Dropped without completing when authentication is abandoned by the caller
+      let abandonment = Dropkick::callback(|| self.record_failure(remote_ip));
+      let result = self
+        .inner
+        .authenticate(channel, tunnel_info, shutdown_notifier)
+        .await;
+      abandonment.counter();
+      match &result {
+        Ok(_) => self.tracker.record_success(remote_ip),
+        Err(AuthenticationError::Remote(
+          RemoteAuthenticationError::Refused | RemoteAuthenticationError::TimedOut,
+        )) => self.record_failure(remote_ip),
+        Err(_) => (),
+      }
+      result
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{assert_matches::assert_matches, net::IpAddr, sync::Arc, time::Duration};
+
+  use super::{AuthenticationFailureTracker, LockoutAuthenticationHandler};
+  use crate::{
+    common::{
+      authentication::{
+        preshared_key_authentication::StaticKeyStore, tests, AuthenticationError,
+        AuthenticationHandler, PresharedKeyAuthenticationHandler, RemoteAuthenticationError,
+        TunnelInfo,
+      },
+      protocol::tunnel::{TunnelAddressInfo, TunnelName, TunnelSide},
+    },
+    util::{cancellation::CancellationListener, tunnel_stream::WrappedStream},
+  };
+
+  #[test]
+  fn exponential_lockout() {
+    let addr: IpAddr = [192, 0, 2, 1].into();
+    let tracker = AuthenticationFailureTracker::new()
+      .with_threshold(3)
+      .with_base_lockout(Duration::from_secs(10))
+      .with_max_lockout(Duration::from_secs(30));
+    assert_eq!(tracker.record_failure(addr), None);
+    assert_eq!(tracker.record_failure(addr), None);
+    assert_eq!(tracker.locked_until(addr), None);
+    assert_eq!(tracker.record_failure(addr), Some(Duration::from_secs(10)));
+    assert!(tracker.locked_until(addr).is_some());
+    assert_eq!(tracker.record_failure(addr), Some(Duration::from_secs(20)));
+    assert_eq!(tracker.record_failure(addr), Some(Duration::from_secs(30)));
+    for _ in 0..64 {
+      tracker.record_failure(addr);
+    }
+    assert_eq!(tracker.record_failure(addr), Some(Duration::from_secs(30)));
+    assert_eq!(tracker.locked_until([192, 0, 2, 2].into()), None);
+
+    tracker.record_success(addr);
+    assert_eq!(tracker.locked_until(addr), None);
+    assert_eq!(tracker.record_failure(addr), None);
+  }
+
+  #[tokio::test]
+  async fn locks_out_refused_remotes() {
+    // With no keys in its store, the listener refuses every name the connector guesses
+    let inner = PresharedKeyAuthenticationHandler::new()
+      .with_key_store(StaticKeyStore::new())
+      .allow_unbound_channels(true);
+    let connector = PresharedKeyAuthenticationHandler::new()
+      .with_credentials(TunnelName::new("guesser"), b"guess".to_vec())
+      .unwrap()
+      .allow_unbound_channels(true);
+    let tracker = Arc::new(
+      AuthenticationFailureTracker::new()
+        .with_threshold(2)
+        .with_base_lockout(Duration::from_secs(60)),
+    );
+    let listener = LockoutAuthenticationHandler::new(inner).with_tracker(Arc::clone(&tracker));
+    let never_shutdown = CancellationListener::default();
+    let addr = std::net::SocketAddr::from(([192, 0, 2, 1], 4000));
+    let tunnel_info = |side| TunnelInfo {
+      addr: TunnelAddressInfo::Socket(addr),
+      ..tests::tunnel_info(side)
+    };
+
+    for _ in 0..2 {
+      let (mut listen_channel, mut connect_channel) = WrappedStream::duplex(4096);
+      let (listen_res, _) = futures::future::join(
+        listener.authenticate(
+          &mut listen_channel,
+          tunnel_info(TunnelSide::Listen),
+          &never_shutdown,
+        ),
+        connector.authenticate(
+          &mut connect_channel,
+          tunnel_info(TunnelSide::Connect),
+          &never_shutdown,
+        ),
+      )
+      .await;
+      assert_matches!(
+        listen_res,
+        Err(AuthenticationError::Remote(
+          RemoteAuthenticationError::Refused
+        ))
+      );
+    }
+    assert!(tracker.locked_until(addr.ip()).is_some());
+
+    // Locked out remotes are refused before anything is exchanged
+    let (mut listen_channel, _connect_channel) = WrappedStream::duplex(4096);
+    assert_matches!(
+      listener
+        .authenticate(
+          &mut listen_channel,
+          tunnel_info(TunnelSide::Listen),
+          &never_shutdown,
+        )
+        .await,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+  }
+}
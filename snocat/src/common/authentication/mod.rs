@@ -5,8 +5,43 @@
 mod traits;
 pub use traits::*;
 
+pub mod combinators;
+pub use combinators::{
+  FallbackAuthenticationHandler, MethodSelectingAuthenticationHandler,
+  RequireAllAuthenticationHandler,
+};
+
 mod no_op_authentication;
 pub use no_op_authentication::NoOpAuthenticationHandler;
 
 mod simple_ack_authentication;
 pub use simple_ack_authentication::SimpleAckAuthenticationHandler;
+
+pub mod certificate_authentication;
+pub use certificate_authentication::CertificateAuthenticationHandler;
+
+pub mod preshared_key_authentication;
+pub use preshared_key_authentication::PresharedKeyAuthenticationHandler;
+
+pub mod lockout;
+pub use lockout::LockoutAuthenticationHandler;
+
+pub mod jwt_authentication;
+pub use jwt_authentication::JwtAuthenticationHandler;
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::TunnelInfo;
+  use crate::common::protocol::tunnel::{TunnelAddressInfo, TunnelId, TunnelSide};
+
+  /// Describes a tunnel of unidentified address, with no peer certificates or channel binding
+  pub(crate) fn tunnel_info(side: TunnelSide) -> TunnelInfo {
+    TunnelInfo {
+      tunnel_id: TunnelId::new(1),
+      side,
+      addr: TunnelAddressInfo::Unidentified,
+      peer_certificates: None,
+      channel_binding: None,
+    }
+  }
+}
@@ -0,0 +1,541 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Authenticates connecting tunnels by an HMAC keyed with a secret shared with the listener
+//!
+//! The listening side sends a random nonce, and the connecting side answers with the name it
+//! claims and an HMAC-SHA256 keyed with that name's pre-shared key. The MAC covers the nonce,
+//! the claimed name, and the tunnel's [channel binding](TunnelInfo::channel_binding), so that
+//! a relay cannot replay it over a tunnel of its own. The listening side looks the key up in a
+//! [PresharedKeyStore], then reports the outcome to the connecting side as a single byte.
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use ring::{hmac, rand::SecureRandom};
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{
+  AuthenticationAttributes, AuthenticationChannel, AuthenticationError, AuthenticationHandler,
+  AuthenticationHandlingError, RemoteAuthenticationError, TunnelInfo,
+};
+use crate::{
+  common::protocol::tunnel::{TunnelName, TunnelSide},
+  util::cancellation::CancellationListener,
+};
+
+const NONCE_LENGTH: usize = 32;
+const MAC_LENGTH: usize = 32;
+const MAX_NAME_LENGTH: u16 = 1024;
+
+const AUTHENTICATION_ACCEPTED: u8 = 1;
+const AUTHENTICATION_REFUSED: u8 = 0;
+
+/// Looks up the keys shared with the tunnels permitted to connect
+pub trait PresharedKeyStore: std::fmt::Debug + Send + Sync {
+  /// Finds the key shared with the named tunnel, or `None` if it may not connect
+  fn key<'a>(&'a self, name: &'a TunnelName) -> BoxFuture<'a, Option<Vec<u8>>>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyStoreLoadError {
+  #[error("Key store could not be read")]
+  IOError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    std::io::Error,
+  ),
+  #[error("Key store environment variable {0} is unset or not unicode")]
+  MissingEnvironmentVariable(String),
+  #[error("Key store entry {0} is not of the form <name>=<base64 key>")]
+  InvalidEntry(usize),
+  #[error("Key store entry {0} does not hold a non-empty base64 key")]
+  InvalidKey(usize),
+}
+
+/// Keys known ahead of time, provided directly or loaded from a file or environment variable
+#[derive(Clone, Default)]
+pub struct StaticKeyStore {
+  keys: HashMap<TunnelName, Vec<u8>>,
+}
+
+impl StaticKeyStore {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  pub fn with_key<K: Into<Vec<u8>>>(mut self, name: TunnelName, key: K) -> Self {
+    self.keys.insert(name, key.into());
+    self
+  }
+
+  pub fn len(&self) -> usize {
+    self.keys.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.keys.is_empty()
+  }
+
+  /// Parses entries of the form `<name>=<base64 key>`, separated by newlines or commas
+  ///
+  /// Blank entries and lines beginning with `#` are ignored. Later entries for a name replace
+  /// earlier ones.
+  pub fn parse(entries: &str) -> Result<Self, KeyStoreLoadError> {
+    entries
+      .split(['\n', ','])
+      .map(str::trim)
+      .enumerate()
+      .filter(|(_, entry)| !entry.is_empty() && !entry.starts_with('#'))
+      .try_fold(Self::new(), |store, (index, entry)| {
+        let entry_number = index + 1;
+        let (name, key) = entry
+          .split_once('=')
+          .filter(|(name, _)| !name.trim().is_empty())
+          .ok_or(KeyStoreLoadError::InvalidEntry(entry_number))?;
+        // Base64 keys may themselves end in padding, so only the first `=` separates the name
+        let key = BASE64
+          .decode(key.trim())
+          .ok()
+          .filter(|key| !key.is_empty())
+          .ok_or(KeyStoreLoadError::InvalidKey(entry_number))?;
+        Ok(store.with_key(TunnelName::new(name.trim()), key))
+      })
+  }
+
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, KeyStoreLoadError> {
+    Self::parse(&std::fs::read_to_string(path)?)
+  }
+
+  pub fn from_env(variable: &str) -> Result<Self, KeyStoreLoadError> {
+    let entries = std::env::var(variable)
+      .map_err(|_| KeyStoreLoadError::MissingEnvironmentVariable(variable.to_string()))?;
+    Self::parse(&entries)
+  }
+}
+
+impl std::fmt::Debug for StaticKeyStore {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    // Keys are secret, so only the names they are shared with are shown
+    f.debug_struct("StaticKeyStore")
+      .field("names", &self.keys.keys().collect::<Vec<_>>())
+      .finish()
+  }
+}
+
+impl PresharedKeyStore for StaticKeyStore {
+  fn key<'a>(&'a self, name: &'a TunnelName) -> BoxFuture<'a, Option<Vec<u8>>> {
+    futures::future::ready(self.keys.get(name).cloned()).boxed()
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PresharedKeyError {
+  #[error("No key store is configured to authenticate connecting tunnels")]
+  MissingKeyStore,
+  #[error("No credentials are configured to authenticate with listening tunnels")]
+  MissingCredentials,
+  #[error("Tunnel provides no channel binding to authenticate over")]
+  UnboundChannel,
+  #[error("Random nonce generation failed")]
+  RandomnessFailure,
+  #[error(
+    "Tunnel names must be 1 to {} bytes long, but was {0} bytes",
+    MAX_NAME_LENGTH
+  )]
+  InvalidNameLength(usize),
+}
+
+/// The message authenticated by the connecting side's MAC
+fn mac_message(nonce: &[u8], channel_binding: &[u8], name: &TunnelName) -> Vec<u8> {
+  let mut message = Vec::with_capacity(nonce.len() + 2 + channel_binding.len() + name.raw().len());
+  message.extend_from_slice(nonce);
+  message.extend_from_slice(&(channel_binding.len() as u16).to_be_bytes());
+  message.extend_from_slice(channel_binding);
+  message.extend_from_slice(name.raw().as_bytes());
+  message
+}
+
+/// Authenticates tunnels by HMAC challenge-response over a pre-shared key
+///
+/// Listening requires a key store, and connecting requires credentials; a handler may be
+/// configured with both to authenticate in either role.
+pub struct PresharedKeyAuthenticationHandler {
+  key_store: Option<Arc<dyn PresharedKeyStore>>,
+  credentials: Option<(TunnelName, Vec<u8>)>,
+  allow_unbound_channels: bool,
+}
+
+impl PresharedKeyAuthenticationHandler {
+  pub fn new() -> Self {
+    Self {
+      key_store: None,
+      credentials: None,
+      allow_unbound_channels: false,
+    }
+  }
+
+  /// Authenticates connecting tunnels against the keys in the given store
+  pub fn with_key_store<S: PresharedKeyStore + 'static>(mut self, key_store: S) -> Self {
+    self.key_store = Some(Arc::new(key_store));
+    self
+  }
+
+  /// Authenticates with listening tunnels as the given name, using the key shared with them
+  ///
+  /// Fails if the name is empty or longer than listeners accept.
+  pub fn with_credentials<K: Into<Vec<u8>>>(
+    mut self,
+    name: TunnelName,
+    key: K,
+  ) -> Result<Self, PresharedKeyError> {
+    let name_length = name.raw().len();
+    if name_length == 0 || name_length > MAX_NAME_LENGTH as usize {
+      return Err(PresharedKeyError::InvalidNameLength(name_length));
+    }
+    self.credentials = Some((name, key.into()));
+    Ok(self)
+  }
+
+  /// Permits authentication over tunnels which cannot export keying material
+  ///
+  /// Without a channel binding, a MAC observed by a malicious listener can be relayed to
+  /// authenticate as the connecting tunnel elsewhere; only enable this for trusted transports.
+  pub fn allow_unbound_channels(mut self, allow: bool) -> Self {
+    self.allow_unbound_channels = allow;
+    self
+  }
+
+  fn channel_binding<'b>(
+    &self,
+    tunnel_info: &'b TunnelInfo,
+  ) -> Result<&'b [u8], AuthenticationError<PresharedKeyError>> {
+    match &tunnel_info.channel_binding {
+      Some(channel_binding) => Ok(channel_binding),
+      None if self.allow_unbound_channels => Ok(&[]),
+      None => {
+        Err(AuthenticationHandlingError::ApplicationError(PresharedKeyError::UnboundChannel).into())
+      }
+    }
+  }
+
+  async fn authenticate_listen_side(
+    &self,
+    channel: &mut AuthenticationChannel<'_>,
+    tunnel_info: TunnelInfo,
+  ) -> Result<(TunnelName, AuthenticationAttributes), AuthenticationError<PresharedKeyError>> {
+    let key_store =
+      self
+        .key_store
+        .as_ref()
+        .ok_or(AuthenticationHandlingError::ApplicationError(
+          PresharedKeyError::MissingKeyStore,
+        ))?;
+    let channel_binding = self.channel_binding(&tunnel_info)?;
+    let mut nonce = [0u8; NONCE_LENGTH];
+    ring::rand::SystemRandom::new()
+      .fill(&mut nonce)
+      .map_err(|_| {
+        AuthenticationHandlingError::ApplicationError(PresharedKeyError::RandomnessFailure)
+      })?;
+    channel
+      .write_all(&nonce)
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+      .await?;
+
+    let name_length = channel
+      .read_u16()
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+    if name_length == 0 || name_length > MAX_NAME_LENGTH {
+      return Err(
+        RemoteAuthenticationError::ProtocolViolation("Invalid name length".into()).into(),
+      );
+    }
+    let mut name = vec![0u8; name_length as usize];
+    let mut mac = [0u8; MAC_LENGTH];
+    for buffer in [&mut name[..], &mut mac[..]] {
+      channel
+        .read_exact(buffer)
+        .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+        .await?;
+    }
+    let name = String::from_utf8(name).map_err(|_| {
+      RemoteAuthenticationError::ProtocolViolation("Received name was not valid UTF8".into())
+    })?;
+    let name = TunnelName::new(name);
+
+    let verified = match key_store.key(&name).await {
+      Some(key) => hmac::verify(
+        &hmac::Key::new(hmac::HMAC_SHA256, &key),
+        &mac_message(&nonce, channel_binding, &name),
+        &mac,
+      )
+      .is_ok(),
+      None => false,
+    };
+    let outcome = match verified {
+      true => AUTHENTICATION_ACCEPTED,
+      false => AUTHENTICATION_REFUSED,
+    };
+    channel
+      .write_all(&[outcome])
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+      .await?;
+    if !verified {
+      tracing::debug!(name = ?name, "Pre-shared key authentication refused");
+      return Err(RemoteAuthenticationError::Refused.into());
+    }
+    Ok((name, AuthenticationAttributes::default()))
+  }
+
+  async fn authenticate_connecting_side(
+    &self,
+    channel: &mut AuthenticationChannel<'_>,
+    tunnel_info: TunnelInfo,
+  ) -> Result<(TunnelName, AuthenticationAttributes), AuthenticationError<PresharedKeyError>> {
+    let (name, key) =
+      self
+        .credentials
+        .as_ref()
+        .ok_or(AuthenticationHandlingError::ApplicationError(
+          PresharedKeyError::MissingCredentials,
+        ))?;
+    let channel_binding = self.channel_binding(&tunnel_info)?;
+    let mut nonce = [0u8; NONCE_LENGTH];
+    channel
+      .read_exact(&mut nonce)
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+
+    let mac = hmac::sign(
+      &hmac::Key::new(hmac::HMAC_SHA256, key),
+      &mac_message(&nonce, channel_binding, name),
+    );
+    let mut response = Vec::with_capacity(2 + name.raw().len() + MAC_LENGTH);
+    response.extend_from_slice(&(name.raw().len() as u16).to_be_bytes());
+    response.extend_from_slice(name.raw().as_bytes());
+    response.extend_from_slice(mac.as_ref());
+    channel
+      .write_all(&response)
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Write refused".into()))
+      .await?;
+
+    let outcome = channel
+      .read_u8()
+      .map_err(|_| RemoteAuthenticationError::ProtocolViolation("Read unavailable".into()))
+      .await?;
+    match outcome {
+      AUTHENTICATION_ACCEPTED => Ok((
+        TunnelName::new(tunnel_info.addr.to_string()),
+        AuthenticationAttributes::default(),
+      )),
+      AUTHENTICATION_REFUSED => Err(RemoteAuthenticationError::Refused.into()),
+      _ => Err(
+        RemoteAuthenticationError::ProtocolViolation("Invalid authentication outcome".into())
+          .into(),
+      ),
+    }
+  }
+}
+
+impl Default for PresharedKeyAuthenticationHandler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl std::fmt::Debug for PresharedKeyAuthenticationHandler {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("PresharedKeyAuthenticationHandler")
+      .field("key_store", &self.key_store)
+      .field("name", &self.credentials.as_ref().map(|(name, _)| name))
+      .field("allow_unbound_channels", &self.allow_unbound_channels)
+      .finish()
+  }
+}
+
+impl AuthenticationHandler for PresharedKeyAuthenticationHandler {
+  type Error = PresharedKeyError;
+
+  fn authenticate<'a>(
+    &'a self,
+    channel: &'a mut AuthenticationChannel<'a>,
+    tunnel_info: TunnelInfo,
+    _shutdown_notifier: &'a CancellationListener,
+  ) -> BoxFuture<'a, Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>>
+  {
+    match tunnel_info.side {
+      TunnelSide::Listen => self.authenticate_listen_side(channel, tunnel_info).boxed(),
+      TunnelSide::Connect => self
+        .authenticate_connecting_side(channel, tunnel_info)
+        .boxed(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::assert_matches::assert_matches;
+
+  use super::{
+    KeyStoreLoadError, PresharedKeyAuthenticationHandler, PresharedKeyError, StaticKeyStore,
+    TunnelInfo,
+  };
+  use crate::{
+    common::{
+      authentication::{
+        tests, AuthenticationError, AuthenticationHandler, AuthenticationHandlingError,
+        RemoteAuthenticationError,
+      },
+      protocol::tunnel::{TunnelName, TunnelSide},
+    },
+    util::{cancellation::CancellationListener, tunnel_stream::WrappedStream},
+  };
+
+  #[test]
+  fn key_store_parsing() {
+    // "secret" and "other"
+    let store =
+      StaticKeyStore::parse("# Edge devices\nalice = c2VjcmV0\n\nbob=b3RoZXI=,carol=c2VjcmV0\n")
+        .unwrap();
+    assert_eq!(store.len(), 3);
+    assert_eq!(
+      store.keys.get(&TunnelName::new("bob")).map(Vec::as_slice),
+      Some(&b"other"[..])
+    );
+    assert_matches!(
+      StaticKeyStore::parse("alice"),
+      Err(KeyStoreLoadError::InvalidEntry(1))
+    );
+    assert_matches!(
+      StaticKeyStore::parse("alice=c2VjcmV0\nbob=not base64"),
+      Err(KeyStoreLoadError::InvalidKey(2))
+    );
+    assert_matches!(
+      StaticKeyStore::from_env("SNOCAT_TEST_UNSET_KEY_STORE"),
+      Err(KeyStoreLoadError::MissingEnvironmentVariable(_))
+    );
+  }
+
+  /// Test that names listeners would refuse are rejected before connecting
+  #[test]
+  fn credential_name_length() {
+    let longest = "a".repeat(super::MAX_NAME_LENGTH as usize);
+    PresharedKeyAuthenticationHandler::new()
+      .with_credentials(TunnelName::new(longest.clone()), b"secret".to_vec())
+      .expect("Names of the maximum length must be accepted");
+    assert_matches!(
+      PresharedKeyAuthenticationHandler::new()
+        .with_credentials(TunnelName::new(longest + "a"), b"secret".to_vec()),
+      Err(PresharedKeyError::InvalidNameLength(1025))
+    );
+    assert_matches!(
+      PresharedKeyAuthenticationHandler::new()
+        .with_credentials(TunnelName::new(""), b"secret".to_vec()),
+      Err(PresharedKeyError::InvalidNameLength(0))
+    );
+  }
+
+  fn tunnel_info(side: TunnelSide, channel_binding: Option<&[u8]>) -> TunnelInfo {
+    TunnelInfo {
+      channel_binding: channel_binding.map(<[u8]>::to_vec),
+      ..tests::tunnel_info(side)
+    }
+  }
+
+  async fn authenticate(
+    listener: &PresharedKeyAuthenticationHandler,
+    listener_binding: Option<&[u8]>,
+    connector: &PresharedKeyAuthenticationHandler,
+    connector_binding: Option<&[u8]>,
+  ) -> (
+    Result<TunnelName, AuthenticationError<PresharedKeyError>>,
+    Result<TunnelName, AuthenticationError<PresharedKeyError>>,
+  ) {
+    let never_shutdown = CancellationListener::default();
+    let (mut listen_channel, mut connect_channel) = WrappedStream::duplex(256);
+    let (listen_res, connect_res) = futures::future::join(
+      listener.authenticate(
+        &mut listen_channel,
+        tunnel_info(TunnelSide::Listen, listener_binding),
+        &never_shutdown,
+      ),
+      connector.authenticate(
+        &mut connect_channel,
+        tunnel_info(TunnelSide::Connect, connector_binding),
+        &never_shutdown,
+      ),
+    )
+    .await;
+    (
+      listen_res.map(|(name, _)| name),
+      connect_res.map(|(name, _)| name),
+    )
+  }
+
+  #[tokio::test]
+  async fn run_auth() {
+    let listener = PresharedKeyAuthenticationHandler::new()
+      .with_key_store(StaticKeyStore::new().with_key(TunnelName::new("alice"), b"secret".to_vec()));
+    let binding = Some(&[7u8; 32][..]);
+
+    let alice = PresharedKeyAuthenticationHandler::new()
+      .with_credentials(TunnelName::new("alice"), b"secret".to_vec())
+      .unwrap();
+    let (listen_res, connect_res) = authenticate(&listener, binding, &alice, binding).await;
+    assert_eq!(listen_res.unwrap(), TunnelName::new("alice"));
+    assert_eq!(connect_res.unwrap(), TunnelName::new("Unidentified"));
+
+    // A MAC computed over another tunnel's session is refused
+    let (listen_res, connect_res) =
+      authenticate(&listener, binding, &alice, Some(&[8u8; 32])).await;
+    assert_matches!(
+      listen_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+    assert_matches!(
+      connect_res,
+      Err(AuthenticationError::Remote(
+        RemoteAuthenticationError::Refused
+      ))
+    );
+
+    for impostor in [
+      PresharedKeyAuthenticationHandler::new()
+        .with_credentials(TunnelName::new("alice"), b"guess".to_vec())
+        .unwrap(),
+      PresharedKeyAuthenticationHandler::new()
+        .with_credentials(TunnelName::new("mallory"), b"secret".to_vec())
+        .unwrap(),
+    ] {
+      let (listen_res, connect_res) = authenticate(&listener, binding, &impostor, binding).await;
+      assert_matches!(
+        listen_res,
+        Err(AuthenticationError::Remote(
+          RemoteAuthenticationError::Refused
+        ))
+      );
+      assert_matches!(
+        connect_res,
+        Err(AuthenticationError::Remote(
+          RemoteAuthenticationError::Refused
+        ))
+      );
+    }
+
+    // Tunnels without keying material are refused unless explicitly permitted
+    let (listen_res, _) = authenticate(&listener, None, &alice, None).await;
+    assert_matches!(
+      listen_res,
+      Err(AuthenticationError::Handling(
+        AuthenticationHandlingError::ApplicationError(PresharedKeyError::UnboundChannel)
+      ))
+    );
+    let listener = listener.allow_unbound_channels(true);
+    let alice = alice.allow_unbound_channels(true);
+    let (listen_res, connect_res) = authenticate(&listener, None, &alice, None).await;
+    assert_eq!(listen_res.unwrap(), TunnelName::new("alice"));
+    assert!(connect_res.is_ok());
+  }
+}
@@ -3,8 +3,11 @@ use crate::ext::future::TryFutureExtExt;
 // Licensed under the MIT license OR Apache 2.0
 #[warn(unused_imports)]
 use crate::{
-  common::protocol::tunnel::{
-    Tunnel, TunnelAddressInfo, TunnelError, TunnelId, TunnelIncomingType, TunnelName, TunnelSide,
+  common::{
+    authentication::combinators::FallbackAuthenticationHandler,
+    protocol::tunnel::{
+      Tunnel, TunnelAddressInfo, TunnelError, TunnelId, TunnelIncomingType, TunnelName, TunnelSide,
+    },
   },
   util::{cancellation::CancellationListener, tunnel_stream::TunnelStream},
 };
@@ -14,13 +17,35 @@ use std::{
   fmt::Debug,
   marker::{PhantomData, Unpin},
   sync::Arc,
+  time::{Duration, SystemTime},
 };
 
+/// Label under which [TunnelInfo::channel_binding] is exported from the tunnel's TLS session
+pub const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-snocat-channel-binding";
+pub const CHANNEL_BINDING_LENGTH: usize = 32;
+
 #[derive(Debug, Clone)]
 pub struct TunnelInfo {
   pub tunnel_id: TunnelId,
   pub side: TunnelSide,
   pub addr: TunnelAddressInfo,
+  /// The certificate chain presented by the remote, end-entity certificate first, if any
+  pub peer_certificates: Option<Vec<rustls::Certificate>>,
+  /// Keying material unique to the tunnel's session, identical on both sides, if available
+  pub channel_binding: Option<Vec<u8>>,
+}
+
+impl TunnelInfo {
+  /// Describes a tunnel, exporting its channel binding from its session if possible
+  pub fn from_tunnel(tunnel: &(dyn Tunnel + Send + Sync + '_)) -> Self {
+    Self {
+      tunnel_id: *tunnel.id(),
+      side: tunnel.side(),
+      addr: tunnel.addr(),
+      peer_certificates: tunnel.peer_certificates(),
+      channel_binding: tunnel.keying_material(CHANNEL_BINDING_LENGTH, CHANNEL_BINDING_LABEL, &[]),
+    }
+  }
 }
 
 /// Some errors within the authentication layer are considered fatal to the authenticator
@@ -145,6 +170,32 @@ impl<TInner> From<std::convert::Infallible> for AuthenticationError<TInner> {
 
 pub type AuthenticationAttributes = HashMap<String, Vec<u8>>;
 
+/// Attribute holding the time after which an authentication's credentials are no longer valid
+///
+/// Stored as decimal seconds since the unix epoch. Daemons close or re-authenticate tunnels
+/// whose authentication carries an expiry before it passes.
+pub const EXPIRY_ATTRIBUTE: &str = "authentication.expiry";
+
+/// Reads the expiry recorded in a set of attributes, if any
+pub fn authentication_expiry(attributes: &AuthenticationAttributes) -> Option<SystemTime> {
+  let seconds: u64 = std::str::from_utf8(attributes.get(EXPIRY_ATTRIBUTE)?)
+    .ok()?
+    .parse()
+    .ok()?;
+  SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// Records an expiry in a set of attributes, unless an earlier expiry is already recorded
+pub fn limit_authentication_expiry(attributes: &mut AuthenticationAttributes, expiry: SystemTime) {
+  if authentication_expiry(attributes).map_or(true, |existing| expiry < existing) {
+    let seconds = expiry
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+    attributes.insert(EXPIRY_ATTRIBUTE.into(), seconds.to_string().into_bytes());
+  }
+}
+
 pub type AuthenticationChannel<'a> = dyn TunnelStream + Send + Unpin + 'a;
 
 pub trait AuthenticationHandler: std::fmt::Debug + Send + Sync {
@@ -258,6 +309,21 @@ pub trait AuthenticationHandlerExt: AuthenticationHandler {
       phantom_output: PhantomData,
     }
   }
+
+  /// Falls back to another handler when the remote is refused by this one
+  fn fallback<TFallback>(
+    self,
+    fallback: TFallback,
+  ) -> FallbackAuthenticationHandler<Self, TFallback>
+  where
+    TFallback: AuthenticationHandler<Error = Self::Error>,
+    Self: Sized,
+  {
+    FallbackAuthenticationHandler {
+      primary: self,
+      fallback,
+    }
+  }
 }
 
 impl<T: AuthenticationHandler> AuthenticationHandlerExt for T {}
@@ -302,6 +368,9 @@ fn tunnel_error_to_remote_auth_error(e: TunnelError) -> RemoteAuthenticationErro
     TunnelError::ConnectionClosed => RemoteAuthenticationError::LinkClosedRemotely,
     TunnelError::TimedOut => RemoteAuthenticationError::TimedOut,
     TunnelError::TransportError => RemoteAuthenticationError::TransportError,
+    TunnelError::DatagramsUnsupported | TunnelError::DatagramTooLarge => {
+      RemoteAuthenticationError::TransportError
+    }
   }
 }
 
@@ -314,11 +383,7 @@ where
   T::Error: std::fmt::Debug + Send,
 {
   use tracing::{debug, debug_span, warn, Instrument};
-  let tunnel_info = TunnelInfo {
-    tunnel_id: tunnel.id().clone(),
-    side: tunnel.side(),
-    addr: tunnel.addr(),
-  };
+  let tunnel_info = TunnelInfo::from_tunnel(tunnel);
   let tracing_span_authentication =
     debug_span!("authentication", side=?tunnel_info.side, addr=?tunnel_info.addr);
 
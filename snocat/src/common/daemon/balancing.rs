@@ -0,0 +1,676 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Routers spreading requests across every tunnel connected under a name
+//!
+//! A [BalancingRouter] orders the tunnels connected under the requested name by its
+//! [BalancingPolicy], then opens its link on the first of them able to, moving on to the next
+//! candidate whenever a tunnel fails to open one.
+use dashmap::DashMap;
+use futures::{
+  future::{BoxFuture, FutureExt},
+  TryFutureExt,
+};
+use rand::Rng;
+use std::{
+  cmp::Ordering,
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
+  ops::RangeInclusive,
+  sync::Arc,
+};
+
+use super::{PeerRecord, PeersView};
+use crate::{
+  common::protocol::{
+    negotiation::{self, NegotiationClient},
+    service::{Client, Request, Router, RouterResult, RoutingError},
+    tunnel::{ArcTunnel, TunnelName},
+    RequestHeaders, RouteAddress,
+  },
+  util::tunnel_stream::WrappedStream,
+};
+
+/// The part of a request which [BalancingPolicy::ConsistentHash] keeps on one tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestKey {
+  Address,
+  /// The value of the named request header, or the address for requests without it
+  Header(String),
+}
+
+impl RequestKey {
+  fn of(&self, address: &RouteAddress, headers: &RequestHeaders) -> String {
+    match self {
+      Self::Header(name) if headers.contains_key(name) => headers[name].clone(),
+      Self::Address | Self::Header(_) => address.to_string(),
+    }
+  }
+}
+
+/// Chooses the order in which a [BalancingRouter] tries the tunnels connected under a name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BalancingPolicy {
+  /// Takes each tunnel in turn
+  RoundRobin,
+  /// Prefers the tunnels with the fewest active streams, taking each of those tied in turn
+  ///
+  /// Tunnels which do not track their streams are treated as idle.
+  LeastActiveStreams,
+  /// Picks tunnels at random, in proportion to a weight held in an authentication attribute
+  ///
+  /// Weights are read as decimal integers, with tunnels lacking a valid weight given the default.
+  /// Tunnels weighted zero are only tried once all others have failed.
+  WeightedByAttribute {
+    attribute: String,
+    default_weight: u64,
+  },
+  /// Sends requests with equal keys to the same tunnel for as long as it remains connected
+  ///
+  /// Uses rendezvous hashing, so a tunnel connecting or disconnecting only moves the requests
+  /// which it gains or loses.
+  ConsistentHash(RequestKey),
+}
+
+impl BalancingPolicy {
+  pub fn weighted_by_attribute<S: Into<String>>(attribute: S, default_weight: u64) -> Self {
+    Self::WeightedByAttribute {
+      attribute: attribute.into(),
+      default_weight,
+    }
+  }
+
+  /// Orders candidates, given in order of ID, from most to least preferred
+  fn order(
+    &self,
+    turn: usize,
+    address: &RouteAddress,
+    headers: &RequestHeaders,
+    mut candidates: Vec<Arc<PeerRecord>>,
+  ) -> Vec<Arc<PeerRecord>> {
+    if candidates.is_empty() {
+      return candidates;
+    }
+    match self {
+      Self::RoundRobin => {
+        let len = candidates.len();
+        candidates.rotate_left(turn % len);
+      }
+      Self::LeastActiveStreams => {
+        let len = candidates.len();
+        candidates.rotate_left(turn % len);
+        // Stable, so ties remain in their round-robin order
+        candidates.sort_by_key(|peer| peer.tunnel.tracked_stream_count().unwrap_or(0));
+      }
+      Self::WeightedByAttribute {
+        attribute,
+        default_weight,
+      } => {
+        // Weighted random sampling without replacement, by descending `u ^ (1 / weight)`
+        let mut rng = rand::thread_rng();
+        let mut keyed: Vec<(f64, Arc<PeerRecord>)> = candidates
+          .into_iter()
+          .map(|peer| {
+            let weight = peer
+              .attributes
+              .load()
+              .get(attribute)
+              .and_then(|value| std::str::from_utf8(value).ok())
+              .and_then(|value| value.trim().parse::<u64>().ok())
+              .unwrap_or(*default_weight);
+            let key = match weight {
+              0 => -1.0,
+              weight => rng.gen::<f64>().powf(1.0 / weight as f64),
+            };
+            (key, peer)
+          })
+          .collect();
+        keyed.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        candidates = keyed.into_iter().map(|(_, peer)| peer).collect();
+      }
+      Self::ConsistentHash(key) => {
+        let key = key.of(address, headers);
+        candidates.sort_by_cached_key(|peer| {
+          let mut hasher = DefaultHasher::new();
+          (&key, peer.id).hash(&mut hasher);
+          std::cmp::Reverse(hasher.finish())
+        });
+      }
+    }
+    candidates
+  }
+
+  fn uses_turns(&self) -> bool {
+    matches!(self, Self::RoundRobin | Self::LeastActiveStreams)
+  }
+}
+
+/// Routes requests for a name to one of the tunnels connected under it, chosen by [BalancingPolicy]
+///
+/// Where [PeersView::find_by_name_and_comparator] always chooses the same tunnel among several
+/// connected under one name, this spreads requests across all of them, and retries each request
+/// on the next tunnel in order when one cannot open a link.
+pub struct BalancingRouter {
+  peers: Arc<PeersView>,
+  policy: BalancingPolicy,
+  turns: DashMap<TunnelName, usize>,
+  protocol_versions: Option<RangeInclusive<u8>>,
+}
+
+impl BalancingRouter {
+  pub fn new(peers: PeersView, policy: BalancingPolicy) -> Self {
+    Self {
+      peers: Arc::new(peers),
+      policy,
+      turns: Default::default(),
+      protocol_versions: None,
+    }
+  }
+
+  pub fn round_robin(peers: PeersView) -> Self {
+    Self::new(peers, BalancingPolicy::RoundRobin)
+  }
+
+  pub fn least_active_streams(peers: PeersView) -> Self {
+    Self::new(peers, BalancingPolicy::LeastActiveStreams)
+  }
+
+  pub fn weighted_by_attribute<S: Into<String>>(
+    peers: PeersView,
+    attribute: S,
+    default_weight: u64,
+  ) -> Self {
+    Self::new(
+      peers,
+      BalancingPolicy::weighted_by_attribute(attribute, default_weight),
+    )
+  }
+
+  pub fn consistent_hash(peers: PeersView, key: RequestKey) -> Self {
+    Self::new(peers, BalancingPolicy::ConsistentHash(key))
+  }
+
+  /// Sets the negotiation protocol versions offered to the services of the selected tunnel
+  ///
+  /// If unset, only requests with headers offer v1, as by [NegotiationClient::with_headers].
+  /// Tunnels whose services only speak v0 are retried over another link with v0 alone, as by
+  /// [NegotiationClient::negotiate_with_fallback]. Panics if `protocol_versions` is empty or is
+  /// not a subset of [SUPPORTED_PROTOCOL_VERSIONS](negotiation::SUPPORTED_PROTOCOL_VERSIONS).
+  pub fn with_protocol_versions(mut self, protocol_versions: RangeInclusive<u8>) -> Self {
+    assert!(
+      negotiation::is_supported_range(&protocol_versions),
+      "Unsupported negotiation protocol version range {:?}",
+      protocol_versions
+    );
+    self.protocol_versions = Some(protocol_versions);
+    self
+  }
+
+  pub fn policy(&self) -> &BalancingPolicy {
+    &self.policy
+  }
+
+  /// The tunnels a request would be tried upon, in order
+  ///
+  /// Advances the name's turn for policies which take tunnels in turn.
+  pub fn candidates(
+    &self,
+    tunnel_name: &TunnelName,
+    address: &RouteAddress,
+    headers: &RequestHeaders,
+  ) -> Vec<Arc<PeerRecord>> {
+    let mut candidates = self.peers.get_by_name(tunnel_name);
+    if candidates.is_empty() {
+      self.turns.remove(tunnel_name);
+      return candidates;
+    }
+    candidates.sort_by_key(|peer| peer.id);
+    let turn = if self.policy.uses_turns() {
+      let mut turn = self.turns.entry(tunnel_name.clone()).or_insert(0);
+      let current = *turn;
+      *turn = current.wrapping_add(1);
+      current
+    } else {
+      0
+    };
+    self.policy.order(turn, address, headers, candidates)
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BalancingRouterError {}
+
+impl Router for BalancingRouter {
+  type Error = BalancingRouterError;
+  type Stream = WrappedStream;
+  type LocalAddress = TunnelName;
+
+  fn route<'client, 'result, TProtocolClient, IntoLocalAddress: Into<Self::LocalAddress>>(
+    &self,
+    request: Request<'client, Self::Stream, TProtocolClient>,
+    local_address: IntoLocalAddress,
+  ) -> BoxFuture<'client, RouterResult<'client, 'result, Self, TProtocolClient>>
+  where
+    TProtocolClient: Client<'result, Self::Stream> + Send + 'client,
+  {
+    let addr = request.address.clone();
+    let dest_name: TunnelName = local_address.into();
+    let candidates = self.candidates(&dest_name, &request.address, &request.headers);
+    let protocol_versions = self.protocol_versions.clone();
+    async move {
+      let mut failure = RoutingError::RouteNotFound(addr.clone());
+      let mut selected = None;
+      for peer in candidates {
+        match peer.tunnel.open_link().await {
+          Ok(link) => {
+            selected = Some((peer, link));
+            break;
+          }
+          Err(e) => {
+            tracing::debug!(
+              id = ?peer.id,
+              name = ?peer.name,
+              error = ?e,
+              "Tunnel failed to open a link; trying the next candidate"
+            );
+            failure = RoutingError::LinkOpenFailure(e);
+          }
+        }
+      }
+      let (peer, link) = selected.ok_or(failure)?;
+      let mut negotiator = NegotiationClient::new()
+        .with_service_versions(TProtocolClient::service_versions())
+        .with_headers(request.headers);
+      if let Some(protocol_versions) = protocol_versions {
+        negotiator = negotiator.with_protocol_versions(protocol_versions);
+      }
+      let (link, service_version) = negotiator
+        .negotiate_with_fallback::<_, Self::Error, _, _>(addr.clone(), link, || {
+          peer
+            .tunnel
+            .open_link()
+            .map_err(RoutingError::LinkOpenFailure)
+        })
+        .await?;
+      let tunnel: ArcTunnel<'static> = Arc::new(Arc::clone(&peer.tunnel));
+      Ok(
+        request
+          .protocol_client
+          .handle(addr, service_version, link, tunnel),
+      )
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use arc_swap::ArcSwap;
+  use futures::{
+    future::{self, BoxFuture, Either, FutureExt},
+    TryStreamExt,
+  };
+  use std::{
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Instant, SystemTime},
+  };
+
+  use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+  use super::{BalancingRouter, RequestKey};
+  use crate::{
+    client::tests::{
+      accept_stream, serve_one, RecordingService, SingleServiceRegistry, VersionedClient,
+    },
+    common::{
+      authentication::AuthenticationAttributes,
+      daemon::{PeerRecord, PeerTracker},
+      protocol::{
+        negotiation::{tests::emulate_v0_service, NegotiationService, SUPPORTED_PROTOCOL_VERSIONS},
+        proxy_tcp::{TcpStreamClient, TcpStreamService, TcpStreamTarget},
+        service::{Request, Router, RoutingError},
+        tunnel::{
+          duplex::{self, DuplexTunnel},
+          ArcTunnel, Sided, Tunnel, TunnelCloseReason, TunnelControl, TunnelDownlink, TunnelError,
+          TunnelId, TunnelIncomingType, TunnelName, TunnelSide, TunnelUplink, WithTunnelId,
+        },
+        RequestHeaders, RouteAddress,
+      },
+    },
+    util::tunnel_stream::WrappedStream,
+  };
+
+  struct NoControl;
+
+  impl TunnelControl for NoControl {
+    fn close<'a>(
+      &'a self,
+      reason: TunnelCloseReason,
+    ) -> BoxFuture<'a, Result<Arc<TunnelCloseReason>, Arc<TunnelCloseReason>>> {
+      futures::future::ready(Ok(Arc::new(reason))).boxed()
+    }
+
+    fn report_authentication_success<'a>(
+      &self,
+      _tunnel_name: TunnelName,
+    ) -> BoxFuture<'a, Result<(), Option<Arc<TunnelCloseReason>>>> {
+      futures::future::ready(Ok(())).boxed()
+    }
+  }
+
+  /// A tunnel reporting a fixed number of active streams
+  struct BusyTunnel(DuplexTunnel, usize);
+
+  impl WithTunnelId for BusyTunnel {
+    fn id(&self) -> &TunnelId {
+      self.0.id()
+    }
+  }
+
+  impl Sided for BusyTunnel {
+    fn side(&self) -> TunnelSide {
+      self.0.side()
+    }
+  }
+
+  impl TunnelUplink for BusyTunnel {
+    fn open_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+      self.0.open_link()
+    }
+
+    fn tracked_stream_count(&self) -> Option<usize> {
+      Some(self.1)
+    }
+  }
+
+  impl Tunnel for BusyTunnel {
+    fn downlink<'a>(&'a self) -> BoxFuture<'a, Option<Box<dyn TunnelDownlink + Send + Unpin>>> {
+      self.0.downlink()
+    }
+  }
+
+  pub(crate) fn peer(
+    id: u64,
+    tunnel: Arc<dyn Tunnel + Send + Sync + 'static>,
+    attributes: AuthenticationAttributes,
+  ) -> Arc<PeerRecord> {
+    Arc::new(PeerRecord {
+      id: TunnelId::new(id),
+      name: TunnelName::new("site"),
+      registered_at: (Instant::now(), SystemTime::now()),
+      attributes: Arc::new(ArcSwap::from_pointee(attributes)),
+      tunnel,
+      control: Arc::new(NoControl),
+    })
+  }
+
+  /// Registers peers with a tracker, which is private to the daemon module
+  pub(crate) fn track(tracker: &PeerTracker, peers: &[Arc<PeerRecord>]) {
+    peers.iter().for_each(|peer| tracker.insert(peer));
+  }
+
+  fn idle_peer(id: u64) -> Arc<PeerRecord> {
+    peer(id, Arc::new(duplex::channel().listener), Default::default())
+  }
+
+  fn candidate_ids(
+    router: &BalancingRouter,
+    address: &RouteAddress,
+    headers: &RequestHeaders,
+  ) -> Vec<u64> {
+    router
+      .candidates(&TunnelName::new("site"), address, headers)
+      .iter()
+      .map(|peer| peer.id.inner())
+      .collect()
+  }
+
+  #[test]
+  fn candidate_ordering() {
+    let tracker = PeerTracker::new();
+    let address: RouteAddress = "/tcp/example/80".parse().unwrap();
+    let headers = RequestHeaders::new();
+    let busy = |id, streams| {
+      peer(
+        id,
+        Arc::new(BusyTunnel(duplex::channel().listener, streams)),
+        [("weight".to_string(), format!("{}", id - 1).into_bytes())]
+          .into_iter()
+          .collect(),
+      )
+    };
+    let peers = [busy(1, 3), busy(2, 1), busy(3, 1)];
+    peers.iter().for_each(|peer| tracker.insert(peer));
+
+    let router = BalancingRouter::round_robin(tracker.view());
+    assert_eq!(candidate_ids(&router, &address, &headers), vec![1, 2, 3]);
+    assert_eq!(candidate_ids(&router, &address, &headers), vec![2, 3, 1]);
+    assert_eq!(candidate_ids(&router, &address, &headers), vec![3, 1, 2]);
+    assert_eq!(candidate_ids(&router, &address, &headers), vec![1, 2, 3]);
+    assert!(router
+      .candidates(&TunnelName::new("elsewhere"), &address, &headers)
+      .is_empty());
+
+    // The busiest tunnel is tried last, and those tied alternate
+    let router = BalancingRouter::least_active_streams(tracker.view());
+    assert_eq!(candidate_ids(&router, &address, &headers), vec![2, 3, 1]);
+    assert_eq!(candidate_ids(&router, &address, &headers), vec![2, 3, 1]);
+    assert_eq!(candidate_ids(&router, &address, &headers), vec![3, 2, 1]);
+
+    // The tunnel weighted zero is always tried last
+    let router = BalancingRouter::weighted_by_attribute(tracker.view(), "weight", 1);
+    let mut first_choices = [0usize; 4];
+    for _ in 0..300 {
+      let ids = candidate_ids(&router, &address, &headers);
+      assert_eq!(ids.len(), 3);
+      assert_eq!(ids[2], 1);
+      first_choices[ids[0] as usize] += 1;
+    }
+    assert!(first_choices[3] > first_choices[2]);
+  }
+
+  #[test]
+  fn consistent_hashing() {
+    let tracker = PeerTracker::new();
+    let mut peers: Vec<_> = (1..=4).map(idle_peer).collect();
+    peers.iter().for_each(|peer| tracker.insert(peer));
+    let router =
+      BalancingRouter::consistent_hash(tracker.view(), RequestKey::Header("session".to_string()));
+    let address: RouteAddress = "/tcp/example/80".parse().unwrap();
+    let session: RequestHeaders = [("session".to_string(), "abc".to_string())]
+      .into_iter()
+      .collect();
+
+    let order = candidate_ids(&router, &address, &session);
+    assert_eq!(order.len(), 4);
+    assert_eq!(candidate_ids(&router, &address, &session), order);
+    // Requests without the header are keyed by their address
+    let by_address = candidate_ids(&router, &address, &RequestHeaders::new());
+    let router_by_address = BalancingRouter::consistent_hash(tracker.view(), RequestKey::Address);
+    assert_eq!(
+      candidate_ids(&router_by_address, &address, &RequestHeaders::new()),
+      by_address
+    );
+
+    // Losing the chosen tunnel moves its requests to the next in order, leaving others in place
+    peers.retain(|peer| peer.id.inner() != order[0]);
+    assert_eq!(candidate_ids(&router, &address, &session), order[1..]);
+  }
+
+  type EchoRequest = Request<
+    'static,
+    WrappedStream,
+    TcpStreamClient<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>,
+  >;
+
+  fn echo_request() -> EchoRequest {
+    let (_, local) = tokio::io::duplex(64);
+    let (local_reader, local_writer) = tokio::io::split(local);
+    Request::new(
+      TcpStreamClient::new(local_reader, local_writer),
+      TcpStreamTarget::SocketAddr((Ipv4Addr::LOCALHOST, 7).into()),
+    )
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn retries_next_candidate() {
+    // The first tunnel's remote side is gone, so it cannot open links
+    let dead = duplex::channel();
+    drop(dead.connector);
+    let live = duplex::channel();
+    let tracker = PeerTracker::new();
+    let mut peers = vec![
+      peer(1, Arc::new(dead.listener), Default::default()),
+      peer(2, Arc::new(live.listener), Default::default()),
+    ];
+    peers.iter().for_each(|peer| tracker.insert(peer));
+    let router = BalancingRouter::round_robin(tracker.view());
+
+    // The request is sent over the second tunnel once the first fails to open a link
+    let mut downlink = live.connector.downlink().await.unwrap();
+    let mut incoming = downlink.as_stream();
+    let routing = router.route(echo_request(), TunnelName::new("site"));
+    assert!(matches!(
+      future::select(routing, incoming.try_next()).await,
+      Either::Right((Ok(Some(TunnelIncomingType::BiStream(_))), _))
+    ));
+
+    // With no tunnel able to open a link, the last failure is reported
+    peers.pop();
+    assert!(matches!(
+      router.route(echo_request(), TunnelName::new("site")).await,
+      Err(RoutingError::LinkOpenFailure(TunnelError::ConnectionClosed))
+    ));
+  }
+
+  /// Test that routers offering v1 negotiate it, and still reach services which only speak v0
+  #[tokio::test]
+  async fn negotiates_offered_protocol_versions() {
+    let remote = duplex::channel();
+    let tracker = PeerTracker::new();
+    let peers = [peer(1, Arc::new(remote.listener), Default::default())];
+    peers.iter().for_each(|peer| tracker.insert(peer));
+    let router = BalancingRouter::round_robin(tracker.view())
+      .with_protocol_versions(SUPPORTED_PROTOCOL_VERSIONS);
+    let services: ArcTunnel<'static> = Arc::new(remote.connector);
+    let address = echo_request().address;
+
+    // Services which only accept v1 are reached over the first link
+    let v1_service = async {
+      let stream = accept_stream(&services).await;
+      let registry = SingleServiceRegistry(Arc::new(TcpStreamService::new(true)));
+      NegotiationService::new(Arc::new(registry))
+        .with_protocol_versions(1..=1)
+        .negotiate(stream, Arc::clone(&services))
+        .await
+        .map(|(_, addr, ..)| addr)
+    };
+    let (routed, negotiated) = future::join(
+      router.route(echo_request(), TunnelName::new("site")),
+      v1_service,
+    )
+    .await;
+    routed.map(drop).expect("Routing must succeed");
+    assert_eq!(negotiated.unwrap(), address);
+
+    // Services which only speak v0 refuse the first link, and are reached over a second
+    let v0_service = async {
+      let refused = emulate_v0_service(accept_stream(&services).await).await;
+      let accepted = emulate_v0_service(accept_stream(&services).await).await;
+      (refused.unwrap(), accepted.unwrap())
+    };
+    let (routed, negotiated) = future::join(
+      router.route(echo_request(), TunnelName::new("site")),
+      v0_service,
+    )
+    .await;
+    routed.map(drop).expect("Routing must succeed");
+    assert_eq!(negotiated, (None, Some(address.to_string())));
+  }
+
+  /// Test that service versions offered by clients reach the service once v1 is negotiated
+  #[tokio::test]
+  async fn delivers_service_version() {
+    let remote = duplex::channel();
+    let tracker = PeerTracker::new();
+    let peers = [peer(1, Arc::new(remote.listener), Default::default())];
+    peers.iter().for_each(|peer| tracker.insert(peer));
+    let router = BalancingRouter::round_robin(tracker.view())
+      .with_protocol_versions(SUPPORTED_PROTOCOL_VERSIONS);
+    let (service, mut handled) = RecordingService::new(0..=1);
+
+    let request = Request::new(VersionedClient, "/versioned".parse().unwrap()).unwrap();
+    let (routed, ()) = future::join(
+      router.route(request, TunnelName::new("site")),
+      serve_one(Arc::new(remote.connector), Arc::new(service)),
+    )
+    .await;
+    assert_eq!(routed.expect("Routing must succeed").await.unwrap(), 1);
+    assert_eq!(handled.recv().await.map(|(version, _)| version), Some(1));
+  }
+
+  /// Test that requests with headers offer v1 to deliver them, without routers opting into it
+  #[tokio::test]
+  async fn delivers_headers() {
+    let remote = duplex::channel();
+    let tracker = PeerTracker::new();
+    let peers = [peer(1, Arc::new(remote.listener), Default::default())];
+    track(&tracker, &peers);
+    let router = BalancingRouter::round_robin(tracker.view());
+    let (service, mut handled) = RecordingService::new(0..=0);
+
+    let request = Request::new(VersionedClient, "/versioned".parse().unwrap())
+      .unwrap()
+      .with_header("tenant", "contoso");
+    let (routed, ()) = future::join(
+      router.route(request, TunnelName::new("site")),
+      serve_one(Arc::new(remote.connector), Arc::new(service)),
+    )
+    .await;
+    assert_eq!(routed.expect("Routing must succeed").await.unwrap(), 0);
+    let (_, headers) = handled.recv().await.unwrap();
+    assert_eq!(headers.get("tenant").map(String::as_str), Some("contoso"));
+  }
+
+  /// Test that the trace context of a routed request reaches its service without routers opting
+  /// into v1, as its trace headers are sent as any other
+  #[cfg(feature = "trace-context")]
+  #[tokio::test]
+  async fn delivers_trace_context() {
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use tracing_futures::Instrument;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt};
+
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+    let subscriber = tracing_subscriber::registry()
+      .with(LevelFilter::INFO)
+      .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("snocat-test")));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let remote = duplex::channel();
+    let tracker = PeerTracker::new();
+    let peers = [peer(1, Arc::new(remote.listener), Default::default())];
+    track(&tracker, &peers);
+    let router = BalancingRouter::round_robin(tracker.view());
+    let (service, mut handled) = RecordingService::new(0..=0);
+
+    let request_span = tracing::info_span!("request");
+    let request = Request::new(VersionedClient, "/versioned".parse().unwrap()).unwrap();
+    let (routed, ()) = future::join(
+      router
+        .route(request, TunnelName::new("site"))
+        .instrument(request_span.clone()),
+      serve_one(Arc::new(remote.connector), Arc::new(service)),
+    )
+    .await;
+    routed.map(drop).expect("Routing must succeed");
+    let (_, headers) = handled.recv().await.unwrap();
+    let remote = crate::common::protocol::trace_context::extract_context(&headers);
+    assert_eq!(
+      remote.span().span_context().trace_id(),
+      request_span.context().span().span_context().trace_id()
+    );
+  }
+}
@@ -1,33 +1,36 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
 
+use arc_swap::ArcSwap;
 use authentication::perform_authentication;
 use dashmap::DashMap;
 use futures::{
-  future::{self, TryFutureExt},
+  future::{self, BoxFuture, Either, FutureExt, TryFutureExt},
   Future, Stream, StreamExt, TryStream, TryStreamExt,
 };
 use std::{
   fmt::{Debug, Display},
   hash::Hash,
   sync::{Arc, Mutex, Weak},
-  time::{Instant, SystemTime},
+  time::{Duration, Instant, SystemTime},
 };
 use tokio::sync::broadcast::{channel as event_channel, Sender as Broadcaster};
+use tokio_util::sync::CancellationToken;
 use tracing::Instrument;
 
 use crate::{
   common::{
-    authentication::{self, AuthenticationError, AuthenticationHandler},
+    authentication::{self, AuthenticationError, AuthenticationHandler, TunnelInfo},
     protocol::{
-      negotiation::{self, NegotiationError, NegotiationService},
-      service::Router,
+      datagram::DatagramDispatcher,
+      negotiation::{self, NegotiationClient, NegotiationError, NegotiationService},
+      service::{Router, RoutingError},
       tunnel::{
         self,
         id::{TunnelIdGenerator, TunnelIdGeneratorExt},
         registry::TunnelRegistry,
-        IntoTunnel, Tunnel, TunnelDownlink, TunnelError, TunnelId, TunnelIncomingType, TunnelName,
-        WithTunnelId,
+        ArcTunnel, IntoTunnel, Tunnel, TunnelDownlink, TunnelError, TunnelId, TunnelIncomingType,
+        TunnelName, WithTunnelId,
       },
       RouteAddress, ServiceRegistry,
     },
@@ -36,17 +39,40 @@ use crate::{
 };
 
 use super::{
-  authentication::{AuthenticationAttributes, AuthenticationHandlingError},
+  authentication::{
+    AuthenticationAttributes, AuthenticationHandlingError, RemoteAuthenticationError,
+  },
   protocol::tunnel::{TunnelControl, TunnelMonitoring},
 };
 
+pub mod balancing;
+mod reauthentication;
+pub use reauthentication::reauthentication_address;
+use reauthentication::{ReauthenticatingServiceRegistry, ReauthenticationStream, Reauthenticator};
+pub mod revocation;
+use revocation::{RevocationList, RevocationTarget};
+pub mod shared_attributes;
+use shared_attributes::{authentication_attributes_key, PublishedAttributes, SharedAttributes};
+
 #[derive(Clone)]
 pub struct PeerRecord {
   pub id: TunnelId,
   pub name: TunnelName,
   pub registered_at: (Instant, std::time::SystemTime),
-  pub attributes: Arc<AuthenticationAttributes>,
+  /// Attributes from the tunnel's most recent authentication
+  pub attributes: Arc<ArcSwap<AuthenticationAttributes>>,
   pub tunnel: Arc<dyn Tunnel + Send + Sync + 'static>,
+  control: Arc<dyn TunnelControl + Send + Sync + 'static>,
+}
+
+impl PeerRecord {
+  /// Closes the tunnel, failing with the reason it was already closed for if it was
+  pub fn close(
+    &self,
+    reason: tunnel::TunnelCloseReason,
+  ) -> BoxFuture<'_, Result<Arc<tunnel::TunnelCloseReason>, Arc<tunnel::TunnelCloseReason>>> {
+    self.control.close(reason)
+  }
 }
 
 impl Debug for PeerRecord {
@@ -187,6 +213,45 @@ impl PeersView {
       .flat_map(|by_id| by_id.iter().filter_map(|kv| kv.value().upgrade()))
       .collect()
   }
+
+  /// Closes every active tunnel matching a target, returning the number of tunnels closed
+  pub async fn revoke(
+    &self,
+    target: &RevocationTarget,
+    reason: tunnel::TunnelCloseReason,
+  ) -> usize {
+    let peers = match target {
+      RevocationTarget::Name(name) => self.get_by_name(name),
+      RevocationTarget::Id(id) => self.get_by_id(id).into_iter().collect(),
+      RevocationTarget::Attributes(_) => self.all(),
+    };
+    Self::close_matching(peers, |peer| target.matches_peer(peer), reason).await
+  }
+
+  /// Closes every active tunnel matching a predicate, returning the number of tunnels closed
+  pub async fn revoke_matching<P: Fn(&PeerRecord) -> bool>(
+    &self,
+    predicate: P,
+    reason: tunnel::TunnelCloseReason,
+  ) -> usize {
+    Self::close_matching(self.all(), predicate, reason).await
+  }
+
+  async fn close_matching<P: Fn(&PeerRecord) -> bool>(
+    peers: Vec<Arc<PeerRecord>>,
+    predicate: P,
+    reason: tunnel::TunnelCloseReason,
+  ) -> usize {
+    let closures = peers.iter().filter(|peer| predicate(peer)).map(|peer| {
+      tracing::info!(id = ?peer.id, name = ?peer.name, %reason, "Revoking tunnel");
+      peer.close(reason.clone())
+    });
+    future::join_all(closures)
+      .await
+      .into_iter()
+      .filter(Result::is_ok)
+      .count()
+  }
 }
 
 #[derive(Clone, Default)]
@@ -224,7 +289,9 @@ impl PeerTracker {
 
 struct DeregisteringTunnelWrapper<TRegistry: ?Sized, TRecordIdent> {
   registry: Arc<TRegistry>,
-  record_identifier: TRecordIdent,
+  record_identifier: Arc<Mutex<TRecordIdent>>,
+  shared_attributes: Option<Arc<dyn SharedAttributes + 'static>>,
+  published_attributes: Arc<Mutex<Option<PublishedAttributes>>>,
   peers: PeersView,
   peer_record: Arc<PeerRecord>,
   disconnection_broadcaster: Arc<Broadcaster<TunnelDisconnectedEvent>>,
@@ -275,16 +342,40 @@ where
     })
     .await
     .expect("PeerTunnel clear operation failed to rejoin");
-    let res = self
-      .registry
-      .deregister_identifier(self.record_identifier)
-      .await;
+    let record_identifier = self
+      .record_identifier
+      .lock()
+      .expect("Tunnel registration identifier mutex poisoned")
+      .clone();
+    let res = self.registry.deregister_identifier(record_identifier).await;
     if let Err(e) = res {
       tracing::warn!(error = ?e, "Failed to deregister tunnel: {}", e);
     }
+    let published_attributes = self
+      .published_attributes
+      .lock()
+      .expect("Tunnel published attributes mutex poisoned")
+      .take();
+    if let (Some(shared_attributes), Some(published)) =
+      (self.shared_attributes, published_attributes)
+    {
+      if let Err(e) = shared_attributes.withdraw(published).await {
+        tracing::warn!(error = ?e, "Failed to withdraw tunnel attributes: {}", e);
+      }
+    }
   }
 }
 
+/// The records of a registered tunnel, which are replaced when it is re-authenticated
+struct TunnelRegistration<TRecordIdent> {
+  peer_record: Arc<PeerRecord>,
+  record_identifier: Arc<Mutex<TRecordIdent>>,
+  /// Handle to the tunnel's attributes in the daemon's shared attributes, if it has any
+  published_attributes: Arc<Mutex<Option<PublishedAttributes>>>,
+  /// Cancelled when the tunnel's authentication lapses or is refused upon re-authentication
+  revoked: CancellationToken,
+}
+
 struct WrappedTunnel<TTunnel> {
   tunnel: Arc<TTunnel>,
   drop_callback: Arc<Dropkick<Arc<Mutex<Option<Box<dyn FnOnce() + Send + Sync + 'static>>>>>>,
@@ -303,9 +394,9 @@ impl<TTunnel> WrappedTunnel<TTunnel> {
   pub fn new_deregistering<TRegistry: ?Sized, TRecordIdent>(
     tunnel: Arc<TTunnel>,
     registry: Arc<TRegistry>,
-    record_identifier: TRecordIdent,
+    registration: &TunnelRegistration<TRecordIdent>,
+    shared_attributes: Option<Arc<dyn SharedAttributes + 'static>>,
     peers: PeersView,
-    peer_record: Arc<PeerRecord>,
     disconnection_broadcaster: Arc<Broadcaster<TunnelDisconnectedEvent>>,
   ) -> Self
   where
@@ -313,9 +404,11 @@ impl<TTunnel> WrappedTunnel<TTunnel> {
   {
     let inner = DeregisteringTunnelWrapper {
       registry,
-      record_identifier,
+      record_identifier: Arc::clone(&registration.record_identifier),
+      shared_attributes,
+      published_attributes: Arc::clone(&registration.published_attributes),
       peers,
-      peer_record,
+      peer_record: Arc::clone(&registration.peer_record),
       disconnection_broadcaster,
     };
     Self {
@@ -437,6 +530,11 @@ pub struct ModularDaemon<
   tunnel_id_generator: Arc<dyn TunnelIdGenerator + Send + Sync + 'static>,
   record_constructor: Arc<TRecordConstructor>,
   peers: PeerTracker,
+  datagram_dispatcher: Option<Arc<DatagramDispatcher>>,
+  authentication_timeout: Option<Duration>,
+  reauthentication_lead: Option<Duration>,
+  revocation_list: Option<Arc<RevocationList>>,
+  shared_attributes: Option<Arc<dyn SharedAttributes + 'static>>,
 
   // event hooks
   pub tunnel_connected: Arc<Broadcaster<TunnelConnectedEvent>>,
@@ -754,6 +852,11 @@ where
       tunnel_id_generator,
       record_constructor,
       peers: peer_tracker,
+      datagram_dispatcher: None,
+      authentication_timeout: None,
+      reauthentication_lead: None,
+      revocation_list: None,
+      shared_attributes: None,
 
       // For event handlers, we simply drop the receive sides,
       // as new ones can be made with Sender::subscribe(&self)
@@ -764,6 +867,96 @@ where
     s
   }
 
+  /// Forwards datagrams arriving on tunnels to the flows registered with the given dispatcher
+  ///
+  /// Without a dispatcher, incoming datagrams are dropped.
+  pub fn with_datagram_dispatcher(mut self, dispatcher: Arc<DatagramDispatcher>) -> Self {
+    self.datagram_dispatcher = Some(dispatcher);
+    self
+  }
+
+  /// Closes tunnels which fail to complete authentication within the given duration
+  ///
+  /// Without a timeout, authentication only ends early when the daemon shuts down.
+  pub fn with_authentication_timeout(mut self, timeout: Duration) -> Self {
+    self.authentication_timeout = Some(timeout);
+    self
+  }
+
+  /// Re-authenticates tunnels the given duration before their authentication expires
+  ///
+  /// Tunnels whose authentication carries an expiry are otherwise closed once it passes.
+  pub fn with_reauthentication(mut self, lead_time: Duration) -> Self {
+    self.reauthentication_lead = Some(lead_time);
+    self
+  }
+
+  /// Refuses tunnels which authenticate as a target of the given list
+  ///
+  /// The list may be replaced while in use; tunnels which are already connected are only closed
+  /// by [Self::revoke_listed].
+  pub fn with_revocation_list(mut self, revocation_list: Arc<RevocationList>) -> Self {
+    self.revocation_list = Some(revocation_list);
+    self
+  }
+
+  /// Publishes the authentication attributes of registered tunnels to a store shared with other daemons
+  ///
+  /// Attributes are published under [authentication_attributes_key](shared_attributes::authentication_attributes_key)
+  /// for as long as the tunnel remains registered, and are replaced upon re-authentication.
+  pub fn with_shared_attributes(
+    mut self,
+    shared_attributes: Arc<dyn SharedAttributes + 'static>,
+  ) -> Self {
+    self.shared_attributes = Some(shared_attributes);
+    self
+  }
+
+  /// The store tunnel attributes are published to, through which services may share metadata
+  pub fn shared_attributes(&self) -> Option<&Arc<dyn SharedAttributes + 'static>> {
+    self.shared_attributes.as_ref()
+  }
+
+  /// Closes every connected tunnel matching a target, returning the number of tunnels closed
+  pub async fn revoke(
+    &self,
+    target: &RevocationTarget,
+    reason: tunnel::TunnelCloseReason,
+  ) -> usize {
+    self.peers().revoke(target, reason).await
+  }
+
+  /// Closes every connected tunnel matching the daemon's revocation list, if it has one
+  pub async fn revoke_listed(&self, reason: tunnel::TunnelCloseReason) -> usize {
+    match &self.revocation_list {
+      Some(revocation_list) => {
+        self
+          .peers()
+          .revoke_matching(|peer| revocation_list.is_peer_revoked(peer), reason)
+          .await
+      }
+      None => 0,
+    }
+  }
+
+  fn is_revoked(
+    &self,
+    id: TunnelId,
+    name: &TunnelName,
+    attributes: &AuthenticationAttributes,
+  ) -> bool {
+    let revoked = self
+      .revocation_list
+      .as_ref()
+      .map_or(false, |revocation_list| {
+        revocation_list.is_revoked(id, name, attributes)
+      });
+    if revoked {
+      tracing::info!(?id, ?name, "Refusing revoked tunnel");
+    }
+    revoked
+  }
+
   pub fn peers(&self) -> PeersView {
     PeersView {
       by_name: Arc::downgrade(&self.peers.by_name),
@@ -891,10 +1084,19 @@ where
     >,
   > + 'static {
     let authentication_handler = Arc::clone(&self.authentication_handler);
+    let authentication_timeout = self.authentication_timeout;
     let tunnel = tunnel.clone();
     async move {
       let result: Result<(_, _), AuthenticationError<_>> = tokio::task::spawn(async move {
-        perform_authentication(authentication_handler.as_ref(), &tunnel, &shutdown.into()).await
+        let shutdown = shutdown.into();
+        let authentication =
+          perform_authentication(authentication_handler.as_ref(), &tunnel, &shutdown);
+        match authentication_timeout {
+          Some(timeout) => tokio::time::timeout(timeout, authentication)
+            .await
+            .unwrap_or(Err(RemoteAuthenticationError::TimedOut.into())),
+          None => authentication.await,
+        }
       })
       .unwrap_or_else(|e| {
         Err(AuthenticationError::Handling(
@@ -902,26 +1104,247 @@ where
         ))
       })
       .await;
-      match result {
-        Err(AuthenticationError::Handling(handling_error)) => {
-          // Non-fatal handling errors are passed to tracing and close the tunnel
-          // TODO: Feed this upward as a tunnel lifecycle failure
-          tracing::warn!(
-            reason = ?&handling_error,
-            "Tunnel closed due to authentication handling failure"
-          );
-          Err(TunnelLifecycleError::AuthenticationHandlingError(
-            handling_error.err_into(),
-          ))
-        }
-        Err(AuthenticationError::Remote(remote_error)) => {
-          tracing::debug!(
-            reason = (&remote_error as &dyn std::error::Error),
-            "Tunnel closed due to remote authentication failure"
+      result.map_err(Self::authentication_failure)
+    }
+  }
+
+  fn authentication_failure<ApplicationError>(
+    error: AuthenticationError<TAuthenticationHandler::Error>,
+  ) -> TunnelLifecycleError<ApplicationError, TAuthenticationHandler::Error, TTunnelRegistry::Error>
+  {
+    match error {
+      AuthenticationError::Handling(handling_error) => {
+        // Non-fatal handling errors are passed to tracing and close the tunnel
+        // TODO: Feed this upward as a tunnel lifecycle failure
+        tracing::warn!(
+          reason = ?&handling_error,
+          "Tunnel closed due to authentication handling failure"
+        );
+        TunnelLifecycleError::AuthenticationHandlingError(handling_error.err_into())
+      }
+      AuthenticationError::Remote(remote_error) => {
+        tracing::debug!(
+          reason = (&remote_error as &dyn std::error::Error),
+          "Tunnel closed due to remote authentication failure"
+        );
+        TunnelLifecycleError::AuthenticationRefused
+      }
+    }
+  }
+
+  /// Resolves once the tunnel's authentication lapses, re-authenticating it beforehand if enabled
+  async fn maintain_authentication(
+    self: Arc<Self>,
+    tunnel: ArcTunnel<'static>,
+    registration: Arc<TunnelRegistration<TTunnelRegistry::Identifier>>,
+    shutdown: CancellationListener,
+  ) {
+    let current_expiry =
+      || authentication::authentication_expiry(&registration.peer_record.attributes.load());
+    let mut renewed_expiry = None;
+    loop {
+      let expiry = match current_expiry() {
+        Some(expiry) => expiry,
+        // Authentication without an expiry lasts until the tunnel closes or is refused by its remote
+        None => return registration.revoked.cancelled().await,
+      };
+      // Renewal is attempted once per expiry, so credentials which are not renewed still lapse
+      let lead_time = self
+        .reauthentication_lead
+        .filter(|_| renewed_expiry.map_or(true, |renewed| expiry > renewed));
+      let wake_at = lead_time
+        .and_then(|lead_time| expiry.checked_sub(lead_time))
+        .unwrap_or(expiry);
+      let wait = wake_at
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+      if let Either::Right(_) = future::select(
+        tokio::time::sleep(wait).boxed(),
+        registration.revoked.cancelled().boxed(),
+      )
+      .await
+      {
+        return;
+      }
+      // The remote may have re-authenticated the tunnel while we waited
+      if current_expiry() != Some(expiry) {
+        continue;
+      }
+      if lead_time.is_none() {
+        tracing::info!(?expiry, "Tunnel authentication expired");
+        return registration.revoked.cancel();
+      }
+      renewed_expiry = Some(expiry);
+      let remaining = expiry.duration_since(SystemTime::now()).unwrap_or_default();
+      let reauthentication = self.clone().reauthenticate_remote(
+        Arc::clone(&tunnel),
+        Arc::clone(&registration),
+        shutdown.clone(),
+      );
+      match tokio::time::timeout(remaining, reauthentication).await {
+        Ok(true) => (),
+        Ok(false) => return,
+        Err(_) => {
+          tracing::info!(
+            ?expiry,
+            "Tunnel re-authentication did not complete before expiry"
           );
-          Err(TunnelLifecycleError::AuthenticationRefused)
+          return registration.revoked.cancel();
         }
-        Ok(tunnel) => Ok(tunnel),
+      }
+    }
+  }
+
+  /// Negotiates a stream to the remote's re-authentication service and re-authenticates over it
+  async fn reauthenticate_remote(
+    self: Arc<Self>,
+    tunnel: ArcTunnel<'static>,
+    registration: Arc<TunnelRegistration<TTunnelRegistry::Identifier>>,
+    shutdown: CancellationListener,
+  ) -> bool {
+    let open_link = || tunnel.open_link().map_err(RoutingError::LinkOpenFailure);
+    let link = open_link()
+      .and_then(|link| {
+        NegotiationClient::new().negotiate_with_fallback::<_, anyhow::Error, _, _>(
+          reauthentication_address(),
+          link,
+          open_link,
+        )
+      })
+      .await;
+    match link {
+      Ok((link, _)) => {
+        self
+          .reauthenticate(tunnel, Box::new(link), registration, shutdown)
+          .await
+      }
+      Err(e) => {
+        tracing::info!(error = ?e, "Tunnel re-authentication could not be negotiated");
+        registration.revoked.cancel();
+        false
+      }
+    }
+  }
+
+  /// Re-authenticates a registered tunnel over the given stream, then updates its records
+  ///
+  /// Revokes the tunnel's registration if re-authentication fails or names a different tunnel.
+  async fn reauthenticate(
+    self: Arc<Self>,
+    tunnel: ArcTunnel<'static>,
+    mut link: ReauthenticationStream,
+    registration: Arc<TunnelRegistration<TTunnelRegistry::Identifier>>,
+    shutdown: CancellationListener,
+  ) -> bool {
+    let result = async {
+      let authentication = self.authentication_handler.authenticate(
+        &mut link,
+        TunnelInfo::from_tunnel(tunnel.as_ref()),
+        &shutdown,
+      );
+      let result = match self.authentication_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, authentication)
+          .await
+          .unwrap_or(Err(RemoteAuthenticationError::TimedOut.into())),
+        None => authentication.await,
+      };
+      let (name, attributes) = result.map_err(Self::authentication_failure::<anyhow::Error>)?;
+      if name != registration.peer_record.name {
+        tracing::warn!(
+          previous = ?registration.peer_record.name,
+          ?name,
+          "Tunnel re-authenticated under a different name"
+        );
+        return Err(TunnelLifecycleError::AuthenticationRefused);
+      }
+      if self.is_revoked(registration.peer_record.id, &name, &attributes) {
+        return Err(TunnelLifecycleError::AuthenticationRefused);
+      }
+      self
+        .update_registration(&tunnel, &registration, attributes)
+        .await
+        .map_err(TunnelLifecycleError::RegistrationError)
+    }
+    .await;
+    match result {
+      Ok(()) => {
+        tracing::debug!("Tunnel re-authenticated");
+        true
+      }
+      Err(e) => {
+        tracing::info!(error = %e, "Tunnel re-authentication failed");
+        registration.revoked.cancel();
+        false
+      }
+    }
+  }
+
+  /// Replaces a tunnel's registry record and peer attributes with those of a new authentication
+  async fn update_registration(
+    &self,
+    tunnel: &ArcTunnel<'static>,
+    registration: &TunnelRegistration<TTunnelRegistry::Identifier>,
+    attributes: AuthenticationAttributes,
+  ) -> Result<(), TTunnelRegistry::Error> {
+    let peer_record = &registration.peer_record;
+    let (record, attributes) = self
+      .record_constructor
+      .construct_record(RecordConstructorArgs {
+        id: peer_record.id,
+        name: peer_record.name.clone(),
+        attributes,
+        tunnel: Arc::clone(tunnel),
+      })
+      .await?;
+    let record_identifier = self
+      .tunnel_registry
+      .register(peer_record.name.clone(), &record)
+      .await?;
+    let previous_identifier = std::mem::replace(
+      &mut *registration
+        .record_identifier
+        .lock()
+        .expect("Tunnel registration identifier mutex poisoned"),
+      record_identifier,
+    );
+    // Registries holding several records per name would otherwise keep the stale record as well
+    if let Err(e) = self
+      .tunnel_registry
+      .deregister_identifier(previous_identifier)
+      .await
+    {
+      tracing::warn!(error = ?e, "Failed to deregister previous tunnel registration: {}", e);
+    }
+    if let Some(published) = self
+      .publish_attributes(&peer_record.name, &attributes)
+      .await
+    {
+      // Replacing the previous handle releases it without withdrawing the new entry
+      *registration
+        .published_attributes
+        .lock()
+        .expect("Tunnel published attributes mutex poisoned") = Some(published);
+    }
+    peer_record.attributes.store(attributes);
+    Ok(())
+  }
+
+  /// Publishes a tunnel's attributes to the daemon's shared attributes, if it has any
+  ///
+  /// Failure to publish is logged rather than failing the tunnel, as the tunnel remains usable
+  /// through this daemon regardless.
+  async fn publish_attributes(
+    &self,
+    tunnel_name: &TunnelName,
+    attributes: &AuthenticationAttributes,
+  ) -> Option<PublishedAttributes> {
+    let shared_attributes = self.shared_attributes.as_ref()?;
+    let key = authentication_attributes_key(tunnel_name);
+    match shared_attributes.publish(&key, attributes).await {
+      Ok(published) => Some(published),
+      Err(e) => {
+        tracing::warn!(error = ?e, ?tunnel_name, "Failed to publish tunnel attributes: {}", e);
+        None
       }
     }
   }
@@ -965,9 +1388,12 @@ where
         .await;
       res
     }?;
+    if self.is_revoked(*tunnel.id(), &tunnel_name, &tunnel_attrs) {
+      return Err(TunnelLifecycleError::AuthenticationRefused);
+    }
 
     // Tunnel naming - The tunnel registry is notified of the authenticator-provided tunnel name
-    let tunnel: WrappedTunnel<TTunnel> = {
+    let (tunnel, registration) = {
       self
         .register_tunnel(
           tunnel,
@@ -984,16 +1410,17 @@ where
     // Phases resume in registered_tunnel_lifecycle.
     self
       .clone()
-      .registered_tunnel_lifecycle(tunnel, tunnel_name, shutdown)
+      .registered_tunnel_lifecycle(tunnel, tunnel_name, registration, shutdown)
       .await?;
     Ok(())
   }
 
-  #[tracing::instrument(err, skip(self, tunnel, shutdown), fields(name=?tunnel_name, id=?tunnel.id()))]
+  #[tracing::instrument(err, skip(self, tunnel, registration, shutdown), fields(name=?tunnel_name, id=?tunnel.id()))]
   async fn registered_tunnel_lifecycle<TTunnel>(
     self: Arc<Self>,
     tunnel: WrappedTunnel<TTunnel>,
     tunnel_name: TunnelName,
+    registration: Arc<TunnelRegistration<TTunnelRegistry::Identifier>>,
     shutdown: CancellationListener,
   ) -> Result<
     (),
@@ -1002,9 +1429,36 @@ where
   where
     TTunnel: Tunnel + 'static,
   {
-    // Process incoming requests until the incoming channel is closed.
-    {
-      let service_registry = Arc::clone(&self.service_registry);
+    let arc_tunnel: ArcTunnel<'static> = tunnel.as_inner().clone() as Arc<_>;
+    // Serve the remote's re-authentication requests alongside the registered services
+    let service_registry = {
+      let reauthenticator: Reauthenticator = {
+        let this = Arc::clone(&self);
+        let tunnel = Arc::clone(&arc_tunnel);
+        let registration = Arc::clone(&registration);
+        let shutdown = shutdown.clone();
+        Arc::new(move |link| {
+          Arc::clone(&this)
+            .reauthenticate(
+              Arc::clone(&tunnel),
+              link,
+              Arc::clone(&registration),
+              shutdown.clone(),
+            )
+            .boxed()
+        })
+      };
+      Arc::new(ReauthenticatingServiceRegistry::new(
+        Arc::clone(&self.service_registry),
+        reauthenticator,
+      ))
+    };
+    let authentication_lapse =
+      Arc::clone(&self).maintain_authentication(arc_tunnel, registration, shutdown.clone());
+
+    // Process incoming requests until the incoming channel is closed, or authentication lapses.
+    let request_handling = async {
+      let datagram_dispatcher = self.datagram_dispatcher.clone();
       let incoming =
         tunnel
           .downlink()
@@ -1012,10 +1466,21 @@ where
           .ok_or(TunnelLifecycleError::RequestProcessingError(
             RequestProcessingError::TunnelError(TunnelError::ConnectionClosed),
           ))?;
-      Self::handle_incoming_requests(tunnel, incoming, service_registry, shutdown)
-        .instrument(tracing::debug_span!("request_handling"))
+      Self::handle_incoming_requests(
+        tunnel,
+        incoming,
+        service_registry,
+        datagram_dispatcher,
+        shutdown,
+      )
+      .instrument(tracing::debug_span!("request_handling"))
+      .await
+      .map_err(TunnelLifecycleError::RequestProcessingError)
+    };
+    match future::select(request_handling.boxed(), authentication_lapse.boxed()).await {
+      Either::Left((result, _)) => result?,
+      Either::Right(((), _)) => return Err(TunnelLifecycleError::AuthenticationRefused),
     }
-    .await?;
 
     // The tunnel is dropped by this point, and will be automatically deregistered on a background task
     Ok(())
@@ -1029,17 +1494,20 @@ where
   // The request handler for this side should be configured to send a close request for
   // the tunnel with the given ID when it sees a request fail due to tunnel closure.
   // TODO: configure request handler (?) to do that using a std::sync::Weak<ModularDaemon>.
-  async fn handle_incoming_requests<TTunnel, TTunnelDownlink>(
+  async fn handle_incoming_requests<TTunnel, TTunnelDownlink, TServices>(
     tunnel: TTunnel,
     mut incoming: TTunnelDownlink,
-    service_registry: Arc<TServiceRegistry>,
+    service_registry: Arc<TServices>,
+    datagram_dispatcher: Option<Arc<DatagramDispatcher>>,
     shutdown: CancellationListener,
   ) -> Result<(), RequestProcessingError<anyhow::Error>>
   where
     TTunnel: Tunnel + Clone + 'static,
     TTunnelDownlink: TunnelDownlink + Send + Unpin + 'static,
+    TServices: ServiceRegistry<Error = TServiceRegistry::Error> + Send + Sync + ?Sized + 'static,
   {
-    let negotiator = Arc::new(NegotiationService::new(service_registry));
+    let negotiator =
+      Arc::new(NegotiationService::new(service_registry).with_shutdown_listener(shutdown.clone()));
 
     incoming
       .as_stream()
@@ -1051,7 +1519,13 @@ where
         future::ready(Some(res))
       })
       .try_for_each_concurrent(None, move |(negotiator, shutdown, link)| {
-        Self::handle_incoming_request(tunnel.clone(), link, negotiator, shutdown)
+        Self::handle_incoming_request(
+          tunnel.clone(),
+          link,
+          negotiator,
+          datagram_dispatcher.clone(),
+          shutdown,
+        )
       })
       .await?;
 
@@ -1062,6 +1536,7 @@ where
     tunnel: TTunnel,
     link: TunnelIncomingType,
     negotiator: Arc<NegotiationService<Services>>,
+    datagram_dispatcher: Option<Arc<DatagramDispatcher>>,
     shutdown: CancellationListener,
   ) -> Result<(), RequestProcessingError<anyhow::Error>>
   where
@@ -1078,8 +1553,23 @@ where
           );
           Self::handle_incoming_request_bistream(tunnel, link, negotiator, shutdown).await
         }
-          .instrument(tracing::info_span!("tunnel_stream", tunnel_id = ?tid))
-          .await
+        .instrument(tracing::info_span!("tunnel_stream", tunnel_id = ?tid))
+        .await
+      }
+      // Datagrams belong to flows established over previously negotiated links
+      tunnel::TunnelIncomingType::Datagram(datagram) => {
+        let len = datagram.len();
+        let dispatched = datagram_dispatcher
+          .map(|dispatcher| dispatcher.dispatch(*tunnel.id(), datagram))
+          .unwrap_or(false);
+        if !dispatched {
+          tracing::trace!(
+            tunnel_id = ?tunnel.id(),
+            len,
+            "Dropping unclaimed tunnel datagram"
+          );
+        }
+        Ok(())
       }
     }
   }
@@ -1109,6 +1599,17 @@ where
         tracing::debug!("Refused remote protocol request");
         Ok(())
       }
+      // Structured refusals are sent to the remote with their reason, and only affect this link
+      Err(
+        e @ (NegotiationError::NoSuchService(_)
+        | NegotiationError::Forbidden(_)
+        | NegotiationError::Overloaded(_)
+        | NegotiationError::BadAddress(_)
+        | NegotiationError::ShuttingDown(_)),
+      ) => {
+        tracing::debug!(reason = %e, "Refused remote protocol request");
+        Ok(())
+      }
       // Lack of support for a service is just a more specific refusal
       Err(NegotiationError::UnsupportedServiceVersion) => {
         tracing::debug!("Refused request due to unsupported service version");
@@ -1124,7 +1625,7 @@ where
           NegotiationError::FatalError(e).into(),
         ))
       }
-      Ok((link, route_addr, service)) => {
+      Ok((link, route_addr, service_version, headers, service)) => {
         if shutdown.is_cancelled() {
           // Drop services post-negotiation if the connection is awaiting
           // shutdown, instead of handing them to the service to be performed.
@@ -1132,8 +1633,20 @@ where
         }
         let route_addr: RouteAddress = route_addr;
         let service: negotiation::ArcService<_> = service;
+        let request_span =
+          tracing::info_span!("tunnel_request", addr = %route_addr, service_version);
+        // Join the trace of the remote which opened the request, when it provided one
+        #[cfg(feature = "trace-context")]
+        crate::common::protocol::trace_context::set_remote_parent(&request_span, &headers);
         match service
-          .handle(route_addr.clone(), Box::new(link), Arc::new(tunnel) as _)
+          .handle(
+            route_addr.clone(),
+            service_version,
+            headers,
+            Box::new(link),
+            Arc::new(tunnel) as _,
+          )
+          .instrument(request_span)
           .await
         {
           // TODO: Figure out which of these should be considered fatal to the tunnel, if any
@@ -1164,7 +1677,13 @@ where
     tunnel_registry: Arc<TTunnelRegistry>,
     attributes: AuthenticationAttributes,
     record_constructor: Arc<TRecordConstructor>,
-  ) -> Result<WrappedTunnel<TTunnel>, TTunnelRegistry::Error>
+  ) -> Result<
+    (
+      WrappedTunnel<TTunnel>,
+      Arc<TunnelRegistration<TTunnelRegistry::Identifier>>,
+    ),
+    TTunnelRegistry::Error,
+  >
   where
     TTunnel: Tunnel + TunnelControl + 'static,
   {
@@ -1180,14 +1699,16 @@ where
     let identifier = tunnel_registry
       .register(tunnel_name.clone(), &record)
       .await?;
+    let published_attributes = self.publish_attributes(&tunnel_name, &attributes).await;
 
     let tunnel_id = *tunnel.id();
     let peer_record = Arc::new(PeerRecord {
       id: tunnel_id,
       name: tunnel_name.clone(),
       registered_at,
-      attributes: Arc::clone(&attributes),
+      attributes: Arc::new(ArcSwap::new(Arc::clone(&attributes))),
       tunnel: tunnel.as_inner().clone() as Arc<_>,
+      control: tunnel.as_inner().clone() as Arc<_>,
     });
     self.peers.insert(&peer_record);
 
@@ -1205,13 +1726,494 @@ where
       });
     }
 
-    Ok(WrappedTunnel::new_deregistering(
+    let registration = Arc::new(TunnelRegistration {
+      peer_record,
+      record_identifier: Arc::new(Mutex::new(identifier)),
+      published_attributes: Arc::new(Mutex::new(published_attributes)),
+      revoked: CancellationToken::new(),
+    });
+    let tunnel = WrappedTunnel::new_deregistering(
       tunnel.extract_inner().clone(),
       tunnel_registry,
-      identifier,
+      &registration,
+      self.shared_attributes.clone(),
       self.peers(),
-      peer_record,
       self.tunnel_disconnected.clone(),
-    ))
+    );
+    Ok((tunnel, registration))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::{BoxFuture, FutureExt};
+  use std::{
+    assert_matches::assert_matches,
+    net::SocketAddr,
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+  };
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio_util::sync::CancellationToken;
+
+  use super::{
+    revocation::RevocationList, ArcRecordConstructor, ModularDaemon, PeerTracker,
+    RecordConstructorArgs,
+  };
+  use crate::{
+    client::tests::LoopbackRouter,
+    common::{
+      authentication::{
+        authentication_expiry, limit_authentication_expiry, lockout::AuthenticationFailureTracker,
+        preshared_key_authentication::StaticKeyStore, AuthenticationAttributes,
+        AuthenticationChannel, AuthenticationError, AuthenticationHandler,
+        LockoutAuthenticationHandler, PresharedKeyAuthenticationHandler, RemoteAuthenticationError,
+        TunnelInfo,
+      },
+      protocol::{
+        negotiation::ArcService,
+        tunnel::{
+          duplex::{self, DuplexTunnel},
+          id::MonotonicAtomicGenerator,
+          registry::{
+            memory::{
+              InMemoryMultiTunnelRegistry, InMemoryTunnelRegistry, InMemoryTunnelRegistryError,
+            },
+            MultiOccupancyTunnelRegistry, SelectionPolicy, TunnelRegistry,
+          },
+          ArcTunnel, Sided, Tunnel, TunnelAddressInfo, TunnelCloseReason, TunnelControl,
+          TunnelDownlink, TunnelError, TunnelId, TunnelMonitoring, TunnelName, TunnelSide,
+          TunnelUplink, WithTunnelId,
+        },
+        RouteAddress, ServiceRegistry,
+      },
+    },
+    util::{cancellation::CancellationListener, tunnel_stream::WrappedStream},
+  };
+
+  /// A duplex tunnel which records how it was closed, providing the control the daemon requires
+  struct TestTunnel {
+    inner: DuplexTunnel,
+    id: TunnelId,
+    addr: TunnelAddressInfo,
+    close_reason: Arc<Mutex<Option<Arc<TunnelCloseReason>>>>,
+    closed: CancellationToken,
+  }
+
+  impl TestTunnel {
+    fn new(inner: DuplexTunnel, addr: TunnelAddressInfo) -> Self {
+      Self {
+        id: *inner.id(),
+        inner,
+        addr,
+        close_reason: Default::default(),
+        closed: CancellationToken::new(),
+      }
+    }
+
+    /// Replaces the duplex tunnel's ID, which is shared by every duplex channel's listener
+    fn with_id(mut self, id: TunnelId) -> Self {
+      self.id = id;
+      self
+    }
+  }
+
+  impl WithTunnelId for TestTunnel {
+    fn id(&self) -> &TunnelId {
+      &self.id
+    }
+  }
+
+  impl Sided for TestTunnel {
+    fn side(&self) -> TunnelSide {
+      self.inner.side()
+    }
+  }
+
+  impl TunnelUplink for TestTunnel {
+    fn open_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
+      self.inner.open_link()
+    }
+
+    fn addr(&self) -> TunnelAddressInfo {
+      self.addr.clone()
+    }
+  }
+
+  impl Tunnel for TestTunnel {
+    fn downlink<'a>(&'a self) -> BoxFuture<'a, Option<Box<dyn TunnelDownlink + Send + Unpin>>> {
+      self.inner.downlink()
+    }
+  }
+
+  impl TunnelControl for TestTunnel {
+    fn close<'a>(
+      &'a self,
+      reason: TunnelCloseReason,
+    ) -> BoxFuture<'a, Result<Arc<TunnelCloseReason>, Arc<TunnelCloseReason>>> {
+      let mut close_reason = self.close_reason.lock().unwrap();
+      let result = match &*close_reason {
+        Some(existing) => Err(Arc::clone(existing)),
+        None => {
+          let reason = Arc::new(reason);
+          *close_reason = Some(Arc::clone(&reason));
+          self.closed.cancel();
+          Ok(reason)
+        }
+      };
+      futures::future::ready(result).boxed()
+    }
+
+    fn report_authentication_success<'a>(
+      &self,
+      _tunnel_name: TunnelName,
+    ) -> BoxFuture<'a, Result<(), Option<Arc<TunnelCloseReason>>>> {
+      let result = match &*self.close_reason.lock().unwrap() {
+        Some(reason) => Err(Some(Arc::clone(reason))),
+        None => Ok(()),
+      };
+      futures::future::ready(result).boxed()
+    }
+  }
+
+  impl TunnelMonitoring for TestTunnel {
+    fn is_closed(&self) -> bool {
+      self.closed.is_cancelled()
+    }
+
+    fn on_closed(&'_ self) -> BoxFuture<'static, Arc<TunnelCloseReason>> {
+      let (closed, close_reason) = (self.closed.clone(), Arc::clone(&self.close_reason));
+      async move {
+        closed.cancelled().await;
+        let reason = close_reason.lock().unwrap().clone();
+        reason.expect("Closed tunnels must record their reason")
+      }
+      .boxed()
+    }
+
+    fn on_authenticated(
+      &'_ self,
+    ) -> BoxFuture<'static, Result<TunnelName, Arc<TunnelCloseReason>>> {
+      self.on_closed().map(Err).boxed()
+    }
+  }
+
+  /// Serves no services, leaving tunnels to those the daemon provides itself
+  struct NoServices;
+
+  impl ServiceRegistry for NoServices {
+    type Error = anyhow::Error;
+
+    fn find_service(
+      self: Arc<Self>,
+      _addr: &RouteAddress,
+      _tunnel: &ArcTunnel,
+    ) -> Option<ArcService<Self::Error>> {
+      None
+    }
+  }
+
+  /// Records tunnels by the attributes they were authenticated with
+  type TestRecord = Arc<AuthenticationAttributes>;
+
+  type TestDaemon<T, H> = ModularDaemon<
+    T,
+    NoServices,
+    LoopbackRouter,
+    H,
+    ArcRecordConstructor<'static, TestRecord, InMemoryTunnelRegistryError>,
+  >;
+
+  fn test_daemon<T, H>(authentication_handler: H, tunnel_registry: Arc<T>) -> TestDaemon<T, H>
+  where
+    T: TunnelRegistry<Record = TestRecord, Error = InMemoryTunnelRegistryError>
+      + Send
+      + Sync
+      + 'static,
+    H: AuthenticationHandler + 'static,
+    H::Error: std::fmt::Debug + std::fmt::Display + Send + 'static,
+  {
+    ModularDaemon::new(
+      Arc::new(NoServices),
+      tunnel_registry,
+      PeerTracker::new(),
+      Arc::new(LoopbackRouter::new(TunnelName::new("unused"))),
+      Arc::new(authentication_handler),
+      Arc::new(MonotonicAtomicGenerator::new(0)),
+      Arc::new(ArcRecordConstructor::new(|args: RecordConstructorArgs| {
+        let attributes = Arc::new(args.attributes);
+        futures::future::ready(Ok((Arc::clone(&attributes), attributes))).boxed()
+      })),
+    )
+  }
+
+  /// Attribute under which listeners record the identity byte sent by their connector
+  const IDENTITY_ATTRIBUTE: &str = "test.identity";
+
+  /// Authenticates with an identity byte, which the listener answers with whether it is accepted
+  ///
+  /// Listeners accept a limited number of attempts, each lasting for the given lifetime.
+  #[derive(Debug)]
+  struct ExpiringAuthenticationHandler {
+    attempts: Arc<AtomicUsize>,
+    accepted_attempts: usize,
+    lifetime: Duration,
+    identity: u8,
+  }
+
+  impl ExpiringAuthenticationHandler {
+    fn new(accepted_attempts: usize, lifetime: Duration) -> Self {
+      Self {
+        attempts: Default::default(),
+        accepted_attempts,
+        lifetime,
+        identity: 0,
+      }
+    }
+
+    fn with_identity(mut self, identity: u8) -> Self {
+      self.identity = identity;
+      self
+    }
+  }
+
+  impl AuthenticationHandler for ExpiringAuthenticationHandler {
+    type Error = anyhow::Error;
+
+    fn authenticate<'a>(
+      &'a self,
+      channel: &'a mut AuthenticationChannel<'a>,
+      tunnel_info: TunnelInfo,
+      _shutdown_notifier: &'a CancellationListener,
+    ) -> BoxFuture<
+      'a,
+      Result<(TunnelName, AuthenticationAttributes), AuthenticationError<Self::Error>>,
+    > {
+      async move {
+        let transport_error = |_| RemoteAuthenticationError::TransportError;
+        match tunnel_info.side {
+          TunnelSide::Connect => {
+            channel
+              .write_u8(self.identity)
+              .await
+              .map_err(transport_error)?;
+            match channel.read_u8().await.map_err(transport_error)? {
+              1 => Ok((TunnelName::new("listener"), Default::default())),
+              _ => Err(RemoteAuthenticationError::Refused.into()),
+            }
+          }
+          TunnelSide::Listen => {
+            let identity = channel.read_u8().await.map_err(transport_error)?;
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+            let accepted = attempt <= self.accepted_attempts;
+            channel
+              .write_u8(accepted as u8)
+              .await
+              .map_err(transport_error)?;
+            if !accepted {
+              return Err(RemoteAuthenticationError::Refused.into());
+            }
+            let mut attributes = AuthenticationAttributes::new();
+            attributes.insert(IDENTITY_ATTRIBUTE.into(), vec![identity]);
+            limit_authentication_expiry(&mut attributes, SystemTime::now() + self.lifetime);
+            Ok((TunnelName::new("connector"), attributes))
+          }
+        }
+      }
+      .boxed()
+    }
+  }
+
+  /// Lifetime of authentication granted by listeners in re-authentication tests
+  const AUTHENTICATION_LIFETIME: Duration = Duration::from_secs(2);
+
+  /// Connects daemons over a duplex tunnel, with the listener re-authenticating its connector
+  ///
+  /// Returns the listener's tunnel closure token and its count of authentication attempts.
+  fn connect_reauthenticating<T>(
+    accepted_attempts: usize,
+    listener_registry: Arc<T>,
+  ) -> (
+    CancellationToken,
+    BoxFuture<'static, Arc<TunnelCloseReason>>,
+    Arc<AtomicUsize>,
+  )
+  where
+    T: TunnelRegistry<Record = TestRecord, Error = InMemoryTunnelRegistryError>
+      + Send
+      + Sync
+      + 'static,
+  {
+    let listener_handler =
+      ExpiringAuthenticationHandler::new(accepted_attempts, AUTHENTICATION_LIFETIME);
+    let attempts = Arc::clone(&listener_handler.attempts);
+    let listener_daemon = test_daemon(listener_handler, listener_registry)
+      .with_reauthentication(Duration::from_secs(1));
+    let connector_daemon = test_daemon(
+      ExpiringAuthenticationHandler::new(0, AUTHENTICATION_LIFETIME),
+      Arc::new(InMemoryTunnelRegistry::new()),
+    );
+    let duplex::EntangledTunnels {
+      listener,
+      connector,
+    } = duplex::channel();
+    let listener = TestTunnel::new(listener, TunnelAddressInfo::Unidentified);
+    let (listener_closed, listener_close_reason) = (listener.closed.clone(), listener.on_closed());
+    let connector = TestTunnel::new(connector, TunnelAddressInfo::Unidentified);
+    Arc::new(listener_daemon).run(
+      futures::stream::iter([listener]),
+      CancellationListener::default(),
+    );
+    Arc::new(connector_daemon).run(
+      futures::stream::iter([connector]),
+      CancellationListener::default(),
+    );
+    (listener_closed, listener_close_reason, attempts)
+  }
+
+  /// Test that tunnels stalling authentication are closed at the deadline and locked out
+  #[tokio::test]
+  async fn authentication_timeout() {
+    let tracker = Arc::new(AuthenticationFailureTracker::new().with_threshold(1));
+    let authentication_handler = LockoutAuthenticationHandler::new(
+      PresharedKeyAuthenticationHandler::new()
+        .with_key_store(StaticKeyStore::new())
+        .allow_unbound_channels(true),
+    )
+    .with_tracker(Arc::clone(&tracker));
+    let daemon = test_daemon(
+      authentication_handler,
+      Arc::new(InMemoryTunnelRegistry::<TestRecord>::new()),
+    )
+    .with_authentication_timeout(Duration::from_millis(50));
+
+    // The remote never answers the listener's challenge
+    let remote = SocketAddr::from(([192, 0, 2, 1], 4000));
+    let duplex::EntangledTunnels {
+      listener,
+      connector: _stalled,
+    } = duplex::channel();
+    let tunnel = TestTunnel::new(listener, TunnelAddressInfo::Socket(remote));
+    let closed = tunnel.on_closed();
+    let _daemon = Arc::new(daemon).run(
+      futures::stream::iter([tunnel]),
+      CancellationListener::default(),
+    );
+    let reason = tokio::time::timeout(Duration::from_secs(5), closed)
+      .await
+      .expect("Stalled tunnels must be closed");
+    assert_matches!(*reason, TunnelCloseReason::AuthenticationFailure { .. });
+    assert!(tracker.locked_until(remote.ip()).is_some());
+  }
+
+  /// Test that authentication nearing expiry is renewed, keeping the tunnel open past it
+  #[tokio::test]
+  async fn reauthentication_renews_expiry() {
+    let (closed, _, attempts) =
+      connect_reauthenticating(usize::MAX, Arc::new(InMemoryTunnelRegistry::new()));
+    tokio::time::sleep(AUTHENTICATION_LIFETIME + Duration::from_millis(1500)).await;
+    assert!(!closed.is_cancelled(), "Renewed tunnels must remain open");
+    assert!(attempts.load(Ordering::SeqCst) >= 2);
+  }
+
+  /// Test that a refused re-authentication closes the tunnel as an authentication failure
+  #[tokio::test]
+  async fn reauthentication_refusal() {
+    let (_, close_reason, attempts) =
+      connect_reauthenticating(1, Arc::new(InMemoryTunnelRegistry::new()));
+    let reason = tokio::time::timeout(AUTHENTICATION_LIFETIME * 2, close_reason)
+      .await
+      .expect("Tunnels refused re-authentication must be closed");
+    assert_matches!(*reason, TunnelCloseReason::AuthenticationFailure { .. });
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+  }
+
+  /// Test that re-authentication replaces the tunnel's registration rather than adding another
+  #[tokio::test]
+  async fn reauthentication_updates_registration() {
+    let registry = Arc::new(InMemoryMultiTunnelRegistry::new(SelectionPolicy::Newest));
+    let initial_expiry = SystemTime::now() + AUTHENTICATION_LIFETIME;
+    let (closed, _, attempts) = connect_reauthenticating(usize::MAX, Arc::clone(&registry));
+    tokio::time::sleep(AUTHENTICATION_LIFETIME + Duration::from_millis(1500)).await;
+    assert!(!closed.is_cancelled());
+    assert!(attempts.load(Ordering::SeqCst) >= 2);
+    let occupants = registry
+      .lookup_all(&TunnelName::new("connector"))
+      .await
+      .unwrap();
+    assert_eq!(
+      occupants.len(),
+      1,
+      "Re-authentication must not duplicate registrations"
+    );
+    let renewed_expiry = authentication_expiry(&occupants[0]).expect("Records must have an expiry");
+    assert!(renewed_expiry > initial_expiry);
+  }
+
+  /// Test that revoking listed tunnels closes those connected which match, leaving the others
+  #[tokio::test]
+  async fn revoke_listed() {
+    let revocation_list = Arc::new(RevocationList::new());
+    let listener_daemon = Arc::new(
+      test_daemon(
+        ExpiringAuthenticationHandler::new(usize::MAX, Duration::from_secs(600)),
+        Arc::new(InMemoryTunnelRegistry::new()),
+      )
+      .with_revocation_list(Arc::clone(&revocation_list)),
+    );
+    let mut listeners = Vec::new();
+    for identity in [1, 2] {
+      let duplex::EntangledTunnels {
+        listener,
+        connector,
+      } = duplex::channel();
+      let connector_daemon = test_daemon(
+        ExpiringAuthenticationHandler::new(0, Duration::from_secs(600)).with_identity(identity),
+        Arc::new(InMemoryTunnelRegistry::new()),
+      );
+      Arc::new(connector_daemon).run(
+        futures::stream::iter([TestTunnel::new(connector, TunnelAddressInfo::Unidentified)]),
+        CancellationListener::default(),
+      );
+      listeners.push(
+        TestTunnel::new(listener, TunnelAddressInfo::Unidentified)
+          .with_id(TunnelId::new(identity.into())),
+      );
+    }
+    let (revoked_closed, revoked_close_reason) =
+      (listeners[0].closed.clone(), listeners[0].on_closed());
+    let unlisted_closed = listeners[1].closed.clone();
+    Arc::clone(&listener_daemon).run(
+      futures::stream::iter(listeners),
+      CancellationListener::default(),
+    );
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+      while listener_daemon.peers().all().len() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    })
+    .await
+    .expect("Tunnels must authenticate");
+    assert!(!revoked_closed.is_cancelled());
+
+    // Listing a connected tunnel, as when reloading a list from its file, leaves it open until revoked
+    revocation_list
+      .replace(RevocationList::parse(&format!("attribute:{}=hex:01", IDENTITY_ATTRIBUTE)).unwrap());
+    let reason = TunnelCloseReason::AuthenticationFailure {
+      remote_responsible: None,
+    };
+    assert_eq!(listener_daemon.revoke_listed(reason).await, 1);
+    let reason = tokio::time::timeout(Duration::from_secs(5), revoked_close_reason)
+      .await
+      .expect("Revoked tunnels must be closed");
+    assert_matches!(*reason, TunnelCloseReason::AuthenticationFailure { .. });
+    assert!(
+      !unlisted_closed.is_cancelled(),
+      "Unlisted tunnels must remain open"
+    );
   }
 }
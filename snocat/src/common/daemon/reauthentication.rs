@@ -0,0 +1,108 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Re-authentication of established tunnels whose credentials expire
+//!
+//! Either side of a tunnel may re-authenticate it by negotiating a stream to the
+//! [reauthentication_address] and running its authentication handler over that stream, with
+//! each side taking the same role as in the tunnel's initial authentication. Each daemon serves
+//! the address itself, ahead of the services of its registry.
+use futures::future::{BoxFuture, FutureExt};
+use std::{iter::FromIterator, marker::PhantomData, sync::Arc};
+
+use crate::{
+  common::protocol::{
+    negotiation::Refusal, tunnel::ArcTunnel, RequestHeaders, RouteAddress, Service, ServiceError,
+    ServiceRegistry, ServiceVersion,
+  },
+  util::tunnel_stream::TunnelStream,
+};
+
+/// Address of the stream over which a tunnel is re-authenticated
+pub fn reauthentication_address() -> RouteAddress {
+  RouteAddress::from_iter(["snocat", "reauthenticate"])
+}
+
+pub(super) type ReauthenticationStream = Box<dyn TunnelStream + Send + 'static>;
+
+/// Re-authenticates a tunnel over a stream opened by its remote, returning whether it succeeded
+pub(super) type Reauthenticator =
+  Arc<dyn Fn(ReauthenticationStream) -> BoxFuture<'static, bool> + Send + Sync>;
+
+pub(super) struct ReauthenticationService<TError> {
+  reauthenticator: Reauthenticator,
+  phantom_error: PhantomData<fn() -> TError>,
+}
+
+impl<TError> Service for ReauthenticationService<TError> {
+  type Error = TError;
+
+  fn accepts(&self, addr: &RouteAddress, _tunnel: &ArcTunnel) -> bool {
+    *addr == reauthentication_address()
+  }
+
+  fn handle<'a>(
+    &'a self,
+    _addr: RouteAddress,
+    _version: ServiceVersion,
+    _headers: RequestHeaders,
+    stream: Box<dyn TunnelStream + Send + 'static>,
+    _tunnel: ArcTunnel<'static>,
+  ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>> {
+    let reauthentication = (self.reauthenticator)(stream);
+    async move {
+      match reauthentication.await {
+        true => Ok(()),
+        false => Err(ServiceError::Refused),
+      }
+    }
+    .boxed()
+  }
+}
+
+/// Serves re-authentication of a single tunnel ahead of the services of another registry
+pub(super) struct ReauthenticatingServiceRegistry<TServiceRegistry: ServiceRegistry + ?Sized> {
+  inner: Arc<TServiceRegistry>,
+  service: Arc<ReauthenticationService<TServiceRegistry::Error>>,
+}
+
+impl<TServiceRegistry: ServiceRegistry + ?Sized> ReauthenticatingServiceRegistry<TServiceRegistry> {
+  pub fn new(inner: Arc<TServiceRegistry>, reauthenticator: Reauthenticator) -> Self {
+    Self {
+      inner,
+      service: Arc::new(ReauthenticationService {
+        reauthenticator,
+        phantom_error: PhantomData,
+      }),
+    }
+  }
+}
+
+impl<TServiceRegistry> ServiceRegistry for ReauthenticatingServiceRegistry<TServiceRegistry>
+where
+  TServiceRegistry: ServiceRegistry + ?Sized,
+  TServiceRegistry::Error: 'static,
+{
+  type Error = TServiceRegistry::Error;
+
+  fn find_service(
+    self: Arc<Self>,
+    addr: &RouteAddress,
+    tunnel: &ArcTunnel,
+  ) -> Option<Arc<dyn Service<Error = Self::Error> + Send + Sync + 'static>> {
+    if self.service.accepts(addr, tunnel) {
+      return Some(Arc::clone(&self.service) as Arc<_>);
+    }
+    Arc::clone(&self.inner).find_service(addr, tunnel)
+  }
+
+  fn try_find_service(
+    self: Arc<Self>,
+    addr: &RouteAddress,
+    tunnel: &ArcTunnel,
+  ) -> Result<Arc<dyn Service<Error = Self::Error> + Send + Sync + 'static>, Refusal> {
+    if self.service.accepts(addr, tunnel) {
+      return Ok(Arc::clone(&self.service) as Arc<_>);
+    }
+    Arc::clone(&self.inner).try_find_service(addr, tunnel)
+  }
+}
@@ -0,0 +1,282 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Revocation of connected tunnels by name, ID, or authentication attributes
+//!
+//! A [RevocationList] holds the [RevocationTarget]s which may no longer connect. Daemons given a
+//! list refuse tunnels matching it upon authentication, and close live tunnels matching it when
+//! asked to by [ModularDaemon::revoke_listed](super::ModularDaemon::revoke_listed).
+use arc_swap::ArcSwap;
+use std::{fmt::Debug, path::Path, str::FromStr, sync::Arc};
+
+use super::PeerRecord;
+use crate::common::{
+  authentication::AuthenticationAttributes,
+  protocol::tunnel::{TunnelId, TunnelName},
+};
+
+/// Marks a listed attribute value as hexadecimal, for binary values such as certificate fingerprints
+///
+/// Pairs of digits may be separated by colons, as in fingerprints printed by `openssl x509`.
+pub const HEX_VALUE_PREFIX: &str = "hex:";
+
+/// Selects the tunnels to be revoked
+#[derive(Clone)]
+pub enum RevocationTarget {
+  Name(TunnelName),
+  Id(TunnelId),
+  Attributes(Arc<dyn Fn(&AuthenticationAttributes) -> bool + Send + Sync + 'static>),
+}
+
+impl RevocationTarget {
+  /// Targets tunnels authenticated with the given attribute holding exactly the given value
+  pub fn attribute<K: Into<String>, V: Into<Vec<u8>>>(key: K, value: V) -> Self {
+    let (key, value) = (key.into(), value.into());
+    Self::Attributes(Arc::new(move |attributes| {
+      attributes.get(&key) == Some(&value)
+    }))
+  }
+
+  pub fn matches(
+    &self,
+    id: TunnelId,
+    name: &TunnelName,
+    attributes: &AuthenticationAttributes,
+  ) -> bool {
+    match self {
+      Self::Name(target) => target == name,
+      Self::Id(target) => *target == id,
+      Self::Attributes(predicate) => predicate(attributes),
+    }
+  }
+
+  pub fn matches_peer(&self, peer: &PeerRecord) -> bool {
+    self.matches(peer.id, &peer.name, &peer.attributes.load())
+  }
+}
+
+impl Debug for RevocationTarget {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Name(name) => f.debug_tuple("Name").field(name).finish(),
+      Self::Id(id) => f.debug_tuple("Id").field(id).finish(),
+      Self::Attributes(_) => f.write_str("Attributes(..)"),
+    }
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RevocationListLoadError {
+  #[error("Revocation list could not be read")]
+  IOError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    std::io::Error,
+  ),
+  #[error(
+    "Revocation list entry {0} is not of the form name:<name>, id:<id>, attribute:<key>=<value>, or attribute:<key>=hex:<value>"
+  )]
+  InvalidEntry(usize),
+}
+
+impl FromStr for RevocationTarget {
+  type Err = ();
+
+  fn from_str(entry: &str) -> Result<Self, Self::Err> {
+    let (kind, target) = entry.split_once(':').ok_or(())?;
+    let target = target.trim();
+    match kind.trim() {
+      "name" if !target.is_empty() => Ok(Self::Name(TunnelName::new(target))),
+      "id" => target
+        .parse::<u64>()
+        .map(|id| Self::Id(id.into()))
+        .map_err(|_| ()),
+      "attribute" => {
+        let (key, value) = target.split_once('=').ok_or(())?;
+        let value = match value.trim().strip_prefix(HEX_VALUE_PREFIX) {
+          Some(hex) => decode_hex(hex).ok_or(())?,
+          None => value.trim().as_bytes().to_vec(),
+        };
+        Ok(Self::attribute(key.trim(), value))
+      }
+      _ => Err(()),
+    }
+  }
+}
+
+/// Decodes pairs of hexadecimal digits, optionally separated by colons
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+  let digits: Vec<u8> = hex.bytes().filter(|&digit| digit != b':').collect();
+  if digits.len() % 2 != 0 || !digits.iter().all(u8::is_ascii_hexdigit) {
+    return None;
+  }
+  digits
+    .chunks(2)
+    .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+    .collect()
+}
+
+/// Targets which may not connect, replaceable while in use by a daemon
+#[derive(Debug)]
+pub struct RevocationList {
+  targets: ArcSwap<Vec<RevocationTarget>>,
+}
+
+impl RevocationList {
+  pub fn new() -> Self {
+    Self {
+      targets: ArcSwap::from_pointee(Vec::new()),
+    }
+  }
+
+  pub fn with_target(self, target: RevocationTarget) -> Self {
+    let mut targets = Vec::clone(&self.targets.load());
+    targets.push(target);
+    self.replace(targets);
+    self
+  }
+
+  /// Parses one target per line, as `name:<name>`, `id:<id>`, or `attribute:<key>=<value>`
+  ///
+  /// Attribute values beginning with [HEX_VALUE_PREFIX] are decoded from hexadecimal, such as
+  /// `attribute:x509.fingerprint.sha256=hex:<fingerprint>`. Blank lines and lines beginning
+  /// with `#` are ignored.
+  pub fn parse(entries: &str) -> Result<Vec<RevocationTarget>, RevocationListLoadError> {
+    entries
+      .lines()
+      .map(str::trim)
+      .enumerate()
+      .filter(|(_, entry)| !entry.is_empty() && !entry.starts_with('#'))
+      .map(|(index, entry)| {
+        entry
+          .parse()
+          .map_err(|()| RevocationListLoadError::InvalidEntry(index + 1))
+      })
+      .collect()
+  }
+
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, RevocationListLoadError> {
+    let list = Self::new();
+    list.reload_from_file(path)?;
+    Ok(list)
+  }
+
+  /// Replaces the list's targets with those in a file, keeping the current targets on failure
+  pub fn reload_from_file<P: AsRef<Path>>(&self, path: P) -> Result<(), RevocationListLoadError> {
+    self.replace(Self::parse(&std::fs::read_to_string(path)?)?);
+    Ok(())
+  }
+
+  pub fn replace(&self, targets: Vec<RevocationTarget>) {
+    self.targets.store(Arc::new(targets));
+  }
+
+  pub fn targets(&self) -> Arc<Vec<RevocationTarget>> {
+    self.targets.load_full()
+  }
+
+  pub fn is_revoked(
+    &self,
+    id: TunnelId,
+    name: &TunnelName,
+    attributes: &AuthenticationAttributes,
+  ) -> bool {
+    self
+      .targets
+      .load()
+      .iter()
+      .any(|target| target.matches(id, name, attributes))
+  }
+
+  pub fn is_peer_revoked(&self, peer: &PeerRecord) -> bool {
+    self.is_revoked(peer.id, &peer.name, &peer.attributes.load())
+  }
+}
+
+impl Default for RevocationList {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::assert_matches::assert_matches;
+
+  use super::{RevocationList, RevocationListLoadError};
+  use crate::common::{
+    authentication::{certificate_authentication::CertificateIdentity, AuthenticationAttributes},
+    protocol::tunnel::{TunnelId, TunnelName},
+  };
+
+  /// Attributes authenticated by a freshly generated certificate, and its fingerprint
+  fn certificate_attributes() -> (AuthenticationAttributes, [u8; 32]) {
+    let certificate = rcgen::generate_simple_self_signed(vec!["laptop-9.example.com".into()])
+      .unwrap()
+      .serialize_der()
+      .unwrap();
+    let identity = CertificateIdentity::from_der(&certificate).unwrap();
+    (identity.to_attributes(), identity.fingerprint)
+  }
+
+  #[test]
+  fn parse_and_match() {
+    let (fingerprinted, fingerprint) = certificate_attributes();
+    let (other_fingerprinted, _) = certificate_attributes();
+    let fingerprint_hex: String = fingerprint.iter().map(|b| format!("{:02x}", b)).collect();
+    let list = RevocationList::new();
+    list.replace(
+      RevocationList::parse(&format!(
+        "# stolen devices\nname:laptop-7\n\n  id:42\nattribute:x509.fingerprint.sha256=hex:{}\n",
+        fingerprint_hex
+      ))
+      .unwrap(),
+    );
+    assert_eq!(list.targets().len(), 3);
+
+    let attributes = AuthenticationAttributes::new();
+    let other = TunnelName::new("laptop-8");
+    assert!(list.is_revoked(TunnelId::new(1), &TunnelName::new("laptop-7"), &attributes));
+    assert!(list.is_revoked(TunnelId::new(42), &other, &attributes));
+    assert!(list.is_revoked(TunnelId::new(1), &other, &fingerprinted));
+    assert!(!list.is_revoked(TunnelId::new(1), &other, &other_fingerprinted));
+    assert!(!list.is_revoked(TunnelId::new(1), &other, &attributes));
+
+    // Fingerprints as printed by openssl, in uppercase pairs separated by colons
+    let openssl_fingerprint = fingerprint
+      .iter()
+      .map(|b| format!("{:02X}", b))
+      .collect::<Vec<_>>()
+      .join(":");
+    list.replace(
+      RevocationList::parse(&format!(
+        "attribute:x509.fingerprint.sha256=hex:{}",
+        openssl_fingerprint
+      ))
+      .unwrap(),
+    );
+    assert!(list.is_revoked(TunnelId::new(1), &other, &fingerprinted));
+
+    // Values without the prefix are matched as text
+    let mut labelled = AuthenticationAttributes::new();
+    labelled.insert("jwt.groups".into(), br#"["lost"]"#.to_vec());
+    list.replace(RevocationList::parse(r#"attribute:jwt.groups=["lost"]"#).unwrap());
+    assert!(list.is_revoked(TunnelId::new(1), &other, &labelled));
+
+    assert_matches!(
+      RevocationList::parse("name:a\nid:not-a-number"),
+      Err(RevocationListLoadError::InvalidEntry(2))
+    );
+    assert_matches!(
+      RevocationList::parse("laptop-7"),
+      Err(RevocationListLoadError::InvalidEntry(1))
+    );
+    assert_matches!(
+      RevocationList::parse("attribute:x509.fingerprint.sha256=hex:abc"),
+      Err(RevocationListLoadError::InvalidEntry(1))
+    );
+    assert_matches!(
+      RevocationList::parse("attribute:x509.fingerprint.sha256=hex:+f"),
+      Err(RevocationListLoadError::InvalidEntry(1))
+    );
+  }
+}
@@ -0,0 +1,148 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Sharing of tunnel authentication attributes and service metadata between daemons
+//!
+//! A daemon given [SharedAttributes] publishes the attributes of each tunnel it registers under
+//! [authentication_attributes_key], replacing them upon re-authentication and withdrawing them
+//! once the tunnel is deregistered. Services may publish their own metadata to the same store.
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use std::{any::Any, fmt::Debug};
+
+use crate::common::{
+  authentication::AuthenticationAttributes,
+  protocol::tunnel::{
+    registry::{AttributeValue, SharedAttributeRegistry},
+    TunnelName,
+  },
+};
+
+/// Key under which the attributes of a tunnel's most recent authentication are published
+pub fn authentication_attributes_key(tunnel_name: &TunnelName) -> String {
+  format!("tunnel/{}/authentication", tunnel_name.raw())
+}
+
+/// Keeps a published entry alive, identifying it for withdrawal
+///
+/// Dropping the handle without withdrawing it leaves the entry to expire as the registry
+/// backing it would for a dropped identifier.
+pub struct PublishedAttributes {
+  key: String,
+  identifier: Box<dyn Any + Send + Sync + 'static>,
+}
+
+impl PublishedAttributes {
+  pub fn key(&self) -> &str {
+    &self.key
+  }
+}
+
+impl Debug for PublishedAttributes {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("PublishedAttributes")
+      .field("key", &self.key)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Object-safe access to a [SharedAttributeRegistry] storing [AuthenticationAttributes]
+pub trait SharedAttributes: Send + Sync {
+  fn lookup<'a>(
+    &'a self,
+    key: &'a str,
+  ) -> BoxFuture<'static, Result<Option<AuthenticationAttributes>, anyhow::Error>>;
+
+  /// Publishes attributes under a key, replacing any previously published there
+  fn publish<'a>(
+    &'a self,
+    key: &'a str,
+    attributes: &'a AuthenticationAttributes,
+  ) -> BoxFuture<'static, Result<PublishedAttributes, anyhow::Error>>;
+
+  /// Withdraws published attributes, unless they have since been replaced
+  fn withdraw(
+    &self,
+    published: PublishedAttributes,
+  ) -> BoxFuture<'static, Result<(), anyhow::Error>>;
+}
+
+impl<R> SharedAttributes for R
+where
+  R: SharedAttributeRegistry
+    + AttributeValue<AuthenticationAttributes, Stored = <R as SharedAttributeRegistry>::Value>
+    + Send
+    + Sync,
+  R::Error: std::error::Error + Send + Sync,
+{
+  fn lookup<'a>(
+    &'a self,
+    key: &'a str,
+  ) -> BoxFuture<'static, Result<Option<AuthenticationAttributes>, anyhow::Error>> {
+    self.lookup_attr(key).map_err(anyhow::Error::from).boxed()
+  }
+
+  fn publish<'a>(
+    &'a self,
+    key: &'a str,
+    attributes: &'a AuthenticationAttributes,
+  ) -> BoxFuture<'static, Result<PublishedAttributes, anyhow::Error>> {
+    let registration = self.register_attr(key, attributes);
+    let key = key.to_owned();
+    registration
+      .map_ok(move |identifier| PublishedAttributes {
+        key,
+        identifier: Box::new(identifier),
+      })
+      .map_err(anyhow::Error::from)
+      .boxed()
+  }
+
+  fn withdraw(
+    &self,
+    published: PublishedAttributes,
+  ) -> BoxFuture<'static, Result<(), anyhow::Error>> {
+    match published.identifier.downcast::<R::Identifier>() {
+      Ok(identifier) => self
+        .deregister_attr_identifier(*identifier)
+        .map_ok(|_| ())
+        .map_err(anyhow::Error::from)
+        .boxed(),
+      Err(_) => futures::future::ready(Err(anyhow::Error::msg(
+        "Published attributes were not issued by this registry",
+      )))
+      .boxed(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::{authentication_attributes_key, SharedAttributes};
+  use crate::common::{
+    authentication::certificate_authentication::{CertificateIdentity, FINGERPRINT_ATTRIBUTE},
+    protocol::tunnel::{registry::memory::InMemoryAttributeRegistry, TunnelName},
+  };
+
+  #[tokio::test]
+  async fn publish_and_withdraw() {
+    let shared: Arc<dyn SharedAttributes> = Arc::new(InMemoryAttributeRegistry::new());
+    let key = authentication_attributes_key(&TunnelName::new("laptop-7"));
+    // Certificate attributes include binary values, such as the 32-byte SHA-256 fingerprint
+    let certificate = rcgen::generate_simple_self_signed(vec!["laptop-7.example.com".into()])
+      .unwrap()
+      .serialize_der()
+      .unwrap();
+    let attributes = CertificateIdentity::from_der(&certificate)
+      .unwrap()
+      .to_attributes();
+    assert_eq!(attributes[FINGERPRINT_ATTRIBUTE].len(), 32);
+
+    let published = shared.publish(&key, &attributes).await.unwrap();
+    assert_eq!(published.key(), key);
+    assert_eq!(shared.lookup(&key).await.unwrap(), Some(attributes));
+
+    shared.withdraw(published).await.unwrap();
+    assert_eq!(shared.lookup(&key).await.unwrap(), None);
+  }
+}
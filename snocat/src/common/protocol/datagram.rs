@@ -0,0 +1,202 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Demultiplexing of tunnel datagrams into per-flow channels
+//!
+//! Datagrams sent over a tunnel carry no routing information of their own, so services
+//! which exchange them prefix each with a [FlowId] agreed upon over a negotiated link.
+//! The daemon hands incoming datagrams to a shared [DatagramDispatcher], which forwards
+//! them to the [DatagramFlow] registered for that tunnel and flow.
+
+use std::{
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+  task::{Context, Poll},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use dashmap::DashMap;
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use super::tunnel::{TunnelId, TunnelSide};
+
+/// Identifies a datagram flow within the scope of a single tunnel
+///
+/// The lowest bit records the [TunnelSide] which allocated the flow,
+/// allowing both peers to allocate flows without coordination.
+pub type FlowId = u32;
+
+/// Length of the [FlowId] prefix on each tunnel datagram
+pub const FLOW_ID_LENGTH: usize = std::mem::size_of::<FlowId>();
+
+/// Datagrams buffered per flow before further arrivals are dropped
+const FLOW_BUFFER_LENGTH: usize = 256;
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatagramFlowError {
+  #[error("Datagram flow {0} is already registered for this tunnel")]
+  AlreadyRegistered(FlowId),
+}
+
+#[derive(Debug)]
+pub struct DatagramDispatcher {
+  flows: DashMap<(TunnelId, FlowId), mpsc::Sender<Bytes>>,
+  next_flow: AtomicU32,
+}
+
+impl Default for DatagramDispatcher {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl DatagramDispatcher {
+  pub fn new() -> Self {
+    Self {
+      flows: DashMap::new(),
+      next_flow: AtomicU32::new(0),
+    }
+  }
+
+  /// Allocates a flow ID which will not collide with those allocated by the remote side
+  pub fn allocate_flow_id(&self, side: TunnelSide) -> FlowId {
+    let side_bit = match side {
+      TunnelSide::Connect => 0,
+      TunnelSide::Listen => 1,
+    };
+    (self.next_flow.fetch_add(1, Ordering::Relaxed) << 1) | side_bit
+  }
+
+  /// Registers a flow to receive datagrams from the given tunnel until the flow is dropped
+  pub fn register(
+    self: &Arc<Self>,
+    tunnel_id: TunnelId,
+    flow_id: FlowId,
+  ) -> Result<DatagramFlow, DatagramFlowError> {
+    use dashmap::mapref::entry::Entry;
+    match self.flows.entry((tunnel_id, flow_id)) {
+      Entry::Occupied(_) => Err(DatagramFlowError::AlreadyRegistered(flow_id)),
+      Entry::Vacant(vacant) => {
+        let (sender, receiver) = mpsc::channel(FLOW_BUFFER_LENGTH);
+        vacant.insert(sender);
+        Ok(DatagramFlow {
+          dispatcher: Arc::clone(self),
+          tunnel_id,
+          flow_id,
+          receiver,
+        })
+      }
+    }
+  }
+
+  /// Forwards a datagram received from a tunnel to its flow
+  ///
+  /// Returns false if the datagram was malformed, unclaimed, or its flow was full.
+  pub fn dispatch(&self, tunnel_id: TunnelId, mut datagram: Bytes) -> bool {
+    if datagram.len() < FLOW_ID_LENGTH {
+      return false;
+    }
+    let payload = datagram.split_off(FLOW_ID_LENGTH);
+    let flow_id = FlowId::from_be_bytes(
+      datagram[..]
+        .try_into()
+        .expect("Prefix length must match flow ID length"),
+    );
+    match self.flows.get(&(tunnel_id, flow_id)) {
+      Some(sender) => sender.try_send(payload).is_ok(),
+      None => false,
+    }
+  }
+
+  /// Prefixes a payload with its flow ID for transmission over a tunnel
+  pub fn encode(flow_id: FlowId, payload: &[u8]) -> Bytes {
+    let mut datagram = BytesMut::with_capacity(FLOW_ID_LENGTH + payload.len());
+    datagram.put_u32(flow_id);
+    datagram.put_slice(payload);
+    datagram.freeze()
+  }
+}
+
+/// Receives the datagrams dispatched to a single flow, deregistering it when dropped
+#[derive(Debug)]
+pub struct DatagramFlow {
+  dispatcher: Arc<DatagramDispatcher>,
+  tunnel_id: TunnelId,
+  flow_id: FlowId,
+  receiver: mpsc::Receiver<Bytes>,
+}
+
+impl DatagramFlow {
+  pub fn flow_id(&self) -> FlowId {
+    self.flow_id
+  }
+
+  pub fn tunnel_id(&self) -> TunnelId {
+    self.tunnel_id
+  }
+
+  pub async fn recv(&mut self) -> Option<Bytes> {
+    self.receiver.recv().await
+  }
+}
+
+impl Stream for DatagramFlow {
+  type Item = Bytes;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    self.receiver.poll_recv(cx)
+  }
+}
+
+impl Drop for DatagramFlow {
+  fn drop(&mut self) {
+    self
+      .dispatcher
+      .flows
+      .remove(&(self.tunnel_id, self.flow_id));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use bytes::Bytes;
+
+  use super::{DatagramDispatcher, DatagramFlowError};
+  use crate::common::protocol::tunnel::{TunnelId, TunnelSide};
+
+  #[test]
+  fn flow_ids_are_sided() {
+    let dispatcher = DatagramDispatcher::new();
+    let connector = dispatcher.allocate_flow_id(TunnelSide::Connect);
+    let listener = dispatcher.allocate_flow_id(TunnelSide::Listen);
+    assert_eq!(connector & 1, 0);
+    assert_eq!(listener & 1, 1);
+    assert_ne!(connector >> 1, listener >> 1);
+  }
+
+  #[tokio::test]
+  async fn dispatch_to_flow() {
+    let dispatcher = Arc::new(DatagramDispatcher::new());
+    let (tunnel_a, tunnel_b) = (TunnelId::new(1), TunnelId::new(2));
+    let mut flow = dispatcher.register(tunnel_a, 7).unwrap();
+    assert_eq!(
+      dispatcher.register(tunnel_a, 7).unwrap_err(),
+      DatagramFlowError::AlreadyRegistered(7)
+    );
+    // Flows are scoped to their tunnel
+    assert!(!dispatcher.dispatch(tunnel_b, DatagramDispatcher::encode(7, b"other")));
+    // Datagrams too short to hold a flow ID are rejected
+    assert!(!dispatcher.dispatch(tunnel_a, Bytes::from_static(b"\0")));
+    assert!(dispatcher.dispatch(tunnel_a, DatagramDispatcher::encode(7, b"hello")));
+    assert_eq!(flow.recv().await.unwrap(), Bytes::from_static(b"hello"));
+    drop(flow);
+    assert!(!dispatcher.dispatch(tunnel_a, DatagramDispatcher::encode(7, b"gone")));
+    // Dropping a flow frees its ID for reuse
+    dispatcher.register(tunnel_a, 7).unwrap();
+  }
+}
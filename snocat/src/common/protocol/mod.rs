@@ -22,12 +22,21 @@
 // Note that authentication doesn't occur on this level- this is *after* tunnel establishment
 
 pub mod traits;
-pub use traits::{MappedService, Service, ServiceError, ServiceRegistry};
+pub use traits::{
+  MappedService, RequestHeaders, Service, ServiceError, ServiceRegistry, ServiceVersion,
+  DEFAULT_SERVICE_VERSIONS,
+};
 
 pub mod address;
+pub mod datagram;
 pub mod negotiation;
 pub mod proxy_tcp;
+pub mod proxy_udp;
+#[cfg(unix)]
+pub mod proxy_unix;
 pub mod service;
+#[cfg(feature = "trace-context")]
+pub mod trace_context;
 pub mod tunnel;
 
 pub use address::RouteAddress;
@@ -1,21 +1,99 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, ops::RangeInclusive, sync::Arc};
 
 use futures::{
   future::{BoxFuture, FutureExt},
   Future,
 };
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing_futures::Instrument;
 
-use crate::util::tunnel_stream::TunnelStream;
+use crate::util::{cancellation::CancellationListener, tunnel_stream::TunnelStream};
 
-use super::{traits::ServiceRegistry, tunnel::Tunnel, RouteAddress, Service, ServiceError};
+use super::{
+  service::RoutingError, traits::ServiceRegistry, tunnel::Tunnel, RequestHeaders, RouteAddress,
+  Service, ServiceError, ServiceVersion, DEFAULT_SERVICE_VERSIONS,
+};
 
 /// Identifies the SNOCAT protocol over a stream
 pub const SNOCAT_NEGOTIATION_MAGIC: &[u8; 4] = &[0x4e, 0x59, 0x41, 0x4e]; // UTF-8 "NYAN"
 
+/// Reasons a service may refuse an address, sent to v1+ clients in a refusal frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RefusalReason {
+  /// No service is registered to handle the address
+  NoSuchService,
+  /// A service exists for the address, but policy forbids its use by this tunnel
+  Forbidden,
+  /// The service is temporarily unable to accept more requests
+  Overloaded,
+  /// The address was malformed or otherwise unacceptable to the service
+  BadAddress,
+  /// The remote is shutting down and no longer accepts new requests
+  ShuttingDown,
+}
+
+impl RefusalReason {
+  fn code(self) -> u8 {
+    match self {
+      RefusalReason::NoSuchService => ADDRESS_REFUSED,
+      RefusalReason::Forbidden => 3,
+      RefusalReason::Overloaded => 4,
+      RefusalReason::BadAddress => 5,
+      RefusalReason::ShuttingDown => 6,
+    }
+  }
+
+  fn from_code(code: u8) -> Option<Self> {
+    match code {
+      ADDRESS_REFUSED => Some(RefusalReason::NoSuchService),
+      3 => Some(RefusalReason::Forbidden),
+      4 => Some(RefusalReason::Overloaded),
+      5 => Some(RefusalReason::BadAddress),
+      6 => Some(RefusalReason::ShuttingDown),
+      _ => None,
+    }
+  }
+}
+
+/// A refusal of an address by the service side of negotiation,
+/// with an optional human-readable message to pass along to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refusal {
+  pub reason: RefusalReason,
+  pub message: Option<String>,
+}
+
+impl Refusal {
+  pub fn new(reason: RefusalReason) -> Self {
+    Self {
+      reason,
+      message: None,
+    }
+  }
+
+  pub fn with_message<S: Into<String>>(reason: RefusalReason, message: S) -> Self {
+    Self {
+      reason,
+      message: Some(message.into()),
+    }
+  }
+}
+
+impl<AE> From<Refusal> for NegotiationError<AE> {
+  fn from(refusal: Refusal) -> Self {
+    let Refusal { reason, message } = refusal;
+    match reason {
+      RefusalReason::NoSuchService => NegotiationError::NoSuchService(message),
+      RefusalReason::Forbidden => NegotiationError::Forbidden(message),
+      RefusalReason::Overloaded => NegotiationError::Overloaded(message),
+      RefusalReason::BadAddress => NegotiationError::BadAddress(message),
+      RefusalReason::ShuttingDown => NegotiationError::ShuttingDown(message),
+    }
+  }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum NegotiationError<ApplicationError> {
   #[error("Stream read failed")]
@@ -26,6 +104,16 @@ pub enum NegotiationError<ApplicationError> {
   ProtocolViolation,
   #[error("Protocol refused")]
   Refused,
+  #[error("No service available for address: {0:?}")]
+  NoSuchService(Option<String>),
+  #[error("Address forbidden by policy: {0:?}")]
+  Forbidden(Option<String>),
+  #[error("Service overloaded: {0:?}")]
+  Overloaded(Option<String>),
+  #[error("Address refused as invalid: {0:?}")]
+  BadAddress(Option<String>),
+  #[error("Remote is shutting down: {0:?}")]
+  ShuttingDown(Option<String>),
   #[error("Protocol version not supported")]
   UnsupportedProtocolVersion,
   #[error("Service version not supported")]
@@ -46,6 +134,11 @@ impl<ApplicationError> NegotiationError<ApplicationError> {
       NegotiationError::WriteError => NegotiationError::WriteError,
       NegotiationError::ProtocolViolation => NegotiationError::ProtocolViolation,
       NegotiationError::Refused => NegotiationError::Refused,
+      NegotiationError::NoSuchService(m) => NegotiationError::NoSuchService(m),
+      NegotiationError::Forbidden(m) => NegotiationError::Forbidden(m),
+      NegotiationError::Overloaded(m) => NegotiationError::Overloaded(m),
+      NegotiationError::BadAddress(m) => NegotiationError::BadAddress(m),
+      NegotiationError::ShuttingDown(m) => NegotiationError::ShuttingDown(m),
       NegotiationError::UnsupportedProtocolVersion => NegotiationError::UnsupportedProtocolVersion,
       NegotiationError::UnsupportedServiceVersion => NegotiationError::UnsupportedServiceVersion,
       NegotiationError::ApplicationError(e) => NegotiationError::ApplicationError(f(e)),
@@ -67,6 +160,11 @@ impl<SourceError: Into<OutError>, OutError> From<NegotiationError<SourceError>>
       NegotiationError::WriteError => ServiceError::UnexpectedEnd,
       NegotiationError::ProtocolViolation => ServiceError::IllegalResponse,
       NegotiationError::Refused => ServiceError::Refused,
+      NegotiationError::NoSuchService(_) => ServiceError::Refused,
+      NegotiationError::Forbidden(_) => ServiceError::Refused,
+      NegotiationError::Overloaded(_) => ServiceError::Refused,
+      NegotiationError::BadAddress(_) => ServiceError::AddressError,
+      NegotiationError::ShuttingDown(_) => ServiceError::Refused,
       NegotiationError::UnsupportedProtocolVersion => ServiceError::Refused,
       NegotiationError::UnsupportedServiceVersion => ServiceError::Refused,
       NegotiationError::ApplicationError(e) => ServiceError::InternalError(e.into()),
@@ -75,103 +173,386 @@ impl<SourceError: Into<OutError>, OutError> From<NegotiationError<SourceError>>
   }
 }
 
+/// Negotiation protocol versions supported by this implementation
+///
+/// Version 0 is the original fixed-version handshake and is retained so v0 peers keep working.
+/// Version 1 exchanges a range of supported versions and confirms the selected version.
+pub const SUPPORTED_PROTOCOL_VERSIONS: RangeInclusive<u8> = 0..=1;
+
+/// Negotiation protocol versions offered by clients without headers, unless otherwise configured
+///
+/// v0 services refuse clients offering any later version, which may only reach them by retrying
+/// over another link with [NegotiationClient::negotiate_with_fallback]. Clients therefore only
+/// speak v0 by default, sparing that retry; offering [SUPPORTED_PROTOCOL_VERSIONS] enables service
+/// versions, structured refusals, and request headers with services which have been upgraded.
+/// Clients with headers to send offer [SUPPORTED_PROTOCOL_VERSIONS] unless otherwise configured,
+/// including those carrying the trace context of their span under the `trace-context` feature.
+pub const DEFAULT_CLIENT_PROTOCOL_VERSIONS: RangeInclusive<u8> = 0..=0;
+
+/// Maximum length of the frame containing request headers
+const MAX_HEADERS_LENGTH: usize = 16 * 1024;
+
+/// Maximum length of the message in a refusal frame
+const MAX_REFUSAL_MESSAGE_LENGTH: usize = 1024;
+
+/// Sent by services to accept an address; v1+ follows this with the selected service version
+const ADDRESS_ACCEPTED: u8 = 0;
+/// Sent by services to refuse an address; v0 treats any non-zero value as a refusal
+///
+/// v1+ uses this as the code for [RefusalReason::NoSuchService], and follows all non-zero
+/// codes with a frame containing a UTF-8 message, which is left empty if none is provided.
+const ADDRESS_REFUSED: u8 = 1;
+/// Sent by v1+ services to refuse an address with which no service version is shared
+const SERVICE_VERSION_UNSUPPORTED: u8 = 2;
+
+/// Writes a refusal of the address to the remote
+async fn write_refusal<S: AsyncWrite + Send + Unpin, AE>(
+  stream: &mut S,
+  protocol_version: u8,
+  refusal: &Refusal,
+) -> Result<(), NegotiationError<AE>> {
+  write_refusal_code(
+    stream,
+    protocol_version,
+    refusal.reason.code(),
+    refusal.message.as_deref(),
+  )
+  .await
+}
+
+/// Writes a refusal code to the remote, followed by a message frame for v1+ remotes
+///
+/// v0 remotes only understand that the address was refused, so the code and message are not sent.
+/// Messages are truncated to [MAX_REFUSAL_MESSAGE_LENGTH] bytes.
+async fn write_refusal_code<S: AsyncWrite + Send + Unpin, AE>(
+  stream: &mut S,
+  protocol_version: u8,
+  code: u8,
+  message: Option<&str>,
+) -> Result<(), NegotiationError<AE>> {
+  if protocol_version == 0 {
+    stream
+      .write_u8(ADDRESS_REFUSED)
+      .await
+      .map_err(|_| NegotiationError::WriteError)?;
+  } else {
+    let message = message.unwrap_or_default();
+    let mut message_length = message.len().min(MAX_REFUSAL_MESSAGE_LENGTH);
+    while !message.is_char_boundary(message_length) {
+      message_length -= 1;
+    }
+    stream
+      .write_u8(code)
+      .await
+      .map_err(|_| NegotiationError::WriteError)?;
+    crate::util::framed::write_frame(&mut *stream, &message.as_bytes()[..message_length])
+      .await
+      .map_err(|_| NegotiationError::WriteError)?;
+  }
+  stream
+    .flush()
+    .await
+    .map_err(|_| NegotiationError::WriteError)
+}
+
+/// Reads the message frame following a v1+ refusal code, returning `None` if it was empty
+async fn read_refusal_message<S: AsyncRead + Send + Unpin, AE>(
+  stream: &mut S,
+) -> Result<Option<String>, NegotiationError<AE>> {
+  let message = crate::util::framed::read_frame(&mut *stream, Some(MAX_REFUSAL_MESSAGE_LENGTH))
+    .await
+    .map_err(|_| NegotiationError::ProtocolViolation)?;
+  let message = String::from_utf8(message).map_err(|_| NegotiationError::ProtocolViolation)?;
+  Ok(Some(message).filter(|message| !message.is_empty()))
+}
+
+/// Selects the highest version present in both ranges, if any
+fn highest_common_version(a: &RangeInclusive<u8>, b: &RangeInclusive<u8>) -> Option<u8> {
+  let highest = (*a.end()).min(*b.end());
+  Some(highest).filter(|highest| *highest >= (*a.start()).max(*b.start()))
+}
+
 /// Write future to send our magic and version to the remote,
 /// returning an error if writes are refused by the stream.
+///
+/// If a minimum version is provided, it is sent immediately after the version,
+/// completing the supported version range for v1+ handshakes.
 #[tracing::instrument(level = tracing::Level::TRACE, err, skip(stream))]
 async fn write_magic_and_version<S: AsyncWrite + Send + Unpin, AE: Debug>(
-  mut stream: S,
+  stream: &mut S,
   protocol_version: u8,
-) -> Result<S, NegotiationError<AE>> {
+  minimum_version: Option<u8>,
+) -> Result<(), NegotiationError<AE>> {
+  let mut header = Vec::with_capacity(SNOCAT_NEGOTIATION_MAGIC.len() + 2);
+  header.extend_from_slice(SNOCAT_NEGOTIATION_MAGIC);
+  header.push(protocol_version);
+  header.extend(minimum_version);
   stream
-    .write_all(SNOCAT_NEGOTIATION_MAGIC)
+    .write_all(&header)
     .await
     .map_err(|_| NegotiationError::WriteError)?;
   stream
-    .write_u8(protocol_version)
+    .flush()
     .await
     .map_err(|_| NegotiationError::WriteError)?;
+  Ok(())
+}
+
+/// Read future to get the magic from the remote, returning an error on magic mismatch,
+/// or the version which follows the magic if it was as expected.
+#[tracing::instrument(level = tracing::Level::TRACE, err, skip(stream))]
+async fn read_magic_and_version<S: AsyncRead + Send + Unpin, AE: Debug>(
+  stream: &mut S,
+) -> Result<u8, NegotiationError<AE>> {
+  let mut remote_magic = [0u8; 4];
+  let remote_magic_len = stream
+    .read_exact(&mut remote_magic)
+    .await
+    .map_err(|_| NegotiationError::ProtocolViolation)?;
+  if remote_magic_len < remote_magic.len() || &remote_magic != SNOCAT_NEGOTIATION_MAGIC {
+    tracing::trace!("magic mismatch");
+    return Err(NegotiationError::ProtocolViolation);
+  }
+  tracing::trace!("magic matched expectation");
   stream
-    .flush()
+    .read_u8()
     .await
-    .map_err(|_| NegotiationError::WriteError)?;
-  Result::<S, NegotiationError<AE>>::Ok(stream)
+    .map_err(|_| NegotiationError::ReadError)
 }
 
-// Note: Protocol v0 is symmetric until negotiation handshake completes
-fn protocol_magic<'a, S: TunnelStream + Send + 'a, AE: Debug + 'a>(
-  stream: S,
-  protocol_version: u8,
-) -> impl Future<Output = Result<u8, NegotiationError<AE>>> + 'a {
-  let (mut read, write) = tokio::io::split(stream);
-  // Write future to send our magic and version to the remote,
-  // returning an error if writes are refused by the stream.
-  let send_magic = write_magic_and_version(write, protocol_version);
-  // Read future to get the magic from the remote, returning an error on magic mismatch
-  let read_magic = async {
-    let mut remote_magic = [0u8; 4];
-    let remote_magic_len = read
-      .read_exact(&mut remote_magic)
+/// Client half of the negotiation protocol handshake, returning the selected protocol version
+///
+/// We send our magic and highest supported version, followed by our lowest supported version
+/// if the highest is v1 or later. v0 services send `0` regardless of what we send, and v1+ services
+/// reply with the highest version common to both ranges. Any version above 0 is then confirmed by
+/// echoing it back to the service.
+///
+/// Note that v0 services refuse any version but 0 by closing the stream, and send their `0`
+/// without waiting to see ours. A reply of `0` to a v1+ offer is thus refused, as the remote may
+/// be a v0 service which will not proceed; v1+ services accept v0 clients by replying with `0`.
+#[tracing::instrument(level = tracing::Level::TRACE, err, skip(stream))]
+async fn client_handshake<S, AE: Debug>(
+  stream: &mut S,
+  supported_versions: &RangeInclusive<u8>,
+) -> Result<u8, NegotiationError<AE>>
+where
+  S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+  let (minimum_version, maximum_version) = (*supported_versions.start(), *supported_versions.end());
+  // v0 remotes only expect a single version byte, so the range is only sent for v1+
+  let range_start = Some(minimum_version).filter(|_| maximum_version > 0);
+  write_magic_and_version(stream, maximum_version, range_start).await?;
+  let selected_version = read_magic_and_version(stream).await?;
+  if !supported_versions.contains(&selected_version) {
+    tracing::trace!(
+      version = selected_version,
+      "unsupported remote protocol version"
+    );
+    return Err(NegotiationError::UnsupportedProtocolVersion);
+  }
+  if selected_version == 0 && maximum_version > 0 {
+    tracing::trace!("remote selected v0 after a later version was offered");
+    return Err(NegotiationError::UnsupportedProtocolVersion);
+  }
+  if selected_version > 0 {
+    // v1+ requires that we explicitly confirm the version the service selected
+    stream
+      .write_u8(selected_version)
       .await
-      .map_err(|_| NegotiationError::ProtocolViolation)?;
-    if remote_magic_len < remote_magic.len() || &remote_magic != SNOCAT_NEGOTIATION_MAGIC {
-      tracing::trace!("magic mismatch");
-      return Err(NegotiationError::ProtocolViolation);
-    }
-    tracing::trace!("magic matched expectation");
-    Result::<_, NegotiationError<AE>>::Ok(read)
+      .map_err(|_| NegotiationError::WriteError)?;
+    stream
+      .flush()
+      .await
+      .map_err(|_| NegotiationError::WriteError)?;
+  }
+  Ok(selected_version)
+}
+
+/// Service half of the negotiation protocol handshake, returning the selected protocol version
+///
+/// Unlike v0, which sends its magic without waiting for the remote, we wait for the client's
+/// supported versions before replying with the highest version common to both ranges. This
+/// allows replying to v0 clients with the `0` they expect, while v1+ clients are sent the
+/// selected version and must confirm it before negotiation proceeds.
+#[tracing::instrument(level = tracing::Level::TRACE, err, skip(stream))]
+async fn service_handshake<S, AE: Debug>(
+  stream: &mut S,
+  supported_versions: &RangeInclusive<u8>,
+) -> Result<u8, NegotiationError<AE>>
+where
+  S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+  let remote_maximum = read_magic_and_version(stream).await?;
+  // v0 clients send only their version, while v1+ clients follow it with their minimum version
+  let remote_minimum = if remote_maximum > 0 {
+    stream
+      .read_u8()
+      .await
+      .map_err(|_| NegotiationError::ReadError)?
+  } else {
+    0
   };
+  if remote_minimum > remote_maximum {
+    tracing::trace!(
+      remote_minimum,
+      remote_maximum,
+      "remote sent an empty protocol version range"
+    );
+    return Err(NegotiationError::ProtocolViolation);
+  }
 
-  async move {
-    let (read, write) = futures::future::try_join(read_magic, send_magic).await?;
-    let mut stream = read.unsplit(write);
-    let remote_version = stream
+  let selected_version =
+    match highest_common_version(&(remote_minimum..=remote_maximum), supported_versions) {
+      Some(selected_version) => selected_version,
+      None => {
+        tracing::trace!(
+          remote_minimum,
+          remote_maximum,
+          "no protocol version in common with remote"
+        );
+        // Our highest version is outside the remote's range, so the remote will refuse it as well
+        write_magic_and_version(stream, *supported_versions.end(), None).await?;
+        return Err(NegotiationError::UnsupportedProtocolVersion);
+      }
+    };
+  write_magic_and_version(stream, selected_version, None).await?;
+
+  if selected_version > 0 {
+    let confirmed_version = stream
       .read_u8()
       .await
       .map_err(|_| NegotiationError::ReadError)?;
-    Ok(remote_version)
+    if confirmed_version != selected_version {
+      tracing::trace!(
+        selected_version,
+        confirmed_version,
+        "remote did not confirm the selected protocol version"
+      );
+      return Err(NegotiationError::ProtocolViolation);
+    }
   }
-  .instrument(tracing::trace_span!(
-    stringify!(protocol_magic),
-    ?protocol_version
-  ))
+  Ok(selected_version)
 }
 
-pub struct NegotiationClient;
+/// Whether a range of negotiation protocol versions is non-empty and within [SUPPORTED_PROTOCOL_VERSIONS]
+pub fn is_supported_range(protocol_versions: &RangeInclusive<u8>) -> bool {
+  !protocol_versions.is_empty()
+    && SUPPORTED_PROTOCOL_VERSIONS.contains(protocol_versions.start())
+    && SUPPORTED_PROTOCOL_VERSIONS.contains(protocol_versions.end())
+}
+
+pub struct NegotiationClient {
+  protocol_versions: Option<RangeInclusive<u8>>,
+  service_versions: RangeInclusive<ServiceVersion>,
+  headers: RequestHeaders,
+}
 
 impl NegotiationClient {
   pub fn new() -> Self {
-    Self {}
+    Self {
+      protocol_versions: None,
+      service_versions: DEFAULT_SERVICE_VERSIONS,
+      headers: RequestHeaders::new(),
+    }
   }
 
+  /// Sets the negotiation protocol versions offered to the remote
+  ///
+  /// Defaults to [DEFAULT_CLIENT_PROTOCOL_VERSIONS], which v0 services accept, or to
+  /// [SUPPORTED_PROTOCOL_VERSIONS] if there are headers to send. v0 services refuse
+  /// clients offering any later version, so clients offering v1 reach them only through
+  /// [negotiate_with_fallback](Self::negotiate_with_fallback). Panics if `protocol_versions` is
+  /// empty or is not a subset of [SUPPORTED_PROTOCOL_VERSIONS].
+  pub fn with_protocol_versions(mut self, protocol_versions: RangeInclusive<u8>) -> Self {
+    assert!(
+      is_supported_range(&protocol_versions),
+      "Unsupported negotiation protocol version range {:?}",
+      protocol_versions
+    );
+    self.protocol_versions = Some(protocol_versions);
+    self
+  }
+
+  /// Sets the service versions offered to the remote, defaulting to [DEFAULT_SERVICE_VERSIONS]
+  ///
+  /// Routers will generally offer the versions supported by the client in use, as provided by
+  /// [ProtocolInfo::service_versions](super::service::ProtocolInfo::service_versions).
+  /// Panics if `service_versions` is empty.
+  pub fn with_service_versions(mut self, service_versions: RangeInclusive<ServiceVersion>) -> Self {
+    assert!(
+      !service_versions.is_empty(),
+      "Service version range must not be empty"
+    );
+    self.service_versions = service_versions;
+    self
+  }
+
+  /// Sets the headers sent to the remote after the address
+  ///
+  /// Headers are only sent by v1+, so clients with headers offer v1 unless their protocol versions
+  /// are set explicitly. v0 remotes do not support headers, so they are dropped with a warning
+  /// when negotiating with them.
+  pub fn with_headers(mut self, headers: RequestHeaders) -> Self {
+    self.headers = headers;
+    self
+  }
+
+  /// Adds the trace context of the current span to the headers, returning them along with the
+  /// protocol versions to offer and the service versions
+  fn prepare(
+    self,
+  ) -> (
+    RangeInclusive<u8>,
+    RangeInclusive<ServiceVersion>,
+    RequestHeaders,
+  ) {
+    #[cfg_attr(not(feature = "trace-context"), allow(unused_mut))]
+    let mut headers = self.headers;
+    // Services parent their handling of the request to the span which opened it; the negotiation
+    // span is only enabled when tracing at TRACE level, so cannot be relied upon to carry a context
+    #[cfg(feature = "trace-context")]
+    super::trace_context::inject_span_context(&tracing::Span::current(), &mut headers);
+    let protocol_versions = self.protocol_versions.unwrap_or(match headers.is_empty() {
+      true => DEFAULT_CLIENT_PROTOCOL_VERSIONS,
+      false => SUPPORTED_PROTOCOL_VERSIONS,
+    });
+    (protocol_versions, self.service_versions, headers)
+  }
+
+  /// Performs negotiation, returning the stream and the service version selected by the remote
   pub fn negotiate<'stream, S, AE: Debug + 'stream>(
     self,
     addr: RouteAddress,
     mut link: S,
-  ) -> impl Future<Output = Result<S, NegotiationError<AE>>> + 'stream
+  ) -> impl Future<Output = Result<(S, ServiceVersion), NegotiationError<AE>>> + 'stream
   where
     S: TunnelStream + Send + 'stream,
     for<'a> &'a mut S: TunnelStream + Send,
   {
-    const LOCAL_PROTOCOL_VERSION: u8 = 0;
+    let (protocol_versions, service_versions, headers) = self.prepare();
     let negotiation_span = tracing::trace_span!("protocol_negotiation_client", addr=?addr);
     async move {
-      // Absolute most-basic negotiation protocol - sends the address in a frame and waits for 0u8-or-fail
+      // Both v0 and v1 send the address in a frame and wait for 0u8-or-fail after the handshake
 
       tracing::trace!("performing negotiation protocol handshake");
-      let remote_version = protocol_magic::<&mut S, AE>(&mut link, LOCAL_PROTOCOL_VERSION).await?;
-      // TODO: Consider adding a confirmation for negotiation protocol acceptance here
+      let protocol_version = client_handshake::<S, AE>(&mut link, &protocol_versions).await?;
+      tracing::trace!(protocol_version, "negotiation protocol handshake complete");
 
-      // TODO: support multiple versions of negotiation mechanism
-      if remote_version > 0 {
-        // We don't support anything beyond this basic protocol yet
+      if protocol_version == 0 && !service_versions.contains(&0) {
+        // v0 has no service versioning, so services always speak their initial version
         tracing::trace!(
-          version = remote_version,
-          "unsupported remote protocol version"
+          ?service_versions,
+          "v0 remote cannot speak a supported service version"
+        );
+        return Err(NegotiationError::UnsupportedServiceVersion);
+      }
+      if protocol_version == 0 && !headers.is_empty() {
+        tracing::warn!(
+          ?addr,
+          headers = ?headers.keys().collect::<Vec<_>>(),
+          "v0 remote does not support request headers; dropping them"
         );
-        return Err(NegotiationError::UnsupportedProtocolVersion);
       }
-
-      // TODO: support service/client protocol versioning (May require Service/Client cooperation)
 
       tracing::trace!("writing address");
       // Write address to the remote, and see if the requested protocol is supported
@@ -179,30 +560,132 @@ impl NegotiationClient {
         .await
         .map_err(|_| NegotiationError::WriteError)?;
 
+      if protocol_version > 0 {
+        // v1+ offers the range of service versions the client supports after the address,
+        // followed by the request headers as a JSON object in a frame
+        link
+          .write_all(&[*service_versions.start(), *service_versions.end()])
+          .await
+          .map_err(|_| NegotiationError::WriteError)?;
+        crate::util::framed::write_framed_json(&mut link, &headers, Some(MAX_HEADERS_LENGTH))
+          .await
+          .map_err(|e| {
+            tracing::trace!(error = ?e, "failed to write request headers");
+            NegotiationError::WriteError
+          })?;
+        link
+          .flush()
+          .await
+          .map_err(|_| NegotiationError::WriteError)?;
+      }
+
       tracing::trace!("awaiting remote protocol service acceptance");
       // Await acceptance of address by a service, or refusal if none are compatible
       let accepted = link
         .read_u8()
         .await
         .map_err(|_| NegotiationError::ReadError)?;
-      if accepted > 0 {
-        // For v0, this byte doesn't carry any useful info beyond accepted or not
-        tracing::trace!(
-          code = accepted,
-          "address refused by remote protocol services"
-        );
-        Err(NegotiationError::Refused)
-      } else {
-        tracing::trace!("address accepted by remote protocol services");
-        Ok(link)
+      match accepted {
+        ADDRESS_ACCEPTED if protocol_version == 0 => {
+          tracing::trace!("address accepted by remote protocol services");
+          Ok((link, 0))
+        }
+        ADDRESS_ACCEPTED => {
+          let service_version = link
+            .read_u8()
+            .await
+            .map_err(|_| NegotiationError::ReadError)?;
+          if !service_versions.contains(&service_version) {
+            tracing::trace!(
+              service_version,
+              "remote selected a service version which was not offered"
+            );
+            return Err(NegotiationError::ProtocolViolation);
+          }
+          tracing::trace!(
+            service_version,
+            "address accepted by remote protocol services"
+          );
+          Ok((link, service_version))
+        }
+        code if protocol_version == 0 => {
+          // For v0, this byte doesn't carry any useful info beyond accepted or not
+          tracing::trace!(code, "address refused by remote protocol services");
+          Err(NegotiationError::Refused)
+        }
+        SERVICE_VERSION_UNSUPPORTED => {
+          read_refusal_message(&mut link).await?;
+          tracing::trace!("no service version in common with remote protocol service");
+          Err(NegotiationError::UnsupportedServiceVersion)
+        }
+        code => {
+          let message = read_refusal_message(&mut link).await?;
+          tracing::trace!(
+            code,
+            ?message,
+            "address refused by remote protocol services"
+          );
+          match RefusalReason::from_code(code) {
+            Some(reason) => Err(Refusal { reason, message }.into()),
+            // Codes from later versions are treated as a generic refusal
+            None => Err(NegotiationError::Refused),
+          }
+        }
       }
     }
     .instrument(negotiation_span)
   }
+
+  /// Performs negotiation, retrying with only v0 offered over a link from `reopen` if the remote
+  /// selects v0 despite being offered a later version
+  ///
+  /// A remote selecting v0 may be a v0 service, which closes links offering later versions, so
+  /// reaching it costs the additional link.
+  pub async fn negotiate_with_fallback<S, AE, F, Fut>(
+    self,
+    addr: RouteAddress,
+    link: S,
+    reopen: F,
+  ) -> Result<(S, ServiceVersion), RoutingError<AE>>
+  where
+    S: TunnelStream + Send,
+    for<'a> &'a mut S: TunnelStream + Send,
+    AE: Debug,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<S, RoutingError<AE>>>,
+  {
+    let (protocol_versions, service_versions, headers) = self.prepare();
+    let fallback = (protocol_versions.contains(&0) && *protocol_versions.end() > 0).then(|| Self {
+      protocol_versions: Some(0..=0),
+      service_versions: service_versions.clone(),
+      headers: headers.clone(),
+    });
+    let negotiator = Self {
+      protocol_versions: Some(protocol_versions),
+      service_versions,
+      headers,
+    };
+    match (
+      negotiator.negotiate::<_, AE>(addr.clone(), link).await,
+      fallback,
+    ) {
+      (Err(NegotiationError::UnsupportedProtocolVersion), Some(fallback)) => {
+        tracing::debug!(
+          ?addr,
+          "Remote only speaks negotiation v0; retrying over a new link"
+        );
+        let link = reopen().await?;
+        Ok(fallback.negotiate::<_, AE>(addr, link).await?)
+      }
+      (negotiated, _) => Ok(negotiated?),
+    }
+  }
 }
 
 pub struct NegotiationService<ServiceRegistry: ?Sized> {
   service_registry: Arc<ServiceRegistry>,
+  protocol_versions: RangeInclusive<u8>,
+  shutdown: Option<CancellationListener>,
 }
 
 pub type ArcService<TServiceError> =
@@ -210,7 +693,30 @@ pub type ArcService<TServiceError> =
 
 impl<R: ?Sized> NegotiationService<R> {
   pub fn new(service_registry: Arc<R>) -> Self {
-    Self { service_registry }
+    Self {
+      service_registry,
+      protocol_versions: SUPPORTED_PROTOCOL_VERSIONS,
+      shutdown: None,
+    }
+  }
+
+  /// Refuses addresses as [RefusalReason::ShuttingDown] once the given listener is cancelled
+  pub fn with_shutdown_listener(mut self, shutdown: CancellationListener) -> Self {
+    self.shutdown = Some(shutdown);
+    self
+  }
+
+  /// Limits the negotiation protocol versions accepted from the remote
+  ///
+  /// Panics if `protocol_versions` is empty or is not a subset of [SUPPORTED_PROTOCOL_VERSIONS].
+  pub fn with_protocol_versions(mut self, protocol_versions: RangeInclusive<u8>) -> Self {
+    assert!(
+      is_supported_range(&protocol_versions),
+      "Unsupported negotiation protocol version range {:?}",
+      protocol_versions
+    );
+    self.protocol_versions = protocol_versions;
+    self
   }
 }
 
@@ -218,7 +724,8 @@ impl<R> NegotiationService<R>
 where
   R: ServiceRegistry + Send + Sync + ?Sized,
 {
-  /// Performs negotiation, returning the stream if successful
+  /// Performs negotiation, returning the stream if successful, along with the address,
+  /// the service version selected for it, any headers sent by the client, and the service.
   ///
   /// If the negotiation task is dropped, the stream is dropped in an indeterminate state.
   /// In scenarios involving an owned stream, this will drop the stream, otherwise the
@@ -231,7 +738,13 @@ where
   ) -> BoxFuture<
     'stream,
     Result<
-      (S, RouteAddress, ArcService<<R as ServiceRegistry>::Error>),
+      (
+        S,
+        RouteAddress,
+        ServiceVersion,
+        RequestHeaders,
+        ArcService<<R as ServiceRegistry>::Error>,
+      ),
       NegotiationError<anyhow::Error>,
     >,
   >
@@ -241,56 +754,119 @@ where
     for<'a> &'a mut S: TunnelStream + Send,
     TTunnel: Tunnel + 'static,
   {
-    const CURRENT_PROTOCOL_VERSION: u8 = 0u8;
     let service_registry = Arc::clone(&self.service_registry);
+    let protocol_versions = self.protocol_versions.clone();
+    let shutdown = self.shutdown.clone();
     let tunnel_id = *tunnel.id();
     async move {
       tracing::trace!("performing negotiation protocol handshake");
-      let remote_version = protocol_magic(&mut link, CURRENT_PROTOCOL_VERSION).await?;
-      // TODO: Consider adding a confirmation for negotiation protocol acceptance here
+      let protocol_version = service_handshake(&mut link, &protocol_versions).await?;
+      tracing::trace!(protocol_version, "negotiation protocol handshake complete");
+
+      // Address must be sent as a frame in v0 and v1
+      let raw_addr = crate::util::framed::read_frame(&mut link, Some(2048))
+        .await
+        .map_err(|_| NegotiationError::ProtocolViolation)?;
 
-      if remote_version > 0 {
-        // This should map to multiple supported versions, where possible
+      let (offered_service_versions, headers) = if protocol_version > 0 {
+        // v1+ clients offer the range of service versions they support after the address,
+        // followed by their request headers as a JSON object in a frame
+        let mut offered = [0u8; 2];
+        link
+          .read_exact(&mut offered)
+          .await
+          .map_err(|_| NegotiationError::ReadError)?;
+        let headers: RequestHeaders =
+          crate::util::framed::read_framed_json(&mut link, Some(MAX_HEADERS_LENGTH))
+            .await
+            .map_err(|_| NegotiationError::ProtocolViolation)?;
+        (offered[0]..=offered[1], headers)
+      } else {
+        // v0 has no service versioning or headers, so clients always speak the initial version
+        (0..=0, RequestHeaders::new())
+      };
+      if offered_service_versions.is_empty() {
         tracing::trace!(
-          version = remote_version,
-          "unsupported remote protocol version"
+          ?offered_service_versions,
+          "remote offered no service versions"
         );
-        return Err(NegotiationError::UnsupportedProtocolVersion);
+        return Err(NegotiationError::ProtocolViolation);
       }
 
-      let addr: RouteAddress = crate::util::framed::read_frame(&mut link, Some(2048))
-        .await
-        .map_err(|_| NegotiationError::ProtocolViolation) // Address must be sent as a frame in v0
-        // Addresses must be valid UTF-8
-        .and_then(|raw| String::from_utf8(raw).map_err(|_| NegotiationError::ProtocolViolation))
-        // Addresses must be legal SlashAddrs
-        .and_then(|raw| raw.parse().map_err(|_| NegotiationError::ProtocolViolation))?;
+      // Addresses must be valid UTF-8, and must be legal SlashAddrs
+      let addr: RouteAddress = match String::from_utf8(raw_addr)
+        .ok()
+        .and_then(|raw| raw.parse().ok())
+      {
+        Some(addr) => addr,
+        // v0 clients are not told why their address was refused, so this is a protocol violation
+        None if protocol_version == 0 => return Err(NegotiationError::ProtocolViolation),
+        None => {
+          tracing::trace!("refusing unparseable address");
+          let refusal = Refusal::with_message(
+            RefusalReason::BadAddress,
+            "Address must be a UTF-8 route address",
+          );
+          write_refusal(&mut link, protocol_version, &refusal).await?;
+          return Err(refusal.into());
+        }
+      };
+
+      if shutdown.map_or(false, |shutdown| shutdown.is_cancelled()) {
+        tracing::trace!(?addr, "refusing address during shutdown");
+        let refusal = Refusal::new(RefusalReason::ShuttingDown);
+        write_refusal(&mut link, protocol_version, &refusal).await?;
+        return Err(refusal.into());
+      }
 
       tracing::trace!("searching service registry for address handlers");
-      let found = service_registry.find_service(&addr, &(Arc::new(tunnel) as Arc<_>));
+      let found = service_registry.try_find_service(&addr, &(Arc::new(tunnel) as Arc<_>));
 
-      match found {
-        None => {
+      let service = match found {
+        Err(refusal) => {
           // Write refusal
           // v0 calls for a non-zero u8 to be written to the stream to refuse an address
-          tracing::trace!(?addr, "refusing address");
-          link
-            .write_u8(1)
-            .await
-            .map_err(|_| NegotiationError::WriteError)?;
-          Err(NegotiationError::Refused)
+          tracing::trace!(?addr, reason = ?refusal.reason, "refusing address");
+          write_refusal(&mut link, protocol_version, &refusal).await?;
+          return Err(refusal.into());
         }
-        Some(service) => {
-          // Write acceptance
-          // v0 calls for a 0u8 to be written to the stream to accept an address
-          tracing::trace!("accepting address");
-          link
-            .write_u8(0)
-            .await
-            .map_err(|_| NegotiationError::WriteError)?;
-          Ok((link, addr, service))
-        }
-      }
+        Ok(service) => service,
+      };
+
+      let service_version =
+        match highest_common_version(&offered_service_versions, &service.service_versions()) {
+          None => {
+            tracing::trace!(
+              ?addr,
+              ?offered_service_versions,
+              "refusing address due to unsupported service versions"
+            );
+            write_refusal_code(
+              &mut link,
+              protocol_version,
+              SERVICE_VERSION_UNSUPPORTED,
+              None,
+            )
+            .await?;
+            return Err(NegotiationError::UnsupportedServiceVersion);
+          }
+          Some(service_version) => service_version,
+        };
+
+      // Write acceptance
+      // v0 calls for a 0u8 to be written to the stream to accept an address
+      // v1+ follows acceptance with the selected service version
+      tracing::trace!(service_version, "accepting address");
+      let acceptance: &[u8] = if protocol_version > 0 {
+        &[ADDRESS_ACCEPTED, service_version]
+      } else {
+        &[ADDRESS_ACCEPTED]
+      };
+      link
+        .write_all(acceptance)
+        .await
+        .map_err(|_| NegotiationError::WriteError)?;
+      Ok((link, addr, service_version, headers, service))
     }
     .instrument(tracing::trace_span!("protocol_negotiation_service", source_tunnel=?tunnel_id))
     .boxed()
@@ -298,18 +874,28 @@ where
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
   use futures::{FutureExt, TryStreamExt};
-  use std::{sync::Arc, time::Duration};
-  use tokio::time::timeout;
-
-  use super::{ArcService, NegotiationClient, NegotiationError, NegotiationService};
-  use crate::common::protocol::{
-    traits::ServiceRegistry,
-    tunnel::{
-      duplex::EntangledTunnels, ArcTunnel, Tunnel, TunnelDownlink, TunnelIncomingType, TunnelUplink,
+  use std::{assert_matches::assert_matches, ops::RangeInclusive, sync::Arc, time::Duration};
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+  };
+
+  use super::{
+    ArcService, NegotiationClient, NegotiationError, NegotiationService, Refusal, RefusalReason,
+    SNOCAT_NEGOTIATION_MAGIC, SUPPORTED_PROTOCOL_VERSIONS,
+  };
+  use crate::{
+    common::protocol::{
+      traits::ServiceRegistry,
+      tunnel::{
+        duplex::EntangledTunnels, ArcTunnel, Tunnel, TunnelDownlink, TunnelIncomingType,
+        TunnelUplink,
+      },
+      RequestHeaders, RouteAddress, Service, ServiceVersion, DEFAULT_SERVICE_VERSIONS,
     },
-    Service,
+    util::{cancellation::CancellationListener, tunnel_stream::WrappedStream},
   };
 
   struct TestServiceRegistry {
@@ -344,6 +930,8 @@ mod tests {
     fn handle(
       &'_ self,
       _addr: crate::common::protocol::RouteAddress,
+      _version: ServiceVersion,
+      _headers: RequestHeaders,
       _stream: Box<dyn crate::util::tunnel_stream::TunnelStream + Send + 'static>,
       _tunnel: ArcTunnel,
     ) -> futures::future::BoxFuture<
@@ -363,7 +951,6 @@ mod tests {
       .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
       .finish();
     tracing::subscriber::with_default(collector, || async move {
-      const TEST_ADDR: &str = "/test/addr";
       let service_registry = TestServiceRegistry {
         services: vec![Arc::new(NoOpServiceAcceptAll)],
       };
@@ -401,11 +988,11 @@ mod tests {
           .expect("Must fetch next connection");
         let server_stream = match server_stream {
           Some(TunnelIncomingType::BiStream(s)) => s,
-          #[allow(unreachable_patterns)]
           Some(_other) => unreachable!("Non-bistream opened to the test server"),
           None => panic!("No stream was opened to the test server"),
         };
-        let (_stream, addr, service) = service.negotiate(server_stream, listener).await?;
+        let (_stream, addr, _version, _headers, service) =
+          service.negotiate(server_stream, listener).await?;
         Result::<_, NegotiationError<anyhow::Error>>::Ok((addr, service))
       };
       let fut = futures::future::try_join(client_future, server_future);
@@ -415,4 +1002,425 @@ mod tests {
     })
     .await;
   }
+
+  const TEST_ADDR: &str = "/test/addr";
+
+  struct VersionedServiceAcceptAll(RangeInclusive<ServiceVersion>);
+
+  impl Service for VersionedServiceAcceptAll {
+    type Error = anyhow::Error;
+
+    fn accepts(&self, _addr: &RouteAddress, _tunnel: &ArcTunnel) -> bool {
+      true
+    }
+
+    fn service_versions(&self) -> RangeInclusive<ServiceVersion> {
+      self.0.clone()
+    }
+
+    fn handle(
+      &'_ self,
+      _addr: RouteAddress,
+      _version: ServiceVersion,
+      _headers: RequestHeaders,
+      _stream: Box<dyn crate::util::tunnel_stream::TunnelStream + Send + 'static>,
+      _tunnel: ArcTunnel,
+    ) -> futures::future::BoxFuture<
+      '_,
+      Result<(), crate::common::protocol::ServiceError<Self::Error>>,
+    > {
+      futures::future::ready(Ok(())).boxed()
+    }
+  }
+
+  /// Runs negotiation between the given client and service over an in-memory stream,
+  /// returning the service versions each side selected
+  async fn run_negotiation<R>(
+    client: NegotiationClient,
+    service: NegotiationService<R>,
+  ) -> (
+    Result<ServiceVersion, NegotiationError<anyhow::Error>>,
+    Result<(RouteAddress, ServiceVersion), NegotiationError<anyhow::Error>>,
+  )
+  where
+    R: ServiceRegistry + Send + Sync + 'static,
+  {
+    let EntangledTunnels { listener, .. } = super::super::tunnel::duplex::channel();
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+
+    let client_future = client
+      .negotiate(
+        TEST_ADDR.parse().expect("Illegal test address"),
+        client_stream,
+      )
+      .map(|res| res.map(|(_stream, version)| version));
+    let server_future = service
+      .negotiate(server_stream, listener)
+      .map(|res| res.map(|(_stream, addr, version, _headers, _service)| (addr, version)));
+    let fut = futures::future::join(client_future, server_future);
+    timeout(Duration::from_secs(5), fut)
+      .await
+      .expect("Must not time out")
+  }
+
+  /// Runs negotiation between the given client and a service supporting the given service
+  /// versions over an in-memory stream, returning the service versions each side selected
+  async fn negotiate_with(
+    client: NegotiationClient,
+    service_protocol_versions: RangeInclusive<u8>,
+    service_versions: RangeInclusive<ServiceVersion>,
+  ) -> (
+    Result<ServiceVersion, NegotiationError<anyhow::Error>>,
+    Result<(RouteAddress, ServiceVersion), NegotiationError<anyhow::Error>>,
+  ) {
+    let service_registry = TestServiceRegistry {
+      services: vec![Arc::new(VersionedServiceAcceptAll(service_versions))],
+    };
+    let service = NegotiationService::new(Arc::new(service_registry))
+      .with_protocol_versions(service_protocol_versions);
+    run_negotiation(client, service).await
+  }
+
+  /// Runs negotiation with the given protocol version ranges and default service versions
+  async fn negotiate_versions(
+    client_versions: RangeInclusive<u8>,
+    service_versions: RangeInclusive<u8>,
+  ) -> (
+    Result<ServiceVersion, NegotiationError<anyhow::Error>>,
+    Result<(RouteAddress, ServiceVersion), NegotiationError<anyhow::Error>>,
+  ) {
+    let client = NegotiationClient::new().with_protocol_versions(client_versions);
+    negotiate_with(client, service_versions, DEFAULT_SERVICE_VERSIONS).await
+  }
+
+  /// A client offering every supported protocol version, as once all services are upgraded
+  fn v1_client() -> NegotiationClient {
+    NegotiationClient::new().with_protocol_versions(SUPPORTED_PROTOCOL_VERSIONS)
+  }
+
+  /// Test that a v1 service accepts clients which only speak v0
+  #[tokio::test]
+  async fn negotiate_v0_client() {
+    let (client_res, service_res) = negotiate_versions(0..=0, 0..=1).await;
+    client_res.expect("v0 client must be accepted");
+    assert_eq!(&service_res.unwrap().0.to_string(), TEST_ADDR);
+  }
+
+  /// Test that peers settle on the highest common version
+  #[tokio::test]
+  async fn negotiate_common_version() {
+    let (client_res, service_res) = negotiate_versions(1..=1, 0..=1).await;
+    client_res.expect("v1 client must be accepted");
+    assert_eq!(&service_res.unwrap().0.to_string(), TEST_ADDR);
+  }
+
+  /// Test that both peers fail when their supported versions do not overlap
+  #[tokio::test]
+  async fn negotiate_version_mismatch() {
+    let (client_res, service_res) = negotiate_versions(0..=0, 1..=1).await;
+    assert_matches!(
+      client_res,
+      Err(NegotiationError::UnsupportedProtocolVersion)
+    );
+    assert_matches!(
+      service_res,
+      Err(NegotiationError::UnsupportedProtocolVersion)
+    );
+  }
+
+  /// Test that the highest service version supported by both client and service is selected
+  #[tokio::test]
+  async fn negotiate_service_version() {
+    let client = v1_client().with_service_versions(0..=2);
+    let (client_res, service_res) = negotiate_with(client, 0..=1, 1..=3).await;
+    assert_eq!(client_res.unwrap(), 2);
+    assert_eq!(service_res.unwrap().1, 2);
+  }
+
+  /// Test that services refuse clients with which they share no service version
+  #[tokio::test]
+  async fn negotiate_service_version_mismatch() {
+    let client = v1_client().with_service_versions(3..=4);
+    let (client_res, service_res) = negotiate_with(client, 0..=1, 0..=2).await;
+    assert_matches!(client_res, Err(NegotiationError::UnsupportedServiceVersion));
+    assert_matches!(
+      service_res,
+      Err(NegotiationError::UnsupportedServiceVersion)
+    );
+  }
+
+  /// Test that v0 negotiation, lacking service versioning, only agrees upon the initial version
+  #[tokio::test]
+  async fn negotiate_v0_service_version() {
+    let client = NegotiationClient::new()
+      .with_protocol_versions(0..=0)
+      .with_service_versions(0..=1);
+    let (client_res, service_res) = negotiate_with(client, 0..=1, 0..=1).await;
+    assert_eq!(client_res.unwrap(), 0);
+    assert_eq!(service_res.unwrap().1, 0);
+
+    let client = NegotiationClient::new().with_protocol_versions(0..=0);
+    let (client_res, service_res) = negotiate_with(client, 0..=1, 1..=1).await;
+    // v0 clients are only capable of understanding a generic refusal
+    assert_matches!(client_res, Err(NegotiationError::Refused));
+    assert_matches!(
+      service_res,
+      Err(NegotiationError::UnsupportedServiceVersion)
+    );
+  }
+
+  /// Emulates negotiation by a service that only speaks v0, accepting any address
+  ///
+  /// Returns the address, or `None` if the client offered a later version and was refused.
+  pub(crate) async fn emulate_v0_service(
+    mut stream: WrappedStream,
+  ) -> anyhow::Result<Option<String>> {
+    // v0 services send their magic without waiting for the client
+    stream.write_all(SNOCAT_NEGOTIATION_MAGIC).await?;
+    stream.write_u8(0).await?;
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header).await?;
+    assert_eq!(&header[..4], SNOCAT_NEGOTIATION_MAGIC);
+    if header[4] != 0 {
+      // Any other version is refused by dropping the stream
+      return Ok(None);
+    }
+    let addr = crate::util::framed::read_frame(&mut stream, Some(2048)).await?;
+    stream.write_u8(0).await?;
+    Ok(Some(String::from_utf8(addr)?))
+  }
+
+  /// Negotiates between the given client and an emulation of a service that only speaks v0
+  async fn negotiate_emulated_v0_service(client: NegotiationClient) {
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+    let client_future = client.negotiate::<_, anyhow::Error>(
+      TEST_ADDR.parse().expect("Illegal test address"),
+      client_stream,
+    );
+    let fut = futures::future::join(client_future, emulate_v0_service(server_stream));
+    let (client_res, server_res) = timeout(Duration::from_secs(5), fut)
+      .await
+      .expect("Must not time out");
+    client_res.expect("v0 service must accept the client");
+    assert_eq!(server_res.unwrap().as_deref(), Some(TEST_ADDR));
+  }
+
+  /// Test that a client limited to v0 can negotiate with a service that only speaks v0
+  #[tokio::test]
+  async fn negotiate_v0_service() {
+    negotiate_emulated_v0_service(NegotiationClient::new().with_protocol_versions(0..=0)).await;
+  }
+
+  /// Test that default clients can reach services which have yet to be upgraded
+  #[tokio::test]
+  async fn negotiate_v0_service_by_default() {
+    negotiate_emulated_v0_service(NegotiationClient::new()).await;
+
+    let (client_res, service_res) =
+      negotiate_with(NegotiationClient::new(), 0..=0, DEFAULT_SERVICE_VERSIONS).await;
+    assert_eq!(client_res.unwrap(), 0);
+    assert_eq!(&service_res.unwrap().0.to_string(), TEST_ADDR);
+  }
+
+  /// Test that clients offering v1 reach v0 services by retrying over a new link with v0 alone
+  #[tokio::test]
+  async fn negotiate_v0_service_fallback() {
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+    let (retry_client_stream, retry_server_stream) = WrappedStream::duplex(8192);
+    let client_future = v1_client().negotiate_with_fallback::<_, anyhow::Error, _, _>(
+      TEST_ADDR.parse().expect("Illegal test address"),
+      client_stream,
+      || futures::future::ready(Ok(retry_client_stream)),
+    );
+    let server_future = async move {
+      let refused = emulate_v0_service(server_stream).await?;
+      let retried = emulate_v0_service(retry_server_stream).await?;
+      anyhow::Result::<_>::Ok((refused, retried))
+    };
+    let fut = futures::future::join(client_future, server_future);
+    let (client_res, server_res) = timeout(Duration::from_secs(5), fut)
+      .await
+      .expect("Must not time out");
+    assert_eq!(client_res.unwrap().1, 0);
+    assert_eq!(server_res.unwrap(), (None, Some(TEST_ADDR.to_string())));
+
+    // v1 services are not retried, as they select v1
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+    let EntangledTunnels { listener, .. } = super::super::tunnel::duplex::channel();
+    let service_registry = TestServiceRegistry {
+      services: vec![Arc::new(VersionedServiceAcceptAll(0..=1))],
+    };
+    let service = NegotiationService::new(Arc::new(service_registry));
+    let client_future = v1_client()
+      .with_service_versions(0..=1)
+      .negotiate_with_fallback::<_, anyhow::Error, _, _>(
+        TEST_ADDR.parse().expect("Illegal test address"),
+        client_stream,
+        || async { panic!("v1 services must not be retried") },
+      );
+    let (client_res, service_res) =
+      futures::future::join(client_future, service.negotiate(server_stream, listener)).await;
+    assert_eq!(client_res.unwrap().1, 1);
+    service_res.expect("Service negotiation must succeed");
+  }
+
+  /// Refuses every address for the reason it was constructed with
+  pub(crate) struct RefusingServiceRegistry(pub(crate) Refusal);
+
+  impl ServiceRegistry for RefusingServiceRegistry {
+    type Error = anyhow::Error;
+
+    fn find_service(
+      self: Arc<Self>,
+      _addr: &RouteAddress,
+      _tunnel: &ArcTunnel,
+    ) -> Option<ArcService<Self::Error>> {
+      None
+    }
+
+    fn try_find_service(
+      self: Arc<Self>,
+      _addr: &RouteAddress,
+      _tunnel: &ArcTunnel,
+    ) -> Result<ArcService<Self::Error>, Refusal> {
+      Err(self.0.clone())
+    }
+  }
+
+  /// Test that refusal reasons and messages are passed along to v1 clients
+  #[tokio::test]
+  async fn negotiate_refusal() {
+    let refusal = Refusal::with_message(RefusalReason::Forbidden, "Denied by ACL");
+    let service = NegotiationService::new(Arc::new(RefusingServiceRegistry(refusal)));
+    let (client_res, service_res) = run_negotiation(v1_client(), service).await;
+    assert_matches!(client_res, Err(NegotiationError::Forbidden(Some(message))) if message == "Denied by ACL");
+    assert_matches!(service_res, Err(NegotiationError::Forbidden(_)));
+
+    let refusal = Refusal::new(RefusalReason::Overloaded);
+    let service = NegotiationService::new(Arc::new(RefusingServiceRegistry(refusal)));
+    let (client_res, _) = run_negotiation(v1_client(), service).await;
+    assert_matches!(client_res, Err(NegotiationError::Overloaded(None)));
+  }
+
+  /// Test that v0 clients see refusals of any reason as a generic refusal
+  #[tokio::test]
+  async fn negotiate_refusal_v0() {
+    let refusal = Refusal::with_message(RefusalReason::Forbidden, "Denied by ACL");
+    let service = NegotiationService::new(Arc::new(RefusingServiceRegistry(refusal)));
+    let client = NegotiationClient::new().with_protocol_versions(0..=0);
+    let (client_res, service_res) = run_negotiation(client, service).await;
+    assert_matches!(client_res, Err(NegotiationError::Refused));
+    assert_matches!(service_res, Err(NegotiationError::Forbidden(_)));
+  }
+
+  /// Test that services refuse new addresses once shutdown has been requested
+  #[tokio::test]
+  async fn negotiate_refusal_shutting_down() {
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    shutdown.cancel();
+    let service_registry = TestServiceRegistry {
+      services: vec![Arc::new(NoOpServiceAcceptAll)],
+    };
+    let service = NegotiationService::new(Arc::new(service_registry))
+      .with_shutdown_listener(CancellationListener::from(&shutdown));
+    let (client_res, service_res) = run_negotiation(v1_client(), service).await;
+    assert_matches!(client_res, Err(NegotiationError::ShuttingDown(None)));
+    assert_matches!(service_res, Err(NegotiationError::ShuttingDown(None)));
+  }
+
+  /// Test that structured refusals map to their own routing errors
+  #[test]
+  fn refusal_routing_errors() {
+    use crate::common::protocol::service::RoutingError;
+    let e: RoutingError<anyhow::Error> =
+      NegotiationError::<anyhow::Error>::Forbidden(Some("Denied".into())).into();
+    assert_matches!(e, RoutingError::Forbidden(Some(_)));
+    let e: RoutingError<anyhow::Error> = NegotiationError::<anyhow::Error>::BadAddress(None).into();
+    assert_matches!(e, RoutingError::BadAddress(None));
+    let e: RoutingError<anyhow::Error> = NegotiationError::<anyhow::Error>::Refused.into();
+    assert_matches!(e, RoutingError::NegotiationError(NegotiationError::Refused));
+  }
+
+  /// Test that request headers are provided to v1 services, and dropped for v0 services, with v1
+  /// offered for them unless the protocol versions are set explicitly
+  #[tokio::test]
+  async fn negotiate_headers() {
+    let mut headers = RequestHeaders::new();
+    headers.insert("tenant".into(), "contoso".into());
+    headers.insert(
+      "traceparent".into(),
+      "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".into(),
+    );
+
+    for (protocol_versions, expected) in [
+      (None, headers.clone()),
+      (Some(0..=1), headers.clone()),
+      (Some(0..=0), RequestHeaders::new()),
+    ] {
+      let service_registry = TestServiceRegistry {
+        services: vec![Arc::new(NoOpServiceAcceptAll)],
+      };
+      let service = NegotiationService::new(Arc::new(service_registry));
+      let mut client = NegotiationClient::new().with_headers(headers.clone());
+      if let Some(protocol_versions) = protocol_versions {
+        client = client.with_protocol_versions(protocol_versions);
+      }
+      let EntangledTunnels { listener, .. } = super::super::tunnel::duplex::channel();
+      let (client_stream, server_stream) = WrappedStream::duplex(8192);
+      let client_future = client.negotiate::<_, anyhow::Error>(
+        TEST_ADDR.parse().expect("Illegal test address"),
+        client_stream,
+      );
+      let server_future = service.negotiate(server_stream, listener);
+      let (client_res, server_res) = futures::future::join(client_future, server_future).await;
+      client_res.expect("Client negotiation must succeed");
+      let (_stream, _addr, _version, received, _service) =
+        server_res.expect("Service negotiation must succeed");
+      assert_eq!(received, expected);
+    }
+  }
+
+  /// Test that the trace context of the span opening a request is sent when tracing at INFO level
+  #[cfg(feature = "trace-context")]
+  #[tokio::test]
+  async fn negotiate_trace_context() {
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use tracing_futures::Instrument;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt};
+
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+    let subscriber = tracing_subscriber::registry()
+      .with(LevelFilter::INFO)
+      .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("snocat-test")));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let service_registry = TestServiceRegistry {
+      services: vec![Arc::new(NoOpServiceAcceptAll)],
+    };
+    let service = NegotiationService::new(Arc::new(service_registry));
+    let EntangledTunnels { listener, .. } = super::super::tunnel::duplex::channel();
+    let (client_stream, server_stream) = WrappedStream::duplex(8192);
+    let request_span = tracing::info_span!("request");
+    // Routers negotiate from within the span of the request being routed
+    let client_future = async {
+      v1_client()
+        .negotiate::<_, anyhow::Error>(
+          TEST_ADDR.parse().expect("Illegal test address"),
+          client_stream,
+        )
+        .await
+    }
+    .instrument(request_span.clone());
+    let server_future = service.negotiate(server_stream, listener);
+    let (client_res, server_res) = futures::future::join(client_future, server_future).await;
+    client_res.expect("Client negotiation must succeed");
+    let (_stream, _addr, _version, headers, _service) =
+      server_res.expect("Service negotiation must succeed");
+    let remote = super::super::trace_context::extract_context(&headers);
+    assert_eq!(
+      remote.span().span_context().trace_id(),
+      request_span.context().span().span_context().trace_id()
+    );
+  }
 }
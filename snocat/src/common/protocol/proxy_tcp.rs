@@ -17,7 +17,7 @@ use super::{
   address::RouteAddressParseError,
   service::{Client, ClientResult, ProtocolInfo, RouteAddressBuilder},
   tunnel::ArcTunnel,
-  RouteAddress, Service, ServiceError,
+  RequestHeaders, RouteAddress, Service, ServiceError, ServiceVersion,
 };
 use crate::{
   common::protocol::service::ClientError,
@@ -82,11 +82,14 @@ where
 
   type Future = BoxFuture<'stream, ClientResult<'stream, Self, TStream>>;
 
-  fn handle(mut self, _addr: RouteAddress, tunnel: TStream) -> Self::Future {
+  fn handle(
+    mut self,
+    _addr: RouteAddress,
+    _version: ServiceVersion,
+    tunnel: TStream,
+    _parent_tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
     let fut = async move {
-      // TODO: Read protocol version here, and ServiceError::Refused if unsupported
-      // TODO: Send protocol version here, allow other side to refuse if unsupported
-      // If a confirmation of support is received by the reading side, resume as supported version
       let (mut tunr, mut tunw) = tokio::io::split(tunnel);
       match proxy_generic_tokio_streams((&mut self.send, &mut self.recv), (&mut tunw, &mut tunr))
         .await
@@ -478,6 +481,8 @@ impl Service for TcpStreamService {
   fn handle<'a>(
     &'a self,
     addr: RouteAddress,
+    _version: ServiceVersion,
+    _headers: RequestHeaders,
     stream: Box<dyn TunnelStream + Send + 'static>,
     _tunnel_id: ArcTunnel,
   ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>> {
@@ -491,9 +496,6 @@ impl Service for TcpStreamService {
       Ok(target) => target,
     };
     let fut = async move {
-      // TODO: Read protocol version here, and ServiceError::Refused if unsupported
-      // TODO: Send protocol version here, allow other side to refuse if unsupported
-      // If a confirmation of support is received by the reading side, resume as supported version
       let addrs = self
         .resolve(target)
         .await
@@ -0,0 +1,982 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Relays UDP flows over tunnels, framed over a negotiated stream or carried as tunnel datagrams
+//!
+//! Each flow is a single negotiated link. Immediately after negotiation, the client requests a
+//! transport by sending [TRANSPORT_STREAM], or [TRANSPORT_DATAGRAM] followed by a [FlowId]; the
+//! service replies with the transport it selected. Payloads are then framed on the link with a
+//! 16-bit length prefix, except that in datagram mode any payload which fits within the tunnel's
+//! datagram limit is sent as a tunnel datagram instead. A flow ends when either side closes
+//! the link, or when no payloads travel in either direction for its idle timeout.
+use bytes::Bytes;
+use futures::{
+  future::{self, BoxFuture, FutureExt},
+  stream::{self, BoxStream},
+  SinkExt, StreamExt, TryStreamExt,
+};
+use std::{
+  collections::HashMap,
+  convert::{Infallible, TryFrom, TryInto},
+  fmt::Display,
+  io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  str::FromStr,
+  sync::Arc,
+  time::Duration,
+};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::UdpSocket,
+  sync::mpsc,
+};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tracing_futures::Instrument;
+
+use super::{
+  address::RouteAddressParseError,
+  datagram::{DatagramDispatcher, DatagramFlow, FlowId, FLOW_ID_LENGTH},
+  proxy_tcp::DnsTarget,
+  service::{Client, ClientError, ClientResult, ProtocolInfo, RouteAddressBuilder},
+  tunnel::{ArcTunnel, TunnelError},
+  RequestHeaders, RouteAddress, Service, ServiceError, ServiceVersion,
+};
+use crate::util::tunnel_stream::TunnelStream;
+
+/// Flows are closed after this long without a payload in either direction
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest UDP payload relayed in either direction
+pub const MAX_UDP_PAYLOAD_LENGTH: usize = u16::MAX as usize;
+
+/// Requests or selects payloads framed exclusively over the negotiated link
+pub const TRANSPORT_STREAM: u8 = 0;
+/// Requests or selects tunnel datagrams, with the link carrying only oversized payloads
+pub const TRANSPORT_DATAGRAM: u8 = 1;
+
+/// Payloads buffered per flow by a [UdpFlowListener] before further arrivals are dropped
+const FLOW_BUFFER_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UdpTarget {
+  Port(u16),
+  SocketAddr(SocketAddr),
+  Dns(DnsTarget),
+}
+
+impl From<DnsTarget> for UdpTarget {
+  fn from(val: DnsTarget) -> Self {
+    UdpTarget::Dns(val)
+  }
+}
+
+impl From<&UdpTarget> for RouteAddress {
+  fn from(target: &UdpTarget) -> Self {
+    let base_addr = RouteAddress::from_iter([UdpProxyService::protocol_name()]);
+    match target {
+      UdpTarget::Port(port) => base_addr.into_suffixed(["udp", port.to_string().as_str()]),
+      UdpTarget::SocketAddr(SocketAddr::V4(s)) => base_addr.into_suffixed([
+        "ip4",
+        s.ip().to_string().as_str(),
+        "udp",
+        s.port().to_string().as_str(),
+      ]),
+      UdpTarget::SocketAddr(SocketAddr::V6(s)) => base_addr.into_suffixed([
+        "ip6",
+        s.ip().to_string().as_str(),
+        "udp",
+        s.port().to_string().as_str(),
+      ]),
+      UdpTarget::Dns(DnsTarget::PreferHigher { host, port }) => {
+        base_addr.into_suffixed(["dns", host.as_str(), "udp", port.to_string().as_str()])
+      }
+      UdpTarget::Dns(DnsTarget::Dns4 { host, port }) => {
+        base_addr.into_suffixed(["dns4", host.as_str(), "udp", port.to_string().as_str()])
+      }
+      UdpTarget::Dns(DnsTarget::Dns6 { host, port }) => {
+        base_addr.into_suffixed(["dns6", host.as_str(), "udp", port.to_string().as_str()])
+      }
+    }
+  }
+}
+
+impl From<UdpTarget> for RouteAddress {
+  fn from(target: UdpTarget) -> Self {
+    (&target).into()
+  }
+}
+
+impl TryFrom<RouteAddress> for UdpTarget {
+  type Error = UdpTargetFormatError;
+
+  fn try_from(value: RouteAddress) -> Result<Self, Self::Error> {
+    (&value).try_into()
+  }
+}
+
+impl TryFrom<&RouteAddress> for UdpTarget {
+  type Error = UdpTargetFormatError;
+
+  fn try_from(value: &RouteAddress) -> Result<Self, Self::Error> {
+    let parts: Vec<&str> =
+      if let Some(stripped) = value.strip_segment_prefix([UdpProxyService::protocol_name()]) {
+        stripped
+      } else {
+        return Err(UdpTargetFormatError::NoMatchingFormat)?;
+      }
+      .take(4)
+      .collect();
+    let (port, parts) = parts
+      .split_last()
+      .ok_or(UdpTargetFormatError::TooFewSegments)?;
+    let port: u16 = port.parse()?;
+    match parts {
+      // `/udp/$PORT`
+      // Implies IPv4 on localhost at the given $Port
+      ["udp"] => Ok(UdpTarget::SocketAddr(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        port,
+      ))),
+      // `/ip4/$IPv4HostAddr/udp/$PORT`
+      ["ip4", addr, "udp"] => addr
+        .parse::<Ipv4Addr>()
+        .map_err(Into::into)
+        .map(|addr| UdpTarget::SocketAddr(SocketAddr::new(IpAddr::V4(addr), port))),
+      // `/ip6/$IPv6Addr/udp/$Port`
+      ["ip6", addr, "udp"] => addr
+        .parse::<Ipv6Addr>()
+        .map_err(Into::into)
+        .map(|addr| UdpTarget::SocketAddr(SocketAddr::new(IpAddr::V6(addr), port))),
+      // `/dns[46]?/$SAN/udp/$Port`
+      [dns_class @ ("dns" | "dns4" | "dns6"), host, "udp"] => {
+        let host = host.to_string();
+        Ok(UdpTarget::Dns(match *dns_class {
+          "dns" => DnsTarget::PreferHigher { host, port },
+          "dns6" => DnsTarget::Dns6 { host, port },
+          "dns4" => DnsTarget::Dns4 { host, port },
+          _ => unreachable!("Checked statically via matcher"),
+        }))
+      }
+      _ => Err(UdpTargetFormatError::NoMatchingFormat),
+    }
+  }
+}
+
+/// Format a [RouteAddress] from a [UdpTarget]
+impl Display for UdpTarget {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let route_address: RouteAddress = self.into();
+    Display::fmt(&route_address, f)
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UdpTargetFormatError {
+  #[error("Not enough segments present to represent valid target")]
+  TooFewSegments,
+  #[error("No supported address type matches the provided format")]
+  NoMatchingFormat,
+  #[error("Port specification invalid")]
+  InvalidPort {
+    #[from]
+    inner: std::num::ParseIntError,
+    #[cfg(feature = "backtrace")]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    backtrace: std::backtrace::Backtrace,
+  },
+  #[error("IP format invalid")]
+  InvalidIP {
+    #[from]
+    inner: std::net::AddrParseError,
+    #[cfg(feature = "backtrace")]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    backtrace: std::backtrace::Backtrace,
+  },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UdpTargetParseError {
+  #[error(transparent)]
+  RouteAddressParseError(#[from] RouteAddressParseError),
+  #[error(transparent)]
+  UdpTargetFormatError(#[from] UdpTargetFormatError),
+}
+
+/// Try to parse a [RouteAddress] into a [UdpTarget]
+///
+/// Expects /udp/<port>, /ip[46]/address/udp/port, or /dns[46]?/address/udp/port,
+/// optionally prefixed with the `proxy-udp` protocol name.
+impl FromStr for UdpTarget {
+  type Err = UdpTargetParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let route_addr = s.parse::<RouteAddress>()?;
+    (&route_addr).try_into().or_else(|e| match e {
+      // If we had an error where the prefix could be missing, retry parsing with it added
+      UdpTargetFormatError::TooFewSegments | UdpTargetFormatError::NoMatchingFormat => {
+        match route_addr.iter_segments().nth(0) {
+          Some("dns" | "dns4" | "dns6" | "ip4" | "ip6" | "udp") => {
+            let prefixed =
+              format!("/{}{}", UdpProxyService::protocol_name(), s).parse::<RouteAddress>()?;
+            Ok(prefixed.try_into()?)
+          }
+          _ => Err(UdpTargetParseError::UdpTargetFormatError(
+            UdpTargetFormatError::NoMatchingFormat,
+          )),
+        }
+      }
+      _ => Err(e.into()),
+    })
+  }
+}
+
+/// The local half of a flow
+enum LocalEndpoint {
+  /// A socket connected to a single peer
+  Connected(Arc<UdpSocket>),
+  /// One peer of a shared socket, whose payloads are demultiplexed by a [UdpFlowListener]
+  Shared {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    inbound: mpsc::Receiver<Bytes>,
+  },
+}
+
+enum LocalSender {
+  Connected(Arc<UdpSocket>),
+  Shared(Arc<UdpSocket>, SocketAddr),
+}
+
+impl LocalSender {
+  async fn send(&self, payload: &[u8]) -> io::Result<()> {
+    let res = match self {
+      LocalSender::Connected(socket) => socket.send(payload).await,
+      LocalSender::Shared(socket, peer) => socket.send_to(payload, peer).await,
+    };
+    match res {
+      Ok(_) => Ok(()),
+      // UDP makes no delivery promises, so an unreachable peer only costs us this payload
+      Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+impl LocalEndpoint {
+  fn split(self) -> (LocalSender, BoxStream<'static, io::Result<Bytes>>) {
+    match self {
+      LocalEndpoint::Connected(socket) => {
+        let incoming = stream::try_unfold(
+          (Arc::clone(&socket), vec![0u8; MAX_UDP_PAYLOAD_LENGTH]),
+          |(socket, mut buffer)| async move {
+            loop {
+              match socket.recv(&mut buffer).await {
+                Ok(length) => {
+                  let payload = Bytes::copy_from_slice(&buffer[..length]);
+                  return Ok(Some((payload, (socket, buffer))));
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err(e),
+              }
+            }
+          },
+        )
+        .boxed();
+        (LocalSender::Connected(socket), incoming)
+      }
+      LocalEndpoint::Shared {
+        socket,
+        peer,
+        inbound,
+      } => {
+        let incoming = tokio_stream::wrappers::ReceiverStream::new(inbound)
+          .map(Ok)
+          .boxed();
+        (LocalSender::Shared(socket, peer), incoming)
+      }
+    }
+  }
+}
+
+enum RelayEvent {
+  Remote(Bytes),
+  Local(Bytes),
+  RemoteClosed,
+  LocalClosed,
+}
+
+fn payload_codec() -> LengthDelimitedCodec {
+  LengthDelimitedCodec::builder()
+    .length_field_length(std::mem::size_of::<u16>())
+    .max_frame_length(MAX_UDP_PAYLOAD_LENGTH)
+    .new_codec()
+}
+
+async fn send_remote<W: AsyncWrite + Unpin>(
+  link: &mut FramedWrite<W, LengthDelimitedCodec>,
+  datagram_path: Option<&(ArcTunnel<'static>, FlowId)>,
+  payload: Bytes,
+) -> io::Result<()> {
+  if let Some((tunnel, flow_id)) = datagram_path {
+    let fits = tunnel
+      .max_datagram_size()
+      .map_or(false, |max| payload.len() + FLOW_ID_LENGTH <= max);
+    if fits {
+      match tunnel.send_datagram(DatagramDispatcher::encode(*flow_id, &payload)) {
+        Ok(()) => return Ok(()),
+        // The datagram path can no longer carry this payload; fall back to the link
+        Err(TunnelError::DatagramTooLarge | TunnelError::DatagramsUnsupported) => (),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, e)),
+      }
+    }
+  }
+  link.send(payload).await
+}
+
+/// Relays payloads between a local endpoint and a negotiated link until either closes or idles out
+///
+/// Returns the byte counts relayed from local to remote and from remote to local, respectively.
+async fn relay_flow<TStream>(
+  link: TStream,
+  local: LocalEndpoint,
+  datagrams: Option<(ArcTunnel<'static>, DatagramFlow)>,
+  idle_timeout: Duration,
+) -> io::Result<(u64, u64)>
+where
+  TStream: AsyncRead + AsyncWrite + Send + Unpin,
+{
+  let (link_reader, link_writer) = tokio::io::split(link);
+  let mut link_writer = FramedWrite::new(link_writer, payload_codec());
+  let remote_frames = FramedRead::new(link_reader, payload_codec())
+    .map_ok(|frame| RelayEvent::Remote(frame.freeze()))
+    .chain(stream::once(future::ready(Ok(RelayEvent::RemoteClosed))));
+  let (datagram_path, remote_datagrams) = match datagrams {
+    Some((tunnel, flow)) => (
+      Some((tunnel, flow.flow_id())),
+      flow.map(|payload| Ok(RelayEvent::Remote(payload))).boxed(),
+    ),
+    None => (None, stream::pending().boxed()),
+  };
+  let (local_sender, local_incoming) = local.split();
+  let local_incoming = local_incoming
+    .map_ok(RelayEvent::Local)
+    .chain(stream::once(future::ready(Ok(RelayEvent::LocalClosed))));
+  let mut events = stream::select(
+    stream::select(remote_frames, remote_datagrams),
+    local_incoming,
+  );
+
+  let (mut local_to_remote, mut remote_to_local) = (0u64, 0u64);
+  loop {
+    let event = match tokio::time::timeout(idle_timeout, events.next()).await {
+      Err(_elapsed) => {
+        tracing::debug!(target = "proxy_udp_idle", "Closing idle UDP flow");
+        break;
+      }
+      Ok(None) => break,
+      Ok(Some(event)) => event?,
+    };
+    match event {
+      RelayEvent::Remote(payload) => {
+        remote_to_local += payload.len() as u64;
+        local_sender.send(&payload).await?;
+      }
+      RelayEvent::Local(payload) => {
+        local_to_remote += payload.len() as u64;
+        send_remote(&mut link_writer, datagram_path.as_ref(), payload).await?;
+      }
+      RelayEvent::RemoteClosed | RelayEvent::LocalClosed => break,
+    }
+  }
+  // Closing our half of the link ends the remote's half of the flow without waiting on its timeout
+  let _ = link_writer.close().await;
+  Ok((local_to_remote, remote_to_local))
+}
+
+pub struct UdpProxyClient {
+  local: LocalEndpoint,
+  idle_timeout: Duration,
+  datagrams: Option<Arc<DatagramDispatcher>>,
+}
+
+impl std::fmt::Debug for UdpProxyClient {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let peer = match &self.local {
+      LocalEndpoint::Connected(socket) => socket.peer_addr().ok(),
+      LocalEndpoint::Shared { peer, .. } => Some(*peer),
+    };
+    f.debug_struct("UdpProxyClient")
+      .field("peer", &peer)
+      .field("idle_timeout", &self.idle_timeout)
+      .field("datagrams", &self.datagrams.is_some())
+      .finish()
+  }
+}
+
+impl UdpProxyClient {
+  /// Relays a socket already connected to the local peer of the flow
+  pub fn connected(socket: UdpSocket) -> Self {
+    Self {
+      local: LocalEndpoint::Connected(Arc::new(socket)),
+      idle_timeout: DEFAULT_IDLE_TIMEOUT,
+      datagrams: None,
+    }
+  }
+
+  pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+    self.idle_timeout = idle_timeout;
+    self
+  }
+
+  /// Requests tunnel datagrams for the flow, receiving them through the given dispatcher
+  ///
+  /// The dispatcher must be the one fed incoming datagrams by the daemon that owns the tunnel.
+  pub fn with_datagram_dispatcher(mut self, dispatcher: Arc<DatagramDispatcher>) -> Self {
+    self.datagrams = Some(dispatcher);
+    self
+  }
+
+  pub fn build_addr(target: UdpTarget) -> RouteAddress {
+    target.into()
+  }
+}
+
+impl ProtocolInfo for UdpProxyService {
+  fn protocol_name() -> &'static str
+  where
+    Self: Sized,
+  {
+    "proxy-udp"
+  }
+}
+
+impl ProtocolInfo for UdpProxyClient {
+  fn protocol_name() -> &'static str
+  where
+    Self: Sized,
+  {
+    UdpProxyService::protocol_name()
+  }
+}
+
+impl RouteAddressBuilder for UdpProxyClient {
+  type Params = UdpTarget;
+  type BuildError = Infallible;
+
+  fn build_addr(args: Self::Params) -> Result<RouteAddress, Self::BuildError>
+  where
+    Self: Sized,
+  {
+    Ok(args.into())
+  }
+}
+
+impl<'stream, TStream> Client<'stream, TStream> for UdpProxyClient
+where
+  TStream: TunnelStream + Send + 'stream,
+{
+  type Response = (u64, u64);
+
+  type Error = io::Error;
+
+  type Future = BoxFuture<'stream, ClientResult<'stream, Self, TStream>>;
+
+  fn handle(
+    self,
+    _addr: RouteAddress,
+    _version: ServiceVersion,
+    mut link: TStream,
+    tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    let fut = async move {
+      // Register before requesting datagrams so that none sent by the service are missed
+      let flow = match &self.datagrams {
+        Some(dispatcher) if tunnel.max_datagram_size().is_some() => {
+          let flow_id = dispatcher.allocate_flow_id(tunnel.side());
+          dispatcher.register(*tunnel.id(), flow_id).ok()
+        }
+        _ => None,
+      };
+      let request = match &flow {
+        Some(flow) => {
+          let mut request = vec![TRANSPORT_DATAGRAM];
+          request.extend_from_slice(&flow.flow_id().to_be_bytes());
+          request
+        }
+        None => vec![TRANSPORT_STREAM],
+      };
+      link
+        .write_all(&request)
+        .await
+        .and(link.flush().await)
+        .map_err(|_| ClientError::UnexpectedEnd)?;
+      let selected = link
+        .read_u8()
+        .await
+        .map_err(|_| ClientError::UnexpectedEnd)?;
+      let datagrams = match selected {
+        TRANSPORT_STREAM => None,
+        TRANSPORT_DATAGRAM if flow.is_some() => flow.map(|flow| (tunnel, flow)),
+        other => {
+          return Err(ClientError::IllegalResponse(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Service selected unrequested UDP transport {}", other),
+          )))
+        }
+      };
+      match relay_flow(link, self.local, datagrams, self.idle_timeout).await {
+        Ok((udp_to_tunnel, tunnel_to_udp)) => {
+          tracing::info!(
+            target = "proxy_udp_close",
+            udp_to_tunnel = udp_to_tunnel,
+            tunnel_to_udp = tunnel_to_udp,
+            "Closing flow",
+          );
+          Ok((udp_to_tunnel, tunnel_to_udp))
+        }
+        Err(e) => {
+          tracing::error!(
+            target = "proxy_udp_error",
+            error = ?e,
+            "UDP proxy IO error: {:#?}",
+            e,
+          );
+          Err(ClientError::IllegalResponse(e))
+        }
+      }
+    };
+    fut.fuse().boxed()
+  }
+}
+
+/// Splits datagrams arriving on a shared local socket into per-peer [UdpProxyClient] flows
+///
+/// Payloads are only received while [UdpFlowListener::accept] is being polled, so it should be
+/// called in a loop, handing each new client off to be routed concurrently.
+pub struct UdpFlowListener {
+  socket: Arc<UdpSocket>,
+  flows: HashMap<SocketAddr, mpsc::Sender<Bytes>>,
+  buffer: Vec<u8>,
+  idle_timeout: Duration,
+  datagrams: Option<Arc<DatagramDispatcher>>,
+}
+
+impl std::fmt::Debug for UdpFlowListener {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("UdpFlowListener")
+      .field("local_addr", &self.socket.local_addr().ok())
+      .field("flows", &self.flows.len())
+      .field("idle_timeout", &self.idle_timeout)
+      .finish_non_exhaustive()
+  }
+}
+
+impl UdpFlowListener {
+  pub fn new(socket: UdpSocket) -> Self {
+    Self {
+      socket: Arc::new(socket),
+      flows: HashMap::new(),
+      buffer: vec![0u8; MAX_UDP_PAYLOAD_LENGTH],
+      idle_timeout: DEFAULT_IDLE_TIMEOUT,
+      datagrams: None,
+    }
+  }
+
+  pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+    self.idle_timeout = idle_timeout;
+    self
+  }
+
+  /// See [UdpProxyClient::with_datagram_dispatcher]
+  pub fn with_datagram_dispatcher(mut self, dispatcher: Arc<DatagramDispatcher>) -> Self {
+    self.datagrams = Some(dispatcher);
+    self
+  }
+
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.socket.local_addr()
+  }
+
+  /// Forwards payloads to their existing flows until one arrives from a new peer
+  pub async fn accept(&mut self) -> io::Result<UdpProxyClient> {
+    loop {
+      let (length, peer) = match self.socket.recv_from(&mut self.buffer).await {
+        Ok(received) => received,
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+        Err(e) => return Err(e),
+      };
+      let mut payload = Bytes::copy_from_slice(&self.buffer[..length]);
+      if let Some(flow) = self.flows.get(&peer) {
+        match flow.try_send(payload) {
+          // Backlogged flows drop payloads, as the peer's network would
+          Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => continue,
+          // The flow ended; the peer is starting a new one
+          Err(mpsc::error::TrySendError::Closed(returned)) => payload = returned,
+        }
+      }
+      self.flows.retain(|_, flow| !flow.is_closed());
+      let (sender, receiver) = mpsc::channel(FLOW_BUFFER_LENGTH);
+      sender
+        .try_send(payload)
+        .expect("A new flow must have capacity for its first payload");
+      self.flows.insert(peer, sender);
+      return Ok(UdpProxyClient {
+        local: LocalEndpoint::Shared {
+          socket: Arc::clone(&self.socket),
+          peer,
+          inbound: receiver,
+        },
+        idle_timeout: self.idle_timeout,
+        datagrams: self.datagrams.clone(),
+      });
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct UdpProxyService {
+  pub local_only: bool,
+  pub idle_timeout: Duration,
+  datagrams: Option<Arc<DatagramDispatcher>>,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum UdpConnectError {
+  #[error("Service failed to bind a UDP socket to the remote target")]
+  ConnectionFailed,
+  #[error(
+    "No addresses provided for target connection fulfilled loopback requirements in local mode"
+  )]
+  NoLoopbackAddressesFound,
+}
+
+impl UdpProxyService {
+  pub fn new(local_only: bool) -> Self {
+    Self {
+      local_only,
+      idle_timeout: DEFAULT_IDLE_TIMEOUT,
+      datagrams: None,
+    }
+  }
+
+  pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+    self.idle_timeout = idle_timeout;
+    self
+  }
+
+  /// Allows clients to request tunnel datagrams, receiving them through the given dispatcher
+  ///
+  /// The dispatcher must be the one fed incoming datagrams by the daemon hosting this service.
+  pub fn with_datagram_dispatcher(mut self, dispatcher: Arc<DatagramDispatcher>) -> Self {
+    self.datagrams = Some(dispatcher);
+    self
+  }
+
+  /// Binds a socket connected to the first acceptable address
+  ///
+  /// UDP offers no handshake with which to fall back to later addresses.
+  async fn connect(&self, mut addrs: Vec<SocketAddr>) -> Result<UdpSocket, UdpConnectError> {
+    if addrs.is_empty() {
+      return Err(UdpConnectError::ConnectionFailed);
+    }
+    if self.local_only {
+      addrs.retain(|x| x.ip().is_loopback());
+      if addrs.is_empty() {
+        return Err(UdpConnectError::NoLoopbackAddressesFound);
+      }
+    }
+    let target = addrs[0];
+    let bind_addr = match target {
+      SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+      SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(bind_addr)
+      .await
+      .map_err(|_| UdpConnectError::ConnectionFailed)?;
+    socket
+      .connect(target)
+      .await
+      .map_err(|_| UdpConnectError::ConnectionFailed)?;
+    Ok(socket)
+  }
+
+  async fn resolve(&self, target: UdpTarget) -> io::Result<Vec<SocketAddr>> {
+    match target {
+      UdpTarget::Port(port) => Ok(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)]),
+      UdpTarget::SocketAddr(s) => Ok(vec![s]),
+      UdpTarget::Dns(dns_target) => {
+        let resolved = tokio::net::lookup_host(match &dns_target {
+          DnsTarget::PreferHigher { host, port }
+          | DnsTarget::Dns6 { host, port }
+          | DnsTarget::Dns4 { host, port } => format!("{}:{}", host, port),
+        })
+        .await?;
+        Ok(
+          resolved
+            .filter(|addr| dns_target.contains(addr, true))
+            .collect(),
+        )
+      }
+    }
+  }
+}
+
+/// Reads the client's transport request, producing the flow ID of a datagram request
+async fn read_transport_request<R: AsyncRead + Unpin>(link: &mut R) -> io::Result<Option<FlowId>> {
+  match link.read_u8().await? {
+    TRANSPORT_STREAM => Ok(None),
+    TRANSPORT_DATAGRAM => Ok(Some(link.read_u32().await?)),
+    other => Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("Unknown UDP transport {}", other),
+    )),
+  }
+}
+
+impl Service for UdpProxyService {
+  type Error = anyhow::Error;
+
+  fn accepts(&self, addr: &RouteAddress, _tunnel: &ArcTunnel) -> bool {
+    UdpTarget::try_from(addr).is_ok()
+  }
+
+  fn handle<'a>(
+    &'a self,
+    addr: RouteAddress,
+    _version: ServiceVersion,
+    _headers: RequestHeaders,
+    mut stream: Box<dyn TunnelStream + Send + 'static>,
+    tunnel: ArcTunnel<'static>,
+  ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>> {
+    tracing::debug!("UDP proxy flow received for {}; building span...", addr);
+    let span = tracing::span!(tracing::Level::DEBUG, "proxy_udp", target = ?addr);
+    let target: UdpTarget = match addr.try_into().map_err(|_| ServiceError::AddressError) {
+      Err(e) => return futures::future::ready(Err(e)).boxed(),
+      Ok(target) => target,
+    };
+    let fut = async move {
+      let requested_flow = match read_transport_request(&mut stream).await {
+        Ok(requested_flow) => requested_flow,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(ServiceError::IllegalResponse)?,
+        Err(_) => Err(ServiceError::UnexpectedEnd)?,
+      };
+      let addrs = self
+        .resolve(target)
+        .await
+        .or(Err(ServiceError::AddressError))?;
+      let socket = self.connect(addrs).await.map_err(|e| match e {
+        UdpConnectError::ConnectionFailed => ServiceError::DependencyFailure,
+        UdpConnectError::NoLoopbackAddressesFound => ServiceError::AddressError,
+      })?;
+      let flow = match (requested_flow, &self.datagrams) {
+        (Some(flow_id), Some(dispatcher)) if tunnel.max_datagram_size().is_some() => {
+          dispatcher.register(*tunnel.id(), flow_id).ok()
+        }
+        _ => None,
+      };
+      let selected = match flow {
+        Some(_) => TRANSPORT_DATAGRAM,
+        None => TRANSPORT_STREAM,
+      };
+      stream
+        .write_u8(selected)
+        .await
+        .and(stream.flush().await)
+        .map_err(|_| ServiceError::UnexpectedEnd)?;
+      tracing::debug!(
+        target = "proxy_udp_relaying",
+        datagrams = flow.is_some(),
+        "Relaying UDP flow"
+      );
+
+      let local = LocalEndpoint::Connected(Arc::new(socket));
+      let datagrams = flow.map(|flow| (tunnel, flow));
+      match relay_flow(stream, local, datagrams, self.idle_timeout).await {
+        Ok((udp_to_tunnel, tunnel_to_udp)) => {
+          tracing::info!(
+            target = "proxy_udp_close",
+            udp_to_tunnel = udp_to_tunnel,
+            tunnel_to_udp = tunnel_to_udp,
+            "Closing flow",
+          );
+          Ok(())
+        }
+        Err(e) => {
+          tracing::error!(
+            target = "proxy_udp_error",
+            error = ?e,
+            "UDP proxy IO error: {:#?}",
+            e,
+          );
+          Err(ServiceError::InternalError(e.into()))
+        }
+      }
+    };
+
+    fut.instrument(span).boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc,
+    },
+    time::Duration,
+  };
+
+  use futures::StreamExt;
+  use tokio::net::UdpSocket;
+
+  use super::{UdpFlowListener, UdpProxyService, UdpTarget};
+  use crate::{
+    common::protocol::{
+      datagram::DatagramDispatcher,
+      proxy_tcp::DnsTarget,
+      service::Client,
+      tunnel::{duplex, ArcTunnel, Tunnel, TunnelIncomingType, WithTunnelId},
+      RouteAddress, Service,
+    },
+    util::tunnel_stream::WrappedStream,
+  };
+
+  #[test]
+  fn target_parsing() {
+    assert_eq!(
+      "/udp/53".parse::<UdpTarget>().unwrap(),
+      UdpTarget::SocketAddr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53))
+    );
+    assert_eq!(
+      "/proxy-udp/ip6/::1/udp/514".parse::<UdpTarget>().unwrap(),
+      UdpTarget::SocketAddr(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 514))
+    );
+    assert_eq!(
+      "/dns4/localhost/udp/27015".parse::<UdpTarget>().unwrap(),
+      UdpTarget::Dns(DnsTarget::Dns4 {
+        host: "localhost".into(),
+        port: 27015
+      })
+    );
+    let target = UdpTarget::Dns(DnsTarget::PreferHigher {
+      host: "example.com".into(),
+      port: 53,
+    });
+    assert_eq!(
+      UdpTarget::try_from(&RouteAddress::from(&target)).unwrap(),
+      target
+    );
+    // TCP addresses must not be claimed by the UDP proxy
+    assert!("/tcp/53".parse::<UdpTarget>().is_err());
+  }
+
+  /// Feeds datagrams arriving on a tunnel to a dispatcher, as the daemon would
+  ///
+  /// Returns a count of the datagrams delivered to a flow registered with the dispatcher.
+  fn dispatch_datagrams(
+    tunnel: Arc<duplex::DuplexTunnel>,
+    dispatcher: Arc<DatagramDispatcher>,
+  ) -> Arc<AtomicUsize> {
+    let delivered = Arc::new(AtomicUsize::new(0));
+    tokio::task::spawn({
+      let delivered = Arc::clone(&delivered);
+      async move {
+        let mut downlink = tunnel.downlink().await.unwrap();
+        let mut incoming = downlink.as_stream();
+        while let Some(Ok(TunnelIncomingType::Datagram(datagram))) = incoming.next().await {
+          if dispatcher.dispatch(*tunnel.id(), datagram) {
+            delivered.fetch_add(1, Ordering::Relaxed);
+          }
+        }
+      }
+    });
+    delivered
+  }
+
+  async fn relay_echo(use_datagrams: bool) {
+    const IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::task::spawn(async move {
+      let mut buffer = vec![0u8; 2048];
+      loop {
+        let (length, peer) = echo.recv_from(&mut buffer).await.unwrap();
+        echo.send_to(&buffer[..length], peer).await.unwrap();
+      }
+    });
+
+    let duplex::EntangledTunnels {
+      listener,
+      connector,
+    } = duplex::channel();
+    let (listener, connector) = (Arc::new(listener), Arc::new(connector));
+    let mut service = UdpProxyService::new(true).with_idle_timeout(IDLE_TIMEOUT);
+    let mut flows = UdpFlowListener::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+      .with_idle_timeout(IDLE_TIMEOUT);
+    let mut delivered = None;
+    if use_datagrams {
+      let service_dispatcher = Arc::new(DatagramDispatcher::new());
+      let client_dispatcher = Arc::new(DatagramDispatcher::new());
+      delivered = Some((
+        dispatch_datagrams(Arc::clone(&listener), Arc::clone(&service_dispatcher)),
+        dispatch_datagrams(Arc::clone(&connector), Arc::clone(&client_dispatcher)),
+      ));
+      service = service.with_datagram_dispatcher(service_dispatcher);
+      flows = flows.with_datagram_dispatcher(client_dispatcher);
+    }
+
+    let user = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    user.connect(flows.local_addr().unwrap()).await.unwrap();
+    user.send(b"ping").await.unwrap();
+    let client = flows.accept().await.unwrap();
+    // Keep feeding existing flows from the shared socket
+    tokio::task::spawn(async move { while flows.accept().await.is_ok() {} });
+
+    let addr: RouteAddress = UdpTarget::SocketAddr(echo_addr).into();
+    let (client_link, service_link) = WrappedStream::duplex(8192);
+    let service_task = tokio::task::spawn({
+      let addr = addr.clone();
+      let tunnel: ArcTunnel<'static> = listener;
+      async move {
+        service
+          .handle(addr, 0, Default::default(), Box::new(service_link), tunnel)
+          .await
+          .map_err(|e| e.to_string())
+      }
+    });
+    let client_task = tokio::task::spawn(client.handle(addr, 0, client_link, connector as _));
+
+    let mut buffer = [0u8; 64];
+    let length = user.recv(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..length], b"ping");
+    user.send(b"pong").await.unwrap();
+    let length = user.recv(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..length], b"pong");
+
+    // Both halves of the flow close once it idles out
+    let (udp_to_tunnel, tunnel_to_udp) = client_task.await.unwrap().unwrap();
+    assert_eq!((udp_to_tunnel, tunnel_to_udp), (8, 8));
+    service_task.await.unwrap().unwrap();
+
+    // Each payload crossed the tunnel as a datagram to the flow registered for it on each side
+    if let Some((to_service, to_client)) = delivered {
+      assert_eq!(to_service.load(Ordering::Relaxed), 2);
+      assert_eq!(to_client.load(Ordering::Relaxed), 2);
+    }
+  }
+
+  #[tokio::test]
+  async fn relay_over_stream() {
+    tokio::time::timeout(Duration::from_secs(5), relay_echo(false))
+      .await
+      .expect("UDP relay deadlocked");
+  }
+
+  #[tokio::test]
+  async fn relay_over_datagrams() {
+    tokio::time::timeout(Duration::from_secs(5), relay_echo(true))
+      .await
+      .expect("UDP relay deadlocked");
+  }
+}
@@ -0,0 +1,498 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Proxying of tunnel streams to local Unix domain sockets
+//!
+//! Addresses take the form `/proxy-unix/<path>`, where each segment of `<path>` is a component
+//! of an absolute socket path; `/proxy-unix/var/run/docker.sock` targets `/var/run/docker.sock`.
+//!
+//! Unlike TCP targets, socket paths name arbitrary local resources, so [UnixStreamService]
+//! only serves paths present in its allowlist.
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+  collections::BTreeSet,
+  convert::{Infallible, TryFrom, TryInto},
+  fmt::Display,
+  path::{Component, Path, PathBuf},
+  str::FromStr,
+};
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::UnixStream,
+};
+use tracing_futures::Instrument;
+
+use super::{
+  address::RouteAddressParseError,
+  service::{Client, ClientResult, ProtocolInfo, RouteAddressBuilder},
+  tunnel::ArcTunnel,
+  RequestHeaders, RouteAddress, Service, ServiceError, ServiceVersion,
+};
+use crate::{
+  common::protocol::service::ClientError,
+  util::{proxy_generic_tokio_streams, tunnel_stream::TunnelStream},
+};
+
+#[derive(Debug, Clone)]
+pub struct UnixStreamClient<Reader, Writer> {
+  recv: Reader,
+  send: Writer,
+}
+
+impl<Reader, Writer> UnixStreamClient<Reader, Writer> {
+  pub fn new(recv: Reader, send: Writer) -> Self {
+    Self { recv, send }
+  }
+
+  pub fn build_addr(target: UnixSocketTarget) -> RouteAddress {
+    target.into()
+  }
+}
+
+impl ProtocolInfo for UnixStreamService {
+  fn protocol_name() -> &'static str
+  where
+    Self: Sized,
+  {
+    "proxy-unix"
+  }
+}
+
+impl<Reader, Writer> ProtocolInfo for UnixStreamClient<Reader, Writer> {
+  fn protocol_name() -> &'static str
+  where
+    Self: Sized,
+  {
+    UnixStreamService::protocol_name()
+  }
+}
+
+impl<Reader, Writer> RouteAddressBuilder for UnixStreamClient<Reader, Writer> {
+  type Params = UnixSocketTarget;
+  type BuildError = Infallible;
+
+  fn build_addr(args: Self::Params) -> Result<RouteAddress, Self::BuildError>
+  where
+    Self: Sized,
+  {
+    Ok(args.into())
+  }
+}
+
+impl<'stream, TStream, Reader, Writer> Client<'stream, TStream> for UnixStreamClient<Reader, Writer>
+where
+  Reader: AsyncRead + Send + Unpin + 'stream,
+  Writer: AsyncWrite + Send + Unpin + 'stream,
+  TStream: TunnelStream + Send + 'stream,
+{
+  type Response = (u64, u64);
+
+  type Error = std::io::Error;
+
+  type Future = BoxFuture<'stream, ClientResult<'stream, Self, TStream>>;
+
+  fn handle(
+    mut self,
+    _addr: RouteAddress,
+    _version: ServiceVersion,
+    tunnel: TStream,
+    _parent_tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    let fut = async move {
+      let (mut tunr, mut tunw) = tokio::io::split(tunnel);
+      match proxy_generic_tokio_streams((&mut self.send, &mut self.recv), (&mut tunw, &mut tunr))
+        .await
+      {
+        Ok((local_to_tunnel_bytes, tunnel_to_local_bytes)) => {
+          tracing::info!(
+            target = "proxy_unix_close",
+            local_to_tunnel = local_to_tunnel_bytes,
+            tunnel_to_local = tunnel_to_local_bytes,
+            "Closing stream",
+          );
+          Ok((local_to_tunnel_bytes, tunnel_to_local_bytes))
+        }
+        Err(e) => {
+          tracing::error!(
+            target = "proxy_unix_error",
+            error = ?e,
+            "Unix socket proxy IO error: {:#?}",
+            e,
+          );
+          Err(ClientError::IllegalResponse(e))
+        }
+      }
+    };
+    fut.fuse().boxed()
+  }
+}
+
+/// An absolute path to a Unix domain socket
+///
+/// Paths are held in normalized form, and may not contain `.` or `..` components,
+/// so that comparisons against an allowlist cannot be escaped by path traversal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UnixSocketTarget {
+  path: PathBuf,
+}
+
+impl UnixSocketTarget {
+  pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, UnixSocketTargetFormatError> {
+    let mut components = path.as_ref().components();
+    if components.next() != Some(Component::RootDir) {
+      return Err(UnixSocketTargetFormatError::RelativePath);
+    }
+    let segments = components
+      .map(|component| match component {
+        Component::Normal(segment) => segment
+          .to_str()
+          .ok_or(UnixSocketTargetFormatError::NonUnicodePath),
+        _ => Err(UnixSocketTargetFormatError::InvalidSegment),
+      })
+      .collect::<Result<Vec<&str>, _>>()?;
+    Self::from_segments(segments)
+  }
+
+  fn from_segments<'a, I: IntoIterator<Item = &'a str>>(
+    segments: I,
+  ) -> Result<Self, UnixSocketTargetFormatError> {
+    let mut path = PathBuf::from("/");
+    for segment in segments {
+      match segment {
+        "" | "." | ".." => return Err(UnixSocketTargetFormatError::InvalidSegment),
+        s if s.contains(['/', '\0']) => return Err(UnixSocketTargetFormatError::InvalidSegment),
+        s => path.push(s),
+      }
+    }
+    if path.parent().is_none() {
+      return Err(UnixSocketTargetFormatError::TooFewSegments);
+    }
+    Ok(Self { path })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn segments(&self) -> impl Iterator<Item = &str> {
+    self
+      .path
+      .components()
+      .filter_map(|component| match component {
+        Component::Normal(segment) => segment.to_str(),
+        _ => None,
+      })
+  }
+}
+
+impl From<UnixSocketTarget> for PathBuf {
+  fn from(target: UnixSocketTarget) -> Self {
+    target.path
+  }
+}
+
+impl TryFrom<PathBuf> for UnixSocketTarget {
+  type Error = UnixSocketTargetFormatError;
+
+  fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+    Self::new(path)
+  }
+}
+
+impl From<&UnixSocketTarget> for RouteAddress {
+  fn from(target: &UnixSocketTarget) -> Self {
+    RouteAddress::from_iter([UnixStreamService::protocol_name()]).into_suffixed(target.segments())
+  }
+}
+
+impl From<UnixSocketTarget> for RouteAddress {
+  fn from(target: UnixSocketTarget) -> Self {
+    (&target).into()
+  }
+}
+
+impl TryFrom<RouteAddress> for UnixSocketTarget {
+  type Error = UnixSocketTargetFormatError;
+
+  fn try_from(value: RouteAddress) -> Result<Self, Self::Error> {
+    (&value).try_into()
+  }
+}
+
+impl TryFrom<&RouteAddress> for UnixSocketTarget {
+  type Error = UnixSocketTargetFormatError;
+
+  fn try_from(value: &RouteAddress) -> Result<Self, Self::Error> {
+    match value.strip_segment_prefix([UnixStreamService::protocol_name()]) {
+      Some(segments) => Self::from_segments(segments),
+      None => Err(UnixSocketTargetFormatError::NoMatchingFormat),
+    }
+  }
+}
+
+/// Format a [RouteAddress] from a [UnixSocketTarget]
+impl Display for UnixSocketTarget {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let route_address: RouteAddress = self.into();
+    Display::fmt(&route_address, f)
+  }
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnixSocketTargetFormatError {
+  #[error("Not enough segments present to represent valid target")]
+  TooFewSegments,
+  #[error("No supported address type matches the provided format")]
+  NoMatchingFormat,
+  #[error("Socket paths must be absolute")]
+  RelativePath,
+  #[error("Socket paths must be valid unicode")]
+  NonUnicodePath,
+  #[error("Socket paths may not contain empty, `.`, or `..` segments")]
+  InvalidSegment,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UnixSocketTargetParseError {
+  #[error(transparent)]
+  RouteAddressParseError(#[from] RouteAddressParseError),
+  #[error(transparent)]
+  UnixSocketTargetFormatError(#[from] UnixSocketTargetFormatError),
+}
+
+/// Try to parse a [RouteAddress] into a [UnixSocketTarget]
+///
+/// Expects /proxy-unix/<path>; the protocol prefix is required, as
+/// any bare route address would otherwise be a valid socket path.
+impl FromStr for UnixSocketTarget {
+  type Err = UnixSocketTargetParseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let route_addr = s.parse::<RouteAddress>()?;
+    Ok((&route_addr).try_into()?)
+  }
+}
+
+/// Proxies streams to the Unix domain sockets named in its allowlist
+///
+/// Paths are compared lexically after normalization; symlinks are not resolved, so
+/// an allowlisted path is served even if it is a link to a socket elsewhere.
+#[derive(Debug)]
+pub struct UnixStreamService {
+  allowed_paths: BTreeSet<PathBuf>,
+}
+
+impl UnixStreamService {
+  pub fn new<I, P>(allowed_paths: I) -> Self
+  where
+    I: IntoIterator<Item = P>,
+    P: Into<PathBuf>,
+  {
+    Self {
+      allowed_paths: allowed_paths.into_iter().map(Into::into).collect(),
+    }
+  }
+
+  pub fn allowed_paths(&self) -> impl Iterator<Item = &Path> {
+    self.allowed_paths.iter().map(PathBuf::as_path)
+  }
+
+  pub fn is_allowed(&self, target: &UnixSocketTarget) -> bool {
+    self.allowed_paths.contains(target.path())
+  }
+}
+
+impl Service for UnixStreamService {
+  type Error = anyhow::Error;
+
+  fn accepts(&self, addr: &RouteAddress, _tunnel: &ArcTunnel) -> bool {
+    UnixSocketTarget::try_from(addr).map_or(false, |target| self.is_allowed(&target))
+  }
+
+  fn handle<'a>(
+    &'a self,
+    addr: RouteAddress,
+    _version: ServiceVersion,
+    _headers: RequestHeaders,
+    stream: Box<dyn TunnelStream + Send + 'static>,
+    _tunnel: ArcTunnel<'static>,
+  ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>> {
+    tracing::debug!(
+      "Unix socket proxy connection received for {}; building span...",
+      addr
+    );
+    let span = tracing::span!(tracing::Level::DEBUG, "proxy_unix", target = ?addr);
+    let target: UnixSocketTarget = match addr.try_into().map_err(|_| ServiceError::AddressError) {
+      Err(e) => return futures::future::ready(Err(e)).boxed(),
+      Ok(target) => target,
+    };
+    // Registries may route addresses here without consulting `accepts`
+    if !self.is_allowed(&target) {
+      tracing::warn!(path = ?target.path(), "Refusing Unix socket outside of allowlist");
+      return futures::future::ready(Err(ServiceError::Refused)).boxed();
+    }
+    let fut = async move {
+      tracing::debug!(
+        target = "proxy_unix_connecting",
+        "Connecting to proxy destination"
+      );
+      let mut connection = UnixStream::connect(target.path())
+        .await
+        .map_err(|_| ServiceError::DependencyFailure)?;
+      tracing::debug!(
+        target = "proxy_unix_streaming",
+        "Performing proxy streaming"
+      );
+
+      let (mut localr, mut localw) = connection.split();
+      let (mut tunr, mut tunw) = tokio::io::split(stream);
+
+      match proxy_generic_tokio_streams((&mut localw, &mut localr), (&mut tunw, &mut tunr)).await {
+        Ok((local_to_tunnel_bytes, tunnel_to_local_bytes)) => {
+          tracing::info!(
+            target = "proxy_unix_close",
+            local_to_tunnel = local_to_tunnel_bytes,
+            tunnel_to_local = tunnel_to_local_bytes,
+            "Closing stream",
+          );
+          Ok(())
+        }
+        Err(e) => {
+          tracing::error!(
+            target = "proxy_unix_error",
+            error = ?e,
+            "Unix socket proxy IO error: {:#?}",
+            e,
+          );
+          Err(ServiceError::InternalError(e.into()))
+        }
+      }
+    };
+
+    fut.instrument(span).boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{path::Path, sync::Arc};
+
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixListener,
+  };
+
+  use super::{UnixSocketTarget, UnixSocketTargetFormatError, UnixStreamClient, UnixStreamService};
+  use crate::{
+    common::protocol::{
+      service::Client,
+      tunnel::{duplex, ArcTunnel},
+      RouteAddress, Service, ServiceError,
+    },
+    util::tunnel_stream::WrappedStream,
+  };
+
+  #[test]
+  fn target_parsing() {
+    let target = "/proxy-unix/var/run/docker.sock"
+      .parse::<UnixSocketTarget>()
+      .unwrap();
+    assert_eq!(target.path(), Path::new("/var/run/docker.sock"));
+    assert_eq!(
+      UnixSocketTarget::try_from(&RouteAddress::from(&target)).unwrap(),
+      target
+    );
+    assert_eq!(
+      UnixSocketTarget::new("/run//postgresql/./.s.PGSQL.5432").unwrap(),
+      "/proxy-unix/run/postgresql/.s.PGSQL.5432"
+        .parse::<UnixSocketTarget>()
+        .unwrap()
+    );
+    // Traversal could otherwise escape an allowlisted directory
+    assert_eq!(
+      UnixSocketTarget::try_from(
+        &"/proxy-unix/run/../etc/shadow"
+          .parse::<RouteAddress>()
+          .unwrap()
+      )
+      .unwrap_err(),
+      UnixSocketTargetFormatError::InvalidSegment
+    );
+    assert_eq!(
+      UnixSocketTarget::new("/run/../etc/shadow").unwrap_err(),
+      UnixSocketTargetFormatError::InvalidSegment
+    );
+    assert_eq!(
+      UnixSocketTarget::new("run/docker.sock").unwrap_err(),
+      UnixSocketTargetFormatError::RelativePath
+    );
+    assert_eq!(
+      UnixSocketTarget::try_from(&"/proxy-unix".parse::<RouteAddress>().unwrap()).unwrap_err(),
+      UnixSocketTargetFormatError::TooFewSegments
+    );
+    assert!("/proxy-tcp/tcp/80".parse::<UnixSocketTarget>().is_err());
+  }
+
+  #[tokio::test]
+  async fn allowlisted_proxying() {
+    let socket_path =
+      std::env::temp_dir().join(format!("snocat-proxy-unix-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path).unwrap();
+    tokio::task::spawn(async move {
+      let (mut connection, _) = listener.accept().await.unwrap();
+      let (mut reader, mut writer) = connection.split();
+      tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let duplex::EntangledTunnels {
+      listener,
+      connector,
+    } = duplex::channel();
+    let tunnel: ArcTunnel<'static> = Arc::new(listener);
+    let service = UnixStreamService::new([&socket_path]);
+    let target = UnixSocketTarget::new(&socket_path).unwrap();
+    let addr: RouteAddress = target.into();
+    let forbidden: RouteAddress = UnixSocketTarget::new("/var/run/docker.sock")
+      .unwrap()
+      .into();
+    assert!(service.accepts(&addr, &tunnel));
+    assert!(!service.accepts(&forbidden, &tunnel));
+
+    let (_, refused_link) = WrappedStream::duplex(64);
+    assert!(matches!(
+      service
+        .handle(
+          forbidden,
+          0,
+          Default::default(),
+          Box::new(refused_link),
+          Arc::clone(&tunnel),
+        )
+        .await,
+      Err(ServiceError::Refused)
+    ));
+
+    let (client_link, service_link) = WrappedStream::duplex(8192);
+    let (mut user, local) = tokio::io::duplex(8192);
+    let (local_reader, local_writer) = tokio::io::split(local);
+    let client = UnixStreamClient::new(local_reader, local_writer);
+    let client_task =
+      tokio::task::spawn(client.handle(addr.clone(), 0, client_link, Arc::new(connector) as _));
+    let service_task = tokio::task::spawn(async move {
+      service
+        .handle(addr, 0, Default::default(), Box::new(service_link), tunnel)
+        .await
+        .map_err(|e| e.to_string())
+    });
+
+    user.write_all(b"ping").await.unwrap();
+    let mut buffer = [0u8; 4];
+    user.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+    drop(user);
+
+    assert_eq!(client_task.await.unwrap().unwrap(), (4, 4));
+    service_task.await.unwrap().unwrap();
+    let _ = std::fs::remove_file(&socket_path);
+  }
+}
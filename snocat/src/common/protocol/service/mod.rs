@@ -6,11 +6,14 @@ use futures::{
   future::{BoxFuture, Then},
   Future, FutureExt, TryFutureExt,
 };
-use std::{any::Any, fmt::Debug, marker::PhantomData};
+use std::{any::Any, fmt::Debug, marker::PhantomData, ops::RangeInclusive};
 
 use crate::util::tunnel_stream::TunnelStream;
 
-use super::{negotiation::NegotiationError, RouteAddress};
+use super::{
+  negotiation::NegotiationError, tunnel::ArcTunnel, RequestHeaders, RouteAddress, ServiceVersion,
+  DEFAULT_SERVICE_VERSIONS,
+};
 
 // Client
 
@@ -44,6 +47,14 @@ pub trait ProtocolInfo {
   fn protocol_name() -> &'static str
   where
     Self: Sized;
+
+  /// Versions of the protocol supported by the implementor, offered to services during negotiation
+  fn service_versions() -> RangeInclusive<ServiceVersion>
+  where
+    Self: Sized,
+  {
+    DEFAULT_SERVICE_VERSIONS
+  }
 }
 
 pub trait RouteAddressBuilder: ProtocolInfo {
@@ -67,7 +78,16 @@ pub trait Client<'result, TStream>: ProtocolInfo {
   type Error: Send + 'result;
   type Future: Future<Output = Result<Self::Response, ClientError<Self::Error>>>;
 
-  fn handle(self, addr: RouteAddress, stream: TStream) -> Self::Future;
+  /// Handles a stream after negotiation, using the service version selected by the remote
+  ///
+  /// The tunnel carrying the stream is provided for clients which exchange datagrams alongside it.
+  fn handle(
+    self,
+    addr: RouteAddress,
+    version: ServiceVersion,
+    stream: TStream,
+    tunnel: ArcTunnel<'static>,
+  ) -> Self::Future;
 }
 
 pub trait BoxedClient<'client, 'result>:
@@ -76,7 +96,9 @@ pub trait BoxedClient<'client, 'result>:
   fn handle(
     self,
     addr: RouteAddress,
+    version: ServiceVersion,
     stream: Box<dyn TunnelStream + Send + 'result>,
+    tunnel: ArcTunnel<'static>,
   ) -> Self::Future;
 }
 
@@ -124,6 +146,13 @@ where
   {
     TInnerClient::protocol_name()
   }
+
+  fn service_versions() -> RangeInclusive<ServiceVersion>
+  where
+    Self: Sized,
+  {
+    TInnerClient::service_versions()
+  }
 }
 
 impl<'c, TStream, TInnerClient: 'c> Client<'static, TStream>
@@ -140,8 +169,14 @@ where
 
   type Future = BoxClientFuture<'c>;
 
-  fn handle(self, addr: RouteAddress, stream: TStream) -> Self::Future {
-    TInnerClient::handle(self.client, addr, stream)
+  fn handle(
+    self,
+    addr: RouteAddress,
+    version: ServiceVersion,
+    stream: TStream,
+    tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    TInnerClient::handle(self.client, addr, version, stream, tunnel)
       .map_ok(|ok| Box::new(ok) as Box<_>)
       .map_err(|err| err.map_err(|err| Box::new(err) as Box<_>))
       .boxed()
@@ -156,8 +191,14 @@ where
   TInnerClient::Response: Any + Send + 'static,
   TInnerClient::Future: Send + 'c,
 {
-  fn handle(self, addr: RouteAddress, stream: Box<dyn TunnelStream + Send + 'c>) -> Self::Future {
-    <TInnerClient as Client<_>>::handle(self.client, addr, stream)
+  fn handle(
+    self,
+    addr: RouteAddress,
+    version: ServiceVersion,
+    stream: Box<dyn TunnelStream + Send + 'c>,
+    tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    <TInnerClient as Client<_>>::handle(self.client, addr, version, stream, tunnel)
       .map_ok(|ok| Box::new(ok) as Box<_>)
       .map_err(|err| err.map_err(|err| Box::new(err) as Box<_>))
       .boxed()
@@ -188,6 +229,13 @@ where
   {
     TInnerClient::protocol_name()
   }
+
+  fn service_versions() -> RangeInclusive<ServiceVersion>
+  where
+    Self: Sized,
+  {
+    TInnerClient::service_versions()
+  }
 }
 
 impl<'c, TInnerClient: 'c> Client<'static, Box<dyn TunnelStream + Send + 'c>>
@@ -204,8 +252,14 @@ where
 
   type Future = BoxClientFuture<'c>;
 
-  fn handle(self, addr: RouteAddress, stream: Box<dyn TunnelStream + Send + 'c>) -> Self::Future {
-    TInnerClient::handle(self.client, addr, stream)
+  fn handle(
+    self,
+    addr: RouteAddress,
+    version: ServiceVersion,
+    stream: Box<dyn TunnelStream + Send + 'c>,
+    tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    TInnerClient::handle(self.client, addr, version, stream, tunnel)
       .map_ok(|ok| Box::new(ok) as Box<_>)
       .map_err(|err| err.map_err(|err| Box::new(err) as Box<_>))
       .boxed()
@@ -219,8 +273,14 @@ where
   TInnerClient::Response: Any + Send + 'static,
   TInnerClient::Future: Send + 'c,
 {
-  fn handle(self, addr: RouteAddress, stream: Box<dyn TunnelStream + Send + 'c>) -> Self::Future {
-    <TInnerClient as Client<_>>::handle(self.client, addr, stream)
+  fn handle(
+    self,
+    addr: RouteAddress,
+    version: ServiceVersion,
+    stream: Box<dyn TunnelStream + Send + 'c>,
+    tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    <TInnerClient as Client<_>>::handle(self.client, addr, version, stream, tunnel)
       .map_ok(|ok| Box::new(ok) as Box<_>)
       .map_err(|err| err.map_err(|err| Box::new(err) as Box<_>))
       .boxed()
@@ -246,6 +306,13 @@ where
   {
     TClient::protocol_name()
   }
+
+  fn service_versions() -> RangeInclusive<ServiceVersion>
+  where
+    Self: Sized,
+  {
+    TClient::service_versions()
+  }
 }
 
 impl<'client, 'result, TStream, TClient, F, ThenFut, TResponse, TError> Client<'result, TStream>
@@ -263,8 +330,17 @@ where
 
   type Future = Then<TClient::Future, ThenFut, F>;
 
-  fn handle(self, addr: RouteAddress, stream: TStream) -> Self::Future {
-    self.client.handle(addr, stream).then(self.f)
+  fn handle(
+    self,
+    addr: RouteAddress,
+    version: ServiceVersion,
+    stream: TStream,
+    tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    self
+      .client
+      .handle(addr, version, stream, tunnel)
+      .then(self.f)
   }
 }
 
@@ -286,6 +362,13 @@ where
   {
     TClient::protocol_name()
   }
+
+  fn service_versions() -> RangeInclusive<ServiceVersion>
+  where
+    Self: Sized,
+  {
+    TClient::service_versions()
+  }
 }
 
 impl<'client, 'result, TStream, TClient, F, TResponse, TError> Client<'result, TStream>
@@ -305,8 +388,17 @@ where
 
   type Future = futures::future::Map<TClient::Future, F>;
 
-  fn handle(self, addr: RouteAddress, stream: TStream) -> Self::Future {
-    self.client.handle(addr, stream).map(self.f)
+  fn handle(
+    self,
+    addr: RouteAddress,
+    version: ServiceVersion,
+    stream: TStream,
+    tunnel: ArcTunnel<'static>,
+  ) -> Self::Future {
+    self
+      .client
+      .handle(addr, version, stream, tunnel)
+      .map(self.f)
   }
 }
 
@@ -360,6 +452,8 @@ impl<'client, 'result, TStream, TClient: Client<'result, TStream> + 'client>
 #[derive(Clone)]
 pub struct Request<'a, TStream, TClient> {
   pub address: RouteAddress,
+  /// Headers sent to the service alongside the address; routers may add their own entries
+  pub headers: RequestHeaders,
   pub protocol_client: TClient,
   phantom_lifetime: PhantomData<&'a ()>,
   phantom_stream: PhantomData<TStream>,
@@ -369,6 +463,7 @@ impl<'a, TStream, TClient> Debug for Request<'a, TStream, TClient> {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     f.debug_struct("Request")
       .field("address", &self.address)
+      .field("headers", &self.headers)
       .finish_non_exhaustive()
   }
 }
@@ -387,11 +482,18 @@ impl<'client, TStream, TClient> Request<'client, TStream, TClient> {
     let address = TClient::build_addr(address_parameters)?;
     Ok(Self {
       address,
+      headers: RequestHeaders::new(),
       protocol_client,
       phantom_lifetime: PhantomData,
       phantom_stream: PhantomData,
     })
   }
+
+  /// Adds a header to be sent to the service, replacing any existing value for the key
+  pub fn with_header<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+    self.headers.insert(key.into(), value.into());
+    self
+  }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -402,6 +504,16 @@ pub enum RoutingError<RouterError> {
   RouteUnavailable(RouteAddress),
   #[error("Invalid tunnel address format")]
   InvalidAddress,
+  #[error("No service on the remote accepts the address: {0:?}")]
+  NoSuchService(Option<String>),
+  #[error("Remote policy forbids the address: {0:?}")]
+  Forbidden(Option<String>),
+  #[error("Remote service is overloaded: {0:?}")]
+  Overloaded(Option<String>),
+  #[error("Remote refused the address as invalid: {0:?}")]
+  BadAddress(Option<String>),
+  #[error("Remote is shutting down: {0:?}")]
+  ShuttingDown(Option<String>),
   #[error("The tunnel failed to provide a link")]
   LinkOpenFailure(
     #[from]
@@ -431,6 +543,11 @@ impl<RouterError> RoutingError<RouterError> {
       RoutingError::RouteNotFound(e) => RoutingError::RouteNotFound(e),
       RoutingError::RouteUnavailable(e) => RoutingError::RouteUnavailable(e),
       RoutingError::InvalidAddress => RoutingError::InvalidAddress,
+      RoutingError::NoSuchService(m) => RoutingError::NoSuchService(m),
+      RoutingError::Forbidden(m) => RoutingError::Forbidden(m),
+      RoutingError::Overloaded(m) => RoutingError::Overloaded(m),
+      RoutingError::BadAddress(m) => RoutingError::BadAddress(m),
+      RoutingError::ShuttingDown(m) => RoutingError::ShuttingDown(m),
       RoutingError::LinkOpenFailure(e) => RoutingError::LinkOpenFailure(e),
       RoutingError::NegotiationError(e) => RoutingError::NegotiationError(e.map_err(f)),
       RoutingError::RouterError(e) => RoutingError::RouterError(f(e)),
@@ -447,16 +564,15 @@ where
   T: Into<RouterError>,
 {
   fn from(negotiation_error: NegotiationError<T>) -> Self {
-    RoutingError::NegotiationError(match negotiation_error {
-      NegotiationError::ReadError => NegotiationError::ReadError,
-      NegotiationError::WriteError => NegotiationError::WriteError,
-      NegotiationError::ProtocolViolation => NegotiationError::ProtocolViolation,
-      NegotiationError::Refused => NegotiationError::Refused,
-      NegotiationError::UnsupportedProtocolVersion => NegotiationError::UnsupportedProtocolVersion,
-      NegotiationError::UnsupportedServiceVersion => NegotiationError::UnsupportedServiceVersion,
-      NegotiationError::ApplicationError(e) => NegotiationError::ApplicationError(e.into()),
-      NegotiationError::FatalError(e) => NegotiationError::FatalError(e.into()),
-    })
+    // Structured refusals from the remote are surfaced as routing failures in their own right
+    match negotiation_error {
+      NegotiationError::NoSuchService(m) => RoutingError::NoSuchService(m),
+      NegotiationError::Forbidden(m) => RoutingError::Forbidden(m),
+      NegotiationError::Overloaded(m) => RoutingError::Overloaded(m),
+      NegotiationError::BadAddress(m) => RoutingError::BadAddress(m),
+      NegotiationError::ShuttingDown(m) => RoutingError::ShuttingDown(m),
+      other => RoutingError::NegotiationError(other.map_err(Into::into)),
+    }
   }
 }
 
@@ -468,7 +584,8 @@ pub type RouterResult<'client, 'result, TRouter, TProtocolClient> = Result<
 /// Routers are responsible for taking an address and forwarding it to
 /// the appropriate tunnel. When forwarding, the router can alter the
 /// address to remove any routing-specific information before it is
-/// handed to the Request's protocol::Client.
+/// handed to the Request's protocol::Client. Routers may likewise add
+/// entries to the Request's headers before they are sent to the service.
 pub trait Router {
   type Error;
   type Stream;
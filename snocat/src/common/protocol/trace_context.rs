@@ -0,0 +1,106 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Propagation of W3C Trace Context through [RequestHeaders] sent during negotiation
+//!
+//! Contexts are only carried when the active `tracing` subscriber includes a
+//! [tracing_opentelemetry] layer; otherwise no headers are added, and none are honored.
+//! Clients carrying a context offer negotiation v1 to send it, as for any other headers, unless
+//! their protocol versions were set explicitly; v0 services drop it.
+
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::RequestHeaders;
+
+/// Header carrying the W3C `traceparent` of the span which opened the request
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+/// Header carrying the W3C `tracestate` of the span which opened the request
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+struct HeaderInjector<'a>(&'a mut RequestHeaders);
+
+impl Injector for HeaderInjector<'_> {
+  fn set(&mut self, key: &str, value: String) {
+    // Headers provided explicitly by the caller or router take precedence
+    self.0.entry(key.to_owned()).or_insert(value);
+  }
+}
+
+struct HeaderExtractor<'a>(&'a RequestHeaders);
+
+impl Extractor for HeaderExtractor<'_> {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).map(String::as_str)
+  }
+
+  fn keys(&self) -> Vec<&str> {
+    self.0.keys().map(String::as_str).collect()
+  }
+}
+
+/// Adds the trace context of the span to the headers, unless trace headers are already present
+pub fn inject_span_context(span: &tracing::Span, headers: &mut RequestHeaders) {
+  TraceContextPropagator::new().inject_context(&span.context(), &mut HeaderInjector(headers));
+}
+
+/// Reads the remote trace context from the headers, which is empty if none was provided
+pub fn extract_context(headers: &RequestHeaders) -> opentelemetry::Context {
+  TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+/// Parents the span to the remote trace context carried by the headers, if one was provided
+pub fn set_remote_parent(span: &tracing::Span, headers: &RequestHeaders) {
+  if headers.contains_key(TRACEPARENT_HEADER) {
+    span.set_parent(extract_context(headers));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+  use opentelemetry_sdk::trace::TracerProvider;
+  use tracing_opentelemetry::OpenTelemetrySpanExt;
+  use tracing_subscriber::layer::SubscriberExt;
+
+  use super::{inject_span_context, set_remote_parent, TRACEPARENT_HEADER};
+  use crate::common::protocol::RequestHeaders;
+
+  /// Test that a span on the receiving side joins the trace of the span which sent the headers
+  #[test]
+  fn propagate_context() {
+    let provider = TracerProvider::builder().build();
+    let subscriber = tracing_subscriber::registry()
+      .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("snocat-test")));
+    tracing::subscriber::with_default(subscriber, || {
+      let sender = tracing::info_span!("sender");
+      let mut headers = RequestHeaders::new();
+      inject_span_context(&sender, &mut headers);
+      assert!(headers.contains_key(TRACEPARENT_HEADER));
+
+      let receiver = tracing::info_span!("receiver");
+      set_remote_parent(&receiver, &headers);
+      let sender_context = sender.context();
+      let receiver_context = receiver.context();
+      assert_eq!(
+        receiver_context.span().span_context().trace_id(),
+        sender_context.span().span_context().trace_id()
+      );
+    });
+  }
+
+  /// Test that trace headers provided explicitly are not replaced
+  #[test]
+  fn explicit_headers_preserved() {
+    let provider = TracerProvider::builder().build();
+    let subscriber = tracing_subscriber::registry()
+      .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("snocat-test")));
+    tracing::subscriber::with_default(subscriber, || {
+      const EXPLICIT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+      let mut headers = RequestHeaders::new();
+      headers.insert(TRACEPARENT_HEADER.into(), EXPLICIT.into());
+      inject_span_context(&tracing::info_span!("sender"), &mut headers);
+      assert_eq!(headers[TRACEPARENT_HEADER], EXPLICIT);
+    });
+  }
+}
@@ -5,9 +5,13 @@ use futures::{
   future::{BoxFuture, FutureExt},
   TryFutureExt,
 };
-use std::{fmt::Debug, marker::PhantomData, sync::Arc};
+use std::{collections::BTreeMap, fmt::Debug, marker::PhantomData, ops::RangeInclusive, sync::Arc};
 
-use super::{tunnel::ArcTunnel, RouteAddress};
+use super::{
+  negotiation::{Refusal, RefusalReason},
+  tunnel::ArcTunnel,
+  RouteAddress,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ServiceError<InternalError> {
@@ -56,17 +60,37 @@ impl<InternalError> ServiceError<InternalError> {
   }
 }
 
+/// Version of a service's protocol, agreed upon between client and service during negotiation
+pub type ServiceVersion = u8;
+
+/// Service versions assumed for services and clients which do not declare their own
+pub const DEFAULT_SERVICE_VERSIONS: RangeInclusive<ServiceVersion> = 0..=0;
+
+/// Key-value metadata attached to a request, such as trace identifiers or tenant information
+///
+/// Sent by v1+ clients during negotiation after the address, and provided to [Service::handle].
+pub type RequestHeaders = BTreeMap<String, String>;
+
 pub trait Service {
   type Error;
 
   fn accepts(&self, addr: &RouteAddress, tunnel: &ArcTunnel) -> bool;
   // fn protocol_id() -> String where Self: Sized;
 
+  /// Versions of its protocol which the service is able to handle
+  ///
+  /// The highest version also offered by the client is selected and passed to [Service::handle].
+  fn service_versions(&self) -> RangeInclusive<ServiceVersion> {
+    DEFAULT_SERVICE_VERSIONS
+  }
+
   fn handle<'a>(
     &'a self,
     addr: RouteAddress,
+    version: ServiceVersion,
+    headers: RequestHeaders,
     stream: Box<dyn TunnelStream + Send + 'static>,
-    tunnel: ArcTunnel,
+    tunnel: ArcTunnel<'static>,
   ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>>;
 }
 
@@ -103,13 +127,19 @@ where
     Service::accepts(self.get_inner(), addr, tunnel)
   }
 
+  fn service_versions(&self) -> RangeInclusive<ServiceVersion> {
+    Service::service_versions(self.get_inner())
+  }
+
   fn handle<'a>(
     &'a self,
     addr: RouteAddress,
+    version: ServiceVersion,
+    headers: RequestHeaders,
     stream: Box<dyn TunnelStream + Send + 'static>,
-    tunnel: ArcTunnel,
+    tunnel: ArcTunnel<'static>,
   ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>> {
-    Service::handle(self.get_inner(), addr, stream, tunnel)
+    Service::handle(self.get_inner(), addr, version, headers, stream, tunnel)
       .map_err(|e| ServiceError::err_into(e))
       .boxed()
   }
@@ -133,17 +163,27 @@ macro_rules! impl_service_ref_type {
         Service::accepts(dereferenced, addr, tunnel)
       }
 
+      fn service_versions(&self) -> RangeInclusive<ServiceVersion> {
+        let dereferenced: &S = {
+          let $this: &Self = self;
+          $dereference
+        };
+        Service::service_versions(dereferenced)
+      }
+
       fn handle<'a>(
         &'a self,
         addr: RouteAddress,
+        version: ServiceVersion,
+        headers: RequestHeaders,
         stream: Box<dyn TunnelStream + Send + 'static>,
-        tunnel: ArcTunnel,
+        tunnel: ArcTunnel<'static>,
       ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>> {
         let dereferenced: &S = {
           let $this: &Self = self;
           $dereference
         };
-        Service::handle(dereferenced, addr, stream, tunnel)
+        Service::handle(dereferenced, addr, version, headers, stream, tunnel)
       }
     }
   };
@@ -170,4 +210,18 @@ pub trait ServiceRegistry {
     addr: &RouteAddress,
     tunnel: &ArcTunnel,
   ) -> Option<Arc<dyn Service<Error = Self::Error> + Send + Sync + 'static>>;
+
+  /// Finds a service for the address, or provides the reason it was refused to send to the remote
+  ///
+  /// Registries enforcing policy or load limits can override this to refuse addresses with reasons
+  /// other than [RefusalReason::NoSuchService], which is used when [Self::find_service] finds none.
+  fn try_find_service(
+    self: Arc<Self>,
+    addr: &RouteAddress,
+    tunnel: &ArcTunnel,
+  ) -> Result<Arc<dyn Service<Error = Self::Error> + Send + Sync + 'static>, Refusal> {
+    self
+      .find_service(addr, tunnel)
+      .ok_or_else(|| Refusal::new(RefusalReason::NoSuchService))
+  }
 }
@@ -3,6 +3,7 @@
 #![forbid(unused_imports, dead_code)]
 use std::sync::Arc;
 
+use bytes::Bytes;
 use futures::{future::BoxFuture, FutureExt, StreamExt};
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
@@ -16,9 +17,13 @@ use crate::{
 
 use super::{TunnelId, WithTunnelId};
 
+/// Largest datagram accepted by [DuplexTunnel::send_datagram], mirroring the UDP payload limit
+pub const DUPLEX_MAX_DATAGRAM_SIZE: usize = 65507;
+
 pub struct DuplexTunnel {
   id: TunnelId,
   channel_to_remote: UnboundedSender<WrappedStream>,
+  datagrams_to_remote: UnboundedSender<Bytes>,
   side: TunnelSide,
   incoming: Arc<tokio::sync::Mutex<TunnelIncoming>>,
 }
@@ -47,6 +52,20 @@ impl TunnelUplink for DuplexTunnel {
     )
     .boxed()
   }
+
+  fn send_datagram(&self, data: Bytes) -> Result<(), TunnelError> {
+    if data.len() > DUPLEX_MAX_DATAGRAM_SIZE {
+      return Err(TunnelError::DatagramTooLarge);
+    }
+    self
+      .datagrams_to_remote
+      .send(data)
+      .map_err(|_| TunnelError::ConnectionClosed)
+  }
+
+  fn max_datagram_size(&self) -> Option<usize> {
+    Some(DUPLEX_MAX_DATAGRAM_SIZE)
+  }
 }
 
 impl Tunnel for DuplexTunnel {
@@ -78,13 +97,17 @@ impl Into<(DuplexTunnel, DuplexTunnel)> for EntangledTunnels {
 pub fn channel() -> EntangledTunnels {
   fn duplex_for(
     id: TunnelId,
-    up: UnboundedSender<WrappedStream>,
-    down: UnboundedReceiver<WrappedStream>,
+    (up, datagrams_up): (UnboundedSender<WrappedStream>, UnboundedSender<Bytes>),
+    (down, datagrams_down): (UnboundedReceiver<WrappedStream>, UnboundedReceiver<Bytes>),
     side: TunnelSide,
   ) -> DuplexTunnel {
     use tokio_stream::wrappers::UnboundedReceiverStream;
-    let down = UnboundedReceiverStream::new(down);
-    let incoming_inner = down.map(TunnelIncomingType::BiStream).map(Ok).boxed();
+    let down = UnboundedReceiverStream::new(down).map(TunnelIncomingType::BiStream);
+    let datagrams_down =
+      UnboundedReceiverStream::new(datagrams_down).map(TunnelIncomingType::Datagram);
+    let incoming_inner = futures::stream::select(down, datagrams_down)
+      .map(Ok)
+      .boxed();
     let incoming = TunnelIncoming {
       id,
       inner: incoming_inner,
@@ -93,15 +116,28 @@ pub fn channel() -> EntangledTunnels {
     DuplexTunnel {
       id,
       channel_to_remote: up,
+      datagrams_to_remote: datagrams_up,
       side,
       incoming: Arc::new(tokio::sync::Mutex::new(incoming)),
     }
   }
   let (left_up, right_down) = mpsc::unbounded_channel::<WrappedStream>();
   let (right_up, left_down) = mpsc::unbounded_channel::<WrappedStream>();
+  let (left_datagrams_up, right_datagrams_down) = mpsc::unbounded_channel::<Bytes>();
+  let (right_datagrams_up, left_datagrams_down) = mpsc::unbounded_channel::<Bytes>();
   let (listener, connector) = (
-    duplex_for(TunnelId::new(0), left_up, left_down, TunnelSide::Listen),
-    duplex_for(TunnelId::new(1), right_up, right_down, TunnelSide::Connect),
+    duplex_for(
+      TunnelId::new(0),
+      (left_up, left_datagrams_up),
+      (left_down, left_datagrams_down),
+      TunnelSide::Listen,
+    ),
+    duplex_for(
+      TunnelId::new(1),
+      (right_up, right_datagrams_up),
+      (right_down, right_datagrams_down),
+      TunnelSide::Connect,
+    ),
   );
   EntangledTunnels {
     listener,
@@ -139,6 +175,39 @@ mod tests {
       .expect("DuplexTunnel test may be failing due to an await deadlock");
   }
 
+  #[tokio::test]
+  async fn duplex_tunnel_datagrams() {
+    use super::{Tunnel, TunnelIncomingType, DUPLEX_MAX_DATAGRAM_SIZE};
+    use crate::common::protocol::tunnel::TunnelError;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    let (a_tun, b_tun) = super::channel().into();
+
+    let fut = async move {
+      a_tun.send_datagram(Bytes::from_static(b"ping")).unwrap();
+      assert!(matches!(
+        a_tun.send_datagram(Bytes::from(vec![0u8; DUPLEX_MAX_DATAGRAM_SIZE + 1])),
+        Err(TunnelError::DatagramTooLarge)
+      ));
+      a_tun.open_link().await.unwrap();
+      let mut b_inc = b_tun.downlink().await.unwrap();
+      drop(a_tun); // Dropping the A tunnel ends the incoming streams and datagrams for B
+      let incoming: Vec<_> = b_inc.as_stream().collect().await;
+      assert_eq!(incoming.len(), 2);
+      let datagrams: Vec<Bytes> = incoming
+        .into_iter()
+        .filter_map(|x| match x.unwrap() {
+          TunnelIncomingType::Datagram(data) => Some(data),
+          TunnelIncomingType::BiStream(_) => None,
+        })
+        .collect();
+      assert_eq!(datagrams, vec![Bytes::from_static(b"ping")]);
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(5), fut)
+      .await
+      .expect("DuplexTunnel test may be failing due to an await deadlock");
+  }
+
   #[tokio::test]
   async fn duplex_tunnel_concurrency() {
     use super::{Tunnel, TunnelIncomingType};
@@ -168,6 +237,7 @@ mod tests {
         .try_filter_map(|x| {
           future::ready(match x {
             TunnelIncomingType::BiStream(stream) => Ok(Some(stream)),
+            TunnelIncomingType::Datagram(_) => Ok(None),
           })
         })
         .try_for_each_concurrent(None, |stream: WrappedStream| async move {
@@ -217,6 +287,7 @@ mod tests {
             .expect("Server must produce one stream per stream sent");
           let mut downlink = match inc {
             TunnelIncomingType::BiStream(stream) => stream,
+            TunnelIncomingType::Datagram(_) => panic!("Server must not send datagrams"),
           };
           // We've received a stream, wait until B receives its own before dropping our write-end
           println!("a2");
@@ -256,6 +327,7 @@ mod tests {
             .expect("Server must produce one stream per stream sent");
           let mut downlink = match inc {
             TunnelIncomingType::BiStream(stream) => stream,
+            TunnelIncomingType::Datagram(_) => panic!("Server must not send datagrams"),
           };
           drop(s);
           println!("b3");
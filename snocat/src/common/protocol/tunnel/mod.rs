@@ -10,6 +10,7 @@ use std::{
   sync::Arc,
 };
 
+use bytes::Bytes;
 use futures::{future::BoxFuture, stream::BoxStream, StreamExt};
 use serde::{Deserializer, Serializer};
 
@@ -88,6 +89,10 @@ pub enum TunnelError {
   TransportError,
   #[error("Connection closed locally")]
   LocallyClosed,
+  #[error("Datagrams are not supported by this tunnel or its remote")]
+  DatagramsUnsupported,
+  #[error("Datagram exceeds the maximum size supported by the tunnel")]
+  DatagramTooLarge,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -323,7 +328,46 @@ pub trait TunnelUplink: WithTunnelId + Sided {
     TunnelAddressInfo::Unidentified
   }
 
+  /// The certificate chain presented by the remote, end-entity certificate first
+  ///
+  /// `None` indicates that the transport does not use certificates, or that none were presented.
+  fn peer_certificates(&self) -> Option<Vec<rustls::Certificate>> {
+    None
+  }
+
+  /// Exports keying material unique to this tunnel's session, as defined by RFC 5705
+  ///
+  /// Both sides of a tunnel derive identical material for the same label and context, which
+  /// allows authentication to be bound to the tunnel it was performed over. `None` indicates
+  /// that the transport is not a TLS session, or that the export failed.
+  fn keying_material(&self, _length: usize, _label: &[u8], _context: &[u8]) -> Option<Vec<u8>> {
+    None
+  }
+
   fn open_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>>;
+
+  /// Sends an unreliable, unordered datagram to the remote
+  ///
+  /// Datagrams arrive as [TunnelIncomingType::Datagram] on the remote's downlink,
+  /// and may be dropped in transit without notice to either side.
+  fn send_datagram(&self, _data: Bytes) -> Result<(), TunnelError> {
+    Err(TunnelError::DatagramsUnsupported)
+  }
+
+  /// The largest datagram payload currently accepted by [TunnelUplink::send_datagram]
+  ///
+  /// `None` indicates that datagrams are unsupported by this tunnel or its remote.
+  fn max_datagram_size(&self) -> Option<usize> {
+    None
+  }
+
+  /// The number of streams currently open on the tunnel, in either direction
+  ///
+  /// Exposes [TunnelActivityMonitoring::active_stream_count] to holders of tunnel trait objects.
+  /// `None` indicates that the tunnel does not track its streams.
+  fn tracked_stream_count(&self) -> Option<usize> {
+    None
+  }
 }
 
 impl<T> TunnelUplink for T
@@ -335,9 +379,29 @@ where
     self.deref().addr()
   }
 
+  fn peer_certificates(&self) -> Option<Vec<rustls::Certificate>> {
+    self.deref().peer_certificates()
+  }
+
+  fn keying_material(&self, length: usize, label: &[u8], context: &[u8]) -> Option<Vec<u8>> {
+    self.deref().keying_material(length, label, context)
+  }
+
   fn open_link(&self) -> BoxFuture<'static, Result<WrappedStream, TunnelError>> {
     self.deref().open_link()
   }
+
+  fn send_datagram(&self, data: Bytes) -> Result<(), TunnelError> {
+    self.deref().send_datagram(data)
+  }
+
+  fn max_datagram_size(&self) -> Option<usize> {
+    self.deref().max_datagram_size()
+  }
+
+  fn tracked_stream_count(&self) -> Option<usize> {
+    self.deref().tracked_stream_count()
+  }
 }
 
 pub trait TunnelDownlink: WithTunnelId + Sided {
@@ -510,6 +574,8 @@ pub use transforming_tunnel_constructors::{IntoArcTunnel, IntoBoxedTunnel, IntoR
 
 pub enum TunnelIncomingType {
   BiStream(WrappedStream),
+  /// An unreliable datagram sent via [TunnelUplink::send_datagram]
+  Datagram(Bytes),
 }
 
 pub struct TunnelIncoming {
@@ -11,6 +11,7 @@ use std::{
 };
 
 use arc_swap::ArcSwap;
+use bytes::Bytes;
 use futures::{
   future::{self, BoxFuture},
   FutureExt, StreamExt, TryFutureExt, TryStreamExt,
@@ -166,7 +167,7 @@ impl QuinnTunnel {
     }
     let close_reason = Arc::new(ArcSwap::new(Arc::new(TunnelCloseReason::Unspecified)));
     let active_stream_count = Arc::new(AtomicUsize::new(0));
-    let stream_tunnels = futures::stream::try_unfold((), {
+    let incoming_streams = futures::stream::try_unfold((), {
       let connection = connection.clone();
       move |()| {
         let connection = connection.clone();
@@ -201,7 +202,20 @@ impl QuinnTunnel {
           Box::new(send),
         ))
       }
+    });
+    let incoming_datagrams = futures::stream::try_unfold((), {
+      let connection = connection.clone();
+      move |()| {
+        let connection = connection.clone();
+        async move { connection.read_datagram().await }.map_ok(move |res| Some((res, ())))
+      }
     })
+    .map_ok(TunnelIncomingType::Datagram);
+    // Datagrams are interleaved with incoming streams in order of arrival
+    let stream_tunnels = futures::stream::select(
+      incoming_streams,
+      incoming_datagrams,
+    )
     .map_err(Into::into)
     // Only take new streams until incoming is cancelled
     .take_until({
@@ -251,6 +265,12 @@ impl QuinnTunnel {
               active_streams = active,
               "QUIC incoming stream acceptance stopped: connection closed locally"
             ),
+            TunnelError::DatagramsUnsupported | TunnelError::DatagramTooLarge => tracing::debug!(
+              tunnel_id = ?tunnel_id,
+              active_streams = active,
+              "QUIC incoming datagram read failed: {}",
+              tunnel_error
+            ),
           }
         }
         let close_reason = TunnelCloseReason::Error(TunnelError::ConnectionClosed);
@@ -535,6 +555,12 @@ impl TunnelUplink for QuinnTunnel {
                 active_streams = active,
                 "QUIC outgoing stream open stopped: connection closed locally"
               ),
+              TunnelError::DatagramsUnsupported | TunnelError::DatagramTooLarge => tracing::debug!(
+                tunnel_id = ?tunnel_id,
+                active_streams = active,
+                "QUIC outgoing stream open failed: {}",
+                tunnel_error
+              ),
             }
           }
           let close_reason = TunnelCloseReason::Error(tunnel_error.clone());
@@ -550,9 +576,42 @@ impl TunnelUplink for QuinnTunnel {
       .boxed()
   }
 
+  fn send_datagram(&self, data: Bytes) -> Result<(), TunnelError> {
+    if self.is_closed_uplink() {
+      return Err(TunnelError::ConnectionClosed);
+    }
+    self.connection.send_datagram(data).map_err(Into::into)
+  }
+
+  fn max_datagram_size(&self) -> Option<usize> {
+    self.connection.max_datagram_size()
+  }
+
+  fn tracked_stream_count(&self) -> Option<usize> {
+    Some(self.active_stream_count.load(Ordering::Relaxed))
+  }
+
   fn addr(&self) -> TunnelAddressInfo {
     TunnelAddressInfo::Socket(self.connection.remote_address())
   }
+
+  fn peer_certificates(&self) -> Option<Vec<rustls::Certificate>> {
+    // Quinn's rustls session reports its peer identity as the presented certificate chain
+    self
+      .connection
+      .peer_identity()
+      .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+      .map(|certificates| *certificates)
+  }
+
+  fn keying_material(&self, length: usize, label: &[u8], context: &[u8]) -> Option<Vec<u8>> {
+    let mut output = vec![0u8; length];
+    self
+      .connection
+      .export_keying_material(&mut output, label, context)
+      .ok()?;
+    Some(output)
+  }
 }
 
 impl Tunnel for QuinnTunnel {
@@ -643,6 +702,18 @@ impl From<quinn::ConnectionError> for TunnelError {
   }
 }
 
+impl From<quinn::SendDatagramError> for TunnelError {
+  fn from(send_error: quinn::SendDatagramError) -> Self {
+    match send_error {
+      quinn::SendDatagramError::UnsupportedByPeer | quinn::SendDatagramError::Disabled => {
+        Self::DatagramsUnsupported
+      }
+      quinn::SendDatagramError::TooLarge => Self::DatagramTooLarge,
+      quinn::SendDatagramError::ConnectionLost(connection_error) => connection_error.into(),
+    }
+  }
+}
+
 impl IntoTunnel for (quinn::Connection, TunnelSide) {
   type Tunnel = QuinnTunnel;
   fn into_tunnel(self, tunnel_id: TunnelId) -> Self::Tunnel {
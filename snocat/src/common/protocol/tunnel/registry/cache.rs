@@ -1,20 +1,47 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
 
-use std::{fmt::Debug, sync::Arc};
+use std::{
+  fmt::Debug,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
 
+use dashmap::DashMap;
 use futures::{
   future::{BoxFuture, FutureExt},
-  TryFutureExt,
+  pin_mut, StreamExt, TryFutureExt,
 };
+use tokio_util::sync::CancellationToken;
 
-use super::super::{registry::TunnelRegistry, TunnelName};
+use super::super::{
+  registry::{RegistryConfig, RegistryEvent, TunnelRegistry, WatchFilter, WatchableTunnelRegistry},
+  TunnelName,
+};
+use crate::util::dropkick::Dropkick;
 
 pub struct WriteThroughCache<Encode, Decode, Cache, Target> {
   encode: Arc<Encode>,
   decode: Arc<Decode>,
   a: Arc<Cache>,
   b: Arc<Target>,
+  /// Names cached from lookups in the target, rather than registered through this cache
+  cached_from_target: Arc<DashMap<TunnelName, CachedFromTarget>>,
+  next_lookup: Arc<AtomicU64>,
+  cached_lifetime: Duration,
+  // Stops invalidation of cached entries once the cache itself is dropped
+  core_canceller: Arc<Dropkick<CancellationToken>>,
+}
+
+/// A record cached by a lookup in the target
+#[derive(Debug, Clone, Copy)]
+struct CachedFromTarget {
+  /// Identifies the lookup which cached the record, so that it can tell if it was invalidated since
+  lookup: u64,
+  expires_at: Instant,
 }
 
 #[derive(Debug, Clone, Hash)]
@@ -72,29 +99,60 @@ where
     let a = self.a.clone();
     let b = self.b.clone();
     let decode = self.decode.clone();
+    let cached_from_target = self.cached_from_target.clone();
+    let lookup = self.next_lookup.fetch_add(1, Ordering::Relaxed);
+    let cached_lifetime = self.cached_lifetime;
     async move {
-      if let Some(res) = a
+      let cached = a
         .lookup(&tunnel_name)
         .await
-        .map_err(Self::Error::CacheError)?
-      {
-        Ok(Some(res))
-      } else {
-        if let Some(res) = b
-          .lookup(&tunnel_name)
-          .await
-          .map_err(Self::Error::TargetError)?
-        {
-          let decoded = decode(res);
-          // TODO: By not returning this registration, the identifier won't clear the cached version when the remote one is cleared...
-          a.register(tunnel_name, &decoded)
+        .map_err(Self::Error::CacheError)?;
+      // Expiry of the target's entry may go unreported, so copies of it are only trusted for a time
+      let expired = cached_from_target
+        .remove_if(&tunnel_name, |_, cached| {
+          cached.expires_at <= Instant::now()
+        })
+        .is_some();
+      match cached {
+        Some(res) if !expired => return Ok(Some(res)),
+        Some(_) => {
+          a.deregister(&tunnel_name)
             .await
             .map_err(Self::Error::CacheError)?;
-          Ok(Some(decoded))
-        } else {
-          Ok(None)
         }
+        None => (),
+      }
+
+      // Claimed before the target is read, so that changes reported meanwhile invalidate the read
+      cached_from_target.insert(
+        tunnel_name.clone(),
+        CachedFromTarget {
+          lookup,
+          expires_at: Instant::now() + cached_lifetime,
+        },
+      );
+      let decoded = match b.lookup(&tunnel_name).await {
+        Ok(Some(res)) => decode(res),
+        other => {
+          cached_from_target.remove_if(&tunnel_name, |_, cached| cached.lookup == lookup);
+          return other.map(|_| None).map_err(Self::Error::TargetError);
+        }
+      };
+      // The cached copy is cleared when the target reports a change to it; see [Self::invalidate_from_target]
+      let cache_ident = a
+        .register(tunnel_name.clone(), &decoded)
+        .await
+        .map_err(Self::Error::CacheError)?;
+      let still_claimed = cached_from_target
+        .get(&tunnel_name)
+        .map_or(false, |cached| cached.lookup == lookup);
+      if !still_claimed {
+        // The target reported a change while it was being read, so the copy may already be stale
+        a.deregister_identifier(cache_ident)
+          .await
+          .map_err(Self::Error::CacheError)?;
       }
+      Ok(Some(decoded))
     }
     .boxed()
   }
@@ -109,6 +167,8 @@ where
     let a = self.a.clone();
     let b = self.b.clone();
     let encode = self.encode.clone();
+    // Registrations made through the cache supersede any copy cached from the target
+    self.cached_from_target.remove(&tunnel_name);
     async move {
       let encoded = encode(&record);
       let (a_res, b_res) = futures::future::try_join(
@@ -179,6 +239,228 @@ where
       b: target,
       encode: Arc::new(encode),
       decode: Arc::new(decode),
+      cached_from_target: Default::default(),
+      next_lookup: Default::default(),
+      cached_lifetime: RegistryConfig::default().tunnel_entry_lifetime,
+      core_canceller: Arc::new(Dropkick::new(CancellationToken::new())),
+    }
+  }
+
+  /// Sets how long records cached from the target are used before it is read again
+  ///
+  /// Targets may not report entries which expire because their registering node stopped renewing
+  /// them, such as those of a [Redis registry](super::redis::RedisRegistry), so this should not
+  /// exceed the target's [entry lifetime](RegistryConfig::tunnel_entry_lifetime). Defaults to the
+  /// default entry lifetime.
+  pub fn with_cached_lifetime(mut self, cached_lifetime: Duration) -> Self {
+    self.cached_lifetime = cached_lifetime;
+    self
+  }
+
+  /// Clears records cached from the target whenever the target reports a change to them
+  ///
+  /// Runs until the target's change feed ends or the cache is dropped. Registrations made
+  /// through the cache itself are left in place, as they supersede those of the target.
+  /// Failures to clear stale entries from the cache are logged rather than ending invalidation.
+  pub fn invalidate_from_target(&self) -> BoxFuture<'static, Result<(), Target::Error>>
+  where
+    Target: WatchableTunnelRegistry,
+  {
+    let a = self.a.clone();
+    let cached_from_target = self.cached_from_target.clone();
+    let cancellation = self.core_canceller.child_token();
+    let watch = self.b.watch(WatchFilter::All);
+    async move {
+      let events = watch.await?.take_until(cancellation.cancelled());
+      pin_mut!(events);
+      while let Some(event) = events.next().await {
+        let stale: Vec<TunnelName> = match event {
+          RegistryEvent::Registered(tunnel_name) | RegistryEvent::Deregistered(tunnel_name) => {
+            cached_from_target
+              .remove(&tunnel_name)
+              .into_iter()
+              .map(|(tunnel_name, _)| tunnel_name)
+              .collect()
+          }
+          // Any cached record may have changed while events were being missed
+          RegistryEvent::Lagged => {
+            let stale = cached_from_target.iter().map(|n| n.key().clone()).collect();
+            cached_from_target.clear();
+            stale
+          }
+        };
+        for tunnel_name in stale {
+          if let Err(e) = a.deregister(&tunnel_name).await {
+            tracing::warn!(error = ?e, ?tunnel_name, "Failed to clear stale cache entry: {}", e);
+          }
+        }
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::{BoxFuture, FutureExt};
+  use std::{sync::Arc, time::Duration};
+  use tokio::sync::Notify;
+
+  use super::WriteThroughCache;
+  use crate::common::protocol::tunnel::{
+    registry::{
+      memory::{InMemoryTunnelRegistry, InMemoryTunnelRegistryError},
+      RegistryEvent, TunnelRegistry, WatchFilter, WatchableTunnelRegistry,
+    },
+    TunnelName,
+  };
+  #[tokio::test]
+  async fn invalidation_from_target() {
+    let cache = Arc::new(InMemoryTunnelRegistry::<u32>::new());
+    let target = Arc::new(InMemoryTunnelRegistry::<u32>::new());
+    let registry = WriteThroughCache::new(cache.clone(), target.clone(), |r: &u32| *r, |r| r);
+    let invalidation = tokio::task::spawn(registry.invalidate_from_target());
+
+    // Populate the cache from the target, as if registered by another node
+    let remote = TunnelName::new("remote");
+    target.register(remote.clone(), &1).await.unwrap();
+    assert_eq!(registry.lookup(&remote).await.unwrap(), Some(1));
+    assert_eq!(cache.lookup(&remote).await.unwrap(), Some(1));
+
+    let local = TunnelName::new("local");
+    registry.register(local.clone(), &2).await.unwrap();
+
+    // Deregistration from the target clears the cached copy, leaving local registrations alone
+    target.deregister(&remote).await.unwrap();
+    let mut attempts = 0;
+    while cache.lookup(&remote).await.unwrap().is_some() {
+      attempts += 1;
+      assert!(attempts < 100, "Cached entry must be invalidated");
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(registry.lookup(&remote).await.unwrap(), None);
+    assert_eq!(cache.lookup(&local).await.unwrap(), Some(2));
+
+    // Dropping the cache ends its invalidation
+    drop(registry);
+    invalidation.await.unwrap().unwrap();
+  }
+
+  /// Test that records cached from the target are read again once their lifetime passes
+  #[tokio::test]
+  async fn cached_lifetime() {
+    let cache = Arc::new(InMemoryTunnelRegistry::<u32>::new());
+    let target = Arc::new(InMemoryTunnelRegistry::<u32>::new());
+    let registry = WriteThroughCache::new(cache.clone(), target.clone(), |r: &u32| *r, |r| r)
+      .with_cached_lifetime(Duration::from_millis(50));
+
+    // Without invalidation from the target, as when its entries expire unreported
+    let remote = TunnelName::new("remote");
+    let _remote = target.register(remote.clone(), &1).await.unwrap();
+    assert_eq!(registry.lookup(&remote).await.unwrap(), Some(1));
+    let _replacement = target.register(remote.clone(), &2).await.unwrap();
+    assert_eq!(registry.lookup(&remote).await.unwrap(), Some(1));
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(registry.lookup(&remote).await.unwrap(), Some(2));
+
+    // Registrations made through the cache are not re-read
+    let local = TunnelName::new("local");
+    let _local = registry.register(local.clone(), &3).await.unwrap();
+    let _remote_local = target.register(local.clone(), &4).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(registry.lookup(&local).await.unwrap(), Some(3));
+  }
+
+  /// Holds each lookup open once read, until released
+  #[derive(Default)]
+  struct GatedRegistry {
+    inner: InMemoryTunnelRegistry<u32>,
+    read: Arc<Notify>,
+    release: Arc<Notify>,
+  }
+
+  impl TunnelRegistry for GatedRegistry {
+    type Identifier = <InMemoryTunnelRegistry<u32> as TunnelRegistry>::Identifier;
+
+    type Record = u32;
+
+    type Error = InMemoryTunnelRegistryError;
+
+    fn lookup<'a>(
+      &'a self,
+      tunnel_name: &'a TunnelName,
+    ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+      let (lookup, read, release) = (
+        self.inner.lookup(tunnel_name),
+        self.read.clone(),
+        self.release.clone(),
+      );
+      async move {
+        let res = lookup.await;
+        read.notify_one();
+        release.notified().await;
+        res
+      }
+      .boxed()
+    }
+
+    fn register<'a>(
+      &'a self,
+      tunnel_name: TunnelName,
+      record: &'a Self::Record,
+    ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>> {
+      self.inner.register(tunnel_name, record)
+    }
+
+    fn deregister<'a>(
+      &'a self,
+      tunnel_name: &'a TunnelName,
+    ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+      self.inner.deregister(tunnel_name)
+    }
+
+    fn deregister_identifier<'a>(
+      &'a self,
+      identifier: Self::Identifier,
+    ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+      self.inner.deregister_identifier(identifier)
+    }
+  }
+
+  impl WatchableTunnelRegistry for GatedRegistry {
+    fn watch<'a>(
+      &'a self,
+      filter: WatchFilter,
+    ) -> BoxFuture<'static, Result<futures::stream::BoxStream<'static, RegistryEvent>, Self::Error>>
+    {
+      self.inner.watch(filter)
+    }
+  }
+
+  /// Test that changes reported while the target is being read are not lost to the cache
+  #[tokio::test]
+  async fn invalidation_during_lookup() {
+    let cache = Arc::new(InMemoryTunnelRegistry::<u32>::new());
+    let target = Arc::new(GatedRegistry::default());
+    let registry = WriteThroughCache::new(cache.clone(), target.clone(), |r: &u32| *r, |r| r);
+    let _invalidation = tokio::task::spawn(registry.invalidate_from_target());
+
+    let remote = TunnelName::new("remote");
+    let _remote = target.register(remote.clone(), &1).await.unwrap();
+    let lookup = tokio::task::spawn(registry.lookup(&remote));
+    target.read.notified().await;
+
+    // The record is deregistered after being read, but before the lookup caches it
+    target.deregister(&remote).await.unwrap();
+    let mut attempts = 0;
+    while registry.cached_from_target.contains_key(&remote) {
+      attempts += 1;
+      assert!(attempts < 100, "Deregistration must be reported");
+      tokio::time::sleep(Duration::from_millis(10)).await;
     }
+    target.release.notify_one();
+    assert_eq!(lookup.await.unwrap().unwrap(), Some(1));
+    assert_eq!(cache.lookup(&remote).await.unwrap(), None);
   }
 }
@@ -0,0 +1,674 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Single-node tunnel registry persisted to an append-only log
+//!
+//! Each change is appended to the log as a line of JSON, and the log is rewritten to hold only
+//! live registrations once enough of it has been superseded. Registrations are referenced, expired,
+//! and renewed as those of the Redis registry, so a restarted server finds the registrations of
+//! its previous run until they lapse.
+use std::{
+  collections::HashMap,
+  fmt::Debug,
+  fs::{File, OpenOptions},
+  io::{BufRead, BufReader, BufWriter, Write},
+  marker::PhantomData,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex, Weak},
+  time::{Duration, SystemTime},
+};
+
+use dashmap::DashMap;
+use futures::{
+  future::{self, BoxFuture, FutureExt},
+  TryFutureExt,
+};
+use tokio::task::JoinError;
+use tokio_util::sync::CancellationToken;
+
+use super::super::{
+  registry::{RegistryConfig, TunnelRegistry},
+  TunnelName,
+};
+use crate::util::{cancellation::CancellationListener, dropkick::Dropkick};
+
+/// Configuration for expiry and compaction of a file registry
+///
+/// Use `..Default::default()` to initialize, as this is
+/// non-exhaustive for when new parameters are added.
+pub struct FileRegistryConfig {
+  /// Lifetimes and renewal rate of registrations, shared with other registries
+  pub registry: RegistryConfig,
+  /// Number of superseded records the log may hold before it is compacted
+  pub compaction_threshold: usize,
+}
+
+impl Default for FileRegistryConfig {
+  fn default() -> Self {
+    Self {
+      registry: Default::default(),
+      compaction_threshold: 1024,
+    }
+  }
+}
+
+/// A change to the registry, as persisted to its log
+///
+/// Expiry times are in milliseconds since the unix epoch, so that they survive restarts.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogRecord {
+  Reference {
+    tunnel_name: String,
+    rid: String,
+    expires_at: u64,
+  },
+  Entry {
+    rid: String,
+    record: serde_json::Value,
+    expires_at: u64,
+  },
+  RenewReference {
+    tunnel_name: String,
+    expires_at: u64,
+  },
+  RenewEntry {
+    rid: String,
+    expires_at: u64,
+  },
+  RemoveReference {
+    tunnel_name: String,
+  },
+  RemoveEntry {
+    rid: String,
+  },
+}
+
+fn unix_millis_after(lifetime: Duration) -> u64 {
+  let expires_at = SystemTime::now() + lifetime;
+  expires_at
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis()
+    .try_into()
+    .unwrap_or(u64::MAX)
+}
+
+fn unix_millis_now() -> u64 {
+  unix_millis_after(Duration::ZERO)
+}
+
+struct Reference {
+  rid: String,
+  expires_at: u64,
+}
+
+struct Entry {
+  record: serde_json::Value,
+  expires_at: u64,
+}
+
+/// Registrations as of the most recent record of the log
+#[derive(Default)]
+struct LogState {
+  references: HashMap<String, Reference>,
+  entries: HashMap<String, Entry>,
+}
+
+impl LogState {
+  fn apply(&mut self, record: LogRecord) {
+    match record {
+      LogRecord::Reference {
+        tunnel_name,
+        rid,
+        expires_at,
+      } => {
+        self
+          .references
+          .insert(tunnel_name, Reference { rid, expires_at });
+      }
+      LogRecord::Entry {
+        rid,
+        record,
+        expires_at,
+      } => {
+        self.entries.insert(rid, Entry { record, expires_at });
+      }
+      LogRecord::RenewReference {
+        tunnel_name,
+        expires_at,
+      } => {
+        if let Some(reference) = self.references.get_mut(&tunnel_name) {
+          reference.expires_at = expires_at;
+        }
+      }
+      LogRecord::RenewEntry { rid, expires_at } => {
+        if let Some(entry) = self.entries.get_mut(&rid) {
+          entry.expires_at = expires_at;
+        }
+      }
+      LogRecord::RemoveReference { tunnel_name } => {
+        self.references.remove(&tunnel_name);
+      }
+      LogRecord::RemoveEntry { rid } => {
+        self.entries.remove(&rid);
+      }
+    }
+  }
+
+  fn prune(&mut self, now: u64) {
+    self
+      .references
+      .retain(|_, reference| reference.expires_at > now);
+    self.entries.retain(|_, entry| entry.expires_at > now);
+  }
+
+  fn len(&self) -> usize {
+    self.references.len() + self.entries.len()
+  }
+
+  /// The smallest set of records which reproduces this state
+  fn records(&self) -> impl Iterator<Item = LogRecord> + '_ {
+    let entries = self.entries.iter().map(|(rid, entry)| LogRecord::Entry {
+      rid: rid.clone(),
+      record: entry.record.clone(),
+      expires_at: entry.expires_at,
+    });
+    let references = self
+      .references
+      .iter()
+      .map(|(tunnel_name, reference)| LogRecord::Reference {
+        tunnel_name: tunnel_name.clone(),
+        rid: reference.rid.clone(),
+        expires_at: reference.expires_at,
+      });
+    entries.chain(references)
+  }
+
+  /// Reads the live record at a tunnel name's reference, if both have yet to expire
+  fn live_entry(&self, tunnel_name: &str, now: u64) -> Option<&serde_json::Value> {
+    let reference = self
+      .references
+      .get(tunnel_name)
+      .filter(|reference| reference.expires_at > now)?;
+    let entry = self
+      .entries
+      .get(&reference.rid)
+      .filter(|entry| entry.expires_at > now)?;
+    Some(&entry.record)
+  }
+}
+
+struct RegistryLog {
+  path: PathBuf,
+  writer: BufWriter<File>,
+  state: LogState,
+  /// Number of records written to the log since it was last compacted
+  records: usize,
+  compaction_threshold: usize,
+}
+
+impl RegistryLog {
+  fn open(path: PathBuf, compaction_threshold: usize) -> Result<Self, std::io::Error> {
+    let mut state = Self::replay(&path)?;
+    state.prune(unix_millis_now());
+    let writer = Self::write_compacted(&path, &state)?;
+    Ok(Self {
+      path,
+      writer,
+      records: state.len(),
+      state,
+      compaction_threshold,
+    })
+  }
+
+  fn replay(path: &Path) -> Result<LogState, std::io::Error> {
+    let mut state = LogState::default();
+    let file = match File::open(path) {
+      Ok(file) => file,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+      Err(e) => return Err(e),
+    };
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+      let line = line?;
+      if line.trim().is_empty() {
+        continue;
+      }
+      match serde_json::from_str(&line) {
+        Ok(record) => state.apply(record),
+        // A write interrupted by a crash leaves a partial final line
+        Err(e) => {
+          tracing::warn!(line = index + 1, error = %e, "Skipping unreadable registry log record")
+        }
+      }
+    }
+    Ok(state)
+  }
+
+  /// Replaces the log at the path with the records of the state, returning a writer appending to it
+  fn write_compacted(path: &Path, state: &LogState) -> Result<BufWriter<File>, std::io::Error> {
+    let staging_path = path.with_extension("compacting");
+    let mut staging = BufWriter::new(File::create(&staging_path)?);
+    for record in state.records() {
+      serde_json::to_writer(&mut staging, &record)?;
+      staging.write_all(b"\n")?;
+    }
+    staging
+      .into_inner()
+      .map_err(|e| e.into_error())?
+      .sync_all()?;
+    std::fs::rename(&staging_path, path)?;
+    Ok(BufWriter::new(OpenOptions::new().append(true).open(path)?))
+  }
+
+  fn append<I: IntoIterator<Item = LogRecord>>(
+    &mut self,
+    records: I,
+  ) -> Result<(), std::io::Error> {
+    for record in records {
+      serde_json::to_writer(&mut self.writer, &record)?;
+      self.writer.write_all(b"\n")?;
+      self.state.apply(record);
+      self.records += 1;
+    }
+    self.writer.flush()?;
+    if self.records.saturating_sub(self.state.len()) >= self.compaction_threshold {
+      self.compact()?;
+    }
+    Ok(())
+  }
+
+  fn compact(&mut self) -> Result<(), std::io::Error> {
+    self.state.prune(unix_millis_now());
+    self.writer = Self::write_compacted(&self.path, &self.state)?;
+    self.records = self.state.len();
+    Ok(())
+  }
+}
+
+pub type Registration = Dropkick<CancellationToken>;
+pub type RegistrationMap = DashMap<String, Weak<Registration>>;
+
+/// Single-occupancy overriding registry persisted to a file
+///
+/// Registration conflicts are handled by replacement of name ownership (Last wins)
+///
+/// Dropping the last identifier for a key deregisters it from auto-renewal, but does not perform explicit IO.
+pub struct FileRegistry<R> {
+  config: Arc<RegistryConfig>,
+  log: Arc<Mutex<RegistryLog>>,
+  active_registration_map: Arc<RegistrationMap>,
+  phantom_item: PhantomData<fn() -> R>,
+  // Cancels all renewal jobs if the registry itself is dropped; is parent to all renewal task tokens
+  core_canceller: Arc<Dropkick<CancellationToken>>,
+}
+
+impl<R> Debug for FileRegistry<R> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(std::any::type_name::<FileRegistry<R>>())
+      .field(
+        "path",
+        &self.log.lock().expect("Registry log mutex poisoned").path,
+      )
+      .finish_non_exhaustive()
+  }
+}
+
+impl<R> FileRegistry<R> {
+  /// Opens the registry persisted at the given path, creating it if it does not exist
+  ///
+  /// Registrations which expired while the registry was closed are discarded.
+  pub fn open<P: Into<PathBuf>>(
+    path: P,
+    config: FileRegistryConfig,
+  ) -> Result<Self, FileRegistryError> {
+    let log = RegistryLog::open(path.into(), config.compaction_threshold)?;
+    Ok(Self {
+      config: Arc::new(config.registry),
+      log: Arc::new(Mutex::new(log)),
+      active_registration_map: Arc::new(RegistrationMap::default()),
+      phantom_item: PhantomData,
+      core_canceller: Arc::new(Dropkick::new(CancellationToken::new())),
+    })
+  }
+
+  /// Rewrites the log to hold only live registrations
+  pub fn compact(&self) -> BoxFuture<'static, Result<(), FileRegistryError>> {
+    let log = Arc::clone(&self.log);
+    tokio::task::spawn_blocking(move || {
+      log
+        .lock()
+        .expect("Registry log mutex poisoned")
+        .compact()
+        .map_err(FileRegistryError::from)
+    })
+    .unwrap_or_else(|e| Err(FileRegistryError::from(e)))
+    .boxed()
+  }
+
+  /// Removes the entry at a RID, stopping its renewal if it is one of ours
+  fn take_entry(
+    log: &mut RegistryLog,
+    registration_map: &RegistrationMap,
+    rid: &str,
+  ) -> Result<Option<serde_json::Value>, std::io::Error> {
+    if let Some((_, owned_renewer)) = registration_map.remove(rid) {
+      if let Some(owned_renewer) = owned_renewer.upgrade() {
+        owned_renewer.cancel();
+      }
+    }
+    let now = unix_millis_now();
+    let entry = match log.state.entries.get(rid) {
+      Some(entry) => (entry.expires_at > now).then(|| entry.record.clone()),
+      None => return Ok(None),
+    };
+    log.append([LogRecord::RemoveEntry {
+      rid: rid.to_owned(),
+    }])?;
+    Ok(entry)
+  }
+}
+
+async fn run_renewal(
+  log: Arc<Mutex<RegistryLog>>,
+  canceller: CancellationListener,
+  tunnel_name: String,
+  rid: String,
+  record: serde_json::Value,
+  config: Arc<RegistryConfig>,
+) {
+  // Ensure we don't rewrite the log constantly with an absurdly-small delay
+  let renewal_rate = config.effective_renewal_rate();
+  while !canceller.is_cancelled() {
+    futures::future::select(
+      tokio::time::sleep(renewal_rate).boxed(),
+      canceller.cancelled().boxed(),
+    )
+    .await;
+    if canceller.is_cancelled() {
+      break;
+    }
+    let renewal = tokio::task::spawn_blocking({
+      let (log, tunnel_name, rid, record, config) = (
+        Arc::clone(&log),
+        tunnel_name.clone(),
+        rid.clone(),
+        record.clone(),
+        Arc::clone(&config),
+      );
+      move || {
+        let mut log = log.lock().expect("Registry log mutex poisoned");
+        let mut renewals = Vec::with_capacity(2);
+        // Only renew the name while it still refers to this registration
+        if log
+          .state
+          .references
+          .get(&tunnel_name)
+          .map_or(false, |reference| reference.rid == rid)
+        {
+          renewals.push(LogRecord::RenewReference {
+            tunnel_name,
+            expires_at: unix_millis_after(config.tunnel_id_ref_lifetime),
+          });
+        }
+        let expires_at = unix_millis_after(config.tunnel_entry_lifetime);
+        // Re-emplace the entry if it lapsed, such as after a long pause
+        renewals.push(match log.state.entries.contains_key(&rid) {
+          true => LogRecord::RenewEntry { rid, expires_at },
+          false => LogRecord::Entry {
+            rid,
+            record,
+            expires_at,
+          },
+        });
+        log.append(renewals)
+      }
+    });
+    match renewal.await {
+      Ok(Ok(())) => (),
+      Ok(Err(e)) => tracing::warn!(error = %e, "Failed to renew registration in registry log"),
+      Err(e) => tracing::warn!(error = %e, "Registry renewal task failed to rejoin"),
+    }
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum FileRegistryError {
+  #[error("Registry log IO error: {0}")]
+  IOError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    std::io::Error,
+  ),
+  #[error("Registry serialization error: {0}")]
+  SerializationError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    serde_json::Error,
+  ),
+  #[error("Registry task failed to rejoin to async pool")]
+  JoinError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    JoinError,
+  ),
+}
+
+#[derive(Debug, Clone)]
+pub struct FileRegistryIdentifier {
+  tunnel_name: TunnelName,
+  rid: String,
+  _registration: Option<Arc<Registration>>,
+}
+
+impl std::hash::Hash for FileRegistryIdentifier {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.tunnel_name.hash(state);
+    self.rid.hash(state);
+  }
+}
+
+impl PartialEq for FileRegistryIdentifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.tunnel_name == other.tunnel_name && self.rid == other.rid
+  }
+}
+
+impl Eq for FileRegistryIdentifier {}
+
+impl<R> TunnelRegistry for FileRegistry<R>
+where
+  R: serde::ser::Serialize + serde::de::DeserializeOwned + Send + Sync + Debug + Clone + 'static,
+{
+  type Identifier = FileRegistryIdentifier;
+
+  type Record = R;
+
+  type Error = FileRegistryError;
+
+  fn lookup<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let log = Arc::clone(&self.log);
+    let tunnel_name = tunnel_name.clone();
+    tokio::task::spawn_blocking(move || {
+      let log = log.lock().expect("Registry log mutex poisoned");
+      match log.state.live_entry(tunnel_name.raw(), unix_millis_now()) {
+        Some(record) => Ok(Some(serde_json::from_value(record.clone())?)),
+        None => Ok(None),
+      }
+    })
+    .unwrap_or_else(|e| Err(FileRegistryError::from(e)))
+    .boxed()
+  }
+
+  fn register<'a>(
+    &'a self,
+    tunnel_name: TunnelName,
+    record: &'a Self::Record,
+  ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>> {
+    let record = match serde_json::to_value(record) {
+      Ok(record) => record,
+      Err(e) => return future::ready(Err(e.into())).boxed(),
+    };
+    let log = Arc::clone(&self.log);
+    let config = Arc::clone(&self.config);
+    let registration_map = Arc::clone(&self.active_registration_map);
+    let canceller = self.core_canceller.child_token();
+    tokio::task::spawn_blocking(move || {
+      let rid = {
+        let mut log = log.lock().expect("Registry log mutex poisoned");
+        let rid = loop {
+          let rid = uuid::Uuid::new_v4().to_string();
+          if !log.state.entries.contains_key(&rid) {
+            break rid;
+          }
+        };
+        log.append([
+          LogRecord::Entry {
+            rid: rid.clone(),
+            record: record.clone(),
+            expires_at: unix_millis_after(config.tunnel_entry_lifetime),
+          },
+          LogRecord::Reference {
+            tunnel_name: tunnel_name.raw().to_owned(),
+            rid: rid.clone(),
+            expires_at: unix_millis_after(config.tunnel_id_ref_lifetime),
+          },
+        ])?;
+        rid
+      };
+
+      tokio::runtime::Handle::current().spawn(run_renewal(
+        log,
+        canceller.clone().into(),
+        tunnel_name.raw().to_owned(),
+        rid.clone(),
+        record,
+        config,
+      ));
+      let registration = Arc::new(Dropkick::new(canceller));
+      // Add our registration to the map to allow us to cancel entries which are no longer valid
+      registration_map.insert(rid.clone(), Arc::downgrade(&registration));
+
+      Ok(FileRegistryIdentifier {
+        tunnel_name,
+        rid,
+        _registration: Some(registration),
+      })
+    })
+    .unwrap_or_else(|e| Err(FileRegistryError::from(e)))
+    .boxed()
+  }
+
+  fn deregister<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let log = Arc::clone(&self.log);
+    let registration_map = Arc::clone(&self.active_registration_map);
+    let tunnel_name = tunnel_name.raw().to_owned();
+    tokio::task::spawn_blocking(move || {
+      let mut log = log.lock().expect("Registry log mutex poisoned");
+      let rid = match log.state.references.get(&tunnel_name) {
+        Some(reference) => reference.rid.clone(),
+        None => return Ok(None),
+      };
+      log.append([LogRecord::RemoveReference { tunnel_name }])?;
+      match Self::take_entry(&mut log, &registration_map, &rid)? {
+        Some(record) => Ok(Some(serde_json::from_value(record)?)),
+        None => Ok(None),
+      }
+    })
+    .unwrap_or_else(|e| Err(FileRegistryError::from(e)))
+    .boxed()
+  }
+
+  fn deregister_identifier<'a>(
+    &'a self,
+    identifier: Self::Identifier,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let log = Arc::clone(&self.log);
+    let registration_map = Arc::clone(&self.active_registration_map);
+    // Drop our hold on the registration, keeping only its address
+    let FileRegistryIdentifier {
+      tunnel_name, rid, ..
+    } = identifier;
+    tokio::task::spawn_blocking(move || {
+      let mut log = log.lock().expect("Registry log mutex poisoned");
+      // Unlike in Redis, the name can be cleared only if it still refers to this registration
+      if log
+        .state
+        .references
+        .get(tunnel_name.raw())
+        .map_or(false, |reference| reference.rid == rid)
+      {
+        log.append([LogRecord::RemoveReference {
+          tunnel_name: tunnel_name.raw().to_owned(),
+        }])?;
+      }
+      match Self::take_entry(&mut log, &registration_map, &rid)? {
+        Some(record) => Ok(Some(serde_json::from_value(record)?)),
+        None => Ok(None),
+      }
+    })
+    .unwrap_or_else(|e| Err(FileRegistryError::from(e)))
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::{FileRegistry, FileRegistryConfig};
+  use crate::common::protocol::tunnel::{
+    registry::{RegistryConfig, TunnelRegistry},
+    TunnelName,
+  };
+
+  fn test_config() -> FileRegistryConfig {
+    FileRegistryConfig {
+      registry: RegistryConfig {
+        tunnel_id_ref_lifetime: Duration::from_millis(200),
+        tunnel_entry_lifetime: Duration::from_millis(100),
+        renewal_rate: Duration::from_millis(20),
+      },
+      compaction_threshold: 16,
+    }
+  }
+
+  #[tokio::test]
+  async fn persistence_and_expiry() {
+    let path = std::env::temp_dir().join(format!("snocat-registry-{}.log", uuid::Uuid::new_v4()));
+    let edge = TunnelName::new("edge-1");
+    let ident = {
+      let registry = FileRegistry::<u32>::open(&path, test_config()).unwrap();
+      let ident = registry.register(edge.clone(), &7).await.unwrap();
+      // Renewal keeps the registration alive past its lifetimes, compacting the log as it goes
+      tokio::time::sleep(Duration::from_millis(400)).await;
+      assert_eq!(registry.lookup(&edge).await.unwrap(), Some(7));
+      ident
+    };
+
+    // Registrations survive reopening until they lapse without renewal
+    let registry = FileRegistry::<u32>::open(&path, test_config()).unwrap();
+    assert_eq!(registry.lookup(&edge).await.unwrap(), Some(7));
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(registry.lookup(&edge).await.unwrap(), None);
+    drop(ident);
+
+    // Deregistration persists as well
+    let ident = registry.register(edge.clone(), &8).await.unwrap();
+    assert_eq!(
+      registry.deregister_identifier(ident).await.unwrap(),
+      Some(8)
+    );
+    drop(registry);
+    let registry = FileRegistry::<u32>::open(&path, test_config()).unwrap();
+    assert_eq!(registry.lookup(&edge).await.unwrap(), None);
+
+    drop(registry);
+    std::fs::remove_file(&path).unwrap();
+  }
+}
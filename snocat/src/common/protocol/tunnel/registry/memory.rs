@@ -1,31 +1,126 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license OR Apache 2.0
 
-use std::{fmt::Debug, sync::Arc};
+use std::{
+  any::Any,
+  fmt::Debug,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Once, Weak,
+  },
+  time::{Duration, Instant},
+};
 
 use dashmap::DashMap;
 use futures::{
-  future::{BoxFuture, FutureExt},
+  future::{self, BoxFuture, FutureExt},
+  stream::{BoxStream, StreamExt},
   TryFutureExt,
 };
-use tokio::task::JoinError;
+use tokio::{sync::broadcast, task::JoinError};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_util::sync::CancellationToken;
+
+use super::super::{
+  registry::{
+    AttributeValue, AttributeValueError, MultiOccupancyTunnelRegistry, RegistryConfig,
+    RegistryEvent, SelectionPolicy, SharedAttributeRegistry, TunnelRegistry, WatchFilter,
+    WatchableTunnelRegistry,
+  },
+  TunnelName,
+};
+use crate::util::{cancellation::CancellationListener, dropkick::Dropkick};
+
+/// Number of registry events retained for watchers which have yet to receive them
+const WATCH_EVENT_CAPACITY: usize = 256;
+
+fn watch_events(
+  events: &broadcast::Sender<RegistryEvent>,
+  filter: WatchFilter,
+) -> BoxStream<'static, RegistryEvent> {
+  BroadcastStream::new(events.subscribe())
+    .map(|event| event.unwrap_or(RegistryEvent::Lagged))
+    .filter(move |event| future::ready(filter.matches(event)))
+    .boxed()
+}
+
+#[derive(Debug)]
+struct TunnelEntry<R> {
+  rid: u64,
+  record: R,
+  expires_at: Instant,
+}
+
+impl<R> TunnelEntry<R> {
+  fn is_expired(&self, now: Instant) -> bool {
+    self.expires_at <= now
+  }
+}
+
+/// Entries stored under a name, which may be renewed and swept by registration ID
+trait ExpiringEntries {
+  /// Extends the expiry of the live entry with the given registration ID, if present
+  fn renew(&mut self, rid: u64, now: Instant, entry_lifetime: Duration) -> bool;
+
+  /// Drops expired entries, returning whether any were dropped and whether any remain
+  fn remove_expired(&mut self, now: Instant) -> (bool, bool);
+}
+
+impl<R> ExpiringEntries for TunnelEntry<R> {
+  fn renew(&mut self, rid: u64, now: Instant, entry_lifetime: Duration) -> bool {
+    if self.rid != rid || self.is_expired(now) {
+      return false;
+    }
+    self.expires_at = now + entry_lifetime;
+    true
+  }
 
-use super::super::{registry::TunnelRegistry, TunnelName};
+  fn remove_expired(&mut self, now: Instant) -> (bool, bool) {
+    let expired = self.is_expired(now);
+    (expired, !expired)
+  }
+}
+
+type TunnelMap<R> = DashMap<TunnelName, TunnelEntry<R>>;
 
+/// Registry holding one record per name for users of a single registry instance
+///
+/// Registrations are renewed in the background for as long as an identifier for them is held,
+/// and expire after the configured entry lifetime otherwise, as do [Redis](super::redis)
+/// registrations. Expired entries are swept away at the renewal rate. Names and entries are
+/// stored together, so the reference lifetime does not apply to in-memory registrations.
 pub struct InMemoryTunnelRegistry<R> {
-  tunnels: Arc<DashMap<TunnelName, R>>,
+  config: Arc<RegistryConfig>,
+  tunnels: Arc<TunnelMap<R>>,
+  events: Arc<broadcast::Sender<RegistryEvent>>,
+  next_rid: AtomicU64,
+  sweeper: Once,
+  core_canceller: Arc<Dropkick<CancellationToken>>,
 }
 
-#[derive(Debug, Clone, Hash)]
-pub struct InMemoryTunnelRegistryIdentifier(TunnelName);
+pub type Registration = Dropkick<CancellationToken>;
+
+#[derive(Debug, Clone)]
+pub struct InMemoryTunnelRegistryIdentifier {
+  tunnel_name: TunnelName,
+  rid: u64,
+  _registration: Option<Arc<Registration>>,
+}
 
-impl InMemoryTunnelRegistryIdentifier {
-  fn of(tunnel_name: &TunnelName) -> Self {
-    Self(tunnel_name.clone())
+impl std::hash::Hash for InMemoryTunnelRegistryIdentifier {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.tunnel_name.hash(state);
+    self.rid.hash(state);
   }
 }
 
-type Ident = InMemoryTunnelRegistryIdentifier;
+impl PartialEq for InMemoryTunnelRegistryIdentifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.tunnel_name == other.tunnel_name && self.rid == other.rid
+  }
+}
+
+impl Eq for InMemoryTunnelRegistryIdentifier {}
 
 #[derive(thiserror::Error, Debug)]
 pub enum InMemoryTunnelRegistryError {
@@ -54,10 +149,10 @@ where
     let tunnel_name = tunnel_name.clone();
     let tunnels = self.tunnels.clone();
     tokio::task::spawn_blocking(move || {
-      let item = if let Some(item) = tunnels.get(&tunnel_name) {
-        item.value().clone()
-      } else {
-        return Ok(None);
+      let now = Instant::now();
+      let item = match tunnels.get(&tunnel_name) {
+        Some(entry) if !entry.is_expired(now) => entry.record.clone(),
+        _ => return Ok(None),
       };
       Ok(Some(item))
     })
@@ -70,12 +165,37 @@ where
     tunnel_name: TunnelName,
     record: &'a Self::Record,
   ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>> {
+    self.start_sweeping();
     let tunnels = self.tunnels.clone();
+    let events = self.events.clone();
     let record = record.clone();
+    let rid = self.next_rid.fetch_add(1, Ordering::Relaxed);
+    let canceller = self.core_canceller.child_token();
+    tokio::task::spawn(renew_entry(
+      Arc::downgrade(&tunnels),
+      canceller.clone().into(),
+      tunnel_name.clone(),
+      rid,
+      Arc::clone(&self.config),
+    ));
+    let registration = Arc::new(Dropkick::new(canceller));
+    let entry_lifetime = self.config.tunnel_entry_lifetime;
     tokio::task::spawn_blocking(move || {
-      let identifier = Ident::of(&tunnel_name);
-      tunnels.insert(tunnel_name, record);
-      identifier
+      tunnels.insert(
+        tunnel_name.clone(),
+        TunnelEntry {
+          rid,
+          record,
+          expires_at: Instant::now() + entry_lifetime,
+        },
+      );
+      // Send; Ignore errors produced when no watchers exist to read the event
+      let _ = events.send(RegistryEvent::Registered(tunnel_name.clone()));
+      InMemoryTunnelRegistryIdentifier {
+        tunnel_name,
+        rid,
+        _registration: Some(registration),
+      }
     })
     .map_err(InMemoryTunnelRegistryError::from)
     .boxed()
@@ -86,28 +206,842 @@ where
     tunnel_name: &'a TunnelName,
   ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
     let tunnels = self.tunnels.clone();
+    let events = self.events.clone();
     let tunnel_name = tunnel_name.clone();
-    tokio::task::spawn_blocking(move || tunnels.remove(&tunnel_name).map(|(_, record)| record))
-      .map_err(InMemoryTunnelRegistryError::from)
-      .boxed()
+    tokio::task::spawn_blocking(move || {
+      let removed = tunnels
+        .remove(&tunnel_name)
+        .filter(|(_, entry)| !entry.is_expired(Instant::now()))
+        .map(|(_, entry)| entry.record);
+      if removed.is_some() {
+        let _ = events.send(RegistryEvent::Deregistered(tunnel_name));
+      }
+      removed
+    })
+    .map_err(InMemoryTunnelRegistryError::from)
+    .boxed()
   }
 
   fn deregister_identifier<'a>(
     &'a self,
     identifier: Self::Identifier,
   ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
-    Self::deregister(&self, &identifier.0)
+    let tunnels = self.tunnels.clone();
+    let events = self.events.clone();
+    tokio::task::spawn_blocking(move || {
+      let InMemoryTunnelRegistryIdentifier {
+        tunnel_name, rid, ..
+      } = identifier;
+      // Only remove the entry if it has not since been replaced by another registration
+      let removed = tunnels
+        .remove_if(&tunnel_name, |_, entry| entry.rid == rid)
+        .filter(|(_, entry)| !entry.is_expired(Instant::now()))
+        .map(|(_, entry)| entry.record);
+      if removed.is_some() {
+        let _ = events.send(RegistryEvent::Deregistered(tunnel_name));
+      }
+      removed
+    })
+    .map_err(InMemoryTunnelRegistryError::from)
+    .boxed()
+  }
+}
+
+impl<R> WatchableTunnelRegistry for InMemoryTunnelRegistry<R>
+where
+  R: Send + Sync + Debug + Clone + 'static,
+{
+  fn watch<'a>(
+    &'a self,
+    filter: WatchFilter,
+  ) -> BoxFuture<'static, Result<BoxStream<'static, RegistryEvent>, Self::Error>> {
+    future::ready(Ok(watch_events(&self.events, filter))).boxed()
   }
 }
 
 impl<R> InMemoryTunnelRegistry<R> {
   pub fn new() -> Self {
     Self {
+      config: Default::default(),
+      tunnels: Default::default(),
+      events: Arc::new(broadcast::channel(WATCH_EVENT_CAPACITY).0),
+      next_rid: AtomicU64::new(0),
+      sweeper: Once::new(),
+      core_canceller: Arc::new(Dropkick::new(CancellationToken::new())),
+    }
+  }
+
+  /// Sets the lifetime and renewal rate of registrations made from this point onward
+  pub fn with_config(mut self, config: RegistryConfig) -> Self {
+    self.config = Arc::new(config);
+    self
+  }
+
+  /// Counts registered tunnels, including expired entries which have not yet been swept
+  pub fn len(&self) -> usize {
+    self.tunnels.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tunnels.is_empty()
+  }
+}
+
+impl<R> InMemoryTunnelRegistry<R>
+where
+  R: Send + Sync + 'static,
+{
+  /// Starts the background removal of expired entries, if it is not yet running
+  ///
+  /// Deferred until first registration, as registries may be constructed outside of a runtime.
+  fn start_sweeping(&self) {
+    self.sweeper.call_once(|| {
+      tokio::task::spawn(sweep_expired(
+        Arc::downgrade(&self.tunnels),
+        Arc::clone(&self.events),
+        self.core_canceller.child_token().into(),
+        self.config.effective_renewal_rate(),
+      ));
+    });
+  }
+}
+
+impl<R> Default for InMemoryTunnelRegistry<R> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Extends the expiry of an entry until cancelled or the entry is removed or replaced
+async fn renew_entry<E: ExpiringEntries>(
+  tunnels: Weak<DashMap<TunnelName, E>>,
+  canceller: CancellationListener,
+  tunnel_name: TunnelName,
+  rid: u64,
+  config: Arc<RegistryConfig>,
+) {
+  let renewal_rate = config.effective_renewal_rate();
+  while !canceller.is_cancelled() {
+    futures::future::select(
+      tokio::time::sleep(renewal_rate).boxed(),
+      canceller.cancelled().boxed(),
+    )
+    .await;
+    if canceller.is_cancelled() {
+      break;
+    }
+    let tunnels = match tunnels.upgrade() {
+      Some(tunnels) => tunnels,
+      None => break,
+    };
+    let renewed = tunnels.get_mut(&tunnel_name).map_or(false, |mut entries| {
+      entries.renew(rid, Instant::now(), config.tunnel_entry_lifetime)
+    });
+    if !renewed {
+      break;
+    }
+  }
+}
+
+/// Removes expired entries from the registry until it is dropped
+async fn sweep_expired<E: ExpiringEntries + Send + Sync + 'static>(
+  tunnels: Weak<DashMap<TunnelName, E>>,
+  events: Arc<broadcast::Sender<RegistryEvent>>,
+  canceller: CancellationListener,
+  sweep_rate: Duration,
+) {
+  while !canceller.is_cancelled() {
+    futures::future::select(
+      tokio::time::sleep(sweep_rate).boxed(),
+      canceller.cancelled().boxed(),
+    )
+    .await;
+    if canceller.is_cancelled() {
+      break;
+    }
+    let tunnels = match tunnels.upgrade() {
+      Some(tunnels) => tunnels,
+      None => break,
+    };
+    let events = Arc::clone(&events);
+    let swept = tokio::task::spawn_blocking(move || {
+      let now = Instant::now();
+      tunnels.retain(|tunnel_name, entries| {
+        let (removed, remaining) = entries.remove_expired(now);
+        if removed {
+          let _ = events.send(RegistryEvent::Deregistered(tunnel_name.clone()));
+        }
+        remaining
+      });
+    })
+    .await;
+    if let Err(e) = swept {
+      tracing::warn!(error = ?e, "In-memory registry sweep failed to rejoin");
+    }
+  }
+}
+
+/// Records registered under a name, ordered from oldest to newest, keyed by occupant number
+type Occupants<R> = Vec<TunnelEntry<R>>;
+
+impl<R> ExpiringEntries for Occupants<R> {
+  fn renew(&mut self, rid: u64, now: Instant, entry_lifetime: Duration) -> bool {
+    self
+      .iter_mut()
+      .find(|occupant| occupant.rid == rid)
+      .map_or(false, |occupant| occupant.renew(rid, now, entry_lifetime))
+  }
+
+  fn remove_expired(&mut self, now: Instant) -> (bool, bool) {
+    let count = self.len();
+    self.retain(|occupant| !occupant.is_expired(now));
+    (self.len() != count, !self.is_empty())
+  }
+}
+
+/// Registry holding every registration of a name, choosing among them upon lookup by [SelectionPolicy]
+///
+/// Occupants are renewed and expire individually, as entries of an [InMemoryTunnelRegistry] do.
+pub struct InMemoryMultiTunnelRegistry<R> {
+  config: Arc<RegistryConfig>,
+  tunnels: Arc<DashMap<TunnelName, Occupants<R>>>,
+  policy: SelectionPolicy<R>,
+  next_occupant: AtomicU64,
+  events: Arc<broadcast::Sender<RegistryEvent>>,
+  sweeper: Once,
+  core_canceller: Arc<Dropkick<CancellationToken>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InMemoryOccupantIdentifier {
+  tunnel_name: TunnelName,
+  occupant: u64,
+  _registration: Option<Arc<Registration>>,
+}
+
+impl std::hash::Hash for InMemoryOccupantIdentifier {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.tunnel_name.hash(state);
+    self.occupant.hash(state);
+  }
+}
+
+impl PartialEq for InMemoryOccupantIdentifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.tunnel_name == other.tunnel_name && self.occupant == other.occupant
+  }
+}
+
+impl Eq for InMemoryOccupantIdentifier {}
+
+impl<R> InMemoryMultiTunnelRegistry<R> {
+  pub fn new(policy: SelectionPolicy<R>) -> Self {
+    Self {
+      config: Default::default(),
       tunnels: Default::default(),
+      policy,
+      next_occupant: AtomicU64::new(0),
+      events: Arc::new(broadcast::channel(WATCH_EVENT_CAPACITY).0),
+      sweeper: Once::new(),
+      core_canceller: Arc::new(Dropkick::new(CancellationToken::new())),
     }
   }
 
+  /// Sets the lifetime and renewal rate of registrations made from this point onward
+  pub fn with_config(mut self, config: RegistryConfig) -> Self {
+    self.config = Arc::new(config);
+    self
+  }
+
+  /// Number of names with at least one registration, including expired occupants not yet swept
   pub fn len(&self) -> usize {
     self.tunnels.len()
   }
+
+  pub fn is_empty(&self) -> bool {
+    self.tunnels.is_empty()
+  }
+}
+
+impl<R> InMemoryMultiTunnelRegistry<R>
+where
+  R: Send + Sync + 'static,
+{
+  /// Starts the background removal of expired occupants, if it is not yet running
+  fn start_sweeping(&self) {
+    self.sweeper.call_once(|| {
+      tokio::task::spawn(sweep_expired(
+        Arc::downgrade(&self.tunnels),
+        Arc::clone(&self.events),
+        self.core_canceller.child_token().into(),
+        self.config.effective_renewal_rate(),
+      ));
+    });
+  }
+}
+
+impl<R> TunnelRegistry for InMemoryMultiTunnelRegistry<R>
+where
+  R: Send + Sync + Debug + Clone + 'static,
+{
+  type Identifier = InMemoryOccupantIdentifier;
+
+  type Record = R;
+
+  type Error = InMemoryTunnelRegistryError;
+
+  fn lookup<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let policy = self.policy.clone();
+    self
+      .lookup_all(tunnel_name)
+      .map_ok(move |occupants| policy.select(occupants))
+      .boxed()
+  }
+
+  fn register<'a>(
+    &'a self,
+    tunnel_name: TunnelName,
+    record: &'a Self::Record,
+  ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>> {
+    self.start_sweeping();
+    let tunnels = self.tunnels.clone();
+    let events = self.events.clone();
+    let record = record.clone();
+    let occupant = self.next_occupant.fetch_add(1, Ordering::Relaxed);
+    let canceller = self.core_canceller.child_token();
+    tokio::task::spawn(renew_entry(
+      Arc::downgrade(&tunnels),
+      canceller.clone().into(),
+      tunnel_name.clone(),
+      occupant,
+      Arc::clone(&self.config),
+    ));
+    let registration = Arc::new(Dropkick::new(canceller));
+    let entry_lifetime = self.config.tunnel_entry_lifetime;
+    tokio::task::spawn_blocking(move || {
+      tunnels
+        .entry(tunnel_name.clone())
+        .or_default()
+        .push(TunnelEntry {
+          rid: occupant,
+          record,
+          expires_at: Instant::now() + entry_lifetime,
+        });
+      let _ = events.send(RegistryEvent::Registered(tunnel_name.clone()));
+      InMemoryOccupantIdentifier {
+        tunnel_name,
+        occupant,
+        _registration: Some(registration),
+      }
+    })
+    .map_err(InMemoryTunnelRegistryError::from)
+    .boxed()
+  }
+
+  fn deregister<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let tunnels = self.tunnels.clone();
+    let events = self.events.clone();
+    let tunnel_name = tunnel_name.clone();
+    tokio::task::spawn_blocking(move || {
+      let (_, occupants) = tunnels.remove(&tunnel_name)?;
+      let now = Instant::now();
+      let removed = occupants
+        .into_iter()
+        .rev()
+        .find(|occupant| !occupant.is_expired(now))
+        .map(|occupant| occupant.record);
+      if removed.is_some() {
+        let _ = events.send(RegistryEvent::Deregistered(tunnel_name));
+      }
+      removed
+    })
+    .map_err(InMemoryTunnelRegistryError::from)
+    .boxed()
+  }
+
+  fn deregister_identifier<'a>(
+    &'a self,
+    identifier: Self::Identifier,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let tunnels = self.tunnels.clone();
+    let events = self.events.clone();
+    tokio::task::spawn_blocking(move || {
+      let InMemoryOccupantIdentifier {
+        tunnel_name,
+        occupant,
+        ..
+      } = identifier;
+      let removed = tunnels
+        .get_mut(&tunnel_name)
+        .and_then(|mut occupants| {
+          let index = occupants.iter().position(|o| o.rid == occupant)?;
+          Some(occupants.remove(index))
+        })
+        .filter(|occupant| !occupant.is_expired(Instant::now()))
+        .map(|occupant| occupant.record);
+      // Names are dropped from the table along with their last occupant
+      tunnels.remove_if(&tunnel_name, |_, occupants| occupants.is_empty());
+      if removed.is_some() {
+        let _ = events.send(RegistryEvent::Deregistered(tunnel_name));
+      }
+      removed
+    })
+    .map_err(InMemoryTunnelRegistryError::from)
+    .boxed()
+  }
+}
+
+impl<R> MultiOccupancyTunnelRegistry for InMemoryMultiTunnelRegistry<R>
+where
+  R: Send + Sync + Debug + Clone + 'static,
+{
+  fn lookup_all<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Vec<Self::Record>, Self::Error>> {
+    let tunnel_name = tunnel_name.clone();
+    let tunnels = self.tunnels.clone();
+    tokio::task::spawn_blocking(move || {
+      let now = Instant::now();
+      tunnels
+        .get(&tunnel_name)
+        .map(|occupants| {
+          occupants
+            .iter()
+            .filter(|occupant| !occupant.is_expired(now))
+            .map(|occupant| occupant.record.clone())
+            .collect()
+        })
+        .unwrap_or_default()
+    })
+    .map_err(InMemoryTunnelRegistryError::from)
+    .boxed()
+  }
+}
+
+impl<R> WatchableTunnelRegistry for InMemoryMultiTunnelRegistry<R>
+where
+  R: Send + Sync + Debug + Clone + 'static,
+{
+  fn watch<'a>(
+    &'a self,
+    filter: WatchFilter,
+  ) -> BoxFuture<'static, Result<BoxStream<'static, RegistryEvent>, Self::Error>> {
+    future::ready(Ok(watch_events(&self.events, filter))).boxed()
+  }
+}
+
+#[derive(Debug)]
+struct AttributeEntry {
+  rid: u64,
+  value: Arc<dyn Any + Send + Sync>,
+  /// Set once the last identifier for the registration is dropped
+  expires_at: Option<Instant>,
+}
+
+impl AttributeEntry {
+  fn is_expired(&self, now: Instant) -> bool {
+    self
+      .expires_at
+      .map_or(false, |expires_at| expires_at <= now)
+  }
+}
+
+type AttributeMap = DashMap<String, AttributeEntry>;
+
+/// Starts the expiry of a registration when the last identifier holding it is dropped
+#[derive(Debug)]
+struct AttributeLease {
+  attributes: Weak<AttributeMap>,
+  key: String,
+  rid: u64,
+  entry_lifetime: Duration,
+}
+
+impl Drop for AttributeLease {
+  fn drop(&mut self) {
+    if let Some(attributes) = self.attributes.upgrade() {
+      if let Some(mut entry) = attributes.get_mut(&self.key) {
+        if entry.rid == self.rid {
+          entry.expires_at = Some(Instant::now() + self.entry_lifetime);
+        }
+      }
+    }
+  }
+}
+
+/// Attributes shared between the users of a single registry instance
+///
+/// Registrations are renewed for as long as an identifier for them is held, and expire after
+/// the entry lifetime once the last is dropped, as do [Redis](super::redis) registrations.
+/// Values are stored as-is, and may be of any [`Clone`] type.
+pub struct InMemoryAttributeRegistry {
+  attributes: Arc<AttributeMap>,
+  entry_lifetime: Duration,
+  next_rid: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+pub struct InMemoryAttributeIdentifier {
+  key: String,
+  rid: u64,
+  _lease: Option<Arc<AttributeLease>>,
+}
+
+impl std::hash::Hash for InMemoryAttributeIdentifier {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.key.hash(state);
+    self.rid.hash(state);
+  }
+}
+
+impl PartialEq for InMemoryAttributeIdentifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.key == other.key && self.rid == other.rid
+  }
+}
+
+impl Eq for InMemoryAttributeIdentifier {}
+
+#[derive(thiserror::Error, Debug)]
+pub enum InMemoryAttributeRegistryError {
+  #[error("Registry task failed to rejoin to async pool")]
+  JoinError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    JoinError,
+  ),
+  #[error("Registry attribute value error: {0}")]
+  ValueError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    AttributeValueError,
+  ),
+}
+
+impl InMemoryAttributeRegistry {
+  pub fn new() -> Self {
+    Self {
+      attributes: Default::default(),
+      entry_lifetime: Duration::from_secs(60),
+      next_rid: AtomicU64::new(0),
+    }
+  }
+
+  /// Sets how long attributes remain after the last identifier for their registration is dropped
+  pub fn with_entry_lifetime(mut self, entry_lifetime: Duration) -> Self {
+    self.entry_lifetime = entry_lifetime;
+    self
+  }
+
+  /// Counts registered attributes, including expired attributes which have not yet been removed
+  pub fn len(&self) -> usize {
+    self.attributes.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.attributes.is_empty()
+  }
+}
+
+impl Default for InMemoryAttributeRegistry {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T> AttributeValue<T> for InMemoryAttributeRegistry
+where
+  T: Clone + Send + Sync + 'static,
+{
+  type Stored = Arc<dyn Any + Send + Sync>;
+
+  fn unpack(stored: &Self::Stored) -> Result<T, AttributeValueError> {
+    stored
+      .downcast_ref::<T>()
+      .cloned()
+      .ok_or(AttributeValueError::MismatchedType)
+  }
+
+  fn pack(input: &T) -> Result<Self::Stored, AttributeValueError> {
+    Ok(Arc::new(input.clone()))
+  }
+}
+
+impl SharedAttributeRegistry for InMemoryAttributeRegistry {
+  type Identifier = InMemoryAttributeIdentifier;
+
+  type Value = Arc<dyn Any + Send + Sync>;
+
+  type Error = InMemoryAttributeRegistryError;
+
+  fn lookup_attr<'a, V>(
+    &'a self,
+    key: &'a str,
+  ) -> BoxFuture<'static, Result<Option<V>, Self::Error>>
+  where
+    Self: AttributeValue<V, Stored = Self::Value>,
+    V: Send + 'static,
+  {
+    let attributes = Arc::clone(&self.attributes);
+    let key = key.to_owned();
+    tokio::task::spawn_blocking(move || {
+      let now = Instant::now();
+      let value = attributes
+        .get(&key)
+        .map(|entry| (!entry.is_expired(now)).then(|| Arc::clone(&entry.value)));
+      match value {
+        Some(Some(value)) => Ok(Some(<Self as AttributeValue<V>>::unpack(&value)?)),
+        Some(None) => {
+          // Expired entries are removed once the read guard on them has been released
+          attributes.remove_if(&key, |_, entry| entry.is_expired(now));
+          Ok(None)
+        }
+        None => Ok(None),
+      }
+    })
+    .unwrap_or_else(|e| Err(InMemoryAttributeRegistryError::from(e)))
+    .boxed()
+  }
+
+  fn register_attr<'a, V>(
+    &'a self,
+    key: &'a str,
+    value: &'a V,
+  ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>>
+  where
+    Self: AttributeValue<V, Stored = Self::Value>,
+  {
+    let value = match <Self as AttributeValue<V>>::pack(value) {
+      Ok(value) => value,
+      Err(e) => return futures::future::ready(Err(e.into())).boxed(),
+    };
+    let attributes = Arc::clone(&self.attributes);
+    let key = key.to_owned();
+    let rid = self.next_rid.fetch_add(1, Ordering::Relaxed);
+    let lease = Arc::new(AttributeLease {
+      attributes: Arc::downgrade(&attributes),
+      key: key.clone(),
+      rid,
+      entry_lifetime: self.entry_lifetime,
+    });
+    tokio::task::spawn_blocking(move || {
+      let now = Instant::now();
+      attributes.retain(|_, entry| !entry.is_expired(now));
+      attributes.insert(
+        key.clone(),
+        AttributeEntry {
+          rid,
+          value,
+          expires_at: None,
+        },
+      );
+      InMemoryAttributeIdentifier {
+        key,
+        rid,
+        _lease: Some(lease),
+      }
+    })
+    .map_err(InMemoryAttributeRegistryError::from)
+    .boxed()
+  }
+
+  fn deregister_attr<'a>(
+    &'a self,
+    key: &'a str,
+  ) -> BoxFuture<'static, Result<Option<Self::Identifier>, Self::Error>> {
+    let attributes = Arc::clone(&self.attributes);
+    let key = key.to_owned();
+    tokio::task::spawn_blocking(move || {
+      attributes
+        .remove(&key)
+        .filter(|(_, entry)| !entry.is_expired(Instant::now()))
+        .map(|(key, entry)| InMemoryAttributeIdentifier {
+          key,
+          rid: entry.rid,
+          _lease: None,
+        })
+    })
+    .map_err(InMemoryAttributeRegistryError::from)
+    .boxed()
+  }
+
+  fn deregister_attr_identifier<'a>(
+    &'a self,
+    identifier: Self::Identifier,
+  ) -> BoxFuture<'static, Result<Option<Self::Identifier>, Self::Error>> {
+    let attributes = Arc::clone(&self.attributes);
+    tokio::task::spawn_blocking(move || {
+      let InMemoryAttributeIdentifier { key, rid, .. } = identifier;
+      attributes
+        .remove_if(&key, |_, entry| entry.rid == rid)
+        .filter(|(_, entry)| !entry.is_expired(Instant::now()))
+        .map(|(key, entry)| InMemoryAttributeIdentifier {
+          key,
+          rid: entry.rid,
+          _lease: None,
+        })
+    })
+    .map_err(InMemoryAttributeRegistryError::from)
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::Arc, time::Duration};
+
+  use super::{
+    InMemoryAttributeRegistry, InMemoryAttributeRegistryError, InMemoryMultiTunnelRegistry,
+    InMemoryTunnelRegistry,
+  };
+  use crate::common::protocol::tunnel::{
+    registry::{
+      MultiOccupancyTunnelRegistry, RegistryConfig, SelectionPolicy, SharedAttributeRegistry,
+      TunnelRegistry,
+    },
+    TunnelName,
+  };
+
+  #[tokio::test]
+  async fn expiry_and_renewal() {
+    let registry = InMemoryTunnelRegistry::<u32>::new().with_config(RegistryConfig {
+      tunnel_entry_lifetime: Duration::from_millis(100),
+      renewal_rate: Duration::from_millis(20),
+      ..Default::default()
+    });
+    let edge = TunnelName::new("edge-1");
+    let ident = registry.register(edge.clone(), &7).await.unwrap();
+
+    // Renewal keeps the registration alive past its lifetime while its identifier is held
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(registry.lookup(&edge).await.unwrap(), Some(7));
+
+    // A replaced registration can no longer deregister the name
+    let replacement = registry.register(edge.clone(), &8).await.unwrap();
+    assert_eq!(registry.deregister_identifier(ident).await.unwrap(), None);
+    assert_eq!(registry.lookup(&edge).await.unwrap(), Some(8));
+
+    // Dropping the identifier without deregistering lets the entry expire and be swept
+    drop(replacement);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(registry.lookup(&edge).await.unwrap(), None);
+    assert!(registry.is_empty());
+  }
+
+  #[tokio::test]
+  async fn multi_occupancy() {
+    // Records are (edge, active connection count)
+    let registry =
+      InMemoryMultiTunnelRegistry::new(SelectionPolicy::least_loaded(|(_, load): &(&str, u64)| {
+        *load
+      }));
+    let site = TunnelName::new("site-1");
+    let first = registry.register(site.clone(), &("a", 5)).await.unwrap();
+    registry.register(site.clone(), &("b", 2)).await.unwrap();
+    registry.register(site.clone(), &("c", 2)).await.unwrap();
+    assert_eq!(
+      registry.lookup_all(&site).await.unwrap(),
+      vec![("a", 5), ("b", 2), ("c", 2)]
+    );
+    assert_eq!(registry.lookup(&site).await.unwrap(), Some(("b", 2)));
+
+    let occupants = registry.lookup_all(&site).await.unwrap();
+    assert_eq!(
+      SelectionPolicy::Newest.select(occupants.clone()),
+      Some(("c", 2))
+    );
+    assert_eq!(
+      SelectionPolicy::Oldest.select(occupants.clone()),
+      Some(("a", 5))
+    );
+    assert!(occupants.contains(&SelectionPolicy::Random.select(occupants.clone()).unwrap()));
+    assert_eq!(SelectionPolicy::<u64>::Random.select(Vec::new()), None);
+
+    // Deregistering one occupant leaves the others in place
+    assert_eq!(
+      registry.deregister_identifier(first).await.unwrap(),
+      Some(("a", 5))
+    );
+    assert_eq!(registry.lookup_all(&site).await.unwrap().len(), 2);
+
+    // Deregistering the name removes every occupant
+    assert_eq!(registry.deregister(&site).await.unwrap(), Some(("c", 2)));
+    assert_eq!(registry.lookup(&site).await.unwrap(), None);
+    assert!(registry.is_empty());
+  }
+
+  #[tokio::test]
+  async fn multi_occupancy_expiry() {
+    let registry =
+      InMemoryMultiTunnelRegistry::new(SelectionPolicy::Newest).with_config(RegistryConfig {
+        tunnel_entry_lifetime: Duration::from_millis(100),
+        renewal_rate: Duration::from_millis(20),
+        ..Default::default()
+      });
+    let site = TunnelName::new("site-1");
+    let held = registry.register(site.clone(), &1u32).await.unwrap();
+    let dropped = registry.register(site.clone(), &2u32).await.unwrap();
+
+    // Occupants whose identifiers are dropped expire alone, while held ones are renewed
+    drop(dropped);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(registry.lookup_all(&site).await.unwrap(), vec![1]);
+    assert_eq!(registry.lookup(&site).await.unwrap(), Some(1));
+
+    // The name is swept along with its last occupant
+    drop(held);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(registry.lookup(&site).await.unwrap(), None);
+    assert!(registry.is_empty());
+  }
+
+  #[tokio::test]
+  async fn attribute_registration() {
+    let registry = Arc::new(InMemoryAttributeRegistry::new().with_entry_lifetime(Duration::ZERO));
+    let ident = registry
+      .register_attr("ports/edge-1", &8080u16)
+      .await
+      .unwrap();
+    assert_eq!(
+      registry.lookup_attr::<u16>("ports/edge-1").await.unwrap(),
+      Some(8080)
+    );
+    assert!(matches!(
+      registry.lookup_attr::<String>("ports/edge-1").await,
+      Err(InMemoryAttributeRegistryError::ValueError(_))
+    ));
+    assert_eq!(
+      registry.lookup_attr::<u16>("ports/edge-2").await.unwrap(),
+      None
+    );
+
+    // A later registration replaces the earlier, which can then no longer deregister it
+    let replacement = registry
+      .register_attr("ports/edge-1", &8081u16)
+      .await
+      .unwrap();
+    assert_eq!(
+      registry.deregister_attr_identifier(ident).await.unwrap(),
+      None
+    );
+    assert_eq!(
+      registry.lookup_attr::<u16>("ports/edge-1").await.unwrap(),
+      Some(8081)
+    );
+
+    // Dropping the last identifier lets the attribute expire
+    drop(replacement);
+    assert_eq!(
+      registry.lookup_attr::<u16>("ports/edge-1").await.unwrap(),
+      None
+    );
+    assert!(registry.is_empty());
+  }
 }
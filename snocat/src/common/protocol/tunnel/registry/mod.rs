@@ -2,20 +2,73 @@
 // Licensed under the MIT license OR Apache 2.0
 
 use downcast_rs::{impl_downcast, Downcast, DowncastSync};
-use futures::future::BoxFuture;
+use futures::{future::BoxFuture, stream::BoxStream};
+use rand::Rng;
 use std::{
   fmt::{Debug, Display},
   hash::Hash,
+  sync::Arc,
+  time::Duration,
 };
 
 use crate::common::protocol::tunnel::TunnelName;
 
 pub mod cache;
+pub mod file;
 pub mod mapped;
 pub mod memory;
 #[cfg(feature = "redis-store")]
 pub mod redis;
 
+/// Configuration for expiry and renewal of registrations, shared by each registry backend
+///
+/// Registrations remain alive for as long as an identifier for them is held, being renewed in the
+/// background, and expire once their last identifier is dropped without explicit deregistration.
+///
+/// Use `..Default::default()` to initialize, as this is
+/// non-exhaustive for when new parameters are added.
+pub struct RegistryConfig {
+  /// Time before expiring tunnel name to unique-key mappings
+  ///
+  /// If the target key expires before the name-to-id reference,
+  /// it behaves as if it is null anyway.
+  ///
+  /// Only durations higher than seconds count in Redis
+  pub tunnel_id_ref_lifetime: Duration,
+  /// Time before expiring tunnel entries at unique-key locations
+  ///
+  /// Only durations higher than seconds count in Redis
+  pub tunnel_entry_lifetime: Duration,
+  /// How often the background thread will attempt to refresh expiry of its items
+  ///
+  /// If a refresh is attempted and the target is missing, and no current target
+  /// exists at the name mapping, it will attempt reregistration.
+  pub renewal_rate: Duration,
+}
+
+impl RegistryConfig {
+  /// The renewal rate, limited to avoid constant renewal work; minimum is 1 second in non-test envs
+  pub(crate) fn effective_renewal_rate(&self) -> Duration {
+    self.renewal_rate.max({
+      if cfg!(test) {
+        Duration::from_millis(10)
+      } else {
+        Duration::from_secs(1)
+      }
+    })
+  }
+}
+
+impl Default for RegistryConfig {
+  fn default() -> Self {
+    Self {
+      tunnel_id_ref_lifetime: Duration::from_secs(600),
+      tunnel_entry_lifetime: Duration::from_secs(60),
+      renewal_rate: Duration::from_secs(20),
+    }
+  }
+}
+
 /// An eventually-consistent mapping of [`TunnelName`]/Tunnel associations
 ///
 /// This guarantees only eventual consistency, and that tunnel records are safe to cache; as
@@ -69,26 +122,166 @@ pub trait TunnelRegistry: Downcast + DowncastSync {
 }
 impl_downcast!(sync TunnelRegistry assoc Identifier, Record, Error);
 
+/// A change to the registrations of a [`TunnelName`], as reported by a [WatchableTunnelRegistry]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryEvent {
+  Registered(TunnelName),
+  Deregistered(TunnelName),
+  /// Events may have been missed since the previous event was reported
+  ///
+  /// Watchers should discard anything derived from earlier events.
+  Lagged,
+}
+
+impl RegistryEvent {
+  pub fn tunnel_name(&self) -> Option<&TunnelName> {
+    match self {
+      Self::Registered(tunnel_name) | Self::Deregistered(tunnel_name) => Some(tunnel_name),
+      Self::Lagged => None,
+    }
+  }
+}
+
+/// Selects the tunnel names for which a [WatchableTunnelRegistry] reports changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchFilter {
+  All,
+  Name(TunnelName),
+  Prefix(String),
+}
+
+impl WatchFilter {
+  /// Whether an event should be reported to the watcher; [RegistryEvent::Lagged] always is
+  pub fn matches(&self, event: &RegistryEvent) -> bool {
+    match (self, event.tunnel_name()) {
+      (_, None) | (Self::All, _) => true,
+      (Self::Name(name), Some(tunnel_name)) => name == tunnel_name,
+      (Self::Prefix(prefix), Some(tunnel_name)) => tunnel_name.raw().starts_with(prefix.as_str()),
+    }
+  }
+}
+
+/// A [TunnelRegistry] which can report changes to its registrations as they occur
+///
+/// Changes made through other instances sharing the same backing store are reported as well,
+/// when the implementation is capable of observing them.
+pub trait WatchableTunnelRegistry: TunnelRegistry {
+  /// Streams changes to registrations matching the filter, beginning once the returned future completes
+  ///
+  /// Delivery is best-effort; watchers which fall behind receive [RegistryEvent::Lagged] in place
+  /// of the events they missed.
+  fn watch<'a>(
+    &'a self,
+    filter: WatchFilter,
+  ) -> BoxFuture<'static, Result<BoxStream<'static, RegistryEvent>, Self::Error>>;
+}
+
+/// Chooses the registration returned by [lookup](TunnelRegistry::lookup) when a
+/// [multi-occupancy registry](MultiOccupancyTunnelRegistry) holds several under one name
+pub enum SelectionPolicy<R> {
+  Newest,
+  Oldest,
+  Random,
+  /// The registration whose record reports the least load, preferring the oldest among equals
+  LeastLoaded(Arc<dyn Fn(&R) -> u64 + Send + Sync + 'static>),
+}
+
+impl<R> SelectionPolicy<R> {
+  pub fn least_loaded<F: Fn(&R) -> u64 + Send + Sync + 'static>(load: F) -> Self {
+    Self::LeastLoaded(Arc::new(load))
+  }
+
+  /// Selects among registrations ordered from oldest to newest
+  pub fn select(&self, mut occupants: Vec<R>) -> Option<R> {
+    let index = match self {
+      Self::Newest => return occupants.pop(),
+      Self::Oldest => 0,
+      Self::Random if !occupants.is_empty() => rand::thread_rng().gen_range(0..occupants.len()),
+      Self::Random => return None,
+      Self::LeastLoaded(load) => {
+        occupants
+          .iter()
+          .enumerate()
+          .min_by_key(|(_, record)| load(record))?
+          .0
+      }
+    };
+    (index < occupants.len()).then(|| occupants.swap_remove(index))
+  }
+}
+
+impl<R> Clone for SelectionPolicy<R> {
+  fn clone(&self) -> Self {
+    match self {
+      Self::Newest => Self::Newest,
+      Self::Oldest => Self::Oldest,
+      Self::Random => Self::Random,
+      Self::LeastLoaded(load) => Self::LeastLoaded(Arc::clone(load)),
+    }
+  }
+}
+
+impl<R> Debug for SelectionPolicy<R> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Newest => f.write_str("Newest"),
+      Self::Oldest => f.write_str("Oldest"),
+      Self::Random => f.write_str("Random"),
+      Self::LeastLoaded(_) => f.write_str("LeastLoaded(..)"),
+    }
+  }
+}
+
+/// A [TunnelRegistry] which holds every active registration of a name, rather than only the latest
+///
+/// [lookup](TunnelRegistry::lookup) chooses among a name's registrations by [SelectionPolicy],
+/// while [deregister](TunnelRegistry::deregister) removes all of them, returning the newest.
+pub trait MultiOccupancyTunnelRegistry: TunnelRegistry {
+  /// Looks up every active registration of a tunnel name, ordered from oldest to newest
+  fn lookup_all<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Vec<Self::Record>, Self::Error>>;
+}
+
 /// Provides a means of access for a value of a specified type, for use in [Attribute Registries](trait@SharedAttributeRegistry)
 pub trait AttributeValue<T> {
   /// Type stored internally for the given input
   type Stored;
 
-  fn unpack(stored: &Self::Stored) -> T;
+  fn unpack(stored: &Self::Stored) -> Result<T, AttributeValueError>;
 
-  fn pack(input: T) -> Self::Stored;
+  fn pack(input: &T) -> Result<Self::Stored, AttributeValueError>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AttributeValueError {
+  #[error("Attribute is not of the requested type")]
+  MismatchedType,
+  #[error("Attribute serialization error: {0}")]
+  SerializationError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    serde_json::Error,
+  ),
 }
 
 /// A [`Sync`] registry of attributes retrievable [by value](trait@AttributeValue) through string keys
+///
+/// Registrations are kept alive by their identifiers, as with [tunnel registrations](TunnelRegistry::register);
+/// once the last identifier for a registration is dropped, the attribute may expire.
 pub trait SharedAttributeRegistry: Downcast + DowncastSync {
   /// A value returned that uniquely addresses a registered key,
   /// such that it can be cleared without complex queries or lookup.
   ///
   /// Note that Identifiers may be irreversible to their inputs.
-  type Identifier: Send + Sync + Debug + Clone + Hash;
+  type Identifier: Send + Sync + Debug + Clone + Hash + 'static;
+
+  /// Form in which the registry stores attribute values, [packed](AttributeValue::pack) from each value type
+  type Value;
 
   /// Implementation-specific errors from the registry
-  type Error: Debug + Display;
+  type Error: Send + Debug + Display + From<AttributeValueError> + 'static;
 
   /// Looks up a key by name in the target's key-space
   ///
@@ -97,7 +290,10 @@ pub trait SharedAttributeRegistry: Downcast + DowncastSync {
   fn lookup_attr<'a, V>(
     &'a self,
     key: &'a str,
-  ) -> BoxFuture<'static, Result<Option<V>, Self::Error>>;
+  ) -> BoxFuture<'static, Result<Option<V>, Self::Error>>
+  where
+    Self: AttributeValue<V, Stored = Self::Value>,
+    V: Send + 'static;
 
   /// Registers an attribute to a key within the target's key-space
   ///
@@ -107,7 +303,9 @@ pub trait SharedAttributeRegistry: Downcast + DowncastSync {
     &'a self,
     key: &'a str,
     value: &'a V,
-  ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>>;
+  ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>>
+  where
+    Self: AttributeValue<V, Stored = Self::Value>;
 
   /// Deregisters an attribute by its key within the target's key-space
   fn deregister_attr<'a>(
@@ -4,52 +4,35 @@
 use std::{
   fmt::Debug,
   marker::PhantomData,
+  pin::Pin,
   sync::{Arc, Weak},
-  time::Duration,
+  task::{Context, Poll},
+  time::{Duration, SystemTime},
 };
 
 use dashmap::DashMap;
-use futures::future::{BoxFuture, FutureExt};
+use futures::{
+  future::{self, BoxFuture, FutureExt},
+  stream::{self, BoxStream, Stream, StreamExt},
+};
+use tokio_stream::wrappers::BroadcastStream;
 use tokio_util::sync::CancellationToken;
 
 use fred::prelude::*;
 use fred::{pool::RedisPool, types::RedisKey};
 
-use super::super::{registry::TunnelRegistry, TunnelName};
+use super::super::{
+  registry::{
+    AttributeValue, AttributeValueError, MultiOccupancyTunnelRegistry, RegistryConfig,
+    RegistryEvent, SelectionPolicy, SharedAttributeRegistry, TunnelRegistry, WatchFilter,
+    WatchableTunnelRegistry,
+  },
+  TunnelName,
+};
 use crate::util::{cancellation::CancellationListener, dropkick::Dropkick};
 
 /// Configuration for background work for a Redis registry
-///
-/// Use `..Default::default()` to initialize, as this is
-/// non-exhaustive for when new parameters are added.
-pub struct RedisRegistryConfig {
-  /// Time before expiring tunnel name to redis-unique-key mappings
-  ///
-  /// If the target key expires before the name-to-id reference,
-  /// it behaves as if it is null anyway.
-  ///
-  /// Only durations higher than seconds count
-  pub tunnel_id_ref_lifetime: Duration,
-  /// Time before expiring tunnel entries at redis-unique-key locations
-  ///
-  /// Only durations higher than seconds count
-  pub tunnel_entry_lifetime: Duration,
-  /// How often the background thread will attempt to refresh expiry of its items
-  ///
-  /// If a refresh is attempted and the target is missing, and no current target
-  /// exists at the name mapping, it will attempt reregistration.
-  pub renewal_rate: Duration,
-}
-
-impl Default for RedisRegistryConfig {
-  fn default() -> Self {
-    Self {
-      tunnel_id_ref_lifetime: Duration::from_secs(600),
-      tunnel_entry_lifetime: Duration::from_secs(60),
-      renewal_rate: Duration::from_secs(20),
-    }
-  }
-}
+pub type RedisRegistryConfig = RegistryConfig;
 
 pub type Registration = Dropkick<CancellationToken>;
 pub type RegistrationMap = DashMap<String, Weak<Registration>>;
@@ -131,101 +114,6 @@ where
     format!("/tunnel/rid/{}", rid)
   }
 
-  fn register_for_renewals(
-    registration_map: Arc<RegistrationMap>,
-    pool: Arc<RedisPool>,
-    canceller: &CancellationToken,
-    config: &RedisRegistryConfig,
-    tunnel_name: &TunnelName,
-    rid: &str,
-    entry_encoded: Vec<u8>,
-  ) -> Arc<Registration> {
-    let canceller = canceller.child_token();
-
-    // TODO: tracing on error-exit of renewal tasks
-    tokio::task::spawn(Self::run_renewal(
-      pool,
-      canceller.clone().into(),
-      tunnel_name.clone(),
-      rid.to_owned(),
-      entry_encoded,
-      config.tunnel_id_ref_lifetime,
-      config.tunnel_entry_lifetime,
-      config.renewal_rate,
-    ));
-
-    let registration = Arc::new(Dropkick::new(canceller));
-    // Add our registration to the map to allow us to cancel entries which are no longer valid
-    registration_map.insert(rid.to_string(), Arc::downgrade(&registration));
-    registration
-  }
-
-  async fn run_renewal(
-    pool: Arc<RedisPool>,
-    canceller: CancellationListener,
-    tunnel_name: TunnelName,
-    rid: String,
-    entry_encoded: Vec<u8>,
-    tunnel_id_ref_lifetime: Duration,
-    tunnel_entry_lifetime: Duration,
-    renewal_rate: Duration,
-  ) -> Result<(), anyhow::Error> {
-    let tunnel_key = Self::tunnel_rid_key(&rid);
-    let tunnel_ref_key = Self::tunnel_key_for(&tunnel_name);
-    let tunnel_entry_lifetime_secs = tunnel_entry_lifetime
-      .as_secs()
-      .try_into()
-      .unwrap_or(i64::MAX);
-    // Ensure we don't smash the redis server with an absurdly-small delay; minimum is 1 second in non-test envs
-    let renewal_rate = renewal_rate.max({
-      if cfg!(test) {
-        Duration::from_millis(10)
-      } else {
-        Duration::from_secs(1)
-      }
-    });
-    // Run the loop until asked to exit
-    // We'll be cancelled if either the core canceller is disposed *or* the entry itself is deleted
-    while !canceller.is_cancelled() {
-      futures::future::select(
-        tokio::time::sleep(renewal_rate).boxed(),
-        canceller.cancelled().boxed(),
-      )
-      .await;
-      if canceller.is_cancelled() {
-        break;
-      }
-      let conn = Self::get_pool_connection(&*pool).await?;
-      let _ = conn
-        .expire::<bool, _>(
-          &tunnel_ref_key,
-          tunnel_id_ref_lifetime
-            .as_secs()
-            .try_into()
-            .unwrap_or(i64::MAX),
-        )
-        .await;
-      // Update expiration; if that does not add an expiration, check if the key is known to not exist
-      // If it is known-non-existent, try to reinsert it into the dataset from the encoded copy we saved
-      if conn.expire(&tunnel_key, tunnel_entry_lifetime_secs).await == Ok(false)
-        && conn.exists(&tunnel_key).await == Ok(false)
-      {
-        // Try to set the value back to the expected state, but don't overwrite existing keys to do so
-        conn
-          .set::<(), _, _>(
-            &tunnel_key,
-            entry_encoded.as_slice(),
-            Some(Expiration::EX(tunnel_entry_lifetime_secs)),
-            Some(SetOptions::NX),
-            false,
-          )
-          .await
-          .ok();
-      }
-    }
-    Ok(())
-  }
-
   async fn deregister_by_rid(
     registration_map: Arc<RegistrationMap>,
     conn: &RedisClient,
@@ -234,31 +122,271 @@ where
   where
     R: serde::de::DeserializeOwned,
   {
-    // If we have the item in our local map, stop refreshing it, and delete the database-side key
-    let encoded_entry: Option<Vec<u8>> =
-      if let Some((_, owned_renewer)) = registration_map.remove(&rid) {
-        // Cancel the renewer if it is still allocated
-        owned_renewer.upgrade().map(|v| v.cancel());
-        // The remote key is ours; delete it while getting it, if it's there at all
-        getdel_or_get_del(&conn, Self::tunnel_rid_key(rid.as_str())).await?
-      } else {
-        // Read the value at the RID, if present, otherwise act as if the ref-key didn't exist
-        conn.get(Self::tunnel_rid_key(rid.as_str())).await?
-      };
+    let entry_key = Self::tunnel_rid_key(rid.as_str());
+    let encoded_entry = take_registered_entry(registration_map, conn, rid, entry_key).await?;
     Ok(match encoded_entry {
       Some(encoded_entry) => serde_json::from_slice(encoded_entry.as_slice())?,
       None => None,
     })
   }
+}
+
+/// Keys of a registered entry, which lives at a unique RID and is referenced from its lookup key
+struct RegistrationKeys {
+  reference_key: String,
+  entry_key: String,
+}
+
+fn register_for_renewals(
+  registration_map: Arc<RegistrationMap>,
+  pool: Arc<RedisPool>,
+  canceller: &CancellationToken,
+  config: &RedisRegistryConfig,
+  keys: RegistrationKeys,
+  rid: &str,
+  entry_encoded: Vec<u8>,
+) -> Arc<Registration> {
+  let canceller = canceller.child_token();
+
+  // TODO: tracing on error-exit of renewal tasks
+  tokio::task::spawn(run_renewal(
+    pool,
+    canceller.clone().into(),
+    keys,
+    entry_encoded,
+    config.tunnel_id_ref_lifetime,
+    config.tunnel_entry_lifetime,
+    config.renewal_rate,
+  ));
+
+  let registration = Arc::new(Dropkick::new(canceller));
+  // Add our registration to the map to allow us to cancel entries which are no longer valid
+  registration_map.insert(rid.to_string(), Arc::downgrade(&registration));
+  registration
+}
+
+async fn run_renewal(
+  pool: Arc<RedisPool>,
+  canceller: CancellationListener,
+  keys: RegistrationKeys,
+  entry_encoded: Vec<u8>,
+  reference_lifetime: Duration,
+  entry_lifetime: Duration,
+  renewal_rate: Duration,
+) -> Result<(), anyhow::Error> {
+  let RegistrationKeys {
+    reference_key,
+    entry_key,
+  } = keys;
+  let entry_lifetime_secs = entry_lifetime.as_secs().try_into().unwrap_or(i64::MAX);
+  // Ensure we don't smash the redis server with an absurdly-small delay; minimum is 1 second in non-test envs
+  let renewal_rate = renewal_rate.max({
+    if cfg!(test) {
+      Duration::from_millis(10)
+    } else {
+      Duration::from_secs(1)
+    }
+  });
+  // Run the loop until asked to exit
+  // We'll be cancelled if either the core canceller is disposed *or* the entry itself is deleted
+  while !canceller.is_cancelled() {
+    futures::future::select(
+      tokio::time::sleep(renewal_rate).boxed(),
+      canceller.cancelled().boxed(),
+    )
+    .await;
+    if canceller.is_cancelled() {
+      break;
+    }
+    let conn = get_pool_connection(&*pool).await?;
+    let _ = conn
+      .expire::<bool, _>(
+        &reference_key,
+        reference_lifetime.as_secs().try_into().unwrap_or(i64::MAX),
+      )
+      .await;
+    // Update expiration; if that does not add an expiration, check if the key is known to not exist
+    // If it is known-non-existent, try to reinsert it into the dataset from the encoded copy we saved
+    if conn.expire(&entry_key, entry_lifetime_secs).await == Ok(false)
+      && conn.exists(&entry_key).await == Ok(false)
+    {
+      // Try to set the value back to the expected state, but don't overwrite existing keys to do so
+      conn
+        .set::<(), _, _>(
+          &entry_key,
+          entry_encoded.as_slice(),
+          Some(Expiration::EX(entry_lifetime_secs)),
+          Some(SetOptions::NX),
+          false,
+        )
+        .await
+        .ok();
+    }
+  }
+  Ok(())
+}
+
+/// Stores an entry at a newly-generated RID, returning the RID
+///
+/// Repeats SETNX until the key is newly created, with an expiry to ensure that any created
+/// keys are marked for cleanup.
+async fn insert_at_new_rid(
+  conn: &RedisClient,
+  entry_key_for: fn(&str) -> String,
+  entry_encoded: &[u8],
+  expiration: Expiration,
+) -> Result<String, RedisRegistryError> {
+  const MAX_ITERATIONS: usize = 10;
+  for _ in 0..MAX_ITERATIONS {
+    // Create a new RID and key
+    let rid = uuid::Uuid::new_v4().to_string();
+    // Set the value for that key, retrying if it already exists
+    if conn
+      .set::<(), _, _>(
+        entry_key_for(&rid),
+        entry_encoded,
+        Some(expiration.clone()),
+        Some(SetOptions::NX),
+        false,
+      )
+      .await
+      .is_ok()
+    {
+      return Ok(rid);
+    }
+  }
+  Err(RedisRegistryError::RepeatKeyConflicts {
+    num_attempts: MAX_ITERATIONS,
+  })
+}
+
+/// Removes the entry at a RID, stopping its renewal if it is one of ours
+async fn take_registered_entry(
+  registration_map: Arc<RegistrationMap>,
+  conn: &RedisClient,
+  rid: String,
+  entry_key: String,
+) -> Result<Option<Vec<u8>>, RedisError> {
+  // If we have the item in our local map, stop refreshing it, and delete the database-side key
+  if let Some((_, owned_renewer)) = registration_map.remove(&rid) {
+    // Cancel the renewer if it is still allocated
+    owned_renewer.upgrade().map(|v| v.cancel());
+    // The remote key is ours; delete it while getting it, if it's there at all
+    getdel_or_get_del(conn, entry_key).await
+  } else {
+    // Read the value at the RID, if present, otherwise act as if the ref-key didn't exist
+    conn.get(entry_key).await
+  }
+}
+
+async fn get_pool_connection(pool: &RedisPool) -> Result<&RedisClient, RedisRegistryError> {
+  // Get a connection from the pool
+  let conn = pool.next();
+  // The pool is already connected, so we can simply return the client
+  Ok(conn)
+}
+
+/// Pub/sub channel on which registries announce the registrations they make and remove
+const TUNNEL_EVENT_CHANNEL: &str = "/tunnel/events";
+
+fn encode_registry_event(event: &RegistryEvent) -> Option<String> {
+  match event {
+    RegistryEvent::Registered(tunnel_name) => Some(format!("registered:{}", tunnel_name.raw())),
+    RegistryEvent::Deregistered(tunnel_name) => Some(format!("deregistered:{}", tunnel_name.raw())),
+    RegistryEvent::Lagged => None,
+  }
+}
+
+fn decode_registry_event(message: &str) -> Option<RegistryEvent> {
+  match message.split_once(':')? {
+    ("registered", tunnel_name) => Some(RegistryEvent::Registered(TunnelName::new(tunnel_name))),
+    ("deregistered", tunnel_name) => {
+      Some(RegistryEvent::Deregistered(TunnelName::new(tunnel_name)))
+    }
+    _ => None,
+  }
+}
+
+/// Announces a change to watchers on any node
+///
+/// Delivery to watchers is best-effort, so failure is logged rather than failing the change itself.
+async fn publish_registry_event(conn: &RedisClient, event: RegistryEvent) {
+  let message = match encode_registry_event(&event) {
+    Some(message) => message,
+    None => return,
+  };
+  if let Err(e) = conn
+    .publish::<(), _, _>(TUNNEL_EVENT_CHANNEL, message)
+    .await
+  {
+    tracing::warn!(error = ?e, ?event, "Failed to publish registry event: {}", e);
+  }
+}
+
+/// Events from a dedicated subscriber client, which disconnects once the stream is dropped
+struct WatchStream {
+  events: BoxStream<'static, RegistryEvent>,
+  subscriber: RedisClient,
+}
+
+impl Stream for WatchStream {
+  type Item = RegistryEvent;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    self.events.poll_next_unpin(cx)
+  }
+}
 
-  async fn get_pool_connection(pool: &RedisPool) -> Result<&RedisClient, RedisRegistryError> {
-    // Get a connection from the pool
-    let conn = pool.next();
-    // The pool is already connected, so we can simply return the client
-    Ok(conn)
+impl Drop for WatchStream {
+  fn drop(&mut self) {
+    let subscriber = self.subscriber.clone();
+    tokio::task::spawn(async move {
+      let _ = subscriber.quit().await;
+    });
   }
 }
 
+/// Streams registry events published to the server the pool is connected to
+async fn watch_registry_events(
+  pool: Arc<RedisPool>,
+  filter: WatchFilter,
+) -> Result<BoxStream<'static, RegistryEvent>, RedisRegistryError> {
+  // Subscribing occupies a connection, so each watch is given a client of its own
+  let subscriber = get_pool_connection(&*pool).await?.clone_new();
+  subscriber.connect();
+  subscriber.wait_for_connect().await?;
+  let messages = subscriber.on_message();
+  let reconnections = subscriber.on_reconnect();
+  subscriber.subscribe::<(), _>(TUNNEL_EVENT_CHANNEL).await?;
+
+  let messages = BroadcastStream::new(messages).filter_map(|message| {
+    future::ready(match message {
+      Ok(message) => message
+        .value
+        .into_string()
+        .and_then(|message| decode_registry_event(&message)),
+      Err(_) => Some(RegistryEvent::Lagged),
+    })
+  });
+  // Subscriptions are lost when the client reconnects, along with any events published meanwhile
+  let resubscriptions = BroadcastStream::new(reconnections).then({
+    let subscriber = subscriber.clone();
+    move |_| {
+      let subscriber = subscriber.clone();
+      async move {
+        if let Err(e) = subscriber.subscribe::<(), _>(TUNNEL_EVENT_CHANNEL).await {
+          tracing::warn!(error = ?e, "Failed to resubscribe to registry events: {}", e);
+        }
+        RegistryEvent::Lagged
+      }
+    }
+  });
+  let events = stream::select(messages, resubscriptions)
+    .filter(move |event| future::ready(filter.matches(event)))
+    .boxed();
+  Ok(WatchStream { events, subscriber }.boxed())
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum RedisRegistryError {
   #[error("Registry redis error: {0}")]
@@ -275,6 +403,12 @@ pub enum RedisRegistryError {
   ),
   #[error("Could not find a non-conflicting key after {num_attempts} attempts")]
   RepeatKeyConflicts { num_attempts: usize },
+  #[error("Registry attribute value error: {0}")]
+  AttributeValueError(
+    #[from]
+    #[cfg_attr(feature = "backtrace", backtrace)]
+    AttributeValueError,
+  ),
 }
 
 // Tunnel names are their equivalent redis key
@@ -327,7 +461,7 @@ where
     let tunnel_name = tunnel_name.clone();
     let pool = self.pool.clone();
     async move {
-      let conn = Self::get_pool_connection(&*pool).await?;
+      let conn = get_pool_connection(&*pool).await?;
       // Read the reference key, if present, mapping tunnel-name to its RID
       let rid: Option<String> = conn.get(Self::tunnel_key_for(&tunnel_name)).await?;
       // `let-else` can't come soon enough
@@ -377,56 +511,35 @@ where
     async move {
       // Encode and save the record for use in redis calls below
       let encoded = serde_json::to_vec(&record)?;
-      let conn = Self::get_pool_connection(&*pool).await?;
-      // Create-associated entry by repeating SETNX until the key is newly created, in a
-      // transaction with EXPIRE to ensure that any created keys are marked for cleanup.
-      let rid = {
-        const MAX_ITERATIONS: usize = 10;
-        let mut iteration = 0usize;
-        loop {
-          if iteration >= MAX_ITERATIONS {
-            return Err(Self::Error::RepeatKeyConflicts {
-              num_attempts: MAX_ITERATIONS,
-            });
-          }
-          iteration += 1;
-          // Create a new RID and key
-          let rid = uuid::Uuid::new_v4().to_string();
-          let rid_key = Self::tunnel_rid_key(&rid);
-          // Set the value for that key, retrying if it already exists
-          if conn
-            .set::<(), _, _>(
-              rid_key,
-              encoded.as_slice(),
-              Some(tunnel_expiration.clone()),
-              Some(SetOptions::NX),
-              false,
-            )
-            .await
-            .is_ok()
-          {
-            // If we successfully set the key, break to use that RID in the final reference
-            break rid;
-          }
-        }
-      };
+      let conn = get_pool_connection(&*pool).await?;
+      let rid = insert_at_new_rid(
+        conn,
+        Self::tunnel_rid_key,
+        encoded.as_slice(),
+        tunnel_expiration,
+      )
+      .await?;
 
       conn
         .set(
-          tunnel_ref_key,
+          &tunnel_ref_key,
           &rid,
           Some(tunnel_ref_expiration),
           None,
           false,
         )
         .await?;
+      publish_registry_event(conn, RegistryEvent::Registered(tunnel_name.clone())).await;
 
-      let registration = Self::register_for_renewals(
+      let registration = register_for_renewals(
         registration_map,
         pool,
         &core_canceller,
         config.as_ref(),
-        &tunnel_name,
+        RegistrationKeys {
+          reference_key: tunnel_ref_key,
+          entry_key: Self::tunnel_rid_key(&rid),
+        },
         &rid,
         encoded,
       );
@@ -448,7 +561,7 @@ where
     let tunnel_name = tunnel_name.clone();
     let pool = self.pool.clone();
     async move {
-      let conn = Self::get_pool_connection(&*pool).await?;
+      let conn = get_pool_connection(&*pool).await?;
       // Read the reference key, if present, mapping tunnel-name to its RID
       let rid: Option<String> =
         getdel_or_get_del(&conn, Self::tunnel_key_for(&tunnel_name)).await?;
@@ -459,7 +572,11 @@ where
       } else {
         return Ok(None);
       };
-      Self::deregister_by_rid(registration_map, conn, rid).await
+      let removed = Self::deregister_by_rid(registration_map, conn, rid).await?;
+      if removed.is_some() {
+        publish_registry_event(conn, RegistryEvent::Deregistered(tunnel_name)).await;
+      }
+      Ok(removed)
     }
     .boxed()
   }
@@ -473,13 +590,540 @@ where
     let registration_map = Arc::clone(&self.active_registration_map);
     let pool = self.pool.clone();
     async move {
-      let conn = Self::get_pool_connection(&*pool).await?;
+      let conn = get_pool_connection(&*pool).await?;
       let mut identifier = identifier;
       // Drop the identifier after getting the ID from it, in order to decrement our hold on the registration
       let rid = std::mem::replace(&mut identifier.rid, String::new());
+      let tunnel_name = identifier.tunnel_name.clone();
       drop(identifier);
       // Destroy the entry from the redis store and- if present- cancel it and remove it from the registration map
-      Self::deregister_by_rid(registration_map, conn, rid).await
+      let removed = Self::deregister_by_rid(registration_map, conn, rid).await?;
+      if removed.is_some() {
+        publish_registry_event(conn, RegistryEvent::Deregistered(tunnel_name)).await;
+      }
+      Ok(removed)
+    }
+    .boxed()
+  }
+}
+
+/// Reports registrations announced by [RedisRegistry] instances sharing the same server
+///
+/// Only explicit registration and deregistration are announced; entries which expire because
+/// their registering node stopped renewing them are not reported. Keyspace expiry notifications
+/// cannot stand in for this, as they are disabled on servers by default, and an entry's key names
+/// its RID rather than its tunnel. Caches invalidated by this watch should therefore also limit
+/// how long they keep records, as with [WriteThroughCache::with_cached_lifetime].
+///
+/// [WriteThroughCache::with_cached_lifetime]: super::cache::WriteThroughCache::with_cached_lifetime
+impl<R> WatchableTunnelRegistry for RedisRegistry<R>
+where
+  R: serde::ser::Serialize + serde::de::DeserializeOwned + Send + Sync + Debug + Clone + 'static,
+{
+  fn watch<'a>(
+    &'a self,
+    filter: WatchFilter,
+  ) -> BoxFuture<'static, Result<BoxStream<'static, RegistryEvent>, Self::Error>> {
+    watch_registry_events(self.pool.clone(), filter).boxed()
+  }
+}
+
+/// Multi-occupancy registry based on Redis, holding every active registration of a name
+///
+/// A name's registrations are referenced from a sorted set ordered by time of registration, and
+/// are otherwise stored, expired, and renewed as the entries of a [RedisRegistry] with the same
+/// configuration. Lookup chooses among those still present by [SelectionPolicy].
+///
+/// Dropping the last identifier for a key deregisters it from auto-renewal, but does not perform explicit IO.
+#[derive(Clone)]
+pub struct RedisMultiRegistry<R> {
+  config: Arc<RedisRegistryConfig>,
+  pool: Arc<RedisPool>,
+  active_registration_map: Arc<RegistrationMap>,
+  policy: SelectionPolicy<R>,
+  // Cancels all renewal jobs if the registry itself is dropped; is parent to all renewal task tokens
+  core_canceller: Arc<Dropkick<CancellationToken>>,
+}
+
+impl<R> Debug for RedisMultiRegistry<R>
+where
+  R: Debug,
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct(std::any::type_name::<RedisMultiRegistry<R>>())
+      .field("pool_size", &self.pool.size())
+      .field("policy", &self.policy)
+      .finish()
+  }
+}
+
+impl<R> RedisMultiRegistry<R> {
+  #[must_use]
+  pub fn new<Pool: Into<Arc<RedisPool>>>(
+    config: RedisRegistryConfig,
+    pool: Pool,
+    policy: SelectionPolicy<R>,
+  ) -> Self {
+    Self {
+      config: Arc::new(config),
+      pool: pool.into(),
+      active_registration_map: Arc::new(RegistrationMap::default()),
+      policy,
+      core_canceller: Arc::new(Dropkick::new(CancellationToken::new())),
+    }
+  }
+}
+
+impl<R> RedisMultiRegistry<R>
+where
+  R: serde::de::DeserializeOwned + 'static,
+{
+  fn occupants_key_for(tunnel_name: &TunnelName) -> String {
+    format!("/tunnel/occupants/{}", tunnel_name.raw())
+  }
+
+  async fn lookup_occupants(
+    conn: &RedisClient,
+    tunnel_name: &TunnelName,
+  ) -> Result<Vec<R>, RedisRegistryError> {
+    let occupants_key = Self::occupants_key_for(tunnel_name);
+    let rids: Vec<String> = conn
+      .zrange(&occupants_key, 0, -1, None, false, None, false)
+      .await?;
+    let mut occupants = Vec::with_capacity(rids.len());
+    let mut lapsed = Vec::new();
+    for rid in rids {
+      let encoded_entry: Option<Vec<u8>> =
+        conn.get(RedisRegistry::<R>::tunnel_rid_key(&rid)).await?;
+      match encoded_entry {
+        Some(encoded_entry) => occupants.push(serde_json::from_slice(encoded_entry.as_slice())?),
+        None => lapsed.push(rid),
+      }
+    }
+    // Entries which have expired are no longer renewed by any registry, so stop referencing them
+    if !lapsed.is_empty() {
+      let _ = conn.zrem::<(), _, _>(&occupants_key, lapsed).await;
+    }
+    Ok(occupants)
+  }
+}
+
+impl<R> TunnelRegistry for RedisMultiRegistry<R>
+where
+  R: serde::ser::Serialize + serde::de::DeserializeOwned + Send + Sync + Debug + Clone + 'static,
+{
+  type Identifier = RedisIdentifier;
+
+  type Record = R;
+
+  type Error = RedisRegistryError;
+
+  fn lookup<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let policy = self.policy.clone();
+    let occupants = self.lookup_all(tunnel_name);
+    async move { Ok(policy.select(occupants.await?)) }.boxed()
+  }
+
+  fn register<'a>(
+    &'a self,
+    tunnel_name: TunnelName,
+    record: &'a Self::Record,
+  ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>> {
+    let pool = self.pool.clone();
+    let core_canceller = self.core_canceller.clone();
+    let config = self.config.clone();
+    let record = record.clone();
+    let registration_map = Arc::clone(&self.active_registration_map);
+    async move {
+      let encoded = serde_json::to_vec(&record)?;
+      let conn = get_pool_connection(&*pool).await?;
+      let rid = insert_at_new_rid(
+        conn,
+        RedisRegistry::<R>::tunnel_rid_key,
+        encoded.as_slice(),
+        Expiration::EX(
+          config
+            .tunnel_entry_lifetime
+            .as_secs()
+            .try_into()
+            .unwrap_or(i64::MAX),
+        ),
+      )
+      .await?;
+
+      // Occupants are scored by time of registration, ordering them from oldest to newest
+      let registered_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as f64;
+      let occupants_key = Self::occupants_key_for(&tunnel_name);
+      conn
+        .zadd::<(), _, _>(
+          &occupants_key,
+          None,
+          None,
+          false,
+          false,
+          (registered_at, rid.as_str()),
+        )
+        .await?;
+      conn
+        .expire::<(), _>(
+          &occupants_key,
+          config
+            .tunnel_id_ref_lifetime
+            .as_secs()
+            .try_into()
+            .unwrap_or(i64::MAX),
+        )
+        .await?;
+      publish_registry_event(conn, RegistryEvent::Registered(tunnel_name.clone())).await;
+
+      let registration = register_for_renewals(
+        registration_map,
+        pool,
+        &core_canceller,
+        config.as_ref(),
+        RegistrationKeys {
+          reference_key: occupants_key,
+          entry_key: RedisRegistry::<R>::tunnel_rid_key(&rid),
+        },
+        &rid,
+        encoded,
+      );
+
+      Ok(Ident {
+        tunnel_name,
+        rid,
+        _registration: Some(registration),
+      })
+    }
+    .boxed()
+  }
+
+  fn deregister<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let registration_map = Arc::clone(&self.active_registration_map);
+    let tunnel_name = tunnel_name.clone();
+    let pool = self.pool.clone();
+    async move {
+      let conn = get_pool_connection(&*pool).await?;
+      let occupants_key = Self::occupants_key_for(&tunnel_name);
+      let rids: Vec<String> = conn
+        .zrange(&occupants_key, 0, -1, None, false, None, false)
+        .await?;
+      conn.del::<(), _>(&occupants_key).await?;
+      // Remove every occupant, keeping the newest present for the caller
+      let mut newest = None;
+      for rid in rids {
+        if let Some(record) =
+          RedisRegistry::<R>::deregister_by_rid(Arc::clone(&registration_map), conn, rid).await?
+        {
+          newest = Some(record);
+        }
+      }
+      if newest.is_some() {
+        publish_registry_event(conn, RegistryEvent::Deregistered(tunnel_name)).await;
+      }
+      Ok(newest)
+    }
+    .boxed()
+  }
+
+  fn deregister_identifier<'a>(
+    &'a self,
+    identifier: Self::Identifier,
+  ) -> BoxFuture<'static, Result<Option<Self::Record>, Self::Error>> {
+    let registration_map = Arc::clone(&self.active_registration_map);
+    let pool = self.pool.clone();
+    async move {
+      let conn = get_pool_connection(&*pool).await?;
+      // Drop the identifier after taking its address, in order to decrement our hold on the registration
+      let RedisIdentifier {
+        tunnel_name, rid, ..
+      } = identifier;
+      conn
+        .zrem::<(), _, _>(Self::occupants_key_for(&tunnel_name), rid.as_str())
+        .await?;
+      let removed = RedisRegistry::<R>::deregister_by_rid(registration_map, conn, rid).await?;
+      if removed.is_some() {
+        publish_registry_event(conn, RegistryEvent::Deregistered(tunnel_name)).await;
+      }
+      Ok(removed)
+    }
+    .boxed()
+  }
+}
+
+impl<R> MultiOccupancyTunnelRegistry for RedisMultiRegistry<R>
+where
+  R: serde::ser::Serialize + serde::de::DeserializeOwned + Send + Sync + Debug + Clone + 'static,
+{
+  fn lookup_all<'a>(
+    &'a self,
+    tunnel_name: &'a TunnelName,
+  ) -> BoxFuture<'static, Result<Vec<Self::Record>, Self::Error>> {
+    let tunnel_name = tunnel_name.clone();
+    let pool = self.pool.clone();
+    async move {
+      let conn = get_pool_connection(&*pool).await?;
+      Self::lookup_occupants(conn, &tunnel_name).await
+    }
+    .boxed()
+  }
+}
+
+/// Reports registrations announced by [RedisMultiRegistry] instances sharing the same server
+///
+/// As with [RedisRegistry], occupants which expire unrenewed are not reported.
+impl<R> WatchableTunnelRegistry for RedisMultiRegistry<R>
+where
+  R: serde::ser::Serialize + serde::de::DeserializeOwned + Send + Sync + Debug + Clone + 'static,
+{
+  fn watch<'a>(
+    &'a self,
+    filter: WatchFilter,
+  ) -> BoxFuture<'static, Result<BoxStream<'static, RegistryEvent>, Self::Error>> {
+    watch_registry_events(self.pool.clone(), filter).boxed()
+  }
+}
+
+/// Single-occupancy overriding attribute registry based on Redis
+///
+/// Attributes are stored as JSON, with the same indirection, expiry, and renewal as the tunnel
+/// records of a [RedisRegistry] with the same configuration.
+///
+/// Dropping the last identifier for a key deregisters it from auto-renewal, but does not perform explicit IO.
+#[derive(Clone)]
+pub struct RedisAttributeRegistry {
+  config: Arc<RedisRegistryConfig>,
+  pool: Arc<RedisPool>,
+  active_registration_map: Arc<RegistrationMap>,
+  // Cancels all renewal jobs if the registry itself is dropped; is parent to all renewal task tokens
+  core_canceller: Arc<Dropkick<CancellationToken>>,
+}
+
+impl Debug for RedisAttributeRegistry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("RedisAttributeRegistry")
+      .field("pool_size", &self.pool.size())
+      .finish()
+  }
+}
+
+impl RedisAttributeRegistry {
+  #[must_use]
+  pub fn new<Pool: Into<Arc<RedisPool>>>(config: RedisRegistryConfig, pool: Pool) -> Self {
+    Self {
+      config: Arc::new(config),
+      pool: pool.into(),
+      active_registration_map: Arc::new(RegistrationMap::default()),
+      core_canceller: Arc::new(Dropkick::new(CancellationToken::new())),
+    }
+  }
+
+  fn attribute_key_for(key: &str) -> String {
+    format!("/attr/key/{}", key)
+  }
+
+  fn attribute_rid_key(rid: &str) -> String {
+    format!("/attr/rid/{}", rid)
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisAttributeIdentifier {
+  key: String,
+  rid: String,
+  _registration: Option<Arc<Registration>>,
+}
+
+impl std::hash::Hash for RedisAttributeIdentifier {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.key.hash(state);
+    self.rid.hash(state);
+  }
+}
+
+impl PartialEq for RedisAttributeIdentifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.key == other.key && self.rid == other.rid
+  }
+}
+
+impl Eq for RedisAttributeIdentifier {}
+
+impl<T> AttributeValue<T> for RedisAttributeRegistry
+where
+  T: serde::ser::Serialize + serde::de::DeserializeOwned,
+{
+  type Stored = Vec<u8>;
+
+  fn unpack(stored: &Self::Stored) -> Result<T, AttributeValueError> {
+    Ok(serde_json::from_slice(stored.as_slice())?)
+  }
+
+  fn pack(input: &T) -> Result<Self::Stored, AttributeValueError> {
+    Ok(serde_json::to_vec(input)?)
+  }
+}
+
+impl SharedAttributeRegistry for RedisAttributeRegistry {
+  type Identifier = RedisAttributeIdentifier;
+
+  type Value = Vec<u8>;
+
+  type Error = RedisRegistryError;
+
+  fn lookup_attr<'a, V>(
+    &'a self,
+    key: &'a str,
+  ) -> BoxFuture<'static, Result<Option<V>, Self::Error>>
+  where
+    Self: AttributeValue<V, Stored = Self::Value>,
+    V: Send + 'static,
+  {
+    let reference_key = Self::attribute_key_for(key);
+    let pool = self.pool.clone();
+    async move {
+      let conn = get_pool_connection(&*pool).await?;
+      let rid: Option<String> = conn.get(reference_key).await?;
+      let rid: String = if let Some(rid) = rid {
+        rid
+      } else {
+        return Ok(None);
+      };
+      let encoded: Option<Vec<u8>> = conn.get(Self::attribute_rid_key(&rid)).await?;
+      Ok(match encoded {
+        Some(encoded) => Some(<Self as AttributeValue<V>>::unpack(&encoded)?),
+        None => None,
+      })
+    }
+    .boxed()
+  }
+
+  fn register_attr<'a, V>(
+    &'a self,
+    key: &'a str,
+    value: &'a V,
+  ) -> BoxFuture<'static, Result<Self::Identifier, Self::Error>>
+  where
+    Self: AttributeValue<V, Stored = Self::Value>,
+  {
+    let encoded = match <Self as AttributeValue<V>>::pack(value) {
+      Ok(encoded) => encoded,
+      Err(e) => return futures::future::ready(Err(e.into())).boxed(),
+    };
+    let pool = self.pool.clone();
+    let core_canceller = self.core_canceller.clone();
+    let config = self.config.clone();
+    let key = key.to_owned();
+    let registration_map = Arc::clone(&self.active_registration_map);
+    async move {
+      let entry_expiration = Expiration::EX(
+        config
+          .tunnel_entry_lifetime
+          .as_secs()
+          .try_into()
+          .unwrap_or(i64::MAX),
+      );
+      let reference_expiration = Expiration::EX(
+        config
+          .tunnel_id_ref_lifetime
+          .as_secs()
+          .try_into()
+          .unwrap_or(i64::MAX),
+      );
+      let conn = get_pool_connection(&*pool).await?;
+      let rid = insert_at_new_rid(
+        conn,
+        Self::attribute_rid_key,
+        encoded.as_slice(),
+        entry_expiration,
+      )
+      .await?;
+      let reference_key = Self::attribute_key_for(&key);
+      conn
+        .set::<(), _, _>(
+          &reference_key,
+          &rid,
+          Some(reference_expiration),
+          None,
+          false,
+        )
+        .await?;
+
+      let registration = register_for_renewals(
+        registration_map,
+        pool,
+        &core_canceller,
+        config.as_ref(),
+        RegistrationKeys {
+          reference_key,
+          entry_key: Self::attribute_rid_key(&rid),
+        },
+        &rid,
+        encoded,
+      );
+
+      Ok(RedisAttributeIdentifier {
+        key,
+        rid,
+        _registration: Some(registration),
+      })
+    }
+    .boxed()
+  }
+
+  fn deregister_attr<'a>(
+    &'a self,
+    key: &'a str,
+  ) -> BoxFuture<'static, Result<Option<Self::Identifier>, Self::Error>> {
+    let registration_map = Arc::clone(&self.active_registration_map);
+    let key = key.to_owned();
+    let pool = self.pool.clone();
+    async move {
+      let conn = get_pool_connection(&*pool).await?;
+      let rid: Option<String> = getdel_or_get_del(conn, Self::attribute_key_for(&key)).await?;
+      let rid: String = if let Some(rid) = rid {
+        rid
+      } else {
+        return Ok(None);
+      };
+      let entry_key = Self::attribute_rid_key(&rid);
+      let removed = take_registered_entry(registration_map, conn, rid.clone(), entry_key).await?;
+      Ok(removed.map(|_: Vec<u8>| RedisAttributeIdentifier {
+        key,
+        rid,
+        _registration: None,
+      }))
+    }
+    .boxed()
+  }
+
+  fn deregister_attr_identifier<'a>(
+    &'a self,
+    identifier: Self::Identifier,
+  ) -> BoxFuture<'static, Result<Option<Self::Identifier>, Self::Error>> {
+    // As with tunnel records, the key's reference is left for redis to expire
+    let registration_map = Arc::clone(&self.active_registration_map);
+    let pool = self.pool.clone();
+    async move {
+      let conn = get_pool_connection(&*pool).await?;
+      // Drop our hold on the registration, keeping only its address
+      let RedisAttributeIdentifier { key, rid, .. } = identifier;
+      let entry_key = Self::attribute_rid_key(&rid);
+      let removed = take_registered_entry(registration_map, conn, rid.clone(), entry_key).await?;
+      Ok(removed.map(|_: Vec<u8>| RedisAttributeIdentifier {
+        key,
+        rid,
+        _registration: None,
+      }))
     }
     .boxed()
   }
@@ -494,12 +1138,16 @@ mod integration_tests {
     prelude::*,
     types::{ReconnectPolicy, RedisConfig, ServerConfig},
   };
+  use futures::StreamExt;
   use uuid::Uuid;
 
   use crate::{common::protocol::tunnel::TunnelName, ext::future::FutureExtExt};
 
-  use super::super::TunnelRegistry;
-  use super::{RedisRegistry, RedisRegistryConfig};
+  use super::super::{
+    MultiOccupancyTunnelRegistry, RegistryEvent, SelectionPolicy, TunnelRegistry, WatchFilter,
+    WatchableTunnelRegistry,
+  };
+  use super::{RedisMultiRegistry, RedisRegistry, RedisRegistryConfig};
 
   #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash)]
   struct TestEntry {
@@ -794,4 +1442,95 @@ mod integration_tests {
       .expect("Lookup of empty entry must succeed");
     assert_eq!(should_be_empty, None, "Lookup of known-deleted entry should not result in an entry in a known-consistent configuration");
   }
+
+  #[tokio::test]
+  async fn cross_registry_watch() {
+    let TestReg {
+      registry: reg_a, ..
+    } = test_items::<TestEntry>().await;
+    let TestReg {
+      registry: reg_b, ..
+    } = test_items::<TestEntry>().await;
+    let foo_name = TunnelName::new(Uuid::new_v4().to_string());
+    let foo = TestEntry {
+      name: foo_name.raw().to_owned(),
+      id: 12345,
+    };
+    let mut events = reg_b
+      .watch(WatchFilter::Name(foo_name.clone()))
+      .await
+      .expect("Watch must succeed");
+
+    let ident = reg_a
+      .register(foo_name.clone(), &foo)
+      .await
+      .expect("Registration must succeed");
+    reg_a
+      .deregister_identifier(ident)
+      .await
+      .expect("Deregistration must succeed");
+
+    assert_eq!(
+      events
+        .next()
+        .poll_until(tokio::time::sleep(Duration::from_secs(5)))
+        .await
+        .expect("Timeout awaiting registration"),
+      Some(RegistryEvent::Registered(foo_name.clone()))
+    );
+    assert_eq!(
+      events
+        .next()
+        .poll_until(tokio::time::sleep(Duration::from_secs(5)))
+        .await
+        .expect("Timeout awaiting deregistration"),
+      Some(RegistryEvent::Deregistered(foo_name))
+    );
+  }
+
+  #[tokio::test]
+  async fn cross_registry_multi_occupancy() {
+    let pool = Arc::new(create_test_pool().await);
+    let config = || RedisRegistryConfig {
+      renewal_rate: Duration::from_millis(500),
+      tunnel_entry_lifetime: Duration::from_secs(1),
+      tunnel_id_ref_lifetime: Duration::from_secs(2),
+      ..Default::default()
+    };
+    let reg_a = RedisMultiRegistry::new(config(), pool.clone(), SelectionPolicy::Oldest);
+    let reg_b = RedisMultiRegistry::new(config(), pool, SelectionPolicy::Newest);
+    let foo_name = TunnelName::new(Uuid::new_v4().to_string());
+    let foo = |id| TestEntry {
+      name: foo_name.raw().to_owned(),
+      id,
+    };
+    let ident_a = reg_a
+      .register(foo_name.clone(), &foo(1))
+      .await
+      .expect("Registration must succeed");
+    let _ident_b = reg_b
+      .register(foo_name.clone(), &foo(2))
+      .await
+      .expect("Registration must succeed");
+
+    assert_eq!(
+      reg_b
+        .lookup_all(&foo_name)
+        .await
+        .expect("Lookup must succeed"),
+      vec![foo(1), foo(2)]
+    );
+    assert_eq!(reg_a.lookup(&foo_name).await.unwrap(), Some(foo(1)));
+    assert_eq!(reg_b.lookup(&foo_name).await.unwrap(), Some(foo(2)));
+
+    reg_a
+      .deregister_identifier(ident_a)
+      .await
+      .expect("Deregistration must succeed")
+      .expect("Must have an instance of the test entry");
+    assert_eq!(reg_a.lookup(&foo_name).await.unwrap(), Some(foo(2)));
+
+    assert_eq!(reg_a.deregister(&foo_name).await.unwrap(), Some(foo(2)));
+    assert_eq!(reg_b.lookup_all(&foo_name).await.unwrap(), vec![]);
+  }
 }
@@ -0,0 +1,591 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license OR Apache 2.0
+//! Forwarding of streams between the nodes of a server cluster sharing a tunnel registry
+//!
+//! Each node registers its tunnels as [ClusterRecord]s naming the node they are connected to. A
+//! [ClusterRouter] routes requests for its own node's tunnels through a local router, and forwards
+//! requests for tunnels on other nodes over an inter-node tunnel provided by a [NodeConnector].
+//! The owning node's [ClusterForwardingService] relays the forwarded stream to the target tunnel,
+//! so negotiation with the target's service, and all that follows it, passes through unchanged.
+//!
+//! Forwarding addresses take the form `/cluster-forward/<tunnel name>`.
+use dashmap::DashMap;
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::BTreeSet, fmt::Debug, iter::FromIterator, net::SocketAddr, ops::RangeInclusive,
+  sync::Arc,
+};
+use tracing_futures::Instrument;
+
+use crate::{
+  common::{
+    daemon::PeersView,
+    protocol::{
+      negotiation::{self, NegotiationClient},
+      service::{Client, ProtocolInfo, Request, Router, RouterResult, RoutingError},
+      tunnel::{registry::TunnelRegistry, ArcTunnel, TunnelError, TunnelId, TunnelName},
+      RequestHeaders, RouteAddress, Service, ServiceError, ServiceVersion,
+    },
+  },
+  util::{proxy_generic_tokio_streams, tunnel_stream::TunnelStream, tunnel_stream::WrappedStream},
+};
+
+/// Identifies a node of the cluster, and where other nodes may connect to it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NodeAddress {
+  pub node_id: String,
+  pub addr: SocketAddr,
+}
+
+impl NodeAddress {
+  pub fn new<S: Into<String>>(node_id: S, addr: SocketAddr) -> Self {
+    Self {
+      node_id: node_id.into(),
+      addr,
+    }
+  }
+}
+
+/// A registry record naming the node its tunnel is connected to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterRecord<R> {
+  pub node: NodeAddress,
+  pub record: R,
+}
+
+impl<R> ClusterRecord<R> {
+  pub fn new(node: NodeAddress, record: R) -> Self {
+    Self { node, record }
+  }
+}
+
+/// Builds the address under which a node's [ClusterForwardingService] accepts streams for a tunnel
+pub fn forwarding_address(tunnel_name: &TunnelName) -> RouteAddress {
+  RouteAddress::from_iter([ClusterForwardingService::<PeersView>::protocol_name()])
+    .into_suffixed([tunnel_name.raw()])
+}
+
+fn forwarded_tunnel_name(addr: &RouteAddress) -> Option<TunnelName> {
+  let mut segments =
+    addr.strip_segment_prefix([ClusterForwardingService::<PeersView>::protocol_name()])?;
+  match (segments.next(), segments.next()) {
+    (Some(tunnel_name), None) if !tunnel_name.is_empty() => Some(TunnelName::new(tunnel_name)),
+    _ => None,
+  }
+}
+
+/// Provides tunnels to other nodes of the cluster, over which requests are forwarded to them
+pub trait NodeConnector: Send + Sync {
+  /// Provides a tunnel to the node, connecting to it if no usable tunnel is already open
+  fn connect<'a>(
+    &'a self,
+    node: &'a NodeAddress,
+  ) -> BoxFuture<'static, Result<ArcTunnel<'static>, TunnelError>>;
+
+  /// Notifies the connector that the tunnel it provided to a node failed to open a link
+  fn disconnected(&self, _node: &NodeAddress) {}
+}
+
+impl<F> NodeConnector for F
+where
+  F: Fn(&NodeAddress) -> BoxFuture<'static, Result<ArcTunnel<'static>, TunnelError>> + Send + Sync,
+{
+  fn connect<'a>(
+    &'a self,
+    node: &'a NodeAddress,
+  ) -> BoxFuture<'static, Result<ArcTunnel<'static>, TunnelError>> {
+    self(node)
+  }
+}
+
+/// Shares one tunnel to each node between requests, reconnecting once it fails to open links
+pub struct CachingNodeConnector<C> {
+  inner: C,
+  tunnels: Arc<DashMap<NodeAddress, ArcTunnel<'static>>>,
+}
+
+impl<C> CachingNodeConnector<C> {
+  pub fn new(inner: C) -> Self {
+    Self {
+      inner,
+      tunnels: Default::default(),
+    }
+  }
+}
+
+impl<C: NodeConnector> NodeConnector for CachingNodeConnector<C> {
+  fn connect<'a>(
+    &'a self,
+    node: &'a NodeAddress,
+  ) -> BoxFuture<'static, Result<ArcTunnel<'static>, TunnelError>> {
+    if let Some(tunnel) = self.tunnels.get(node) {
+      return futures::future::ready(Ok(Arc::clone(&tunnel))).boxed();
+    }
+    let tunnels = Arc::clone(&self.tunnels);
+    let node = node.clone();
+    self
+      .inner
+      .connect(&node)
+      .map_ok(move |tunnel| {
+        tunnels.insert(node, Arc::clone(&tunnel));
+        tunnel
+      })
+      .boxed()
+  }
+
+  fn disconnected(&self, node: &NodeAddress) {
+    self.tunnels.remove(node);
+    self.inner.disconnected(node);
+  }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ClusterRouterError<LocalError, RegistryError> {
+  #[error("Local routing failed: {0:?}")]
+  LocalRouterError(LocalError),
+  #[error("Tunnel registry lookup failed: {0}")]
+  RegistryError(RegistryError),
+}
+
+/// Routes requests to the node each tunnel is registered to, forwarding them if it is another node
+///
+/// Requests for tunnels registered to this node are handed to the local router, as are those for
+/// tunnels which have not yet been registered, as they can only be known to this node.
+pub struct ClusterRouter<TLocal, TRegistry: ?Sized, TConnector: ?Sized> {
+  node_id: String,
+  local: Arc<TLocal>,
+  registry: Arc<TRegistry>,
+  connector: Arc<TConnector>,
+  protocol_versions: Option<RangeInclusive<u8>>,
+}
+
+impl<TLocal, TRegistry: ?Sized, TConnector: ?Sized> ClusterRouter<TLocal, TRegistry, TConnector> {
+  pub fn new<S: Into<String>>(
+    node_id: S,
+    local: Arc<TLocal>,
+    registry: Arc<TRegistry>,
+    connector: Arc<TConnector>,
+  ) -> Self {
+    Self {
+      node_id: node_id.into(),
+      local,
+      registry,
+      connector,
+      protocol_versions: None,
+    }
+  }
+
+  /// Sets the negotiation protocol versions offered to the services of tunnels on other nodes
+  ///
+  /// If unset, only requests with headers offer v1, as by [NegotiationClient::with_headers].
+  /// Requests for this node's tunnels are negotiated by the local router, which is configured
+  /// separately. Services which only speak v0 are retried over another forwarded link with v0
+  /// alone, as by [NegotiationClient::negotiate_with_fallback]. Panics if `protocol_versions` is
+  /// empty or is not a subset of [SUPPORTED_PROTOCOL_VERSIONS](negotiation::SUPPORTED_PROTOCOL_VERSIONS).
+  pub fn with_protocol_versions(mut self, protocol_versions: RangeInclusive<u8>) -> Self {
+    assert!(
+      negotiation::is_supported_range(&protocol_versions),
+      "Unsupported negotiation protocol version range {:?}",
+      protocol_versions
+    );
+    self.protocol_versions = Some(protocol_versions);
+    self
+  }
+
+  pub fn node_id(&self) -> &str {
+    &self.node_id
+  }
+
+  pub fn local_router(&self) -> &Arc<TLocal> {
+    &self.local
+  }
+}
+
+impl<TLocal, TRegistry, TConnector, R> Router for ClusterRouter<TLocal, TRegistry, TConnector>
+where
+  TLocal: Router<Stream = WrappedStream, LocalAddress = TunnelName> + Send + Sync + 'static,
+  TLocal::Error: Debug + Send + 'static,
+  TRegistry: TunnelRegistry<Record = ClusterRecord<R>> + Send + Sync + ?Sized + 'static,
+  TConnector: NodeConnector + ?Sized + 'static,
+{
+  type Error = ClusterRouterError<TLocal::Error, TRegistry::Error>;
+  type Stream = WrappedStream;
+  type LocalAddress = TunnelName;
+
+  fn route<'client, 'result, TProtocolClient, IntoLocalAddress: Into<Self::LocalAddress>>(
+    &self,
+    request: Request<'client, Self::Stream, TProtocolClient>,
+    local_address: IntoLocalAddress,
+  ) -> BoxFuture<'client, RouterResult<'client, 'result, Self, TProtocolClient>>
+  where
+    TProtocolClient: Client<'result, Self::Stream> + Send + 'client,
+  {
+    let dest_name: TunnelName = local_address.into();
+    let node_id = self.node_id.clone();
+    let local = Arc::clone(&self.local);
+    let registry = Arc::clone(&self.registry);
+    let connector = Arc::clone(&self.connector);
+    let protocol_versions = self.protocol_versions.clone();
+    async move {
+      let owner = registry
+        .lookup(&dest_name)
+        .await
+        .map_err(|e| RoutingError::RouterError(ClusterRouterError::RegistryError(e)))?
+        .map(|record| record.node)
+        .filter(|node| node.node_id != node_id);
+      let node = match owner {
+        Some(node) => node,
+        None => {
+          return local
+            .route(request, dest_name)
+            .await
+            .map_err(|e| e.map_err(ClusterRouterError::LocalRouterError))
+        }
+      };
+
+      tracing::trace!(tunnel = ?dest_name, node = ?node, "forwarding request to owning node");
+      let tunnel = connector
+        .connect(&node)
+        .await
+        .map_err(RoutingError::LinkOpenFailure)?;
+      let (tunnel_ref, connector, node, dest_name) = (&tunnel, &connector, &node, &dest_name);
+      let open_link = move || async move {
+        tunnel_ref.open_link().await.map_err(|e| {
+          connector.disconnected(node);
+          RoutingError::LinkOpenFailure(e)
+        })
+      };
+      let open_forwarded = move || async move {
+        // The owning node relays the link to the tunnel once it accepts the forwarding address,
+        // after which the request is negotiated with the tunnel's service directly
+        let (link, _) = NegotiationClient::new()
+          .negotiate_with_fallback::<_, Self::Error, _, _>(
+            forwarding_address(dest_name),
+            open_link().await?,
+            open_link,
+          )
+          .await?;
+        Ok(link)
+      };
+      let link = open_forwarded().await?;
+      let addr = request.address.clone();
+      let mut negotiator = NegotiationClient::new()
+        .with_service_versions(TProtocolClient::service_versions())
+        .with_headers(request.headers);
+      if let Some(protocol_versions) = protocol_versions {
+        negotiator = negotiator.with_protocol_versions(protocol_versions);
+      }
+      let (link, service_version) = negotiator
+        .negotiate_with_fallback::<_, Self::Error, _, _>(addr.clone(), link, open_forwarded)
+        .await?;
+      Ok(
+        request
+          .protocol_client
+          .handle(addr, service_version, link, tunnel),
+      )
+    }
+    .boxed()
+  }
+}
+
+/// Tunnels connected to this node, to which forwarded streams are relayed
+pub trait LocalTunnels: Send + Sync {
+  /// Finds the tunnel connected under a name, preferring the most recent connection
+  fn find_by_name(&self, tunnel_name: &TunnelName) -> Option<ArcTunnel<'static>>;
+
+  /// Names the tunnel with the given ID, if it is connected
+  fn name_of(&self, tunnel_id: &TunnelId) -> Option<TunnelName>;
+}
+
+impl LocalTunnels for PeersView {
+  fn find_by_name(&self, tunnel_name: &TunnelName) -> Option<ArcTunnel<'static>> {
+    self
+      .find_by_name_and_comparator(tunnel_name, |r| r.registered_at.0)
+      .map(|peer| Arc::new(Arc::clone(&peer.tunnel)) as ArcTunnel<'static>)
+  }
+
+  fn name_of(&self, tunnel_id: &TunnelId) -> Option<TunnelName> {
+    self.get_by_id(tunnel_id).map(|peer| peer.name.clone())
+  }
+}
+
+/// Relays streams forwarded by other nodes of the cluster to the tunnels connected to this node
+///
+/// Only tunnels connected under the name of a cluster node may forward streams, as forwarding
+/// reaches any tunnel connected to this node.
+pub struct ClusterForwardingService<T: ?Sized> {
+  tunnels: Arc<T>,
+  nodes: BTreeSet<TunnelName>,
+}
+
+impl<T: ?Sized> ClusterForwardingService<T> {
+  pub fn new<I: IntoIterator<Item = TunnelName>>(tunnels: Arc<T>, nodes: I) -> Self {
+    Self {
+      tunnels,
+      nodes: nodes.into_iter().collect(),
+    }
+  }
+
+  pub fn nodes(&self) -> impl Iterator<Item = &TunnelName> {
+    self.nodes.iter()
+  }
+}
+
+impl<T: ?Sized> ProtocolInfo for ClusterForwardingService<T> {
+  fn protocol_name() -> &'static str
+  where
+    Self: Sized,
+  {
+    "cluster-forward"
+  }
+}
+
+impl<T> Service for ClusterForwardingService<T>
+where
+  T: LocalTunnels + ?Sized,
+{
+  type Error = anyhow::Error;
+
+  fn accepts(&self, addr: &RouteAddress, tunnel: &ArcTunnel) -> bool {
+    let from_node = self
+      .tunnels
+      .name_of(tunnel.id())
+      .map_or(false, |source| self.nodes.contains(&source));
+    from_node
+      && forwarded_tunnel_name(addr)
+        .and_then(|tunnel_name| self.tunnels.find_by_name(&tunnel_name))
+        .is_some()
+  }
+
+  fn handle<'a>(
+    &'a self,
+    addr: RouteAddress,
+    _version: ServiceVersion,
+    _headers: RequestHeaders,
+    stream: Box<dyn TunnelStream + Send + 'static>,
+    _tunnel: ArcTunnel<'static>,
+  ) -> BoxFuture<'a, Result<(), ServiceError<Self::Error>>> {
+    let tunnel_name = match forwarded_tunnel_name(&addr) {
+      Some(tunnel_name) => tunnel_name,
+      None => return futures::future::ready(Err(ServiceError::AddressError)).boxed(),
+    };
+    // The tunnel may have disconnected since the address was accepted
+    let target = match self.tunnels.find_by_name(&tunnel_name) {
+      Some(target) => target,
+      None => return futures::future::ready(Err(ServiceError::DependencyFailure)).boxed(),
+    };
+    let span = tracing::debug_span!("cluster_forward", tunnel = ?tunnel_name);
+    async move {
+      let link = target
+        .open_link()
+        .await
+        .map_err(|e| ServiceError::InternalError(e.into()))?;
+      let (mut link_reader, mut link_writer) = tokio::io::split(link);
+      let (mut reader, mut writer) = tokio::io::split(stream);
+      let (forwarded, returned) = proxy_generic_tokio_streams(
+        (&mut link_writer, &mut link_reader),
+        (&mut writer, &mut reader),
+      )
+      .await
+      .map_err(|e| ServiceError::InternalError(e.into()))?;
+      tracing::debug!(forwarded, returned, "Closing forwarded stream");
+      Ok(())
+    }
+    .instrument(span)
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::future::{BoxFuture, FutureExt};
+  use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+  };
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+  };
+
+  use super::{
+    forwarding_address, ClusterForwardingService, ClusterRecord, ClusterRouter, LocalTunnels,
+    NodeAddress, NodeConnector,
+  };
+  use crate::{
+    client::tests::{serve_one, LoopbackRouter, RecordingService, VersionedClient},
+    common::protocol::{
+      negotiation::ArcService,
+      proxy_tcp::{TcpStreamClient, TcpStreamService, TcpStreamTarget},
+      service::{Request, Router},
+      tunnel::{
+        duplex,
+        registry::memory::{InMemoryTunnelRegistry, InMemoryTunnelRegistryIdentifier},
+        registry::TunnelRegistry,
+        ArcTunnel, TunnelError, TunnelId, TunnelName,
+      },
+    },
+  };
+
+  struct TestTunnels {
+    by_name: HashMap<TunnelName, ArcTunnel<'static>>,
+    names: HashMap<TunnelId, TunnelName>,
+  }
+
+  impl LocalTunnels for TestTunnels {
+    fn find_by_name(&self, tunnel_name: &TunnelName) -> Option<ArcTunnel<'static>> {
+      self.by_name.get(tunnel_name).cloned()
+    }
+
+    fn name_of(&self, tunnel_id: &TunnelId) -> Option<TunnelName> {
+      self.names.get(tunnel_id).cloned()
+    }
+  }
+
+  #[test]
+  fn forwarding_addresses() {
+    let addr = forwarding_address(&TunnelName::new("edge-1"));
+    assert_eq!(addr.to_string(), "/cluster-forward/edge-1");
+    assert_eq!(
+      super::forwarded_tunnel_name(&addr),
+      Some(TunnelName::new("edge-1"))
+    );
+    let extended = addr.with_suffix(["extra"]);
+    assert_eq!(super::forwarded_tunnel_name(&extended), None);
+  }
+
+  /// Node A of a cluster, which routes to a tunnel named "edge-1" held by node B
+  ///
+  /// Node B serves one forwarded stream, and the edge serves one stream with the given service.
+  struct ForwardingNodes {
+    router:
+      ClusterRouter<LoopbackRouter, InMemoryTunnelRegistry<ClusterRecord<()>>, dyn NodeConnector>,
+    _registration: InMemoryTunnelRegistryIdentifier,
+    node_b_task: JoinHandle<()>,
+    edge_task: JoinHandle<()>,
+  }
+
+  impl ForwardingNodes {
+    async fn new(edge_service: ArcService<anyhow::Error>) -> Self {
+      // Node B holds the edge's tunnel, and an inter-node tunnel from node A, which it trusts
+      let node_b = NodeAddress::new("node-b", SocketAddr::from((Ipv4Addr::LOCALHOST, 9090)));
+      let inter_node = duplex::channel();
+      let edge = duplex::channel();
+      let edge_to_b: ArcTunnel<'static> = Arc::new(edge.listener);
+      let a_to_b: ArcTunnel<'static> = Arc::new(inter_node.connector);
+      let b_from_a: ArcTunnel<'static> = Arc::new(inter_node.listener);
+      let tunnels = TestTunnels {
+        by_name: [(TunnelName::new("edge-1"), edge_to_b)]
+          .into_iter()
+          .collect(),
+        names: [(*b_from_a.id(), TunnelName::new("node-a"))]
+          .into_iter()
+          .collect(),
+      };
+      let forwarding =
+        ClusterForwardingService::new(Arc::new(tunnels), [TunnelName::new("node-a")]);
+      let node_b_task = tokio::task::spawn(serve_one(b_from_a, Arc::new(forwarding)));
+      let edge_task = tokio::task::spawn(serve_one(Arc::new(edge.connector), edge_service));
+
+      // Node A only knows of the edge through the registry
+      let registry = Arc::new(InMemoryTunnelRegistry::new());
+      let registration = registry
+        .register(
+          TunnelName::new("edge-1"),
+          &ClusterRecord::new(node_b.clone(), ()),
+        )
+        .await
+        .unwrap();
+      let connector =
+        move |node: &NodeAddress| -> BoxFuture<'static, Result<ArcTunnel<'static>, TunnelError>> {
+          assert_eq!(node.node_id, "node-b");
+          futures::future::ready(Ok(Arc::clone(&a_to_b))).boxed()
+        };
+      let connector: Arc<dyn NodeConnector> = Arc::new(connector);
+      let router = ClusterRouter::new(
+        "node-a",
+        Arc::new(LoopbackRouter::new(TunnelName::new("local"))),
+        registry,
+        connector,
+      );
+      Self {
+        router,
+        _registration: registration,
+        node_b_task,
+        edge_task,
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn forward_to_owning_node() {
+    let echo = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::task::spawn(async move {
+      let (mut connection, _) = echo.accept().await.unwrap();
+      let (mut reader, mut writer) = connection.split();
+      tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    let nodes = ForwardingNodes::new(Arc::new(TcpStreamService::new(true))).await;
+    let router = &nodes.router;
+
+    let (mut user, local) = tokio::io::duplex(8192);
+    let (local_reader, local_writer) = tokio::io::split(local);
+    let request = Request::new(
+      TcpStreamClient::new(local_reader, local_writer),
+      TcpStreamTarget::SocketAddr(echo_addr),
+    )
+    .unwrap();
+    let client = router
+      .route(request, TunnelName::new("edge-1"))
+      .await
+      .unwrap();
+    let client_task = tokio::task::spawn(client);
+
+    user.write_all(b"ping").await.unwrap();
+    let mut buffer = [0u8; 4];
+    user.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+    drop(user);
+    assert_eq!(client_task.await.unwrap().unwrap(), (4, 4));
+    nodes.edge_task.await.unwrap();
+    nodes.node_b_task.await.unwrap();
+
+    // Tunnels registered to neither node are left to the local router
+    let (_, local) = tokio::io::duplex(64);
+    let (local_reader, local_writer) = tokio::io::split(local);
+    let request = Request::new(
+      TcpStreamClient::new(local_reader, local_writer),
+      TcpStreamTarget::SocketAddr(echo_addr),
+    )
+    .unwrap();
+    assert!(router
+      .route(request, TunnelName::new("edge-2"))
+      .await
+      .is_err());
+  }
+
+  /// Test that request headers reach the service of a tunnel held by another node
+  #[tokio::test]
+  async fn forward_headers() {
+    let (service, mut handled) = RecordingService::new(0..=1);
+    let nodes = ForwardingNodes::new(Arc::new(service)).await;
+
+    let request = Request::new(VersionedClient, "/versioned".parse().unwrap())
+      .unwrap()
+      .with_header("tenant", "contoso");
+    let client = nodes
+      .router
+      .route(request, TunnelName::new("edge-1"))
+      .await
+      .unwrap();
+    assert_eq!(client.await.unwrap(), 1);
+    nodes.edge_task.await.unwrap();
+    nodes.node_b_task.await.unwrap();
+    let (_, headers) = handled.recv().await.unwrap();
+    assert_eq!(headers.get("tenant").map(String::as_str), Some("contoso"));
+  }
+}
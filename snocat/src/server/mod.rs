@@ -7,6 +7,8 @@ use std::collections::HashSet;
 use std::{ops::RangeInclusive, sync::Arc};
 use tokio::sync::Mutex;
 
+pub mod cluster;
+
 #[derive(Debug, Clone)]
 pub struct PortRangeAllocator {
   range: std::ops::RangeInclusive<u16>,